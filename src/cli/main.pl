@@ -1138,7 +1138,13 @@ opt_spec(role,create,'terminusdb role create ROLE_NAME ACTION_1 .. ACTION_N OPTI
            longflags([help]),
            shortflags([h]),
            default(false),
-           help('print help for the `role create` sub command')]]).
+           help('print help for the `role create` sub command')],
+          [opt(policy),
+           type(atom),
+           longflags([policy]),
+           shortflags([p]),
+           default('_'),
+           help('A JSON list of access policies, each with a `class`, and optionally the properties to `hide` or `mask` and a `restriction` documents have to satisfy.')]]).
 opt_spec(role,delete,'terminusdb role create ROLE_ID_OR_ROLE_NAME',
          'Delete a role from the system database',
          [[opt(help),
@@ -1166,7 +1172,13 @@ opt_spec(role,update,'terminusdb role update ROLE_ID_OR_ROLE_NAME ACTIONS OPTION
            longflags([id]),
            shortflags([i]),
            default(false),
-           help('Interpret argument as a role Id rather than a name.')]]).
+           help('Interpret argument as a role Id rather than a name.')],
+          [opt(policy),
+           type(atom),
+           longflags([policy]),
+           shortflags([p]),
+           default('_'),
+           help('A JSON list of access policies, each with a `class`, and optionally the properties to `hide` or `mask` and a `restriction` documents have to satisfy.')]]).
 opt_spec(role,get,'terminusdb role get <ROLE_ID_OR_ROLE_NAME>',
          'Get a role description from name or id, or all roles if unspecified.',
          [[opt(help),
@@ -1497,6 +1509,14 @@ opt_authority(Opt, Auth) :-
     ->  super_user_authority(Auth)
     ;   get_user_or_halt(User, Auth)).
 
+opt_role_policies(Opts, Role, New_Role) :-
+    option(policy(Policy_Atom), Opts),
+    (   var(Policy_Atom)
+    ->  New_Role = Role
+    ;   atom_json_dict(Policy_Atom, Policies, [default_tag(json)]),
+        put_dict(policy, Role, Policies, New_Role)
+    ).
+
 command(Command) :-
     opt_spec_expanded(Command,_,_,_).
 command(Command) :-
@@ -2259,11 +2279,12 @@ run_command(role,create,[Name|Actions], Opts) :-
     opt_authority(Opts, Auth),
     create_context(system_descriptor{}, System_DB),
     atom_concat('Role/',Name,Name_Id),
-    Role =
+    Action_Role =
     _{  '@id' : Name_Id,
         name : Name,
         action : Actions
     },
+    opt_role_policies(Opts, Action_Role, Role),
     api_report_errors(
         role,
         api_add_role(System_DB,Auth,Role,Id)
@@ -2329,7 +2350,8 @@ run_command(role,update,[Role_Id_or_Name|Actions], Opts) :-
         )
     ),
 
-    put_dict(action,Role,Actions,New_Role),
+    put_dict(action,Role,Actions,Action_Role),
+    opt_role_policies(Opts, Action_Role, New_Role),
     api_report_errors(
         role,
         api_update_role(System_DB,Auth,New_Role)
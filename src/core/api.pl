@@ -133,6 +133,7 @@
               api_generate_document_ids/4,
               api_get_documents/4,
               api_get_document/5,
+              document_shape/5,
               call_catch_document_mutation/2,
 
               % api_user_organizations.pl
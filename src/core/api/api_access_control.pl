@@ -104,10 +104,11 @@ api_add_role(_, Auth, Role, Id) :-
         Role,
         _{ '@id' : (*),
            name: (*),
-           action: (*) },
+           action: (*),
+           policy: (*) },
         New_Role
     ),
-    put_dict('@type', New_Role, 'Role', Typed_Role),
+    typed_role(New_Role, Typed_Role),
 
     create_context(system_descriptor{}, commit_info{author: "admin", message: "API: Add Role"},
                    System_Context),
@@ -127,10 +128,11 @@ api_update_role(_, Auth, Role) :-
         Role,
         _{ '@id' : (*),
            name: (*),
-           action: (*)},
+           action: (*),
+           policy: (*) },
         New_Role
     ),
-    put_dict('@type', New_Role, 'Role', Typed_Role),
+    typed_role(New_Role, Typed_Role),
 
     create_context(system_descriptor{}, commit_info{author: "admin", message: "API: Update Role"},
                    System_Context),
@@ -141,6 +143,20 @@ api_update_role(_, Auth, Role) :-
         _
     ).
 
+/*
+ * typed_role(+Role, -Typed_Role) is det.
+ *
+ * Add the types of a role and of the access policies it carries.
+ */
+typed_role(Role, Typed_Role) :-
+    (   get_dict(policy, Role, Policies)
+    ->  maplist([Policy,Typed_Policy]>>put_dict('@type', Policy, 'AccessPolicy', Typed_Policy),
+                Policies, Typed_Policies),
+        put_dict(policy, Role, Typed_Policies, Role_With_Policies)
+    ;   Role_With_Policies = Role
+    ),
+    put_dict('@type', Role_With_Policies, 'Role', Typed_Role).
+
 api_delete_role(_, Auth, Role_Id) :-
     do_or_die(
         is_super_user(Auth),
@@ -12,13 +12,17 @@
               api_generate_document_ids/4,
               api_get_documents/4,
               api_get_document/5,
+              document_shape/5,
               api_full_replace_schema/2,
               idlists_duplicates_toplevel/3,
               nonground_captures/2,
 
               api_insert_documents_core_string/7,
               api_replace_documents_core_string/6,
-              api_delete_documents_by_ids/3
+              api_replace_documents_core_string/7,
+              api_delete_documents_by_ids/3,
+              api_delete_documents_by_ids/4,
+              api_patch_documents_core_string/3
           ]).
 
 :- use_module(core(util)).
@@ -36,6 +40,7 @@
 :- use_module(library(plunit)).
 :- use_module(library(apply)).
 :- use_module(library(pprint), [print_term/2]).
+:- use_module(library(http/json)).
 
 before_read(Descriptor, Requested_Data_Version, Actual_Data_Version, Transaction) :-
     do_or_die(
@@ -100,9 +105,10 @@ api_generate_document_ids_by_query(schema, _Transaction, _Type, _Query, _Config,
 
 api_print_documents_by_query(Transaction, Type, Query, Config, Stream_Started) :-
     '$doc':get_document_context(Transaction, Context),
+    config_document_shape(Config, Shape),
     forall(api_document:api_generate_document_ids_by_query(instance, Transaction, Type, Query, Config, Id),
            (   Stream_Started = started(Started),
-               do_or_die('$doc':print_document_json(current_output, Context, Id, Config.as_list, (Config.compress), (Config.unfold), (Config.minimized), Started),
+               do_or_die('$doc':print_document_json(current_output, Context, Id, Config.as_list, (Config.compress), (Config.unfold), (Config.minimized), Started, Shape),
                          error(document_not_found(Id), _)),
                nb_setarg(1, Stream_Started, true)
            )).
@@ -116,14 +122,59 @@ api_document_exists(schema, Transaction, Id) :-
 api_document_exists(instance, Transaction, Id) :-
     document_exists(Transaction, Id).
 
+%% config_document_shape(+Config, -Shape) is det.
+%
+% The shape documents are retrieved in: either `none` or the json form
+% of the projection, unfold depth and output form, as built by
+% `document_shape/5`.
+config_document_shape(Config, Shape) :-
+    (   get_dict(shape, Config, Shape)
+    ->  true
+    ;   Shape = none
+    ).
+
+%% document_shape(+Include, +Exclude, +Max_Depth, +Canonical, -Shape) is det.
+%
+% Include and Exclude are lists of (dotted) field paths, or unbound if
+% not given. Max_Depth is a non-negative integer or `unlimited`.
+% Canonical says whether documents are written as RFC 8785 canonical
+% json.
+document_shape(Include, Exclude, Max_Depth, Canonical, Shape) :-
+    (   var(Include),
+        var(Exclude),
+        Max_Depth = unlimited,
+        Canonical = false
+    ->  Shape = none
+    ;   (   var(Include)
+        ->  Include_List = []
+        ;   Include_List = Include
+        ),
+        (   var(Exclude)
+        ->  Exclude_List = []
+        ;   Exclude_List = Exclude
+        ),
+        (   Max_Depth = unlimited
+        ->  Depth = null
+        ;   Depth = Max_Depth
+        ),
+        atom_json_dict(Shape_Atom,
+                       json{include: Include_List,
+                            exclude: Exclude_List,
+                            maxDepth: Depth,
+                            canonical: Canonical},
+                       [width(0)]),
+        atom_string(Shape_Atom, Shape)
+    ).
+
 api_print_document(Graph_Type, Transaction, Id, Config) :-
     api_print_document(Graph_Type, Transaction, Id, Config, started(true)).
 api_print_document(instance, Transaction, Id, Config, Stream_Started) :-
     '$doc':get_document_context(Transaction, Context),
+    config_document_shape(Config, Shape),
     Stream_Started = started(Started),
     database_prefixes(Transaction, Prefixes),
     prefix_expand(Id, Prefixes, Id_Ex),
-    do_or_die('$doc':print_document_json(current_output, Context, Id_Ex, Config.as_list, (Config.compress), (Config.unfold), (Config.minimized), Started),
+    do_or_die('$doc':print_document_json(current_output, Context, Id_Ex, Config.as_list, (Config.compress), (Config.unfold), (Config.minimized), Started, Shape),
               error(document_not_found(Id), _)),
    nb_setarg(1, Stream_Started, true).
 api_print_document(schema, Transaction, Id, Config, Stream_Started) :-
@@ -136,22 +187,24 @@ api_print_documents(schema, Transaction, Config, Stream_Started) :-
            json_stream_write_dict(Config, Stream_Started, Document)).
 api_print_documents(instance, Transaction, Config, _Stream_Started) :-
     '$doc':get_document_context(Transaction, Context),
+    config_document_shape(Config, Shape),
     (   parallelize_enabled
-    ->  '$doc':par_print_all_documents_json(current_output, Context, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))
-    ;   '$doc':print_all_documents_json(current_output, Context, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))).
+    ->  '$doc':par_print_all_documents_json(current_output, Context, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)
+    ;   '$doc':print_all_documents_json(current_output, Context, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)).
 
 api_print_documents_by_type(schema, Transaction, Config, Type, Stream_Started) :-
     forall(api_get_documents_by_type(Transaction, schema, Type, Config, Document),
            json_stream_write_dict(Config, Stream_Started, Document)).
 api_print_documents_by_type(instance, Transaction, Config, Type, _Stream_Started) :-
     '$doc':get_document_context(Transaction, Context),
+    config_document_shape(Config, Shape),
     database_and_default_prefixes(Transaction,Prefixes),
     % TODO errors on unknown prefix
     prefix_expand_schema(Type, Prefixes, Type_Ex),
 
     (   parallelize_enabled
-    ->  '$doc':par_print_all_documents_json_by_type(current_output, Context, Type_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))
-    ;   '$doc':print_all_documents_json_by_type(current_output, Context, Type_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))).
+    ->  '$doc':par_print_all_documents_json_by_type(current_output, Context, Type_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)
+    ;   '$doc':print_all_documents_json_by_type(current_output, Context, Type_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)).
 
 api_print_documents_by_id(schema, Transaction, Config, Ids, Stream_Started) :-
     forall((member(Id, Ids),
@@ -159,13 +212,14 @@ api_print_documents_by_id(schema, Transaction, Config, Ids, Stream_Started) :-
            json_stream_write_dict(Config, Stream_Started, Document)).
 api_print_documents_by_id(instance, Transaction, Config, Ids, _Stream_Started) :-
     '$doc':get_document_context(Transaction, Context),
+    config_document_shape(Config, Shape),
     database_and_default_prefixes(Transaction, Prefixes),
     maplist({Prefixes}/[Id, Id_Ex]>>prefix_expand(Id, Prefixes, Id_Ex),
             Ids,
             Ids_Ex),
     (   parallelize_enabled
-    ->  '$doc':par_print_documents_json_by_id(current_output, Context, Ids_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))
-    ;   '$doc':print_documents_json_by_id(current_output, Context, Ids, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized))).
+    ->  '$doc':par_print_documents_json_by_id(current_output, Context, Ids_Ex, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)
+    ;   '$doc':print_documents_json_by_id(current_output, Context, Ids, (Config.skip), (Config.count), (Config.as_list), (Config.compress), (Config.unfold), (Config.minimized), Shape)).
 
 api_get_document(instance, Transaction, Id, Config, Document) :-
     do_or_die(get_document(Transaction, Config.compress, Config.unfold, Id, Document),
@@ -343,7 +397,19 @@ api_insert_documents(SystemDB, Auth, Path, Stream, Requested_Data_Version, New_D
                      Options),
     meta_data_version(Transaction, Meta_Data, New_Data_Version).
 
+api_insert_documents_core(Transaction, Stream, instance, Raw_JSON, false, false, Ids) :-
+    !,
+    ensure_transaction_has_builder(instance, Transaction),
+    read_string(Stream, _, String),
+    (   native_write_documents(insert_documents, Transaction, String, Raw_JSON, Ids)
+    ->  true
+    ;   open_string(String, Fallback_Stream),
+        api_insert_documents_core_(Transaction, Fallback_Stream, instance, Raw_JSON, false, false, Ids)
+    ).
 api_insert_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Full_Replace, Doc_Merge, Ids) :-
+    api_insert_documents_core_(Transaction, Stream, Graph_Type, Raw_JSON, Full_Replace, Doc_Merge, Ids).
+
+api_insert_documents_core_(Transaction, Stream, Graph_Type, Raw_JSON, Full_Replace, Doc_Merge, Ids) :-
     empty_assoc(Captures_In),
     ensure_transaction_has_builder(Graph_Type, Transaction),
     insert_documents_(Full_Replace, Graph_Type, Raw_JSON, Stream, Transaction, Captures_In, Captures_Out, BackLinks, Ids_List),
@@ -358,6 +424,27 @@ api_insert_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Full_Replac
                error(same_ids_in_one_transaction(Duplicates), _))
     ).
 
+%% native_write_documents(+Mode, +Transaction, +String, +Raw_JSON, -Ids) is semidet.
+%
+% Write a payload of instance documents with the native writer, where
+% Mode is insert_documents, replace_documents or
+% replace_or_create_documents. This fails without touching the
+% transaction when the native writer rejects the payload, be it for
+% features it leaves to json.pl (@capture, @ref, @linked-by) or for an
+% error, which the Prolog path then reports in its usual form.
+native_write_documents(Mode, Transaction, String, Raw_JSON, Ids) :-
+    '$doc':get_document_context(Transaction, Context),
+    catch(native_write_documents_(Mode, Context, Transaction, String, Raw_JSON, Ids),
+          error(_, _),
+          fail).
+
+native_write_documents_(insert_documents, Context, Transaction, String, Raw_JSON, Ids) :-
+    '$doc':insert_documents(Context, Transaction, String, Raw_JSON, Ids).
+native_write_documents_(replace_documents, Context, Transaction, String, Raw_JSON, Ids) :-
+    '$doc':replace_documents(Context, Transaction, String, Raw_JSON, false, Ids).
+native_write_documents_(replace_or_create_documents, Context, Transaction, String, Raw_JSON, Ids) :-
+    '$doc':replace_documents(Context, Transaction, String, Raw_JSON, true, Ids).
+
 api_insert_documents_core_string(Transaction, String, Graph_Type, Raw_JSON, Full_Replace, Doc_Merge, Ids) :-
     open_string(String, Stream),
     api_insert_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Full_Replace, Doc_Merge, Ids).
@@ -426,6 +513,15 @@ api_delete_documents(SystemDB, Auth, Path, Stream, Requested_Data_Version, New_D
     meta_data_version(Transaction, Meta_Data, New_Data_Version).
 
 api_delete_documents_by_ids(Transaction, Graph_Type, Ids) :-
+    api_delete_documents_by_ids(Transaction, Graph_Type, Ids, _).
+
+api_delete_documents_by_ids(Transaction, Graph_Type, Ids, If_Matches) :-
+    (   var(If_Matches)
+    ->  true
+    ;   same_length(Ids, If_Matches)
+    ->  maplist(api_check_if_match(Graph_Type, Transaction), Ids, If_Matches)
+    ;   throw(error(if_match_count_mismatch(Ids, If_Matches), _))
+    ),
     forall(
         member(Id, Ids),
         (   atom_string(Id_Atom, Id),
@@ -433,15 +529,31 @@ api_delete_documents_by_ids(Transaction, Graph_Type, Ids) :-
         )
     ).
 
+%% api_check_if_match(+Graph_Type, +Transaction, +Id, ?If_Match) is det.
+%
+% If_Match is either unbound, or the content hash document Id must
+% have for a replace or delete of it to go ahead.
+api_check_if_match(_Graph_Type, _Transaction, _Id, If_Match) :-
+    var(If_Match),
+    !.
+api_check_if_match(instance, Transaction, Id, If_Match) :-
+    !,
+    check_document_hash(Transaction, Id, If_Match).
+api_check_if_match(_Graph_Type, _Transaction, _Id, _If_Match) :-
+    throw(error(if_match_is_only_supported_for_instance_graphs, _)).
+
 api_delete_document(SystemDB, Auth, Path, ID, Requested_Data_Version, New_Data_Version, Options) :-
     option(graph_type(Graph_Type), Options),
     option(author(Author), Options),
     option(message(Message), Options),
+    option(if_match(If_Match), Options, _),
 
     resolve_descriptor_auth(write, SystemDB, Auth, Path, Graph_Type, Descriptor),
     before_write(Descriptor, Author, Message, Requested_Data_Version, Context, Transaction),
     with_transaction(Context,
-                     api_delete_document_(Graph_Type, Transaction, ID),
+                     (   api_check_if_match(Graph_Type, Transaction, ID, If_Match),
+                         api_delete_document_(Graph_Type, Transaction, ID)
+                     ),
                      Meta_Data,
                      Options),
     meta_data_version(Transaction, Meta_Data, New_Data_Version).
@@ -510,18 +622,62 @@ api_replace_documents(SystemDB, Auth, Path, Stream, Requested_Data_Version, New_
     option_or_die(author(Author),Options),
     option(message(Message),Options),
     option(raw_json(Raw_JSON),Options,false),
+    option(if_match(If_Match),Options,_),
     resolve_descriptor_auth(write, SystemDB, Auth, Path, Graph_Type, Descriptor),
     before_write(Descriptor, Author, Message, Requested_Data_Version, Context, Transaction),
     stream_property(Stream, position(Pos)),
     with_transaction(Context,
                      (   set_stream_position(Stream, Pos),
-                         api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, Ids)
+                         api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, If_Match, Ids)
                      ),
                      Meta_Data,
                      Options),
     meta_data_version(Transaction, Meta_Data, New_Data_Version).
 
 api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, Ids) :-
+    api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, _, Ids).
+
+api_replace_documents_core(Transaction, Stream, instance, Raw_JSON, Create, If_Match, Ids) :-
+    !,
+    ensure_transaction_has_builder(instance, Transaction),
+    read_string(Stream, _, String),
+    api_check_replace_if_match(Transaction, String, If_Match),
+    (   Create = true
+    ->  Mode = replace_or_create_documents
+    ;   Mode = replace_documents
+    ),
+    (   native_write_documents(Mode, Transaction, String, Raw_JSON, Ids)
+    ->  true
+    ;   open_string(String, Fallback_Stream),
+        api_replace_documents_core_(Transaction, Fallback_Stream, instance, Raw_JSON, Create, Ids)
+    ).
+api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, If_Match, Ids) :-
+    api_check_if_match(Graph_Type, Transaction, _, If_Match),
+    api_replace_documents_core_(Transaction, Stream, Graph_Type, Raw_JSON, Create, Ids).
+
+%% api_check_replace_if_match(+Transaction, +String, ?If_Match) is det.
+%
+% Check If_Match against the single document a replace payload holds,
+% before anything of it is written.
+api_check_replace_if_match(_Transaction, _String, If_Match) :-
+    var(If_Match),
+    !.
+api_check_replace_if_match(Transaction, String, If_Match) :-
+    open_string(String, Stream),
+    stream_to_lazy_docs(Stream, Documents),
+    (   Documents = [Document]
+    ->  do_or_die(get_dict('@id', Document, Id),
+                  error(missing_field('@id', Document), _)),
+        api_check_if_match(instance, Transaction, Id, If_Match)
+    ;   findall(Id,
+                (   member(Document, Documents),
+                    get_dict('@id', Document, Id)
+                ),
+                Ids),
+        throw(error(if_match_requires_single_document(Ids), _))
+    ).
+
+api_replace_documents_core_(Transaction, Stream, Graph_Type, Raw_JSON, Create, Ids) :-
     empty_assoc(Captures),
     ensure_transaction_has_builder(Graph_Type, Transaction),
     stream_to_lazy_docs(Stream, Lazy_List),
@@ -539,8 +695,14 @@ api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, Id
     die_if(Duplicates \= [], error(same_ids_in_one_transaction(Duplicates), _)).
 
 api_replace_documents_core_string(Transaction, String, Graph_Type, Raw_JSON, Create, Ids) :-
+    api_replace_documents_core_string(Transaction, String, Graph_Type, Raw_JSON, Create, _, Ids).
+
+api_replace_documents_core_string(Transaction, String, Graph_Type, Raw_JSON, Create, If_Match, Ids) :-
     open_string(String, Stream),
-    api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, Ids).
+    api_replace_documents_core(Transaction, Stream, Graph_Type, Raw_JSON, Create, If_Match, Ids).
+
+api_patch_documents_core_string(Transaction, String, Ids) :-
+    patch_documents(Transaction, String, Ids).
 
 api_can_read_document(System_DB, Auth, Path, Graph_Type, Requested_Data_Version, Actual_Data_Version) :-
     resolve_descriptor_auth(read, System_DB, Auth, Path, Graph_Type, Descriptor),
@@ -252,6 +252,33 @@ api_global_error_jsonld(error(document_not_found(Id, Document), _), Type, JSON)
                               'api:document': Document },
              'api:message' : Msg
             }.
+api_global_error_jsonld(error(document_hash_mismatch(Id, Expected, Actual), _), Type, JSON) :-
+    error_type(Type, Type_Displayed),
+    format(string(Msg), "Document ~q has changed: its hash is ~w, not ~w", [Id, Actual, Expected]),
+    JSON = _{'@type' : Type_Displayed,
+             'api:status' : "api:precondition_failed",
+             'api:error' : _{ '@type' : 'api:DocumentHashMismatch',
+                              'api:document_id' : Id,
+                              'api:expected' : Expected,
+                              'api:actual' : Actual },
+             'api:message' : Msg
+            }.
+api_global_error_jsonld(error(delete_restricted(References), _), Type, JSON) :-
+    error_type(Type, Type_Displayed),
+    length(References, Count),
+    format(string(Msg), "Deletion is restricted by ~q reference(s)", [Count]),
+    findall(_{ 'api:document' : Document,
+               'api:subject' : Subject,
+               'api:predicate' : Predicate,
+               'api:object' : Object },
+            member(reference(Document, Subject, Predicate, Object), References),
+            Blocking),
+    JSON = _{'@type' : Type_Displayed,
+             'api:status' : "api:conflict",
+             'api:error' : _{ '@type' : 'api:DeleteRestricted',
+                              'api:references' : Blocking },
+             'api:message' : Msg
+            }.
 api_global_error_jsonld(error(submitted_id_does_not_match_generated_id(Submitted_Id, Generated_Id), _), Type, JSON) :-
     error_type(Type, Type_Displayed),
     format(string(Msg), "Document was submitted with id ~q, but id ~q was generated", [Submitted_Id, Generated_Id]),
@@ -1823,6 +1850,31 @@ api_document_error_jsonld(Type, error(same_ids_in_one_transaction(Ids), _), JSON
                               'api:duplicate_ids' : Ids},
              'api:message' : Msg
             }.
+api_document_error_jsonld(Type, error(if_match_requires_single_document(Ids), _), JSON) :-
+    document_error_type(Type, JSON_Type),
+    format(string(Msg), "ifMatch can only be used when replacing a single document", []),
+    JSON = _{'@type' : JSON_Type,
+             'api:status' : "api:failure",
+             'api:error' : _{ '@type' : 'api:IfMatchRequiresSingleDocument',
+                              'api:document_ids' : Ids},
+             'api:message' : Msg
+            }.
+api_document_error_jsonld(Type, error(if_match_requires_id, _), JSON) :-
+    document_error_type(Type, JSON_Type),
+    format(string(Msg), "ifMatch can only be used when deleting a single document by id", []),
+    JSON = _{'@type' : JSON_Type,
+             'api:status' : "api:failure",
+             'api:error' : _{ '@type' : 'api:IfMatchRequiresId'},
+             'api:message' : Msg
+            }.
+api_document_error_jsonld(Type, error(if_match_is_only_supported_for_instance_graphs, _), JSON) :-
+    document_error_type(Type, JSON_Type),
+    format(string(Msg), "ifMatch is currently only supported for instance graphs", []),
+    JSON = _{'@type' : JSON_Type,
+             'api:status' : "api:failure",
+             'api:error' : _{ '@type' : 'api:IfMatchOnlySupportedForInstanceGraphs'},
+             'api:message' : Msg
+            }.
 api_document_error_jsonld(Type, error(document_access_impossible(Descriptor, Graph_Type, Read_Write), _), JSON) :-
     document_error_type(Type, JSON_Type),
     resolve_absolute_string_descriptor(Descriptor_String, Descriptor),
@@ -2696,6 +2748,7 @@ status_http_code('api:forbidden',403).
 status_http_code('api:not_found',404).
 status_http_code('api:method_not_allowed',405).
 status_http_code('api:conflict',409).
+status_http_code('api:precondition_failed',412).
 status_http_code('api:server_error',500).
 
 status_cli_code('api:success',0).
@@ -2704,6 +2757,7 @@ status_cli_code('api:not_found',2).
 status_cli_code('api:unauthorized',13).
 status_cli_code('api:forbidden',13).
 status_cli_code('api:method_not_allowed',126).
+status_cli_code('api:precondition_failed',1).
 status_cli_code('api:server_error',131).
 
 :- begin_tests(error_reporting).
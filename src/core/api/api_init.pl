@@ -149,6 +149,9 @@ initialize_schema_graph(Simple_Graph_Name, Store, Graph_Name, Graph_String, Forc
     open_string(Graph_String, Graph_Stream),
     create_graph_from_json(Store, Graph_Name, Graph_Stream, schema, Layer).
 
+initialize_system_schema(Store, Force) :-
+    initialize_system_schema(Store, Force, _).
+
 initialize_system_schema(Store, Force, Layer) :-
     system_schema_name(Schema_Name),
     system_schema(System_Schema_String),
@@ -197,6 +200,7 @@ initialize_database_with_store(Key, Store, Force) :-
 
     initialize_system_instance(Store, System_Schema, Key, Force).
 
+current_system_version("v1.0.1").
 current_woql_version("v1.0.3").
 current_repository_version("v1.0.1").
 current_ref_version("v1.0.1").
@@ -209,6 +213,8 @@ has_no_store :-
         true
     ).
 
+current_schema_version(system_schema, Version) :-
+    current_system_version(Version).
 current_schema_version(repo_schema, Version) :-
     current_repository_version(Version).
 current_schema_version(ref_schema, Version) :-
@@ -241,6 +247,16 @@ update_system_graph(Label, Path, Predicate, Initialization) :-
         abolish_all_tables
     ).
 
+% The system instance graph is left as is, so changes to the system
+% schema have to keep existing instance data valid.
+update_system_schema_graph :-
+    system_schema_name(System_Label),
+    api_init:system_schema_json(System_Path),
+    update_system_graph(System_Label,
+                        System_Path,
+                        system_schema,
+                        api_init:initialize_system_schema).
+
 update_repository_graph :-
     repository_ontology(Repo_Label),
     api_init:repository_schema_json(Repo_Path),
@@ -268,7 +284,8 @@ update_woql_graph :-
 update_system_graphs :-
     (   has_no_store
     ->  true
-    ;   update_repository_graph,
+    ;   update_system_schema_graph,
+        update_repository_graph,
         update_commit_graph,
         update_woql_graph
     ).
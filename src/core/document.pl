@@ -37,6 +37,9 @@
               get_schema_document_uri_by_type/3,
               delete_document/2,
               delete_documents_by_type/3,
+              check_document_hash/3,
+              patch_documents/3,
+              referential_integrity/3,
               insert_document/3,
               insert_document/7,
               insert_document_unsafe/8,
@@ -711,10 +711,59 @@ refute_subject_1(Validation_Object,S_Id,Witness) :-
                       subject: Subject
                   }).
 
+refute_instance(Validation_Object, Witness) :-
+    native_instance_witnesses(Validation_Object, Witnesses),
+    !,
+    (   member(Witness, Witnesses)
+    ;   refute_changed_keys(Validation_Object, Witness)
+    ).
 refute_instance(Validation_Object, Witness) :-
     subject_changed(Validation_Object, Subject),
     refute_subject(Validation_Object,Subject,Witness).
 
+/*
+ * native_instance_witnesses(+Validation_Object, -Witnesses) is semidet.
+ *
+ * The witnesses '$doc':validate_layer finds for the changes to the
+ * instance layer. Fails when the native validator does not cover the
+ * case, which is for foreign types, for anything but a single instance
+ * and schema graph, and when it raises an error, leaving validation to
+ * refute_subject/3.
+ */
+native_instance_witnesses(Validation_Object, Witnesses) :-
+    \+ is_foreign(Validation_Object, _),
+    database_instance(Validation_Object, [Instance]),
+    database_schema(Validation_Object, [Schema]),
+    read_write_obj_reader(Instance, Layer),
+    read_write_obj_reader(Schema, Schema_Layer),
+    ground(Layer-Schema_Layer),
+    (   terminus_store:parent(Layer, Base)
+    ->  true
+    ;   true
+    ),
+    catch('$doc':validate_layer(Schema_Layer, Base, Layer, Strings),
+          error(_, _),
+          fail),
+    maplist(native_witness, Strings, Witnesses).
+
+native_witness(String, Witness) :-
+    atom_json_dict(String, Dict, [value_string_as(atom)]),
+    dict_pairs(Dict, _, Pairs),
+    dict_pairs(Witness, witness, Pairs).
+
+/*
+ * refute_changed_keys(+Validation_Object, -Witness) is nondet.
+ *
+ * The key checks of refute_typed_subject/4, which the native validator
+ * leaves to us.
+ */
+refute_changed_keys(Validation_Object, Witness) :-
+    subject_updated(Validation_Object, S_Id),
+    instance_layer(Validation_Object, Layer),
+    terminus_store:subject_id(Layer, Subject, S_Id),
+    instance_of(Validation_Object, Subject, Class),
+    refute_key(Validation_Object, S_Id, P_Id, Class, Witness).
+
 refute_instance_schema(Validation_Object, Witness) :-
     refute_schema(Validation_Object,Witness).
 refute_instance_schema(Validation_Object, Witness) :-
@@ -20,6 +20,9 @@
               get_schema_document_uri_by_type/3,
               delete_document/2,
               delete_documents_by_type/3,
+              check_document_hash/3,
+              patch_documents/3,
+              referential_integrity/3,
               delete_subdocument/3,
               insert_document/3,
               insert_document/7,
@@ -2694,6 +2697,76 @@ delete_documents_by_type(Query_Context, Type, Unlink) :-
     query_default_collection(Query_Context, TO),
     delete_documents_by_type(TO, Type, Unlink).
 
+/*
+ * check_document_hash(+DB, +Id, +Hash) is det.
+ *
+ * Dies unless the document Id currently exists with the content hash
+ * Hash. The hash is over the document as it was before the
+ * transaction started.
+ */
+check_document_hash(DB, Id, Hash) :-
+    is_transaction(DB),
+    !,
+    database_prefixes(DB,Prefixes),
+    prefix_expand(Id,Prefixes,Id_Ex),
+    '$doc':get_document_context(DB, Context),
+    '$doc':check_document_hash(Context, Id_Ex, Hash).
+check_document_hash(Query_Context, Id, Hash) :-
+    is_query_context(Query_Context),
+    !,
+    query_default_collection(Query_Context, TO),
+    check_document_hash(TO, Id, Hash).
+
+/*
+ * patch_documents(+DB, +Patches, -Ids) is det.
+ *
+ * Patches is a json string holding a list of
+ * `{"@id": Id, "patch": Patch}` objects, where Patch is either an
+ * RFC 6902 JSON Patch or an RFC 7396 Merge Patch. Only the triples
+ * that change are written.
+ */
+patch_documents(DB, Patches, Ids) :-
+    is_transaction(DB),
+    !,
+    ensure_transaction_has_builder(instance, DB),
+    '$doc':get_document_context(DB, Context),
+    '$doc':patch_documents(Context, DB, Patches, Ids).
+patch_documents(Query_Context, Patches, Ids) :-
+    is_query_context(Query_Context),
+    !,
+    query_default_collection(Query_Context, TO),
+    patch_documents(TO, Patches, Ids).
+
+/*
+ * referential_integrity(+DB, +Repair, -Broken) is det.
+ *
+ * Broken is the list of node-valued properties in the instance graph
+ * that point at something untyped, or typed as something other than
+ * the range the schema gives, as dicts. This looks at the instance
+ * graph as it was before the transaction started. When Repair is
+ * true, the repairable ones are removed in the transaction as well.
+ */
+referential_integrity(DB, Repair, Broken) :-
+    is_transaction(DB),
+    !,
+    (   Repair = true
+    ->  ensure_transaction_has_builder(instance, DB),
+        '$doc':repair_referential_integrity(DB, Strings)
+    ;   database_schema(DB, [Schema_Object]),
+        database_instance(DB, [Instance_Object]),
+        read_write_obj_reader(Schema_Object, Schema_Layer),
+        read_write_obj_reader(Instance_Object, Layer),
+        ground(Schema_Layer-Layer)
+    ->  '$doc':check_referential_integrity(Schema_Layer, Layer, Strings)
+    ;   Strings = []
+    ),
+    maplist([String, Dict]>>atom_json_dict(String, Dict, []), Strings, Broken).
+referential_integrity(Query_Context, Repair, Broken) :-
+    is_query_context(Query_Context),
+    !,
+    query_default_collection(Query_Context, TO),
+    referential_integrity(TO, Repair, Broken).
+
 nuke_schema_documents(Transaction) :-
     is_transaction(Transaction),
     !,
@@ -2769,7 +2842,8 @@ insert_document(Transaction, Pre_Document, Prefixes, Raw_JSON, Captures, [Id], T
         insert_json_object(Transaction, JSON, Id)
     ;   insert_json_object(Transaction, Document, Id)
     ).
-insert_document(Transaction, Document, Prefixes, false, Captures_In, Ids, SH-ST, Captures_Out) :-
+insert_document(Transaction, Pre_Document, Prefixes, false, Captures_In, Ids, SH-ST, Captures_Out) :-
+    with_native_document_id(Transaction, Pre_Document, Document),
     json_elaborate(Transaction, Document, Prefixes, Captures_In, Elaborated, Id_Pairs, Dependencies, SH-ST, Captures_Out),
     % Are we trying to insert a subdocument?
     do_or_die(
@@ -2799,6 +2873,26 @@ insert_document(Transaction, Document, Prefixes, false, Captures_In, Ids, SH-ST,
              insert_document_expanded(Transaction, Elaborated, _)
          )).
 
+%% with_native_document_id(+Transaction, +Pre_Document, -Document) is det.
+%
+% Fill in the id of a document submitted without one, as generated by
+% '$doc':generate_document_ids. Documents that can't be serialised as
+% they are, such as ones holding unbound captures, and documents whose
+% keys refer to captures are left for json_elaborate to name.
+with_native_document_id(Transaction, Pre_Document, Document) :-
+    \+ get_dict('@id', Pre_Document, _),
+    \+ get_dict('@linked-by', Pre_Document, _),
+    catch(
+        (   atom_json_dict(Text, Pre_Document, [width(0)]),
+            tabled_get_document_context(Transaction, Context),
+            '$doc':generate_document_ids(Context, Text, [Id])
+        ),
+        error(_, _),
+        fail),
+    !,
+    put_dict('@id', Pre_Document, Id, Document).
+with_native_document_id(_Transaction, Document, Document).
+
 extract_return_ids(Id_Pairs, Ids) :-
     convlist([Id-Value,Id]>>(Value\=value_hash), Id_Pairs, Top_Ids),
     % We can't return nothing, even if we're only a value hash...
@@ -2814,7 +2908,8 @@ insert_document_unsafe(Transaction, Prefixes, Document, true, Captures, Id, T-T,
         insert_json_object(Transaction, JSON, Id)
     ;   insert_json_object(Transaction, Document, Id)
     ).
-insert_document_unsafe(Transaction, Prefixes, Document, false, Captures_In, Ids, BLH-BLT, Captures_Out) :-
+insert_document_unsafe(Transaction, Prefixes, Pre_Document, false, Captures_In, Ids, BLH-BLT, Captures_Out) :-
+    with_native_document_id(Transaction, Pre_Document, Document),
     json_elaborate(Transaction, Document, Prefixes, Captures_In, Elaborated, Id_Pairs, Dependencies, BLH-BLT, Captures_Out),
     % Are we trying to insert a subdocument?
     do_or_die(
@@ -4416,6 +4511,52 @@ test(idgen_lexical_set,
     Uri4 = 'http://somewhere.for.now/document/Thing/+none+',
     Uri5 = 'http://somewhere.for.now/document/Thing/+none+'.
 
+test(idgen_lexical_set_standard_order,
+     [setup((setup_temp_store(State),
+             create_db_with_empty_schema("admin", "testdb"),
+             resolve_absolute_string_descriptor("admin/testdb", Desc))),
+      cleanup(teardown_temp_store(State))]) :-
+
+    with_test_transaction(Desc,
+                          C1,
+                          (   insert_schema_document(
+                                  C1,
+                                  _{'@type': "Class",
+                                    '@id': "Numbers",
+                                    '@key': _{'@type': "Lexical",
+                                              '@fields': ["field"]},
+                                    field: _{'@type': "Set",
+                                             '@class': "xsd:integer"}}),
+                              insert_schema_document(
+                                  C1,
+                                  _{'@type': "Class",
+                                    '@id': "Words",
+                                    '@key': _{'@type': "Lexical",
+                                              '@fields': ["field"]},
+                                    field: _{'@type': "Set",
+                                             '@class': "xsd:string"}})
+                          )),
+
+    Numbers = _{'@type': "Numbers", field: [10, 9, 10]},
+    Words = _{'@type': "Words", field: ["foo", "bar", "foo"]},
+    open_descriptor(Desc, Read),
+    '$doc':get_document_context(Read, Context),
+    atom_json_dict(Numbers_Text, Numbers, []),
+    '$doc':generate_document_ids(Context, Numbers_Text, [Native_Uri1]),
+    atom_json_dict(Words_Text, Words, []),
+    '$doc':generate_document_ids(Context, Words_Text, [Native_Uri2]),
+
+    with_test_transaction(Desc,
+                          C2,
+                          (   insert_document(C2, Numbers, Uri1),
+                              insert_document(C2, Words, Uri2)
+                          )),
+
+    Uri1 = 'http://somewhere.for.now/document/Numbers/9++10',
+    Uri2 = 'http://somewhere.for.now/document/Words/bar++foo',
+    Native_Uri1 = Uri1,
+    Native_Uri2 = Uri2.
+
 test(idgen_lexical_list,
      [setup((setup_temp_store(State),
              create_db_with_empty_schema("admin", "testdb"),
@@ -10837,6 +10978,33 @@ test(subdocument_update,
 
     get_document(Desc, Id, _New_Doc).
 
+test(intact_links_are_not_reported,
+     [setup(
+          (   setup_temp_store(State),
+              test_document_label_descriptor(Desc),
+              write_schema(person_schema,Desc)
+          )),
+      cleanup(
+          teardown_temp_store(State)
+      )
+     ]
+    ) :-
+    with_test_transaction(Desc,
+                          C1,
+                          (   insert_document(C1, _{'@type': "Person", name: "Fred"}, _),
+                              insert_document(C1, _{'@type': "MyDoc",
+                                                    title: "Some title",
+                                                    owner: "Person/Fred"}, _)
+                          )),
+    open_descriptor(Desc, Transaction),
+    referential_integrity(Transaction, false, Broken),
+    Broken = [],
+
+    with_test_transaction(Desc,
+                          C2,
+                          referential_integrity(C2, true, Repaired)),
+    Repaired = [].
+
 
 :- end_tests(referential_integrity).
 
@@ -10,6 +10,7 @@
               is_tagged_union/2,
               is_base_type/1,
               is_built_in/1,
+              is_foreign/2,
               is_list_type/1,
               is_array_type/1,
               is_key/1,
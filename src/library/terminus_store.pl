@@ -6,6 +6,11 @@
               open_archive_store/2,
               open_archive_store/3,
               open_grpc_store/5,
+              open_encrypted_archive_store/4,
+              reencrypt_archive_store/3,
+              replicate_store/5,
+              optimize_layer/4,
+              optimize_named_graph/4,
 
               create_named_graph/3,
               open_named_graph/3,
@@ -16,6 +21,10 @@
               nb_set_head/2,
               nb_force_set_head/2,
               nb_force_set_head/3,
+              nb_set_heads/2,
+              label_history/3,
+              nb_restore_head_version/3,
+              nb_restore_head_at/3,
 
               open_write/2,
               merge_base_layers/4,
@@ -61,6 +70,14 @@
               pack_export/3,
               pack_layerids_and_parents/2,
               pack_import/3,
+              pack_export_to_stream/4,
+              pack_export_to_file/4,
+              pack_export_delta/6,
+              pack_import_from_stream/3,
+              pack_import_from_file/3,
+
+              verify_store/5,
+              garbage_collect_layers/7,
 
               count_layer_stack_size/2,
 
@@ -197,6 +214,60 @@ terminus_store_version('0.19.8').
 % @arg Layer the layer to make the new head of the graph.
 % @arg Version the version of the label.
 
+%! nb_set_heads(+Store:store, +Updates:list) is semidet.
+%
+% Sets the heads of several named graphs at once. Updates is a list of
+% `Graph-Version-Layer` terms, where Version is the version the label
+% of Graph is expected to be at, as returned by head/3.
+%
+% Either all heads are set, or none of them. Fails without setting
+% anything if any label is not at its expected version. For stores on
+% disk, a transaction that was interrupted by a crash is completed
+% when the store is opened again. If another process moved one of its
+% labels in the meantime, the transaction is undone instead, and
+% opening the store throws an error naming the labels that moved.
+%
+% Stores opened with open_grpc_store/5 throw
+% `head_transactions_not_available`.
+%
+% This predicate does not support backtracking.
+%
+% @arg Store the store the named graphs live in.
+% @arg Updates the heads to set.
+
+%! label_history(+Store:store, +Name:text, -History:list) is det.
+%
+% Lists every recorded change of a label, oldest first, as terms
+% `label_change(Version, Previous, Layer, Time)`. Previous and Layer
+% are layer ids, or `none` if the label had no layer. Time is in
+% seconds since the epoch, like get_time/1.
+%
+% Stores opened with open_grpc_store/5 keep no history, and throw
+% `label_log_not_available`.
+%
+% @arg Store the store the label lives in.
+% @arg Name the name of the label.
+% @arg History the recorded changes.
+
+%! nb_restore_head_version(+Store:store, +Name:text, +Version:integer) is det.
+%
+% Forces the head of a label back to the layer it pointed at right
+% after the change that gave it the given version. The restore is
+% itself recorded as a new change.
+%
+% @arg Store the store the label lives in.
+% @arg Name the name of the label.
+% @arg Version a version from the label history.
+
+%! nb_restore_head_at(+Store:store, +Name:text, +Time:float) is det.
+%
+% Forces the head of a label back to the layer it pointed at at the
+% given time, in seconds since the epoch.
+%
+% @arg Store the store the label lives in.
+% @arg Name the name of the label.
+% @arg Time the point in time to restore.
+
 %! open_write(+Store_Or_Layer:term, -Builder:layer_builder) is det.
 %
 % Creates a layer builder from either a parent layer, or a store.
@@ -395,6 +466,164 @@ terminus_store_version('0.19.8').
 % @arg Layer The layer from which to obtain the stack.
 % @arg Stack A list of the layer-ids of all ancestors.
 
+%! pack_export_to_stream(+Store:store, +Layer_Ids:list, +Stream:stream, +Compress:boolean) is det.
+%
+% Writes the given layers to a binary stream as a streamed pack. Only
+% one layer is held in memory at a time.
+%
+% @arg Store the store to export from.
+% @arg Layer_Ids the ids of the layers to export.
+% @arg Stream a binary output stream.
+% @arg Compress whether to zstd compress the pack.
+
+%! pack_export_to_file(+Store:store, +Layer_Ids:list, +Path:text, +Compress:boolean) is det.
+%
+% Like pack_export_to_stream/4, but writes to the file at Path.
+
+%! pack_export_delta(+Store:store, +Layer_Id:text, +Known_Ids:list, +Squash:boolean, -Pack:pack, -Head_Id:string) is det.
+%
+% Exports the layers a receiver that already has the layers in
+% Known_Ids needs to load the given layer. With Squash, these layers
+% are squashed into a single layer on top of the newest ancestor the
+% receiver knows about.
+%
+% @arg Store the store to export from.
+% @arg Layer_Id the id of the layer to bring the receiver up to.
+% @arg Known_Ids the ids of layers the receiver already has.
+% @arg Squash whether to squash the missing layers into one.
+% @arg Pack the resulting pack, suitable for pack_import/3.
+% @arg Head_Id the id of the layer the receiver should use as its
+% head, which differs from Layer_Id when squashing.
+
+%! pack_import_from_stream(+Store:store, +Stream:stream, -Imported_Ids:list) is det.
+%
+% Reads a streamed pack from a binary stream, importing the layers
+% that the store doesn't have yet. Throws if the checksum at the end
+% of the pack doesn't match.
+%
+% @arg Store the store to import into.
+% @arg Stream a binary input stream.
+% @arg Imported_Ids the ids of the layers that were imported.
+
+%! pack_import_from_file(+Store:store, +Path:text, -Imported_Ids:list) is det.
+%
+% Like pack_import_from_stream/3, but reads from the file at Path.
+
+%! verify_store(+Path:text, +Kind:atom, +Repair:boolean, -Problems:list, -Quarantined:list) is det.
+%
+% Checks the integrity of the store at Path. Every layer is loaded and
+% the triples it adds and removes are resolved, each child's changes
+% are checked against its parent, each rollup in an archive store is
+% checked to hold the same triples as the layer it rolls up, and each
+% label is checked to point at an existing, loadable layer. The store
+% should not be in use by another process while it is checked.
+% Encrypted archive stores are refused with an error, as their layers
+% can't be read without the keys.
+%
+% Problems are reported as one of
+%
+%   * dangling_label(Label, Layer_Id)
+%   * broken_label(Label, Layer_Id)
+%   * missing_parent(Layer_Id, Parent_Id)
+%   * broken_ancestor(Layer_Id, Ancestor_Id)
+%   * corrupt_layer(Layer_Id, Message)
+%   * inconsistent_delta(Layer_Id, Parent_Id)
+%   * rollup_mismatch(Layer_Id)
+%
+% @arg Path the directory of the store.
+% @arg Kind either `directory` or `archive`, for stores opened with
+% open_directory_store/2 or open_archive_store/2.
+% @arg Repair whether to move layers that can't be loaded into the
+% quarantine directory of the store. Labels are never changed.
+% @arg Problems the problems that were found.
+% @arg Quarantined the ids of the layers that were quarantined.
+
+%! garbage_collect_layers(+Path:text, +Kind:atom, +Grace:integer, +History:integer, +Dry_Run:boolean, -Unreachable:list, -Deleted:list) is det.
+%
+% Finds the layers of the store at Path that can no longer be
+% reached, and unless Dry_Run is true, deletes them. A layer is
+% reachable when a label points at it, when it is the parent or
+% rollup of a reachable layer, or when a labelled or otherwise
+% referenced layer mentions it as a `layer:identifier`, which is how
+% repository and commit graphs refer to the layers they track.
+% Layers that a label pointed at within the last History seconds, as
+% recorded in its history, count as labelled too, so that
+% nb_restore_head_version/3 and nb_restore_head_at/3 keep working
+% after a collection. Older changes no longer keep their layers, so
+% that old resets, squashes and deleted branches are reclaimed.
+% The layers in the journal of an interrupted nb_set_heads/2 are kept
+% as well, as opening the store moves labels onto them.
+%
+% Labels are read again right before deleting. If any of them was
+% moved in the meantime, the reachable layers are determined again.
+% Encrypted archive stores are refused with an error.
+%
+% @arg Path the directory of the store.
+% @arg Kind either `directory` or `archive`.
+% @arg Grace the number of seconds a layer has to be unchanged before
+% it can be deleted, so that layers of commits in progress are kept.
+% @arg History the number of seconds that label history is kept
+% restorable for.
+% @arg Dry_Run whether to only report what would be deleted.
+% @arg Unreachable the ids of unreachable layers older than Grace.
+% @arg Deleted the ids of the layers that were deleted.
+
+%! open_encrypted_archive_store(+Path:text, +Keys, +Cache_Size:integer, -Store:store) is det.
+%
+% Opens an archive store whose layers are encrypted at rest with
+% ChaCha20-Poly1305. Layers are encrypted when they are written, and
+% decrypted and authenticated when they are read.
+%
+% Keys is either `file(Key_File)` or `env(Variable)`. Either holds one
+% key per line as `Id:Hex_Key`, where Id is a number and Hex_Key is 32
+% bytes in hex. An environment variable may also separate keys with
+% commas. New layers are encrypted with the last key, older keys are
+% used to read layers written before a key rotation.
+%
+% Layers that are not encrypted can't be read, use
+% reencrypt_archive_store/3 to encrypt an existing archive store.
+%
+% @arg Path the directory of the store.
+% @arg Keys where to read the keys from.
+% @arg Cache_Size the size of the cache of decrypted layer archives, in
+% megabytes, as for open_archive_store/3.
+% @arg Store the returned store.
+
+%! reencrypt_archive_store(+Path:text, +Keys, -Count:integer) is det.
+%
+% Rewrites every layer archive of the store at Path that is not
+% encrypted with the current key, which is the last one in Keys.
+% Plaintext archives are encrypted. After a key rotation, this allows
+% older keys to be dropped.
+%
+% This should only be done while the store is not opened. The same
+% can be done from the command line with
+% `terminusdb-store-convert reencrypt`.
+%
+% @arg Path the directory of the store.
+% @arg Keys the keys, as for open_encrypted_archive_store/4.
+% @arg Count the number of archives that were rewritten.
+
+%! replicate_store(+Source:store, +Destination:store, +Names:list, +Checkpoint:text, -Copied:list) is det.
+%
+% Copies the layers that Destination is missing for the heads of the
+% named graphs in Names from Source, along with the layers they refer
+% to, and then moves the labels in Destination to the same heads.
+% Labels without a head in Source have their head cleared in
+% Destination. Running this repeatedly keeps Destination up to date as
+% a replica.
+%
+% Progress is recorded in the Checkpoint file. If replication is
+% interrupted, the next call with the same names continues where it
+% left off, with the heads the interrupted call started with. The
+% checkpoint file is removed when replication is done.
+%
+% @arg Source the store to copy from.
+% @arg Destination the store to copy to.
+% @arg Names the names of the graphs to replicate.
+% @arg Checkpoint the file to record progress in.
+% @arg Copied the ids of the layers that were copied, parents first.
+
 %%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%
 %%% End of foreign predicate pldocs   %%%
 %%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%
@@ -643,6 +872,53 @@ open_archive_store(Path, Store) :-
     % default to 512mb
     open_archive_store(Path, 512, Store).
 
+%! optimize_layer(+Store:store, +Layer:layer, +Options:list, -Report) is det.
+%
+% Rolls up the layer stack of Layer when it has gotten too deep.
+% Nothing is done as long as the stack is at most `max_depth` deep.
+% Otherwise, the newer layers are rolled up onto the newest older
+% layer that has at least `merge_ratio` times as many additions and
+% removals as the layers above it, and that leaves a stack within
+% `max_depth`. If there is no such layer, the whole stack is rolled
+% up. The depth is that of the stack queries go through, which ends at
+% the rollup of a rolled up layer, so a stack that was just rolled up
+% isn't rolled up again.
+%
+% Checking the depth is cheap, so this can be called after every
+% commit.
+%
+% Options are
+%
+%   * max_depth(+Integer)
+%     The deepest the stack may get, 8 by default.
+%   * merge_ratio(+Number)
+%     4 by default.
+%   * imprecise(+Boolean)
+%     Whether to use imprecise_rollup_upto/2, false by default.
+%   * dry_run(+Boolean)
+%     Only report what would be done, false by default.
+%
+% Report is `rollup_report(Action, Depth_Before, Depth_After, Merged)`,
+% where Action is `none`, `rollup` or `rollup_upto(Layer_Id)`, and
+% Merged is the number of layers that were rolled up together.
+optimize_layer(Store, Layer, Options, Report) :-
+    option(max_depth(Max_Depth), Options, 8),
+    option(merge_ratio(Merge_Ratio), Options, 4),
+    option(imprecise(Imprecise), Options, false),
+    option(dry_run(Dry_Run), Options, false),
+    Ratio is float(Merge_Ratio),
+    optimize_layer(Store, Layer, Max_Depth, Ratio, Imprecise, Dry_Run, Report).
+
+%! optimize_named_graph(+Store:store, +Graph:named_graph, +Options:list, -Report) is det.
+%
+% Applies optimize_layer/4 to the head of Graph. A graph without a
+% head has nothing to optimize.
+optimize_named_graph(Store, Graph, Options, Report) :-
+    (   head(Graph, Layer)
+    ->  optimize_layer(Store, Layer, Options, Report)
+    ;   Report = rollup_report(none, 0, 0, 0)
+    ).
+
 :- begin_tests(terminus_store).
 
 :- use_module(library(filesex)).
@@ -1277,6 +1553,455 @@ test(imprecise_rollup_rolls_up_imprecisely,[cleanup(clean(TestDir)), setup(creat
 
     Triples = Expected.
 
+test(pack_stream_round_trip, [
+         setup(tmp_file_stream(binary, File, Out)),
+         cleanup(delete_file(File))
+     ]) :-
+    open_memory_store(Store),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    open_write(Layer, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    layer_to_id(Layer, Id),
+    layer_to_id(Layer2, Id2),
+
+    % children come out after their parents, whatever the order asked
+    pack_export_to_stream(Store, [Id2, Id], Out, true),
+    close(Out),
+
+    open_memory_store(Store2),
+    open(File, read, In, [type(binary)]),
+    pack_import_from_stream(Store2, In, Imported),
+    close(In),
+    Imported = [Id, Id2],
+
+    store_id_layer(Store2, Id2, Imported_Layer),
+    findall(X-P-Y, triple(Imported_Layer, X, P, Y), Triples),
+    Triples = ["A"-"B"-node("C"), "D"-"E"-node("F")],
+
+    % importing again has nothing left to import
+    pack_import_from_file(Store2, File, []).
+
+test(pack_stream_import_detects_corruption, [
+         setup(tmp_file_stream(binary, File, Out)),
+         cleanup(delete_file(File)),
+         throws(error(_, _))
+     ]) :-
+    open_memory_store(Store),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    layer_to_id(Layer, Id),
+    pack_export_to_stream(Store, [Id], Out, false),
+    close(Out),
+
+    % flip the last byte of the checksum
+    read_file_to_codes(File, Codes, [type(binary)]),
+    append(Prefix, [Last], Codes),
+    Flipped is Last xor 1,
+    append(Prefix, [Flipped], Corrupt),
+    setup_call_cleanup(open(File, write, Out2, [type(binary)]),
+                       format(Out2, "~s", [Corrupt]),
+                       close(Out2)),
+
+    open_memory_store(Store2),
+    pack_import_from_file(Store2, File, _).
+
+test(pack_stream_corrupt_import_adds_no_layers, [
+         setup(tmp_file_stream(binary, File, Out)),
+         cleanup(delete_file(File))
+     ]) :-
+    open_memory_store(Store),
+    open_write(Store, Builder1),
+    nb_add_triple(Builder1, "A", "B", node("C")),
+    nb_commit(Builder1, Layer1),
+    open_write(Layer1, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    maplist(layer_to_id, [Layer1, Layer2], [Id1, Id2]),
+    pack_export_to_stream(Store, [Id1, Id2], Out, false),
+    close(Out),
+
+    % flip the last byte of the second layer's pack, after the first
+    % frame has been read in full
+    read_file_to_codes(File, Codes, [type(binary)]),
+    length(Trailer, 33),
+    append(Prefix, [Last|Trailer], Codes),
+    Flipped is Last xor 1,
+    append(Prefix, [Flipped|Trailer], Corrupt),
+    setup_call_cleanup(open(File, write, Out2, [type(binary)]),
+                       format(Out2, "~s", [Corrupt]),
+                       close(Out2)),
+
+    open_memory_store(Store2),
+    catch((pack_import_from_file(Store2, File, _),
+           Imported = true),
+          error(_, _),
+          Imported = false),
+    Imported == false,
+    \+ store_id_layer(Store2, Id1, _),
+    \+ store_id_layer(Store2, Id2, _).
+
+test(pack_export_delta) :-
+    open_memory_store(Store),
+    open_write(Store, Builder1),
+    nb_add_triple(Builder1, "A", "B", node("C")),
+    nb_commit(Builder1, Layer1),
+    open_write(Layer1, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    open_write(Layer2, Builder3),
+    nb_remove_triple(Builder3, "A", "B", node("C")),
+    nb_commit(Builder3, Layer3),
+    maplist(layer_to_id, [Layer1, Layer2, Layer3], [Id1, Id2, Id3]),
+
+    pack_export(Store, [Id1], Base_Pack),
+    pack_export_delta(Store, Id3, [Id1], false, Pack, Id3),
+    pack_layerids_and_parents(Pack, Layer_Parents),
+    msort(Layer_Parents, Sorted),
+    msort([Id2-some(Id1), Id3-some(Id2)], Sorted),
+
+    open_memory_store(Store2),
+    pack_import(Store2, [Id1], Base_Pack),
+    pack_import(Store2, [Id2, Id3], Pack),
+    store_id_layer(Store2, Id3, Imported),
+    findall(X-P-Y, triple(Imported, X, P, Y), ["D"-"E"-node("F")]).
+
+test(pack_export_delta_squashed) :-
+    open_memory_store(Store),
+    open_write(Store, Builder1),
+    nb_add_triple(Builder1, "A", "B", node("C")),
+    nb_commit(Builder1, Layer1),
+    open_write(Layer1, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    open_write(Layer2, Builder3),
+    nb_remove_triple(Builder3, "A", "B", node("C")),
+    nb_commit(Builder3, Layer3),
+    maplist(layer_to_id, [Layer1, Layer3], [Id1, Id3]),
+
+    pack_export(Store, [Id1], Base_Pack),
+    pack_export_delta(Store, Id3, [Id1], true, Pack, Head_Id),
+    Head_Id \== Id3,
+    pack_layerids_and_parents(Pack, [Head_Id-some(Id1)]),
+
+    open_memory_store(Store2),
+    pack_import(Store2, [Id1], Base_Pack),
+    pack_import(Store2, [Head_Id], Pack),
+    store_id_layer(Store2, Head_Id, Imported),
+    findall(X-P-Y, triple(Imported, X, P, Y), ["D"-"E"-node("F")]).
+
+test(verify_clean_store, [cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    open_named_graph(Store, "sometestdb", DB),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    open_write(Layer, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_remove_triple(Builder2, "A", "B", node("C")),
+    nb_commit(Builder2, Layer2),
+    nb_set_head(DB, Layer2),
+    rollup(Layer2),
+
+    verify_store(TestDir, archive, false, [], []).
+
+test(verify_store_unknown_kind, [
+         throws(error(domain_error(_, sideways), _))
+     ]) :-
+    verify_store("testdir", sideways, false, _, _).
+
+test(garbage_collect_layers, [cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    open_named_graph(Store, "sometestdb", DB),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    nb_set_head(DB, Layer),
+    layer_to_id(Layer, Id),
+
+    open_write(Layer, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Orphan),
+    layer_to_id(Orphan, Orphan_Id),
+
+    garbage_collect_layers(TestDir, archive, 0, 3600, true, [Orphan_Id], []),
+    garbage_collect_layers(TestDir, archive, 3600, 3600, false, [], []),
+    garbage_collect_layers(TestDir, archive, 0, 3600, false, [Orphan_Id], [Orphan_Id]),
+
+    open_archive_store(TestDir, Store2),
+    store_id_layer(Store2, Id, _),
+    \+ store_id_layer(Store2, Orphan_Id, _).
+
+test(garbage_collect_keeps_label_history, [cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    create_named_graph(Store, "sometestdb", DB),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer1),
+    nb_set_head(DB, Layer1),
+    open_write(Store, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    nb_force_set_head(DB, Layer2),
+    maplist(layer_to_id, [Layer1, Layer2], [Id1, _]),
+    label_history(Store, "sometestdb", [label_change(V1, none, Id1, _)|_]),
+
+    garbage_collect_layers(TestDir, archive, 0, 3600, false, [], []),
+
+    open_archive_store(TestDir, Store2),
+    nb_restore_head_version(Store2, "sometestdb", V1),
+    open_named_graph(Store2, "sometestdb", DB2),
+    head(DB2, Restored),
+    layer_to_id(Restored, Id1),
+    triple(Restored, "A", "B", node("C")).
+
+test(label_history_and_restore) :-
+    open_memory_store(Store),
+    create_named_graph(Store, "sometestdb", DB),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer1),
+    nb_set_head(DB, Layer1),
+    open_write(Store, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    nb_force_set_head(DB, Layer2),
+    maplist(layer_to_id, [Layer1, Layer2], [Id1, Id2]),
+
+    label_history(Store, "sometestdb", History),
+    History = [label_change(V1, none, Id1, _),
+               label_change(V2, Id1, Id2, _)],
+    V2 > V1,
+
+    nb_restore_head_version(Store, "sometestdb", V1),
+    head(DB, Restored),
+    layer_to_id(Restored, Id1),
+    label_history(Store, "sometestdb", [_, _, label_change(_, Id2, Id1, _)]).
+
+test(nb_set_heads) :-
+    open_memory_store(Store),
+    create_named_graph(Store, "branch", Branch),
+    create_named_graph(Store, "system", System),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer1),
+    open_write(Store, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    head(Branch, _, Branch_Version),
+    head(System, _, System_Version),
+
+    Stale_Version is System_Version + 1,
+    \+ nb_set_heads(Store, [Branch-Branch_Version-Layer1,
+                             System-Stale_Version-Layer2]),
+    \+ head(Branch, _),
+    \+ head(System, _),
+
+    nb_set_heads(Store, [Branch-Branch_Version-Layer1,
+                         System-System_Version-Layer2]),
+    head(Branch, Branch_Head),
+    head(System, System_Head),
+    maplist(layer_to_id, [Layer1, Layer2, Branch_Head, System_Head], [Id1, Id2, Id1, Id2]).
+
+test(nb_set_heads_archive,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    create_named_graph(Store, "branch", Branch),
+    create_named_graph(Store, "system", System),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    head(Branch, _, Branch_Version),
+    head(System, _, System_Version),
+    nb_set_heads(Store, [Branch-Branch_Version-Layer,
+                         System-System_Version-Layer]),
+
+    open_archive_store(TestDir, Store2),
+    open_named_graph(Store2, "system", System2),
+    head(System2, Head),
+    layer_to_id(Layer, Id),
+    layer_to_id(Head, Id).
+
+test(nb_set_heads_recovers_journal,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    create_named_graph(Store, "branch", Branch),
+    create_named_graph(Store, "system", System),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer),
+    layer_to_id(Layer, Id),
+    head(Branch, _, Branch_Version),
+    head(System, _, System_Version),
+    % the branch was set before the process died, the system graph wasn't
+    nb_force_set_head(Branch, Layer, Branch_Version),
+    directory_file_path(TestDir, 'heads.journal', Journal),
+    format(string(Contents), "~w - ~w branch~n~w - ~w system~n",
+           [Branch_Version, Id, System_Version, Id]),
+    setup_call_cleanup(open(Journal, write, Out),
+                       write(Out, Contents),
+                       close(Out)),
+
+    open_archive_store(TestDir, Store2),
+    \+ exists_file(Journal),
+    open_named_graph(Store2, "branch", Branch2),
+    open_named_graph(Store2, "system", System2),
+    head(Branch2, Branch_Head, New_Branch_Version),
+    head(System2, System_Head, New_System_Version),
+    maplist(layer_to_id, [Branch_Head, System_Head], [Id, Id]),
+    New_Branch_Version =:= Branch_Version + 1,
+    New_System_Version =:= System_Version + 1.
+
+write_key_file(Keys, Key_File) :-
+    tmp_file_stream(text, Key_File, Out),
+    forall(member(Id-Key, Keys),
+           format(Out, "~w:~w~n", [Id, Key])),
+    close(Out).
+
+file_contains(Dir, String) :-
+    directory_member(Dir, File, [recursive(true)]),
+    exists_file(File),
+    read_file_to_string(File, Contents, [encoding(octet)]),
+    sub_string(Contents, _, _, _, String).
+
+test(encrypted_archive_store,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    Key1 = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    Key2 = "f0e0d0c0b0a090807060504030201000f0e0d0c0b0a090807060504030201000",
+    write_key_file([1-Key1], Key_File1),
+    write_key_file([1-Key1, 2-Key2], Key_File12),
+    write_key_file([2-Key2], Key_File2),
+
+    open_encrypted_archive_store(TestDir, file(Key_File1), 10, Store),
+    create_named_graph(Store, "sometestdb", DB),
+    open_write(Store, Builder),
+    nb_add_triple(Builder, "secret_subject", "B", node("C")),
+    nb_commit(Builder, Layer),
+    nb_set_head(DB, Layer),
+    \+ file_contains(TestDir, "secret_subject"),
+
+    reencrypt_archive_store(TestDir, file(Key_File12), 1),
+    reencrypt_archive_store(TestDir, file(Key_File12), 0),
+
+    open_encrypted_archive_store(TestDir, file(Key_File2), 10, Store2),
+    open_named_graph(Store2, "sometestdb", DB2),
+    head(DB2, Layer2),
+    triple(Layer2, "secret_subject", "B", node("C")),
+
+    open_encrypted_archive_store(TestDir, file(Key_File1), 10, Store3),
+    open_named_graph(Store3, "sometestdb", DB3),
+    catch(head(DB3, _), error(_, _), Failed = true),
+    Failed == true.
+
+test(replicate_store) :-
+    open_memory_store(Source),
+    open_memory_store(Destination),
+    create_named_graph(Source, "sometestdb", DB),
+    open_write(Source, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer1),
+    open_write(Layer1, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    nb_set_head(DB, Layer2),
+    maplist(layer_to_id, [Layer1, Layer2], [Id1, Id2]),
+    tmp_file(checkpoint, Checkpoint),
+
+    replicate_store(Source, Destination, ["sometestdb"], Checkpoint, [Id1, Id2]),
+    \+ exists_file(Checkpoint),
+    open_named_graph(Destination, "sometestdb", Replica),
+    head(Replica, Replica_Head),
+    layer_to_id(Replica_Head, Id2),
+    triple(Replica_Head, "A", "B", node("C")),
+
+    open_write(Layer2, Builder3),
+    nb_add_triple(Builder3, "G", "H", node("I")),
+    nb_commit(Builder3, Layer3),
+    nb_set_head(DB, Layer3),
+    layer_to_id(Layer3, Id3),
+    replicate_store(Source, Destination, ["sometestdb"], Checkpoint, [Id3]),
+    replicate_store(Source, Destination, ["sometestdb"], Checkpoint, []),
+    head(Replica, Replica_Head2),
+    layer_to_id(Replica_Head2, Id3).
+
+test(replicate_store_resumes) :-
+    open_memory_store(Source),
+    open_memory_store(Destination),
+    create_named_graph(Source, "sometestdb", DB),
+    open_write(Source, Builder),
+    nb_add_triple(Builder, "A", "B", node("C")),
+    nb_commit(Builder, Layer1),
+    open_write(Layer1, Builder2),
+    nb_add_triple(Builder2, "D", "E", node("F")),
+    nb_commit(Builder2, Layer2),
+    nb_set_head(DB, Layer2),
+    maplist(layer_to_id, [Layer1, Layer2], [Id1, Id2]),
+
+    % an interrupted replication that started when the head was Layer1,
+    % and had already copied it
+    pack_export(Source, [Id1], Pack),
+    pack_import(Destination, [Id1], Pack),
+    tmp_file(checkpoint, Checkpoint),
+    setup_call_cleanup(open(Checkpoint, write, Out),
+                       format(Out, "head ~w sometestdb~ncopied ~w~n", [Id1, Id1]),
+                       close(Out)),
+
+    replicate_store(Source, Destination, ["sometestdb"], Checkpoint, []),
+    \+ exists_file(Checkpoint),
+    open_named_graph(Destination, "sometestdb", Replica),
+    head(Replica, Replica_Head),
+    layer_to_id(Replica_Head, Id1),
+
+    replicate_store(Source, Destination, ["sometestdb"], Checkpoint, [Id2]),
+    head(Replica, Replica_Head2),
+    layer_to_id(Replica_Head2, Id2).
+
+commit_layers(_Store, Layer, [], Layer).
+commit_layers(Store, Parent, [Triples|Rest], Layer) :-
+    (   var(Parent)
+    ->  open_write(Store, Builder)
+    ;   open_write(Parent, Builder)
+    ),
+    forall(member(S-P-O, Triples),
+           nb_add_triple(Builder, S, P, node(O))),
+    nb_commit(Builder, Child),
+    commit_layers(Store, Child, Rest, Layer).
+
+test(optimize_layer_full_rollup,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    commit_layers(Store, _, [["a"-"p"-"1"], ["b"-"p"-"2"], ["c"-"p"-"3"], ["d"-"p"-"4"]], Layer),
+
+    optimize_layer(Store, Layer, [], rollup_report(none, 4, 4, 0)),
+    optimize_layer(Store, Layer, [max_depth(2), dry_run(true)],
+                   rollup_report(rollup, 4, 1, 4)),
+    optimize_layer(Store, Layer, [max_depth(2)], rollup_report(rollup, 4, 1, 4)),
+    % the stack now ends at the rollup, so there is nothing left to do
+    optimize_layer(Store, Layer, [max_depth(2)], rollup_report(none, 1, 1, 0)),
+
+    layer_to_id(Layer, Id),
+    store_id_layer(Store, Id, Rollup),
+    findall(X, triple(Rollup, X, "p", _), Subjects),
+    Subjects = ["a", "b", "c", "d"].
+
+test(optimize_named_graph_rollup_upto,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
+    open_archive_store(TestDir, Store),
+    create_named_graph(Store, "sometestdb", DB),
+    optimize_named_graph(Store, DB, [], rollup_report(none, 0, 0, 0)),
+    numlist(1, 10, Numbers),
+    findall(S-"p"-"o", (member(N, Numbers), format(string(S), "s~w", [N])), Base_Triples),
+    commit_layers(Store, _, [Base_Triples], Base),
+    commit_layers(Store, Base, [["a"-"q"-"1"], ["b"-"q"-"2"], ["c"-"q"-"3"]], Layer),
+    nb_set_head(DB, Layer),
+    layer_to_id(Base, Base_Id),
+
+    optimize_named_graph(Store, DB, [max_depth(2), merge_ratio(2)], Report),
+    Report = rollup_report(rollup_upto(Base_Id), 4, 2, 3),
+
+    layer_to_id(Layer, Id),
+    store_id_layer(Store, Id, Rollup),
+    findall(X, triple(Rollup, X, "q", _), Subjects),
+    Subjects = ["a", "b", "c"].
+
 test(sp_card,[cleanup(clean(TestDir)), setup(createng(TestDir))]) :-
     open_archive_store(TestDir, Store),
     open_write(Store, Builder),
@@ -35,6 +35,22 @@ pub const SYS_JSON_DOCUMENT: &str = "http://terminusdb.com/schema/sys#JSONDocume
 pub const SYS_FOREIGN_TYPE_PREDICATE_ID: &str = "http://terminusdb.com/schema/sys#foreign_type";
 pub const SYS_KEY: &str = "http://terminusdb.com/schema/sys#key";
 pub const SYS_VALUE_HASH: &str = "http://terminusdb.com/schema/sys#ValueHash";
+pub const SYS_LEXICAL: &str = "http://terminusdb.com/schema/sys#Lexical";
+pub const SYS_HASH: &str = "http://terminusdb.com/schema/sys#Hash";
+pub const SYS_RANDOM: &str = "http://terminusdb.com/schema/sys#Random";
+pub const SYS_FIELDS: &str = "http://terminusdb.com/schema/sys#fields";
+pub const SYS_OPTIONAL: &str = "http://terminusdb.com/schema/sys#Optional";
+pub const SYS_LIST: &str = "http://terminusdb.com/schema/sys#List";
+pub const SYS_TABLE: &str = "http://terminusdb.com/schema/sys#Table";
+pub const SYS_CARDINALITY: &str = "http://terminusdb.com/schema/sys#Cardinality";
+pub const SYS_CLASS_PREDICATE: &str = "http://terminusdb.com/schema/sys#class";
+pub const SYS_DIMENSIONS: &str = "http://terminusdb.com/schema/sys#dimensions";
+pub const SYS_MIN_CARDINALITY: &str = "http://terminusdb.com/schema/sys#min_cardinality";
+pub const SYS_MAX_CARDINALITY: &str = "http://terminusdb.com/schema/sys#max_cardinality";
+pub const SYS_ABSTRACT: &str = "http://terminusdb.com/schema/sys#abstract";
+pub const SYS_UNIT: &str = "http://terminusdb.com/schema/sys#Unit";
+pub const SYS_ONE_OF: &str = "http://terminusdb.com/schema/sys#oneOf";
+pub const SYS_METADATA: &str = "http://terminusdb.com/schema/sys#metadata";
 generate_lookup_type! {
     SysIds {
         class: node SYS_CLASS,
@@ -63,3 +79,12 @@ generate_lookup_type! {
 }
 
 pub const SYS_JSON_PREFIX: &str = "http://terminusdb.com/schema/json#";
+
+pub const RDF_PREFIX: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+pub const RDFS_PREFIX: &str = "http://www.w3.org/2000/01/rdf-schema#";
+pub const XSD_PREFIX: &str = "http://www.w3.org/2001/XMLSchema#";
+pub const XSD_STRING: &str = "http://www.w3.org/2001/XMLSchema#string";
+pub const XDD_PREFIX: &str = "http://terminusdb.com/schema/xdd#";
+pub const OWL_PREFIX: &str = "http://www.w3.org/2002/07/owl#";
+pub const SYS_NAMESPACE: &str = "http://terminusdb.com/schema/sys#";
+pub const JSON_DATA_PREFIX: &str = "terminusdb:///json/";
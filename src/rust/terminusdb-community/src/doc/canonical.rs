@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+
+use super::*;
+
+#[derive(Error, Debug)]
+pub enum DocumentHashError {
+    #[error("document not found: {0}")]
+    DocumentNotFound(String),
+    #[error("document {id} has hash {actual}, not the expected {expected}")]
+    Mismatch {
+        id: String,
+        expected: String,
+        actual: String,
+    },
+    #[error(transparent)]
+    Retrieval(#[from] DocRetrievalError),
+}
+
+impl IntoPrologException for DocumentHashError {
+    fn into_prolog_exception<'a, T: QueryableContextType>(
+        self,
+        context: &'a Context<'_, T>,
+    ) -> PrologResult<Term<'a>> {
+        match self {
+            DocumentHashError::DocumentNotFound(id) => {
+                term! {context: error(document_not_found(#id), _)}
+            }
+            DocumentHashError::Mismatch {
+                id,
+                expected,
+                actual,
+            } => {
+                term! {context: error(document_hash_mismatch(#id, #expected, #actual), _)}
+            }
+            DocumentHashError::Retrieval(e) => e.into_prolog_exception(context),
+        }
+    }
+}
+
+/// Serialize a json value according to RFC 8785, the JSON
+/// Canonicalization Scheme. Object keys are sorted by their UTF-16 code
+/// units, there is no whitespace, and strings and numbers are written
+/// the way ECMAScript would write them.
+///
+/// Numbers that can't be represented as a double without losing
+/// precision, which xsd:decimal and big integers can hold, are written
+/// with all their digits instead, so that they still hash differently.
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => out.push_str(&canonical_number(n)),
+        // serde_json escapes exactly the characters RFC 8785 wants
+        // escaped, using the same short forms and lowercase hex.
+        Value::String(s) => out.push_str(&serde_json::to_string(s).unwrap()),
+        Value::Array(elements) => {
+            out.push('[');
+            for (ix, element) in elements.iter().enumerate() {
+                if ix != 0 {
+                    out.push(',');
+                }
+                write_canonical(element, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => write_canonical_object(map, out),
+    }
+}
+
+fn write_canonical_object(map: &Map<String, Value>, out: &mut String) {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|(k1, _), (k2, _)| k1.encode_utf16().cmp(k2.encode_utf16()));
+    out.push('{');
+    for (ix, (key, value)) in entries.into_iter().enumerate() {
+        if ix != 0 {
+            out.push(',');
+        }
+        out.push_str(&serde_json::to_string(key).unwrap());
+        out.push(':');
+        write_canonical(value, out);
+    }
+    out.push('}');
+}
+
+/// Count the significant digits of a number literal.
+fn significant_digits(literal: &str) -> usize {
+    let mantissa = literal
+        .trim_start_matches('-')
+        .split(['e', 'E'])
+        .next()
+        .unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+
+    digits.trim_start_matches('0').trim_end_matches('0').len()
+}
+
+fn canonical_number(n: &serde_json::Number) -> String {
+    let literal = n.to_string();
+    // up to 15 significant digits always survive the round trip
+    // through a double
+    match n.as_f64() {
+        Some(f) if f.is_finite() && significant_digits(&literal) <= 15 => ecmascript_number(f),
+        _ => literal,
+    }
+}
+
+/// Format a double the way ECMAScript's `Number.prototype.toString`
+/// does, which is what RFC 8785 prescribes.
+fn ecmascript_number(f: f64) -> String {
+    if f == 0.0 {
+        // this includes -0
+        return "0".to_string();
+    }
+    // the exponent format gives the shortest digits that round trip
+    let formatted = format!("{:e}", f.abs());
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let n = exponent + 1;
+
+    let mut out = String::new();
+    if f < 0.0 {
+        out.push('-');
+    }
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.push_str(&"0".repeat((n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.push_str(&"0".repeat(-n as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        out.push('e');
+        out.push(if n > 0 { '+' } else { '-' });
+        out.push_str(&(n - 1).abs().to_string());
+    }
+
+    out
+}
+
+/// The sha256 of the canonical form of a document, hex encoded.
+pub fn content_hash(doc: &Map<String, Value>) -> String {
+    let mut canonical = String::new();
+    write_canonical_object(doc, &mut canonical);
+    hex::encode(Sha256::digest(canonical.as_bytes()))
+}
+
+/// Hashes documents bottom up. Every nested object is replaced by
+/// `{"@merkle": <hash>}` before its parent gets hashed, so a change to
+/// a subdocument only changes the hashes on the path up to the
+/// document.
+///
+/// Hashes of objects with an `@id` are remembered and reused. As the
+/// content of an id is fixed within a layer, a hasher should only be
+/// used for documents retrieved from the same layer.
+#[derive(Debug, Default)]
+pub struct MerkleHasher {
+    hashes: HashMap<String, String>,
+}
+
+impl MerkleHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hash(&mut self, doc: &Map<String, Value>) -> String {
+        let id = match doc.get("@id") {
+            Some(Value::String(id)) => Some(id),
+            _ => None,
+        };
+        if let Some(hash) = id.and_then(|id| self.hashes.get(id)) {
+            return hash.clone();
+        }
+
+        let replaced: Map<String, Value> = doc
+            .iter()
+            .map(|(key, value)| (key.clone(), self.replace(value)))
+            .collect();
+        let hash = content_hash(&replaced);
+        if let Some(id) = id {
+            self.hashes.insert(id.clone(), hash.clone());
+        }
+
+        hash
+    }
+
+    fn replace(&mut self, value: &Value) -> Value {
+        match value {
+            Value::Object(doc) => {
+                let mut merkle = Map::new();
+                merkle.insert("@merkle".to_string(), Value::String(self.hash(doc)));
+                Value::Object(merkle)
+            }
+            Value::Array(elements) => {
+                Value::Array(elements.iter().map(|e| self.replace(e)).collect())
+            }
+            _ => value.clone(),
+        }
+    }
+
+    /// The hash computed earlier for the object with the given id.
+    pub fn hash_of(&self, id: &str) -> Option<&str> {
+        self.hashes.get(id).map(|hash| hash.as_str())
+    }
+}
+
+impl<L: Layer + Clone> DocumentContext<L> {
+    /// The content hash of a document as stored, that is with full
+    /// iris and with linked documents not unfolded.
+    pub fn document_hash(&self, iri: &str) -> Result<Option<String>, DocRetrievalError> {
+        Ok(self
+            .get_document(iri, false, false)?
+            .map(|doc| content_hash(&doc)))
+    }
+
+    /// The merkle hash of a document as stored.
+    pub fn document_merkle_hash(
+        &self,
+        iri: &str,
+        hasher: &mut MerkleHasher,
+    ) -> Result<Option<String>, DocRetrievalError> {
+        Ok(self
+            .get_document(iri, false, false)?
+            .map(|doc| hasher.hash(&doc)))
+    }
+
+    /// Check that a document exists and currently has the expected
+    /// content hash.
+    pub fn check_document_hash(&self, iri: &str, expected: &str) -> Result<(), DocumentHashError> {
+        let actual = self
+            .document_hash(iri)?
+            .ok_or_else(|| DocumentHashError::DocumentNotFound(iri.to_string()))?;
+        if actual != expected {
+            return Err(DocumentHashError::Mismatch {
+                id: iri.to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn document_hash(context, get_context_term, iri_term, hash_term) {
+        let doc_context: DocumentContextBlob = get_context_term.get_ex()?;
+        let iri: PrologText = iri_term.get_ex()?;
+        match context.try_or_die(doc_context.document_hash(&iri))? {
+            Some(hash) => hash_term.unify(hash),
+            None => fail(),
+        }
+    }
+
+    #[module("$doc")]
+    semidet fn check_document_hash(context, get_context_term, iri_term, hash_term) {
+        let doc_context: DocumentContextBlob = get_context_term.get_ex()?;
+        let iri: PrologText = iri_term.get_ex()?;
+        let expected: PrologText = hash_term.get_ex()?;
+        context.try_or_die(doc_context.check_document_hash(&iri, &expected))
+    }
+}
+
+pub fn register() {
+    register_document_hash();
+    register_check_document_hash();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keys_are_sorted_by_utf16_code_units() {
+        // U+1F600 is encoded as a surrogate pair starting with 0xD83D,
+        // which sorts before U+FB33 in UTF-16 but not in UTF-8.
+        let value =
+            json!({"\u{fb33}": 1, "b": [true, null, "x\n"], "\u{1f600}": 2, "a": {"z": 1, "y": 2}});
+        assert_eq!(
+            "{\"a\":{\"y\":2,\"z\":1},\"b\":[true,null,\"x\\n\"],\"\u{1f600}\":2,\"\u{fb33}\":1}",
+            canonical_json(&value)
+        );
+    }
+
+    #[test]
+    fn numbers_are_written_like_ecmascript() {
+        let cases = [
+            ("0", "0"),
+            ("-0.0", "0"),
+            ("1.0", "1"),
+            ("100", "100"),
+            ("1E21", "1e+21"),
+            ("1e20", "100000000000000000000"),
+            ("0.000001", "0.000001"),
+            ("0.0000001", "1e-7"),
+            ("-1.5e-9", "-1.5e-9"),
+            ("123.456", "123.456"),
+            ("4.50", "4.5"),
+            ("333333333.33333329", "333333333.33333329"),
+            ("12345678901234567890123", "12345678901234567890123"),
+        ];
+        for (input, expected) in cases {
+            let value: Value = serde_json::from_str(input).unwrap();
+            assert_eq!(expected, canonical_json(&value), "formatting {input}");
+        }
+    }
+
+    #[test]
+    fn merkle_hash_changes_with_subdocuments_and_reuses_them() {
+        let doc = json!({
+            "@id": "Person/1",
+            "@type": "Person",
+            "address": {"@id": "Person/1/address", "@type": "Address", "city": "Delft"}
+        });
+        let doc = doc.as_object().unwrap();
+        let mut hasher = MerkleHasher::new();
+        let hash = hasher.hash(doc);
+        let address_hash = hasher.hash_of("Person/1/address").unwrap().to_string();
+        assert_eq!(
+            content_hash(
+                json!({"@id": "Person/1", "@type": "Person", "address": {"@merkle": address_hash}})
+                    .as_object()
+                    .unwrap()
+            ),
+            hash
+        );
+
+        let mut moved = doc.clone();
+        moved["address"]["city"] = json!("Leiden");
+        assert_ne!(hash, MerkleHasher::new().hash(&moved));
+        // the same hasher assumes ids keep their content
+        assert_eq!(hash, hasher.hash(&moved));
+    }
+}
@@ -1,11 +1,16 @@
 use terminusdb_store_prolog::terminus_store::layer::LayerBuilder;
 
+use super::model::DeletePolicy;
 use super::*;
-pub fn delete_id_document<L: Layer + Clone>(
+
+/// All triples making up a document, including those of its
+/// subdocuments, lists and arrays, but not those of the documents it
+/// links to.
+pub fn document_id_triples<L: Layer + Clone>(
     context: &DocumentContext<L>,
-    builder: &mut dyn LayerBuilder,
     id: u64,
-) {
+) -> Vec<IdTriple> {
+    let mut triples = Vec::new();
     let mut visit_next = vec![id];
     let mut visited: HashSet<u64> = HashSet::new();
     let layer = context.layer();
@@ -13,14 +18,14 @@ pub fn delete_id_document<L: Layer + Clone>(
     loop {
         let id = visit_next.pop();
         if id.is_none() {
-            return;
+            return triples;
         }
 
         let id = id.unwrap();
         visited.insert(id);
 
         for triple in layer.triples_s(id) {
-            builder.remove_id_triple(triple);
+            triples.push(triple);
             if let Some(true) = layer.id_object_is_node(triple.object) {
                 if rdf_type.is_some() && !visited.contains(&triple.object) {
                     let type_triple = layer.single_triple_sp(triple.object, rdf_type.unwrap());
@@ -39,12 +44,37 @@ pub fn delete_id_document<L: Layer + Clone>(
     }
 }
 
+pub fn delete_id_document<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    id: u64,
+) {
+    for triple in document_id_triples(context, id) {
+        builder.remove_id_triple(triple);
+    }
+}
+
+/// A reference that keeps a document from being deleted, because the
+/// property it is held in has a `restrict` policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockingReference {
+    /// The document holding the reference.
+    pub document: String,
+    /// The node holding the reference, which is either the document
+    /// itself or one of its subdocuments.
+    pub subject: String,
+    pub predicate: String,
+    pub object: String,
+}
+
 #[derive(Error, Debug)]
 pub enum DeleteError {
     #[error("document not found: {0}")]
     DocumentNotFound(String),
     #[error("stored document is not a json: {0}")]
     StoredDocumentIsNotAJson(String),
+    #[error("deletion is restricted by {} reference(s)", .0.len())]
+    Restricted(Vec<BlockingReference>),
 }
 
 impl IntoPrologException for DeleteError {
@@ -57,6 +87,21 @@ impl IntoPrologException for DeleteError {
             DeleteError::StoredDocumentIsNotAJson(s) => {
                 term! {context: error(stored_document_is_not_a_json(#s), _)}?
             }
+            DeleteError::Restricted(references) => {
+                let mut terms = Vec::with_capacity(references.len());
+                for reference in references {
+                    let document = Atom::new(&reference.document);
+                    let subject = Atom::new(&reference.subject);
+                    let predicate = Atom::new(&reference.predicate);
+                    let object = Atom::new(&reference.object);
+                    terms.push(
+                        term! {context: reference(#document, #subject, #predicate, #object)}?,
+                    );
+                }
+                let list = context.new_term_ref();
+                list.unify(terms.as_slice())?;
+                term! {context: error(delete_restricted(#&list), _)}?
+            }
         };
 
         context.raise_exception(&term)
@@ -180,31 +225,212 @@ pub fn delete_all_triples<L: Layer + Clone>(layer: &L, builder: &mut dyn LayerBu
     }
 }
 
+/// The documents a deletion removes and the references it unlinks,
+/// after following the `@onDelete` policies of all referring
+/// properties.
+#[derive(Debug, Default)]
+pub struct DeletionPlan {
+    pub documents: Vec<u64>,
+    pub unlinks: Vec<IdTriple>,
+}
+
+/// The predicates that connect list and array cells to what they
+/// hold, rather than being a property of a document.
+fn is_cell_predicate<L: Layer + Clone>(context: &DocumentContext<L>, predicate: u64) -> bool {
+    let predicate = Some(predicate);
+    predicate == context.rdf.first()
+        || predicate == context.rdf.rest()
+        || predicate == context.sys.value()
+}
+
+fn is_document<L: Layer + Clone>(context: &DocumentContext<L>, rdf_type: u64, id: u64) -> bool {
+    match context.layer().single_triple_sp(id, rdf_type) {
+        Some(t) => {
+            context.document_types.contains(&t.object) || context.value_hashes.contains(&t.object)
+        }
+        None => false,
+    }
+}
+
+/// The triple holding the property that a reference was made
+/// through, walking up from list and array cells.
+fn referring_triple<L: Layer + Clone>(context: &DocumentContext<L>, triple: IdTriple) -> IdTriple {
+    let layer = context.layer();
+    let mut triple = triple;
+    let mut visited = HashSet::new();
+    while is_cell_predicate(context, triple.predicate) && visited.insert(triple.subject) {
+        match layer.triples_o(triple.subject).next() {
+            Some(parent) => triple = parent,
+            None => break,
+        }
+    }
+
+    triple
+}
+
+/// The document a node is part of, walking up from subdocuments.
+fn owning_document<L: Layer + Clone>(context: &DocumentContext<L>, rdf_type: u64, id: u64) -> u64 {
+    let layer = context.layer();
+    let mut id = id;
+    let mut visited = HashSet::new();
+    while !is_document(context, rdf_type, id) && visited.insert(id) {
+        match layer.triples_o(id).next() {
+            Some(parent) => id = parent.subject,
+            None => break,
+        }
+    }
+
+    id
+}
+
+/// Work out what deleting the given documents entails.
+///
+/// References to a deleted document are handled according to the
+/// `@onDelete` policy of the property holding them. `cascade` deletes
+/// the referring document too, `restrict` makes the whole deletion
+/// fail, and `setNull` removes the reference. References without a
+/// policy are removed when `unlink` is set and left dangling
+/// otherwise. Nothing is written, so a restricted deletion leaves the
+/// builder untouched.
+pub fn plan_deletion<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    ids: Vec<u64>,
+    unlink: bool,
+) -> Result<DeletionPlan, DeleteError> {
+    let mut plan = DeletionPlan::default();
+    let rdf_type = match context.rdf.type_() {
+        Some(rdf_type) => rdf_type,
+        None => return Ok(plan),
+    };
+    let layer = context.layer();
+    let model = context.model();
+
+    let mut deleted: HashSet<u64> = HashSet::new();
+    let mut restricted: Vec<(u64, IdTriple, IdTriple)> = Vec::new();
+    let mut unlinks: Vec<(u64, IdTriple)> = Vec::new();
+    let mut work = ids;
+    while let Some(id) = work.pop() {
+        if !deleted.insert(id) {
+            continue;
+        }
+        plan.documents.push(id);
+
+        for triple in layer.triples_o(id) {
+            let referring = referring_triple(context, triple);
+            let document = owning_document(context, rdf_type, referring.subject);
+            if document == id {
+                continue;
+            }
+            let policy = layer
+                .single_triple_sp(referring.subject, rdf_type)
+                .and_then(|t| layer.id_object_node(t.object))
+                .and_then(|class| model.class(&class))
+                .and_then(|class| {
+                    let predicate = layer.id_predicate(referring.predicate)?;
+                    class.on_delete.get(&predicate).copied()
+                });
+            match policy {
+                Some(DeletePolicy::Cascade) => work.push(document),
+                Some(DeletePolicy::Restrict) => restricted.push((document, referring, triple)),
+                Some(DeletePolicy::SetNull) => unlinks.push((document, triple)),
+                None if unlink => unlinks.push((document, triple)),
+                None => {}
+            }
+        }
+    }
+
+    // references held by documents that end up being deleted
+    // themselves don't stand in the way.
+    let blocking: Vec<_> = restricted
+        .into_iter()
+        .filter(|(document, _, _)| !deleted.contains(document))
+        .map(|(document, referring, triple)| BlockingReference {
+            document: layer
+                .id_subject(document)
+                .expect("id was not in dictionary"),
+            subject: layer
+                .id_subject(referring.subject)
+                .expect("id was not in dictionary"),
+            predicate: layer
+                .id_predicate(referring.predicate)
+                .expect("id was not in dictionary"),
+            object: layer
+                .id_subject(triple.object)
+                .expect("id was not in dictionary"),
+        })
+        .collect();
+    if !blocking.is_empty() {
+        return Err(DeleteError::Restricted(blocking));
+    }
+
+    let rdf_first = context.rdf.first();
+    plan.unlinks = unlinks
+        .into_iter()
+        .filter(|(document, triple)| {
+            // unlinking something that is pointed at from a list is
+            // currently not supported.
+            !deleted.contains(document) && Some(triple.predicate) != rdf_first
+        })
+        .map(|(_, triple)| triple)
+        .collect();
+
+    Ok(plan)
+}
+
+/// Delete documents along with everything their `@onDelete` policies
+/// pull in.
+pub fn delete_id_documents<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    ids: Vec<u64>,
+    unlink: bool,
+) -> Result<(), DeleteError> {
+    let plan = plan_deletion(context, ids, unlink)?;
+    let layer = context.layer();
+    let json_type = context.sys.json_document();
+    let rdf_type = context.rdf.type_();
+    for id in plan.documents {
+        let document_type = rdf_type
+            .and_then(|rdf_type| layer.single_triple_sp(id, rdf_type))
+            .map(|t| t.object);
+        if document_type.is_some() && document_type == json_type {
+            delete_json_id_document(context, builder, id)?;
+        } else {
+            delete_id_document(context, builder, id);
+        }
+    }
+    for triple in plan.unlinks {
+        builder.remove_id_triple(triple);
+    }
+
+    Ok(())
+}
+
 pub fn delete_all_documents_by_type<L: Layer + Clone>(
     context: &DocumentContext<L>,
     builder: &mut dyn LayerBuilder,
     type_name: &str,
     unlink: bool,
-) {
+) -> Result<(), DeleteError> {
     let layer = context.layer();
     let rdf_type = context.rdf.type_();
     if rdf_type.is_none() {
-        return;
+        return Ok(());
     }
     let rdf_type = rdf_type.unwrap();
 
+    let mut ids = Vec::new();
     for type_id in context.get_subtypes_for(type_name) {
         for triple in layer.triples_o(type_id) {
             if triple.predicate != rdf_type {
                 continue;
             }
 
-            delete_id_document(context, builder, triple.subject);
-            if unlink {
-                unlink_id_document(context, builder, triple.subject);
-            }
+            ids.push(triple.subject);
         }
     }
+
+    delete_id_documents(context, builder, ids, unlink)
 }
 
 pub fn delete_document_by_iri<L: Layer + Clone>(
@@ -227,18 +453,8 @@ pub fn delete_document_by_iri<L: Layer + Clone>(
     if document_type.is_none() {
         return Err(DeleteError::DocumentNotFound(iri.to_owned()));
     }
-    let json_type = context.sys.json_document();
-    if document_type == json_type {
-        delete_json_id_document(context, builder, id)?
-    } else {
-        delete_id_document(context, builder, id);
-    }
-
-    if unlink {
-        unlink_id_document(context, builder, id);
-    }
 
-    Ok(())
+    delete_id_documents(context, builder, vec![id], unlink)
 }
 
 pub fn delete_json_document_by_iri<L: Layer + Clone>(
@@ -319,9 +535,10 @@ predicates! {
         let builder = builder.unwrap();
         let type_name: PrologText = type_name_term.get_ex()?;
         let unlink: bool = unlink_term.get_ex()?;
-        context.try_or_die(builder.with_builder(|builder| {
-            delete_all_documents_by_type(&document_context, &mut **builder, &type_name, unlink)
-        }))
+        context.try_or_die(
+            context.try_or_die(builder.with_builder(|builder| {
+                delete_all_documents_by_type(&document_context, &mut **builder, &type_name, unlink)
+        }))?)
     }
 
     #[module("$doc")]
@@ -349,3 +566,120 @@ pub fn register() {
     register_delete_documents_by_type();
     register_delete_all();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminusdb_store_prolog::terminus_store::open_sync_memory_store;
+
+    const PERSON: &str = "terminusdb:///schema#Person";
+    const TEAM: &str = "terminusdb:///schema#Team";
+    const FRIEND: &str = "terminusdb:///schema#friend";
+    const LEAD: &str = "terminusdb:///schema#lead";
+
+    fn policy_triples(class: &str, property: &str, policy: &str) -> Vec<ValueTriple> {
+        let metadata = format!("{class}/metadata");
+        let policies = format!("{class}/metadata/onDelete");
+        vec![
+            ValueTriple::new_node(class, SYS_METADATA, &metadata),
+            ValueTriple::new_node(&metadata, RDF_TYPE, SYS_JSON),
+            ValueTriple::new_node(
+                &metadata,
+                &format!("{SYS_JSON_PREFIX}%40onDelete"),
+                &policies,
+            ),
+            ValueTriple::new_node(&policies, RDF_TYPE, SYS_JSON),
+            ValueTriple::new_value(
+                &policies,
+                &format!("{SYS_JSON_PREFIX}{property}"),
+                String::make_entry(policy),
+            ),
+        ]
+    }
+
+    fn context() -> DocumentContext<SyncStoreLayer> {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let mut triples = vec![
+            ValueTriple::new_node(PERSON, RDF_TYPE, SYS_CLASS),
+            ValueTriple::new_node(PERSON, FRIEND, "terminusdb:///schema#Person/friend"),
+            ValueTriple::new_node("terminusdb:///schema#Person/friend", RDF_TYPE, SYS_OPTIONAL),
+            ValueTriple::new_node(
+                "terminusdb:///schema#Person/friend",
+                SYS_CLASS_PREDICATE,
+                PERSON,
+            ),
+            ValueTriple::new_node(TEAM, RDF_TYPE, SYS_CLASS),
+            ValueTriple::new_node(TEAM, LEAD, PERSON),
+        ];
+        triples.extend(policy_triples(PERSON, "friend", "cascade"));
+        triples.extend(policy_triples(TEAM, "lead", "restrict"));
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+        let schema = builder.commit().unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        let triples = [
+            ValueTriple::new_node("terminusdb:///data/Person/a", RDF_TYPE, PERSON),
+            ValueTriple::new_node("terminusdb:///data/Person/b", RDF_TYPE, PERSON),
+            ValueTriple::new_node(
+                "terminusdb:///data/Person/b",
+                FRIEND,
+                "terminusdb:///data/Person/a",
+            ),
+            ValueTriple::new_node("terminusdb:///data/Person/c", RDF_TYPE, PERSON),
+            ValueTriple::new_node("terminusdb:///data/Team/t", RDF_TYPE, TEAM),
+            ValueTriple::new_node(
+                "terminusdb:///data/Team/t",
+                LEAD,
+                "terminusdb:///data/Person/c",
+            ),
+        ];
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+        let instance = builder.commit().unwrap();
+
+        DocumentContext::new(schema, Some(instance))
+    }
+
+    fn id(context: &DocumentContext<SyncStoreLayer>, iri: &str) -> u64 {
+        context.layer().subject_id(iri).unwrap()
+    }
+
+    #[test]
+    fn cascade_deletes_referring_documents() {
+        let context = context();
+        let a = id(&context, "terminusdb:///data/Person/a");
+        let b = id(&context, "terminusdb:///data/Person/b");
+
+        let plan = plan_deletion(&context, vec![a], false).unwrap();
+        assert_eq!(vec![a, b], plan.documents);
+        assert!(plan.unlinks.is_empty());
+    }
+
+    #[test]
+    fn restrict_blocks_deletion_unless_referrer_goes_too() {
+        let context = context();
+        let c = id(&context, "terminusdb:///data/Person/c");
+        let t = id(&context, "terminusdb:///data/Team/t");
+
+        match plan_deletion(&context, vec![c], true) {
+            Err(DeleteError::Restricted(references)) => assert_eq!(
+                vec![BlockingReference {
+                    document: "terminusdb:///data/Team/t".to_string(),
+                    subject: "terminusdb:///data/Team/t".to_string(),
+                    predicate: LEAD.to_string(),
+                    object: "terminusdb:///data/Person/c".to_string(),
+                }],
+                references
+            ),
+            other => panic!("expected a restricted deletion, got {other:?}"),
+        }
+
+        let plan = plan_deletion(&context, vec![c, t], true).unwrap();
+        assert_eq!(2, plan.documents.len());
+        assert!(plan.unlinks.is_empty());
+    }
+}
@@ -0,0 +1,871 @@
+use std::cmp::Ordering;
+
+use serde_json::Number;
+use sha2::{Digest, Sha256};
+use tdb_succinct::*;
+
+use super::insert::{json_to_entry, quoted_json_scalar};
+use super::model::*;
+use super::*;
+use crate::random_base64_string;
+
+/// Length of the random part of generated ids, matching `idgen_random`.
+pub const RANDOM_ID_LENGTH: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum IdGenError {
+    #[error("submitted document is not an object: {0}")]
+    NotAnObject(String),
+    #[error("submitted document has no @type: {0}")]
+    MissingType(String),
+    #[error("unknown type: {0}")]
+    UnknownType(String),
+    #[error("key field {0} is required but missing")]
+    KeyMissingRequiredField(String),
+    #[error("value {1} of key field {0} can't be used in an id")]
+    InvalidKeyValue(String, String),
+    #[error("submitted id {0} does not match generated id {1}")]
+    IdMismatch(String, String),
+    #[error("submitted id {0} does not have expected prefix {1}")]
+    UnexpectedPrefix(String, String),
+    #[error("submitted document is not valid json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl IntoPrologException for IdGenError {
+    fn into_prolog_exception<'a, T: QueryableContextType>(
+        self,
+        context: &'a Context<'_, T>,
+    ) -> PrologResult<Term<'a>> {
+        let term = match self {
+            IdGenError::NotAnObject(s) => term! {context: error(not_a_document(#s), _)}?,
+            IdGenError::MissingType(s) => term! {context: error(missing_type(#s), _)}?,
+            IdGenError::UnknownType(s) => term! {context: error(unknown_type(#s), _)}?,
+            IdGenError::KeyMissingRequiredField(f) => {
+                term! {context: error(key_missing_required_field(#f), _)}?
+            }
+            IdGenError::InvalidKeyValue(f, v) => {
+                term! {context: error(invalid_key_value(#f, #v), _)}?
+            }
+            IdGenError::IdMismatch(submitted, generated) => {
+                term! {context: error(submitted_id_does_not_match_generated_id(#submitted, #generated), _)}?
+            }
+            IdGenError::UnexpectedPrefix(submitted, base) => {
+                term! {context: error(submitted_document_id_does_not_have_expected_prefix(#submitted, #base), _)}?
+            }
+            IdGenError::Json(e) => {
+                let msg = e.to_string();
+                term! {context: error(malformed_json_payload(#msg), _)}?
+            }
+        };
+
+        context.raise_exception(&term)
+    }
+}
+
+/// The raw value of a single key field, before encoding.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyValue {
+    /// An optional field without a value.
+    None,
+    Value(String),
+    /// A set or list with more than one element.
+    List(Vec<KeyValue>),
+}
+
+impl KeyValue {
+    fn encode(&self) -> String {
+        match self {
+            KeyValue::None => "+none+".to_string(),
+            KeyValue::Value(s) => encode_id_fragment(s),
+            KeyValue::List(values) => values
+                .iter()
+                .map(|v| v.encode())
+                .collect::<Vec<_>>()
+                .join("++"),
+        }
+    }
+}
+
+/// Percent-encode a part of an id, the way prolog's
+/// `uri_encoded(segment, ...)` does. A `+` is encoded as well, as it
+/// separates the key fields.
+pub fn encode_id_fragment(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'!'
+            | b'$'
+            | b'&'
+            | b'\''
+            | b'('
+            | b')'
+            | b'*'
+            | b','
+            | b';'
+            | b'='
+            | b':'
+            | b'@' => result.push(b as char),
+            b => result.push_str(&format!("%{b:02X}")),
+        }
+    }
+
+    result
+}
+
+fn key_suffix(values: &[KeyValue]) -> String {
+    values
+        .iter()
+        .map(|v| v.encode())
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+pub fn idgen_lexical(base: &str, values: &[KeyValue]) -> String {
+    format!("{base}{}", key_suffix(values))
+}
+
+pub fn idgen_hash(base: &str, values: &[KeyValue]) -> String {
+    let hash = Sha256::digest(key_suffix(values).as_bytes());
+    format!("{base}{}", hex::encode(hash))
+}
+
+pub fn idgen_random(base: &str) -> String {
+    format!("{base}{}", random_base64_string(RANDOM_ID_LENGTH))
+}
+
+/// Hash the path-value pairs of a document, as rendered by
+/// [`document_path_values`].
+pub fn idgen_value_hash(base: &str, path_values: &[String]) -> String {
+    let rendered = format!("[{}]", path_values.join(","));
+    let hash = Sha256::digest(rendered.as_bytes());
+    format!("{base}{}", hex::encode(hash))
+}
+
+/// Quote an atom the way `~q` does for anything that isn't a plain
+/// lowercase identifier, which covers every IRI.
+fn quote_atom(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => result.push_str("\\'"),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result.push('\'');
+    result
+}
+
+/// The canonical string form of a typed value, as produced by casting
+/// it to `xsd:string`.
+fn canonical_string(entry: &TypedDictEntry) -> String {
+    match value_to_json(entry) {
+        Value::String(s) => s,
+        Value::Object(mut map) => match map.remove("@value") {
+            Some(Value::String(s)) => s,
+            _ => String::new(),
+        },
+        v => v.to_string(),
+    }
+}
+
+fn is_numeric_string(datatype: Datatype) -> bool {
+    matches!(
+        datatype,
+        Datatype::BigInt
+            | Datatype::PositiveInteger
+            | Datatype::NonNegativeInteger
+            | Datatype::NegativeInteger
+            | Datatype::NonPositiveInteger
+            | Datatype::Decimal
+    )
+}
+
+/// Expand the id of a linked document, if the value is a link.
+fn link_id(model: &SchemaModel, value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(model.expand_instance(s)),
+        Value::Object(map) => map
+            .get("@id")
+            .and_then(|id| id.as_str())
+            .map(|id| model.expand_instance(id)),
+        _ => None,
+    }
+}
+
+/// The raw value a single element of a key field contributes, which
+/// is the `xsd:string` cast for base types, and the expanded IRI for
+/// links and enums.
+fn raw_key_value(
+    model: &SchemaModel,
+    field: &str,
+    range: &str,
+    value: &Value,
+) -> Result<KeyValue, IdGenError> {
+    let invalid = || IdGenError::InvalidKeyValue(field.to_string(), value.to_string());
+    if let Some(class) = model.class(range) {
+        return match &class.kind {
+            ClassKind::Enum(_) => value
+                .as_str()
+                .map(|s| KeyValue::Value(format!("{range}/{}", urlencoding::encode(s))))
+                .ok_or_else(invalid),
+            _ => link_id(model, value)
+                .map(KeyValue::Value)
+                .ok_or_else(invalid),
+        };
+    }
+
+    let (base_type, value) = match value {
+        Value::Object(map) if map.contains_key("@id") => {
+            return link_id(model, value)
+                .map(KeyValue::Value)
+                .ok_or_else(invalid)
+        }
+        Value::Object(map) if map.contains_key("@lang") => {
+            return map
+                .get("@value")
+                .and_then(|v| v.as_str())
+                .map(|s| KeyValue::Value(s.to_string()))
+                .ok_or_else(invalid)
+        }
+        Value::Object(map) if map.contains_key("@value") => {
+            let base_type = match map.get("@type").and_then(|t| t.as_str()) {
+                Some(t) => model.expand_schema(t),
+                None => range.to_string(),
+            };
+            (base_type, &map["@value"])
+        }
+        v => (range.to_string(), v),
+    };
+    json_to_entry(&base_type, value)
+        .map(|entry| KeyValue::Value(canonical_string(&entry)))
+        .ok_or_else(invalid)
+}
+
+fn key_value(
+    model: &SchemaModel,
+    property: &str,
+    field: Option<&FieldDefinition>,
+    value: &Value,
+) -> Result<KeyValue, IdGenError> {
+    let range = field.map(|f| f.range.as_str()).unwrap_or(XSD_STRING);
+    let elements: Vec<&Value> = match value {
+        Value::Array(elements) => elements.iter().filter(|e| !e.is_null()).collect(),
+        v => return raw_key_value(model, property, range, v),
+    };
+    match elements.len() {
+        0 => Ok(KeyValue::None),
+        1 => raw_key_value(model, property, range, elements[0]),
+        _ => {
+            let mut values = elements
+                .into_iter()
+                .map(|e| Ok((element_number(e), raw_key_value(model, property, range, e)?)))
+                .collect::<Result<Vec<_>, IdGenError>>()?;
+            if matches!(
+                field.map(|f| &f.kind),
+                Some(FieldKind::Set | FieldKind::Cardinality { .. })
+            ) {
+                // json.pl sorts the elaborated set with sort/2
+                values.sort_by(standard_order);
+                values.dedup();
+            }
+            Ok(KeyValue::List(values.into_iter().map(|(_, v)| v).collect()))
+        }
+    }
+}
+
+/// The number a key field element holds, if any.
+fn element_number(value: &Value) -> Option<&Number> {
+    match value {
+        Value::Number(n) => Some(n),
+        Value::Object(map) => map.get("@value").and_then(|v| v.as_number()),
+        _ => None,
+    }
+}
+
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a.cmp(&b);
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return a.cmp(&b);
+    }
+    let a_value = a.as_f64().unwrap_or(f64::NAN);
+    let b_value = b.as_f64().unwrap_or(f64::NAN);
+    a_value
+        .partial_cmp(&b_value)
+        .unwrap_or(Ordering::Equal)
+        // a float comes before an integer of the same value
+        .then_with(|| b.is_f64().cmp(&a.is_f64()))
+}
+
+/// Prolog's standard order of terms, as far as it matters for set
+/// elements: numbers come before text and are compared by value, and
+/// text is compared by code point.
+fn standard_order(a: &(Option<&Number>, KeyValue), b: &(Option<&Number>, KeyValue)) -> Ordering {
+    match (a.0, b.0) {
+        (Some(a), Some(b)) => compare_numbers(a, b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.1.cmp(&b.1),
+    }
+}
+
+/// Collect the values of the key fields from a submitted document.
+pub fn key_field_values(
+    model: &SchemaModel,
+    class: &ClassDefinition,
+    fields: &[String],
+    map: &Map<String, Value>,
+) -> Result<Vec<KeyValue>, IdGenError> {
+    fields
+        .iter()
+        .map(|property| {
+            let field = class.field(property);
+            let value = map
+                .iter()
+                .find(|(k, v)| {
+                    !k.starts_with('@') && !v.is_null() && &model.expand_schema(k) == property
+                })
+                .map(|(_, v)| v);
+            match value {
+                Some(value) => key_value(model, property, field, value),
+                None => match field.map(|f| &f.kind) {
+                    Some(FieldKind::Optional | FieldKind::Set | FieldKind::Array(_)) => {
+                        Ok(KeyValue::None)
+                    }
+                    _ => Err(IdGenError::KeyMissingRequiredField(
+                        model.compress_schema(property).to_string(),
+                    )),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Render a single element of a field, the way `~q` prints the value
+/// prolog finds for it in the elaborated document. Values that prolog
+/// keeps as structured terms, such as dates, are rendered by their
+/// lexical form.
+fn render_value(model: &SchemaModel, range: &str, value: &Value) -> String {
+    if let Some(class) = model.class(range) {
+        if let (ClassKind::Enum(_), Some(s)) = (&class.kind, value.as_str()) {
+            return quote_atom(&format!("{range}/{}", urlencoding::encode(s)));
+        }
+        if let Some(id) = link_id(model, value) {
+            return quote_atom(&id);
+        }
+    }
+
+    let (base_type, value) = match value {
+        Value::Object(map) if map.contains_key("@lang") => {
+            let lang = map["@lang"].as_str().unwrap_or("");
+            let s = map.get("@value").cloned().unwrap_or(Value::Null);
+            return format!("{}@{lang}", quoted_json_scalar(&s));
+        }
+        Value::Object(map) if map.contains_key("@value") => {
+            let base_type = match map.get("@type").and_then(|t| t.as_str()) {
+                Some(t) => model.expand_schema(t),
+                None => range.to_string(),
+            };
+            (base_type, &map["@value"])
+        }
+        v => (range.to_string(), v),
+    };
+    let lexical = match json_to_entry(&base_type, value) {
+        Some(entry) => match value_to_json(&entry) {
+            Value::Number(n) => {
+                let s = n.to_string();
+                if matches!(entry.datatype(), Datatype::Float32 | Datatype::Float64)
+                    && !s.contains(['.', 'e', 'E'])
+                {
+                    format!("{s}.0")
+                } else {
+                    s
+                }
+            }
+            Value::Bool(b) => b.to_string(),
+            Value::String(s) if is_numeric_string(entry.datatype()) => s,
+            _ => quoted_json_scalar(&Value::String(canonical_string(&entry))),
+        },
+        None => quoted_json_scalar(value),
+    };
+
+    format!("{lexical}^^{}", quote_atom(&base_type))
+}
+
+/// The subdocument class a value of the given range should be written
+/// as, if it is a subdocument at all.
+fn subdocument_class<'a>(
+    model: &'a SchemaModel,
+    range: &str,
+    value: &Value,
+) -> Option<&'a ClassDefinition> {
+    let map = value.as_object()?;
+    if map.keys().all(|k| k == "@id" || k == "@type") {
+        return None;
+    }
+    let typ = match map.get("@type").and_then(|t| t.as_str()) {
+        Some(t) => model.expand_schema(t),
+        None => range.to_string(),
+    };
+    model.class(&typ).filter(|class| class.subdocument)
+}
+
+fn collect_path_values(
+    model: &SchemaModel,
+    class: &ClassDefinition,
+    map: &Map<String, Value>,
+    path: &mut Vec<String>,
+    result: &mut Vec<String>,
+) {
+    let pair = |path: &[String], value: String| {
+        // a negative number directly after the `-` would otherwise
+        // read as `--`
+        let separator = if value.starts_with('-') { "- " } else { "-" };
+        format!("[{}]{separator}{value}", path.join(","))
+    };
+
+    path.push(quote_atom("@type"));
+    result.push(pair(path, quote_atom(&class.id)));
+    path.pop();
+
+    for (key, value) in map {
+        if key.starts_with('@') || value.is_null() {
+            continue;
+        }
+        let property = model.expand_schema(key);
+        let field = class.field(&property);
+        let range = field.map(|f| f.range.as_str()).unwrap_or(XSD_STRING);
+        let single = matches!(
+            field.map(|f| &f.kind),
+            None | Some(FieldKind::Required | FieldKind::Optional)
+        );
+        let mut elements = Vec::new();
+        flatten_elements(value, !single, &mut elements);
+
+        path.push(quote_atom(&property));
+        for (index, element) in elements.into_iter().enumerate() {
+            match subdocument_class(model, range, element) {
+                Some(sub_class) => {
+                    let sub_map = element.as_object().unwrap();
+                    if single {
+                        collect_path_values(model, sub_class, sub_map, path, result);
+                    } else {
+                        path.push(index.to_string());
+                        collect_path_values(model, sub_class, sub_map, path, result);
+                        path.pop();
+                    }
+                }
+                None => result.push(pair(path, render_value(model, range, element))),
+            }
+        }
+        path.pop();
+    }
+}
+
+fn flatten_elements<'a>(value: &'a Value, container: bool, result: &mut Vec<&'a Value>) {
+    match value {
+        Value::Array(elements) if container => {
+            for element in elements {
+                flatten_elements(element, container, result);
+            }
+        }
+        Value::Null => {}
+        v => result.push(v),
+    }
+}
+
+/// All path-value pairs of a document, rendered and sorted, as used by
+/// the `ValueHash` key. This follows `get_all_path_values` in
+/// `json.pl`.
+pub fn document_path_values(
+    model: &SchemaModel,
+    class: &ClassDefinition,
+    map: &Map<String, Value>,
+) -> Vec<String> {
+    let mut result = Vec::new();
+    collect_path_values(model, class, map, &mut Vec::new(), &mut result);
+    result.sort();
+
+    result
+}
+
+/// The part of the id that comes before the key suffix. Subdocuments
+/// get their ids minted under the document and property containing
+/// them.
+fn key_path_base(
+    model: &SchemaModel,
+    class: &ClassDefinition,
+    parent: Option<(&str, &str)>,
+) -> String {
+    match parent {
+        Some((parent_id, property)) => {
+            let property = model.compress_schema(property);
+            let property = if has_protocol(property) {
+                encode_id_fragment(property)
+            } else {
+                property.to_string()
+            };
+            format!("{parent_id}/{property}/{}", class.key_base)
+        }
+        None => model.expand_instance(&class.key_base),
+    }
+}
+
+/// Compute the id of a document of the given class. A submitted `@id`
+/// is checked against the key and returned as is when it matches.
+pub fn generate_id(
+    model: &SchemaModel,
+    class: &ClassDefinition,
+    map: &Map<String, Value>,
+    parent: Option<(&str, &str)>,
+) -> Result<String, IdGenError> {
+    let submitted = map
+        .get("@id")
+        .and_then(|id| id.as_str())
+        .map(|id| model.expand_instance(id));
+    let path_base = key_path_base(model, class, parent);
+    let generated = match &class.key {
+        KeyStrategy::Random => {
+            return match submitted {
+                Some(id) if id.starts_with(&path_base) => Ok(id),
+                Some(id) => Err(IdGenError::UnexpectedPrefix(id, path_base)),
+                None => Ok(idgen_random(&path_base)),
+            }
+        }
+        KeyStrategy::Lexical(fields) => {
+            idgen_lexical(&path_base, &key_field_values(model, class, fields, map)?)
+        }
+        KeyStrategy::Hash(fields) => {
+            idgen_hash(&path_base, &key_field_values(model, class, fields, map)?)
+        }
+        KeyStrategy::ValueHash => idgen_value_hash(
+            &model.expand_instance(&class.key_base),
+            &document_path_values(model, class, map),
+        ),
+    };
+
+    match submitted {
+        Some(id) if id != generated => Err(IdGenError::IdMismatch(id, generated)),
+        _ => Ok(generated),
+    }
+}
+
+/// Compute the id a submitted top-level document will be stored
+/// under.
+pub fn document_id(model: &SchemaModel, document: &Value) -> Result<String, IdGenError> {
+    let map = document
+        .as_object()
+        .ok_or_else(|| IdGenError::NotAnObject(document.to_string()))?;
+    let typ = map
+        .get("@type")
+        .and_then(|t| t.as_str())
+        .map(|t| model.expand_schema(t))
+        .ok_or_else(|| IdGenError::MissingType(document.to_string()))?;
+    let class = model
+        .class(&typ)
+        .ok_or_else(|| IdGenError::UnknownType(typ.clone()))?;
+
+    generate_id(model, class, map, None)
+}
+
+/// Compute the ids for a json payload of either a single document or
+/// an array of documents.
+pub fn document_ids(model: &SchemaModel, payload: &str) -> Result<Vec<String>, IdGenError> {
+    match serde_json::from_str(payload)? {
+        Value::Array(documents) => documents
+            .par_iter()
+            .map(|document| document_id(model, document))
+            .collect(),
+        document => Ok(vec![document_id(model, &document)?]),
+    }
+}
+
+/// A stored document whose id is not the one its key generates.
+#[derive(Debug)]
+pub struct IdMismatch {
+    pub id: String,
+    pub expected: Result<String, IdGenError>,
+}
+
+/// Regenerate the ids of all documents with a derived key, and report
+/// the ones that don't match the id they are stored under.
+pub fn check_document_ids<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+) -> Result<Vec<IdMismatch>, DocRetrievalError> {
+    let mut mismatches = Vec::new();
+    let layer = match context.layer.as_ref() {
+        Some(layer) => layer,
+        None => return Ok(mismatches),
+    };
+    let rdf_type_id = match layer.predicate_id(RDF_TYPE) {
+        Some(id) => id,
+        None => return Ok(mismatches),
+    };
+    let model = context.model();
+    for class in model.classes() {
+        if class.subdocument || class.key == KeyStrategy::Random {
+            continue;
+        }
+        let type_id = match layer.object_node_id(&class.id) {
+            Some(id) => id,
+            None => continue,
+        };
+        for t in layer
+            .triples_o(type_id)
+            .filter(|t| t.predicate == rdf_type_id)
+        {
+            let mut document = match context.get_id_document(t.subject, false, true)? {
+                Some(document) => document,
+                None => continue,
+            };
+            let id = layer.id_subject(t.subject).unwrap();
+            document.remove("@id");
+            match generate_id(model, class, &document, None) {
+                Ok(expected) if expected == id => {}
+                expected => mismatches.push(IdMismatch { id, expected }),
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn generate_document_ids(context, document_context_term, documents_term, ids_term) {
+        let document_context: DocumentContextBlob = document_context_term.get_ex()?;
+        let payload: PrologText = documents_term.get_ex()?;
+        let ids = context.try_or_die(document_ids(document_context.model(), &payload))?;
+
+        let ids: Vec<Atom> = ids.iter().map(|id| Atom::new(id)).collect();
+        ids_term.unify(ids.as_slice())
+    }
+
+    #[module("$doc")]
+    semidet fn verify_document_ids(context, document_context_term, mismatches_term) {
+        let document_context: DocumentContextBlob = document_context_term.get_ex()?;
+        let mismatches = context.try_or_die(check_document_ids(&document_context))?;
+
+        let mut terms = Vec::with_capacity(mismatches.len());
+        for IdMismatch { id, expected } in mismatches {
+            let id = Atom::new(&id);
+            let term = match expected {
+                Ok(expected) => {
+                    let expected = Atom::new(&expected);
+                    term! {context: mismatch(#id, #expected)}?
+                }
+                Err(e) => {
+                    let msg = e.to_string();
+                    term! {context: key_error(#id, #msg)}?
+                }
+            };
+            terms.push(term);
+        }
+        mismatches_term.unify(terms.as_slice())
+    }
+}
+
+pub fn register() {
+    register_generate_document_ids();
+    register_verify_document_ids();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(key: KeyStrategy, fields: &[(&str, FieldKind)]) -> ClassDefinition {
+        ClassDefinition {
+            id: "terminusdb:///schema#Person".to_string(),
+            kind: ClassKind::Class,
+            subdocument: false,
+            abstract_: false,
+            unfoldable: false,
+            key,
+            key_base: "Person/".to_string(),
+            parents: Vec::new(),
+            fields: fields
+                .iter()
+                .map(|(name, kind)| {
+                    (
+                        format!("terminusdb:///schema#{name}"),
+                        FieldDefinition {
+                            kind: kind.clone(),
+                            range: XSD_STRING.to_string(),
+                        },
+                    )
+                })
+                .collect(),
+            one_of: Vec::new(),
+            on_delete: Default::default(),
+        }
+    }
+
+    fn map(s: &str) -> Map<String, Value> {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn id_fragments_are_segment_encoded() {
+        assert_eq!("Gavin", encode_id_fragment("Gavin"));
+        assert_eq!("a%20b%2Fc%2Bd", encode_id_fragment("a b/c+d"));
+        assert_eq!("x:y@z!", encode_id_fragment("x:y@z!"));
+        assert_eq!("%C3%A9", encode_id_fragment("é"));
+    }
+
+    #[test]
+    fn lexical_keys_join_fields() {
+        let model = SchemaModel::default();
+        let class = class(
+            KeyStrategy::Lexical(vec![
+                "terminusdb:///schema#name".to_string(),
+                "terminusdb:///schema#nick".to_string(),
+                "terminusdb:///schema#tags".to_string(),
+            ]),
+            &[
+                ("name", FieldKind::Required),
+                ("nick", FieldKind::Optional),
+                ("tags", FieldKind::Set),
+            ],
+        );
+
+        let id = generate_id(
+            &model,
+            &class,
+            &map(r#"{"name": "Jane Doe", "tags": ["b", "a"]}"#),
+            None,
+        )
+        .unwrap();
+        assert_eq!("terminusdb:///data/Person/Jane%20Doe++none+++a++b", id);
+
+        let err = generate_id(&model, &class, &map(r#"{"nick": "J"}"#), None).unwrap_err();
+        assert!(matches!(err, IdGenError::KeyMissingRequiredField(f) if f == "name"));
+    }
+
+    // The expected ids are the ones json.pl generates in its
+    // idgen_lexical_set tests.
+    #[test]
+    fn set_keys_follow_standard_order() {
+        let model = SchemaModel::default();
+        let mut class = class(
+            KeyStrategy::Lexical(vec!["terminusdb:///schema#field".to_string()]),
+            &[("field", FieldKind::Set)],
+        );
+
+        let id = generate_id(
+            &model,
+            &class,
+            &map(r#"{"field": ["foo", "bar", "foo"]}"#),
+            None,
+        )
+        .unwrap();
+        assert_eq!("terminusdb:///data/Person/bar++foo", id);
+
+        class
+            .fields
+            .get_mut("terminusdb:///schema#field")
+            .unwrap()
+            .range = format!("{XSD_PREFIX}integer");
+        let id = generate_id(&model, &class, &map(r#"{"field": [10, 9, 10]}"#), None).unwrap();
+        assert_eq!("terminusdb:///data/Person/9++10", id);
+    }
+
+    #[test]
+    fn numbers_sort_by_value_before_text() {
+        let n = |s: &str| serde_json::from_str::<Number>(s).unwrap();
+        assert_eq!(Ordering::Less, compare_numbers(&n("9"), &n("10")));
+        assert_eq!(
+            Ordering::Less,
+            compare_numbers(&n("-1"), &n("18446744073709551615"))
+        );
+        assert_eq!(Ordering::Less, compare_numbers(&n("1.5"), &n("2")));
+        assert_eq!(Ordering::Less, compare_numbers(&n("1.0"), &n("1")));
+
+        let one = n("1");
+        assert_eq!(
+            Ordering::Less,
+            standard_order(
+                &(Some(&one), KeyValue::Value("1".to_string())),
+                &(None, KeyValue::Value("0".to_string()))
+            )
+        );
+    }
+
+    #[test]
+    fn hash_keys_hash_the_suffix() {
+        let values = vec![KeyValue::Value("Gavin".to_string())];
+        assert_eq!(
+            format!("Person/{}", hex::encode(Sha256::digest(b"Gavin"))),
+            idgen_hash("Person/", &values)
+        );
+    }
+
+    #[test]
+    fn submitted_ids_are_checked() {
+        let model = SchemaModel::default();
+        let lexical = class(
+            KeyStrategy::Lexical(vec!["terminusdb:///schema#name".to_string()]),
+            &[("name", FieldKind::Required)],
+        );
+        assert!(matches!(
+            generate_id(
+                &model,
+                &lexical,
+                &map(r#"{"@id": "Person/other", "name": "jane"}"#),
+                None
+            ),
+            Err(IdGenError::IdMismatch(_, _))
+        ));
+
+        let random = class(KeyStrategy::Random, &[]);
+        assert_eq!(
+            "terminusdb:///data/Person/mine",
+            generate_id(&model, &random, &map(r#"{"@id": "Person/mine"}"#), None).unwrap()
+        );
+        assert!(generate_id(&model, &random, &map(r#"{"@id": "Thing/mine"}"#), None).is_err());
+    }
+
+    #[test]
+    fn subdocument_ids_nest_under_their_parent() {
+        let model = SchemaModel::default();
+        let class = class(
+            KeyStrategy::Lexical(vec!["terminusdb:///schema#name".to_string()]),
+            &[("name", FieldKind::Required)],
+        );
+        let id = generate_id(
+            &model,
+            &class,
+            &map(r#"{"name": "x"}"#),
+            Some(("terminusdb:///data/Thing/1", "terminusdb:///schema#friend")),
+        )
+        .unwrap();
+        assert_eq!("terminusdb:///data/Thing/1/friend/Person/x", id);
+    }
+
+    #[test]
+    fn path_values_are_rendered_like_prolog() {
+        let model = SchemaModel::default();
+        let class = class(KeyStrategy::ValueHash, &[("name", FieldKind::Required)]);
+        assert_eq!(
+            vec![
+                "['@type']-'terminusdb:///schema#Person'".to_string(),
+                "['terminusdb:///schema#name']-\"it's\"^^'http://www.w3.org/2001/XMLSchema#string'"
+                    .to_string(),
+            ],
+            document_path_values(&model, &class, &map(r#"{"name": "it's"}"#))
+        );
+    }
+}
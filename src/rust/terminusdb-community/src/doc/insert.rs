@@ -0,0 +1,1160 @@
+use rug::Integer;
+use sha1::{Digest, Sha1};
+use tdb_succinct::*;
+use terminusdb_store_prolog::terminus_store::layer::LayerBuilder;
+
+use super::delete::{delete_id_document, DeleteError};
+use super::idgen::{generate_id, IdGenError, RANDOM_ID_LENGTH};
+use super::model::*;
+use super::*;
+use crate::graphql::temporal::parse_temporal_entry;
+use crate::random_base64_string;
+use crate::terminus_store::layer::ObjectType;
+
+#[derive(Error, Debug)]
+pub enum InsertError {
+    #[error("submitted document is not an object: {0}")]
+    NotAnObject(String),
+    #[error("submitted document has no @type: {0}")]
+    MissingType(String),
+    #[error("unknown type: {0}")]
+    UnknownType(String),
+    #[error("type {0} is abstract and can't be instantiated")]
+    AbstractType(String),
+    #[error("type {0} is a subdocument and can only be inserted as part of a document")]
+    SubdocumentAsDocument(String),
+    #[error("type {0} is not a subclass of {1}")]
+    UnexpectedType(String, String),
+    #[error("unknown property {1} for type {0}")]
+    UnknownProperty(String, String),
+    #[error("missing property {1} for type {0}")]
+    MissingProperty(String, String),
+    #[error("value {1} is not a valid {0}")]
+    InvalidValue(String, String),
+    #[error("value {1} is not a member of enum {0}")]
+    InvalidEnumValue(String, String),
+    #[error("value {1} is not an array of {0} dimensions")]
+    WrongArrayDimensions(usize, String),
+    #[error("document already exists: {0}")]
+    DocumentExists(String),
+    #[error("id submitted more than once: {0}")]
+    DuplicateId(String),
+    #[error("{0} is not supported by native insertion")]
+    Unsupported(String),
+    #[error(transparent)]
+    IdGen(#[from] IdGenError),
+    #[error(transparent)]
+    Delete(#[from] DeleteError),
+    #[error("submitted documents are not valid json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl IntoPrologException for InsertError {
+    fn into_prolog_exception<'a, T: QueryableContextType>(
+        self,
+        context: &'a Context<'_, T>,
+    ) -> PrologResult<Term<'a>> {
+        let term = match self {
+            InsertError::NotAnObject(s) => term! {context: error(not_a_document(#s), _)}?,
+            InsertError::MissingType(s) => term! {context: error(missing_type(#s), _)}?,
+            InsertError::UnknownType(s) => term! {context: error(unknown_type(#s), _)}?,
+            InsertError::AbstractType(s) => {
+                term! {context: error(cannot_insert_abstract_type(#s), _)}?
+            }
+            InsertError::SubdocumentAsDocument(s) => {
+                term! {context: error(inserted_subdocument_as_document(#s), _)}?
+            }
+            InsertError::UnexpectedType(t, expected) => {
+                term! {context: error(unexpected_type(#t, #expected), _)}?
+            }
+            InsertError::UnknownProperty(t, p) => {
+                term! {context: error(unknown_property_for_type(#t, #p), _)}?
+            }
+            InsertError::MissingProperty(t, p) => {
+                term! {context: error(missing_property(#t, #p), _)}?
+            }
+            InsertError::InvalidValue(t, v) => term! {context: error(casting_error(#v, #t), _)}?,
+            InsertError::InvalidEnumValue(t, v) => {
+                term! {context: error(not_a_valid_enum(#t, #v), _)}?
+            }
+            InsertError::WrongArrayDimensions(d, v) => {
+                let d = d as u64;
+                term! {context: error(wrong_array_dimensions(#v, #d), _)}?
+            }
+            InsertError::DocumentExists(s) => {
+                term! {context: error(can_not_insert_existing_object_with_id(#s), _)}?
+            }
+            InsertError::DuplicateId(s) => {
+                term! {context: error(same_ids_in_one_transaction(#s), _)}?
+            }
+            InsertError::Unsupported(s) => {
+                term! {context: error(native_insert_unsupported(#s), _)}?
+            }
+            InsertError::IdGen(e) => return e.into_prolog_exception(context),
+            InsertError::Delete(e) => return e.into_prolog_exception(context),
+            InsertError::Json(e) => {
+                let msg = e.to_string();
+                term! {context: error(malformed_json_payload(#msg), _)}?
+            }
+        };
+
+        context.raise_exception(&term)
+    }
+}
+
+/// What to do with documents whose id is already in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Fail if the document already exists.
+    Insert,
+    /// Fail if the document does not exist yet, and overwrite it
+    /// otherwise.
+    Replace,
+    /// Overwrite the document if it exists, and create it otherwise.
+    ReplaceOrCreate,
+}
+
+/// The triples for a single submitted document.
+pub struct DocumentTriples {
+    pub id: String,
+    pub triples: Vec<ValueTriple>,
+}
+
+enum Object {
+    Node(String),
+    Value(TypedDictEntry),
+}
+
+impl Object {
+    fn to_object_type(&self) -> ObjectType {
+        match self {
+            Object::Node(node) => ObjectType::Node(node.clone()),
+            Object::Value(value) => ObjectType::Value(value.clone()),
+        }
+    }
+}
+
+/// The list and array cells of a stored document, keyed by the node
+/// and property holding them.
+///
+/// When a document is written over, cells whose element did not change
+/// are taken from here rather than minted anew, so that only the
+/// triples of the elements that changed get rewritten.
+#[derive(Default)]
+pub struct ExistingCells {
+    lists: HashMap<(String, String), ExistingList>,
+    arrays: HashMap<(String, String), Vec<(String, Vec<(String, ObjectType)>)>>,
+}
+
+#[derive(Default)]
+struct ExistingList {
+    cells: Vec<(String, ObjectType)>,
+    /// Cells are only reused in their original order, so the search
+    /// for the next one starts after the last one taken.
+    next: usize,
+}
+
+impl ExistingCells {
+    pub fn from_triples(triples: &[ValueTriple]) -> Self {
+        let mut types: HashMap<&str, &str> = HashMap::new();
+        let mut by_subject: HashMap<&str, Vec<&ValueTriple>> = HashMap::new();
+        for triple in triples {
+            by_subject
+                .entry(triple.subject.as_str())
+                .or_default()
+                .push(triple);
+            if let (RDF_TYPE, ObjectType::Node(typ)) = (triple.predicate.as_str(), &triple.object) {
+                types.insert(triple.subject.as_str(), typ.as_str());
+            }
+        }
+        let object_of = |cell: &str, predicate: &str| {
+            by_subject.get(cell).and_then(|triples| {
+                triples
+                    .iter()
+                    .find(|t| t.predicate == predicate)
+                    .map(|t| &t.object)
+            })
+        };
+
+        let mut cells = ExistingCells::default();
+        for triple in triples {
+            let cell = match &triple.object {
+                ObjectType::Node(cell) => cell.as_str(),
+                ObjectType::Value(_) => continue,
+            };
+            let key = (triple.subject.clone(), triple.predicate.clone());
+            match types.get(cell) {
+                Some(&RDF_LIST) if triple.predicate != RDF_REST => {
+                    let mut list = ExistingList::default();
+                    let mut cell = cell;
+                    while types.get(cell) == Some(&RDF_LIST) {
+                        if let Some(first) = object_of(cell, RDF_FIRST) {
+                            list.cells.push((cell.to_string(), first.clone()));
+                        }
+                        match object_of(cell, RDF_REST) {
+                            Some(ObjectType::Node(rest)) => cell = rest,
+                            _ => break,
+                        }
+                    }
+                    cells.lists.insert(key, list);
+                }
+                Some(&SYS_ARRAY) => {
+                    let contents = by_subject[cell]
+                        .iter()
+                        .filter(|t| t.predicate != RDF_TYPE)
+                        .map(|t| (t.predicate.clone(), t.object.clone()))
+                        .collect();
+                    cells
+                        .arrays
+                        .entry(key)
+                        .or_default()
+                        .push((cell.to_string(), contents));
+                }
+                _ => {}
+            }
+        }
+
+        cells
+    }
+
+    fn take_list_cell(
+        &mut self,
+        subject: &str,
+        property: &str,
+        first: &ObjectType,
+    ) -> Option<String> {
+        let list = self
+            .lists
+            .get_mut(&(subject.to_string(), property.to_string()))?;
+        let offset = list.cells[list.next..]
+            .iter()
+            .position(|(_, object)| object == first)?;
+        let index = list.next + offset;
+        list.next = index + 1;
+        Some(list.cells[index].0.clone())
+    }
+
+    fn take_array_cell(
+        &mut self,
+        subject: &str,
+        property: &str,
+        contents: &[(String, ObjectType)],
+    ) -> Option<String> {
+        let cells = self
+            .arrays
+            .get_mut(&(subject.to_string(), property.to_string()))?;
+        let index = cells.iter().position(|(_, existing)| {
+            existing.len() == contents.len() && contents.iter().all(|c| existing.contains(c))
+        })?;
+        Some(cells.swap_remove(index).0)
+    }
+}
+
+fn json_scalar_entry(value: &Value) -> Result<TypedDictEntry, InsertError> {
+    match value {
+        Value::String(s) => Ok(String::make_entry(s)),
+        Value::Number(n) => {
+            // numbers in exponent notation are not valid decimals, so
+            // those go through a float first
+            let decimal = Decimal::new(n.to_string())
+                .or_else(|_| Decimal::new(format!("{}", n.as_f64().unwrap_or(0.0))))
+                .map_err(|_| InsertError::InvalidValue(SYS_JSON.to_string(), n.to_string()))?;
+            Ok(Decimal::make_entry(&decimal))
+        }
+        Value::Bool(b) => Ok(bool::make_entry(b)),
+        _ => Ok(Token::make_entry("null")),
+    }
+}
+
+/// The way prolog's `~q` prints a json scalar, which is what goes into
+/// the content hash.
+pub(super) fn quoted_json_scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => {
+            let mut result = String::with_capacity(s.len() + 2);
+            result.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => result.push_str("\\\""),
+                    '\\' => result.push_str("\\\\"),
+                    '\n' => result.push_str("\\n"),
+                    '\t' => result.push_str("\\t"),
+                    c => result.push(c),
+                }
+            }
+            result.push('"');
+            result
+        }
+        Value::Null => "null".to_string(),
+        v => v.to_string(),
+    }
+}
+
+fn hex_digest(hasher: &Sha1) -> String {
+    hex::encode(hasher.clone().finalize())
+}
+
+/// Given a nested json array, return every non-null element with its
+/// index along each of the `dimensions` levels, outermost first.
+fn array_elements(
+    value: &Value,
+    dimensions: usize,
+) -> Result<Vec<(Vec<usize>, &Value)>, InsertError> {
+    fn collect<'a>(
+        value: &'a Value,
+        dimensions: usize,
+        index: &mut Vec<usize>,
+        result: &mut Vec<(Vec<usize>, &'a Value)>,
+    ) -> bool {
+        if index.len() == dimensions {
+            if !value.is_null() {
+                result.push((index.clone(), value));
+            }
+            return true;
+        }
+        match value {
+            Value::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    index.push(i);
+                    let ok = collect(element, dimensions, index, result);
+                    index.pop();
+                    if !ok {
+                        return false;
+                    }
+                }
+                true
+            }
+            // holes in multidimensional arrays come back as null
+            Value::Null => true,
+            _ => false,
+        }
+    }
+
+    let mut result = Vec::new();
+    if collect(value, dimensions, &mut Vec::new(), &mut result) {
+        Ok(result)
+    } else {
+        Err(InsertError::WrongArrayDimensions(
+            dimensions,
+            value.to_string(),
+        ))
+    }
+}
+
+fn index_predicate(level: usize) -> String {
+    if level == 1 {
+        SYS_INDEX.to_string()
+    } else {
+        format!("{SYS_INDEX}{level}")
+    }
+}
+
+/// Convert a json value to a dictionary entry of the given base type.
+pub fn json_to_entry(base_type: &str, value: &Value) -> Option<TypedDictEntry> {
+    let short_type = match base_type.strip_prefix(XSD_PREFIX) {
+        Some(short_type) => short_type,
+        // xdd and other custom base types are stored as plain strings
+        None => return value.as_str().map(String::make_entry),
+    };
+    let text = || match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+    let integer = || text().and_then(|s| s.parse::<Integer>().ok());
+    let string = || value.as_str();
+    match short_type {
+        "boolean" => value.as_bool().map(|b| bool::make_entry(&b)),
+        "byte" => text()?.parse::<i8>().ok().map(|i| i8::make_entry(&i)),
+        "short" => text()?.parse::<i16>().ok().map(|i| i16::make_entry(&i)),
+        "int" => text()?.parse::<i32>().ok().map(|i| i32::make_entry(&i)),
+        "long" => text()?.parse::<i64>().ok().map(|i| i64::make_entry(&i)),
+        "unsignedByte" => text()?.parse::<u8>().ok().map(|i| u8::make_entry(&i)),
+        "unsignedShort" => text()?.parse::<u16>().ok().map(|i| u16::make_entry(&i)),
+        "unsignedInt" => text()?.parse::<u32>().ok().map(|i| u32::make_entry(&i)),
+        "unsignedLong" => text()?.parse::<u64>().ok().map(|i| u64::make_entry(&i)),
+        "integer" => integer().map(|i| Integer::make_entry(&i)),
+        "positiveInteger" => integer()
+            .filter(|i| *i > 0)
+            .map(|i| PositiveInteger::make_entry(&PositiveInteger(i))),
+        "nonNegativeInteger" => integer()
+            .filter(|i| *i >= 0)
+            .map(|i| NonNegativeInteger::make_entry(&NonNegativeInteger(i))),
+        "negativeInteger" => integer()
+            .filter(|i| *i < 0)
+            .map(|i| NegativeInteger::make_entry(&NegativeInteger(i))),
+        "nonPositiveInteger" => integer()
+            .filter(|i| *i <= 0)
+            .map(|i| NonPositiveInteger::make_entry(&NonPositiveInteger(i))),
+        "float" => text()?.parse::<f32>().ok().map(|f| f32::make_entry(&f)),
+        "double" => text()?.parse::<f64>().ok().map(|f| f64::make_entry(&f)),
+        "decimal" => Decimal::new(text()?).ok().map(|d| Decimal::make_entry(&d)),
+        "language" => string().map(Language::make_entry),
+        "normalizedString" => string().map(NormalizedString::make_entry),
+        "token" => string().map(Token::make_entry),
+        "NMTOKEN" => string().map(NMToken::make_entry),
+        "Name" => string().map(Name::make_entry),
+        "NCName" => string().map(NCName::make_entry),
+        "anyURI" => string().map(AnyURI::make_entry),
+        "anySimpleType" => string().map(AnySimpleType::make_entry),
+        "base64Binary" => base64::decode(string()?)
+            .ok()
+            .map(|b| Base64Binary::make_entry(&Base64Binary(b))),
+        "hexBinary" => hex::decode(string()?)
+            .ok()
+            .map(|b| HexBinary::make_entry(&HexBinary(b))),
+        "string" => string().map(String::make_entry),
+        _ => {
+            parse_temporal_entry(short_type, string()?).or_else(|| string().map(String::make_entry))
+        }
+    }
+}
+
+struct TripleWriter<'a> {
+    model: &'a SchemaModel,
+    triples: Vec<ValueTriple>,
+    cells: ExistingCells,
+}
+
+impl<'a> TripleWriter<'a> {
+    fn new(model: &'a SchemaModel) -> Self {
+        Self::reusing(model, ExistingCells::default())
+    }
+
+    fn reusing(model: &'a SchemaModel, cells: ExistingCells) -> Self {
+        Self {
+            model,
+            triples: Vec::new(),
+            cells,
+        }
+    }
+
+    fn node(&mut self, subject: &str, predicate: &str, object: &str) {
+        self.triples
+            .push(ValueTriple::new_node(subject, predicate, object));
+    }
+
+    fn object(&mut self, subject: &str, predicate: &str, object: Object) {
+        match object {
+            Object::Node(node) => self.node(subject, predicate, &node),
+            Object::Value(value) => self
+                .triples
+                .push(ValueTriple::new_value(subject, predicate, value)),
+        }
+    }
+
+    fn random_id(&self, segment: &str) -> String {
+        format!(
+            "{}{segment}{}",
+            self.model.base,
+            random_base64_string(RANDOM_ID_LENGTH)
+        )
+    }
+
+    /// Write a json value that is stored as content-addressed nodes,
+    /// returning its hash and the object that links to it. This
+    /// mirrors `json_subdocument_triple` in `json_rdf.pl`, so the same
+    /// json ends up at the same node either way.
+    fn json(&mut self, value: &Value) -> Result<(String, Object), InsertError> {
+        match value {
+            Value::Object(map) => {
+                let mut members: Vec<_> = map.iter().collect();
+                members.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+                let mut hasher = Sha1::new();
+                hasher.update(b"Dict(");
+                let mut links = Vec::with_capacity(members.len());
+                for (key, value) in members {
+                    let encoded = urlencoding::encode(key);
+                    let (hash, object) = self.json(value)?;
+                    hasher.update(format!("\"{encoded}\"-{hash}").as_bytes());
+                    links.push((format!("{SYS_JSON_PREFIX}{encoded}"), object));
+                }
+                hasher.update(b")");
+                let hash = hex_digest(&hasher);
+                let node = format!("{JSON_DATA_PREFIX}JSON/SHA1/{hash}");
+                self.node(&node, RDF_TYPE, SYS_JSON);
+                for (predicate, object) in links {
+                    self.object(&node, &predicate, object);
+                }
+
+                Ok((hash, Object::Node(node)))
+            }
+            Value::Array(elements) => {
+                let mut hasher = Sha1::new();
+                hasher.update(b"List(");
+                let mut hash = {
+                    let mut h = hasher.clone();
+                    h.update(b")");
+                    hex_digest(&h)
+                };
+                let mut rest = RDF_NIL.to_string();
+                for element in elements.iter().rev() {
+                    let (element_hash, element_object) = self.json(element)?;
+                    hasher.update(element_hash.as_bytes());
+                    let mut h = hasher.clone();
+                    h.update(b")");
+                    hash = hex_digest(&h);
+                    let cell = format!("{JSON_DATA_PREFIX}Cons/SHA1/{hash}");
+                    self.node(&cell, RDF_TYPE, RDF_LIST);
+                    self.object(&cell, RDF_FIRST, element_object);
+                    self.node(&cell, RDF_REST, &rest);
+                    rest = cell;
+                }
+
+                Ok((hash, Object::Node(rest)))
+            }
+            scalar => {
+                let mut hasher = Sha1::new();
+                hasher.update(format!("val({})", quoted_json_scalar(scalar)).as_bytes());
+
+                Ok((
+                    hex_digest(&hasher),
+                    Object::Value(json_scalar_entry(scalar)?),
+                ))
+            }
+        }
+    }
+
+    fn json_document(&mut self, id: &str, map: &Map<String, Value>) -> Result<(), InsertError> {
+        self.node(id, RDF_TYPE, SYS_JSON_DOCUMENT);
+        for (key, value) in map {
+            if key == "@id" {
+                continue;
+            }
+            let predicate = format!("{SYS_JSON_PREFIX}{}", urlencoding::encode(key));
+            let (_, object) = self.json(value)?;
+            self.object(id, &predicate, object);
+        }
+
+        Ok(())
+    }
+
+    fn document(
+        &mut self,
+        class: &ClassDefinition,
+        map: &Map<String, Value>,
+        parent: Option<(&str, &str)>,
+    ) -> Result<String, InsertError> {
+        if class.abstract_ {
+            return Err(InsertError::AbstractType(class.id.clone()));
+        }
+        let id = generate_id(self.model, class, map, parent)?;
+        self.node(&id, RDF_TYPE, &class.id);
+
+        for (key, value) in map {
+            if key == "@capture" || key == "@linked-by" {
+                return Err(InsertError::Unsupported(key.clone()));
+            }
+            if key.starts_with('@') {
+                continue;
+            }
+            let property = self.model.expand_schema(key);
+            let field = class
+                .field(&property)
+                .ok_or_else(|| InsertError::UnknownProperty(class.id.clone(), property.clone()))?;
+            self.field(&id, class, &property, field, value)?;
+        }
+
+        if class.kind != ClassKind::TaggedUnion {
+            for (property, field) in class.fields.iter() {
+                if field.kind == FieldKind::Required
+                    && !map
+                        .iter()
+                        .any(|(k, v)| !v.is_null() && &self.model.expand_schema(k) == property)
+                {
+                    return Err(InsertError::MissingProperty(
+                        class.id.clone(),
+                        property.clone(),
+                    ));
+                }
+            }
+        }
+
+        Ok(id)
+    }
+
+    fn field(
+        &mut self,
+        id: &str,
+        class: &ClassDefinition,
+        property: &str,
+        field: &FieldDefinition,
+        value: &Value,
+    ) -> Result<(), InsertError> {
+        match &field.kind {
+            FieldKind::Required | FieldKind::Optional => {
+                if value.is_null() {
+                    if field.kind == FieldKind::Required {
+                        return Err(InsertError::MissingProperty(
+                            class.id.clone(),
+                            property.to_string(),
+                        ));
+                    }
+                    return Ok(());
+                }
+                let object = self.element(id, property, &field.range, value)?;
+                self.object(id, property, object);
+            }
+            FieldKind::Set | FieldKind::Cardinality { .. } => {
+                let elements = match value {
+                    Value::Array(elements) => elements.iter().collect(),
+                    Value::Null => Vec::new(),
+                    v => vec![v],
+                };
+                for element in elements {
+                    let object = self.element(id, property, &field.range, element)?;
+                    self.object(id, property, object);
+                }
+            }
+            FieldKind::List => {
+                let elements = match value {
+                    Value::Array(elements) => elements,
+                    v => {
+                        return Err(InsertError::InvalidValue(
+                            field.range.clone(),
+                            v.to_string(),
+                        ))
+                    }
+                };
+                let mut subject = id.to_string();
+                let mut predicate = property;
+                for element in elements {
+                    let object = self.element(id, property, &field.range, element)?;
+                    let cell = self
+                        .cells
+                        .take_list_cell(id, property, &object.to_object_type())
+                        .unwrap_or_else(|| self.random_id("Cons/"));
+                    self.node(&subject, predicate, &cell);
+                    self.node(&cell, RDF_TYPE, RDF_LIST);
+                    self.object(&cell, RDF_FIRST, object);
+                    subject = cell;
+                    predicate = RDF_REST;
+                }
+                self.node(&subject, predicate, RDF_NIL);
+            }
+            FieldKind::Array(dimensions) => {
+                for (index, element) in array_elements(value, *dimensions)? {
+                    let object = self.element(id, property, &field.range, element)?;
+                    // sys:index holds the innermost index, sys:index2
+                    // the one around it, and so on.
+                    let mut contents: Vec<(String, ObjectType)> = index
+                        .iter()
+                        .rev()
+                        .enumerate()
+                        .map(|(level, i)| {
+                            let entry = NonNegativeInteger::make_entry(&NonNegativeInteger(
+                                Integer::from(*i),
+                            ));
+                            (index_predicate(level + 1), ObjectType::Value(entry))
+                        })
+                        .collect();
+                    contents.push((SYS_VALUE.to_string(), object.to_object_type()));
+                    let cell = self
+                        .cells
+                        .take_array_cell(id, property, &contents)
+                        .unwrap_or_else(|| self.random_id("Array_"));
+                    self.node(id, property, &cell);
+                    self.node(&cell, RDF_TYPE, SYS_ARRAY);
+                    for (predicate, object) in contents {
+                        self.triples.push(ValueTriple {
+                            subject: cell.clone(),
+                            predicate,
+                            object,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a single value of a field against the field's range.
+    fn element(
+        &mut self,
+        id: &str,
+        property: &str,
+        range: &str,
+        value: &Value,
+    ) -> Result<Object, InsertError> {
+        if range == SYS_JSON {
+            let value = match value {
+                Value::Object(map) if map.contains_key("@type") => {
+                    let mut map = map.clone();
+                    map.remove("@type");
+                    Value::Object(map)
+                }
+                v => v.clone(),
+            };
+            return Ok(self.json(&value)?.1);
+        }
+        if range == SYS_UNIT {
+            return match value {
+                Value::Array(a) if a.is_empty() => Ok(Object::Node(RDF_NIL.to_string())),
+                v => Err(InsertError::InvalidValue(range.to_string(), v.to_string())),
+            };
+        }
+
+        let model = self.model;
+        match model.class(range) {
+            Some(class) => match &class.kind {
+                ClassKind::Enum(values) => {
+                    let s = value.as_str().ok_or_else(|| {
+                        InsertError::InvalidEnumValue(range.to_string(), value.to_string())
+                    })?;
+                    let node = format!("{range}/{}", urlencoding::encode(s));
+                    if values.contains(&node) {
+                        Ok(Object::Node(node))
+                    } else {
+                        Err(InsertError::InvalidEnumValue(
+                            range.to_string(),
+                            s.to_string(),
+                        ))
+                    }
+                }
+                _ => match value {
+                    Value::String(s) => Ok(Object::Node(model.expand_instance(s))),
+                    Value::Object(map) if map.contains_key("@ref") => {
+                        Err(InsertError::Unsupported("@ref".to_string()))
+                    }
+                    Value::Object(map)
+                        if map.keys().all(|k| k == "@id" || k == "@type")
+                            && map.contains_key("@id")
+                            && !class.subdocument =>
+                    {
+                        let id = map["@id"].as_str().ok_or_else(|| {
+                            InsertError::InvalidValue(range.to_string(), value.to_string())
+                        })?;
+                        Ok(Object::Node(model.expand_instance(id)))
+                    }
+                    Value::Object(map) => {
+                        let typ = match map.get("@type").and_then(|t| t.as_str()) {
+                            Some(t) => model.expand_schema(t),
+                            None => range.to_string(),
+                        };
+                        let sub_class = model
+                            .class(&typ)
+                            .ok_or_else(|| InsertError::UnknownType(typ.clone()))?;
+                        if !model.is_subclass_of(&typ, range) {
+                            return Err(InsertError::UnexpectedType(typ, range.to_string()));
+                        }
+                        if !sub_class.subdocument {
+                            return Err(InsertError::InvalidValue(
+                                range.to_string(),
+                                value.to_string(),
+                            ));
+                        }
+                        let sub_id = self.document(sub_class, map, Some((id, property)))?;
+                        Ok(Object::Node(sub_id))
+                    }
+                    v => Err(InsertError::InvalidValue(range.to_string(), v.to_string())),
+                },
+            },
+            None => {
+                let (base_type, value) = match value {
+                    Value::Object(map) if map.contains_key("@lang") => {
+                        let lang = map["@lang"].as_str();
+                        let s = map.get("@value").and_then(|v| v.as_str());
+                        return match (lang, s) {
+                            (Some(lang), Some(s)) => Ok(Object::Value(LangString::make_entry(
+                                &format!("{lang}@{s}"),
+                            ))),
+                            _ => Err(InsertError::InvalidValue(
+                                range.to_string(),
+                                value.to_string(),
+                            )),
+                        };
+                    }
+                    Value::Object(map) if map.contains_key("@value") => {
+                        let base_type = match map.get("@type").and_then(|t| t.as_str()) {
+                            Some(t) => model.expand_schema(t),
+                            None => range.to_string(),
+                        };
+                        (base_type, &map["@value"])
+                    }
+                    v => (range.to_string(), v),
+                };
+                json_to_entry(&base_type, value)
+                    .map(Object::Value)
+                    .ok_or_else(|| InsertError::InvalidValue(base_type, value.to_string()))
+            }
+        }
+    }
+}
+
+/// Convert a single submitted document into the triples that
+/// represent it, resolving its shape against the schema.
+pub fn document_to_triples(
+    model: &SchemaModel,
+    document: &Value,
+) -> Result<DocumentTriples, InsertError> {
+    document_to_triples_reusing(model, document, ExistingCells::default())
+}
+
+/// Like [`document_to_triples`], but taking list and array cells from
+/// the stored version of the document where the element is unchanged.
+pub fn document_to_triples_reusing(
+    model: &SchemaModel,
+    document: &Value,
+    cells: ExistingCells,
+) -> Result<DocumentTriples, InsertError> {
+    let map = document
+        .as_object()
+        .ok_or_else(|| InsertError::NotAnObject(document.to_string()))?;
+    let typ = map
+        .get("@type")
+        .and_then(|t| t.as_str())
+        .map(|t| model.expand_schema(t))
+        .ok_or_else(|| InsertError::MissingType(document.to_string()))?;
+
+    let mut writer = TripleWriter::reusing(model, cells);
+    let id = if typ == SYS_JSON_DOCUMENT {
+        let id = match map.get("@id").and_then(|id| id.as_str()) {
+            Some(id) => model.expand_instance(id),
+            None => writer.random_id("JSONDocument/"),
+        };
+        let mut map = map.clone();
+        map.remove("@type");
+        writer.json_document(&id, &map)?;
+        id
+    } else {
+        let class = model
+            .class(&typ)
+            .ok_or_else(|| InsertError::UnknownType(typ.clone()))?;
+        if class.subdocument {
+            return Err(InsertError::SubdocumentAsDocument(typ));
+        }
+        writer.document(class, map, None)?
+    };
+
+    Ok(DocumentTriples {
+        id,
+        triples: writer.triples,
+    })
+}
+
+/// Convert a schemaless json document into triples, as a
+/// `sys:JSONDocument`.
+pub fn json_document_to_triples(
+    model: &SchemaModel,
+    document: &Value,
+) -> Result<DocumentTriples, InsertError> {
+    let map = document
+        .as_object()
+        .ok_or_else(|| InsertError::NotAnObject(document.to_string()))?;
+    let mut writer = TripleWriter::new(model);
+    let id = match map.get("@id").and_then(|id| id.as_str()) {
+        Some(id) => model.expand_instance(id),
+        None => writer.random_id("JSONDocument/"),
+    };
+    writer.json_document(&id, map)?;
+
+    Ok(DocumentTriples {
+        id,
+        triples: writer.triples,
+    })
+}
+
+/// Convert a batch of documents in parallel. Conversion doesn't touch
+/// the store, so this is where the bulk of the work of an insert can
+/// be spread out.
+pub fn documents_to_triples(
+    model: &SchemaModel,
+    documents: &[Value],
+    raw_json: bool,
+) -> Result<Vec<DocumentTriples>, InsertError> {
+    documents
+        .par_iter()
+        .map(|document| {
+            if raw_json {
+                json_document_to_triples(model, document)
+            } else {
+                document_to_triples(model, document)
+            }
+        })
+        .collect()
+}
+
+/// Write converted documents into the builder, checking their ids
+/// against what is already there.
+pub fn write_documents<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    documents: Vec<DocumentTriples>,
+    mode: WriteMode,
+) -> Result<Vec<String>, InsertError> {
+    let mut seen = HashSet::with_capacity(documents.len());
+    for document in documents.iter() {
+        if !seen.insert(document.id.as_str()) {
+            return Err(InsertError::DuplicateId(document.id.clone()));
+        }
+    }
+
+    // everything is checked before anything is written, so a failed
+    // batch leaves the builder untouched
+    let mut existing_ids = Vec::with_capacity(documents.len());
+    for document in documents.iter() {
+        let existing = context.layer.as_ref().and_then(|layer| {
+            layer
+                .subject_id(&document.id)
+                .filter(|id| context.id_document_exists(*id))
+        });
+        match (existing, mode) {
+            (Some(_), WriteMode::Insert) => {
+                return Err(InsertError::DocumentExists(document.id.clone()))
+            }
+            (None, WriteMode::Replace) => {
+                return Err(DeleteError::DocumentNotFound(document.id.clone()).into())
+            }
+            _ => existing_ids.push(existing),
+        }
+    }
+
+    let mut ids = Vec::with_capacity(documents.len());
+    for (document, existing) in documents.into_iter().zip(existing_ids) {
+        if let Some(existing) = existing {
+            delete_id_document(context, builder, existing);
+        }
+        for triple in document.triples {
+            builder.add_value_triple(triple);
+        }
+        ids.push(document.id);
+    }
+
+    Ok(ids)
+}
+
+pub fn insert_document_batch<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    documents: &[Value],
+    raw_json: bool,
+    mode: WriteMode,
+) -> Result<Vec<String>, InsertError> {
+    let documents = documents_to_triples(context.model(), documents, raw_json)?;
+    write_documents(context, builder, documents, mode)
+}
+
+/// Parse a json payload the way `stream_to_lazy_docs` reads it: a
+/// sequence of values, each either a document or an array of
+/// documents.
+fn parse_documents(s: &str) -> Result<Vec<Value>, InsertError> {
+    let mut documents = Vec::new();
+    for value in serde_json::Deserializer::from_str(s).into_iter::<Value>() {
+        match value? {
+            Value::Array(elements) => documents.extend(elements),
+            document => documents.push(document),
+        }
+    }
+
+    Ok(documents)
+}
+
+fn write_documents_predicate<C: QueryableContextType>(
+    context: &Context<C>,
+    document_context_term: &Term,
+    transaction_term: &Term,
+    documents_term: &Term,
+    raw_json_term: &Term,
+    ids_term: &Term,
+    mode: WriteMode,
+) -> PrologResult<()> {
+    let document_context: DocumentContextBlob = document_context_term.get_ex()?;
+    let payload: PrologText = documents_term.get_ex()?;
+    let raw_json: bool = raw_json_term.get_ex()?;
+    let documents = context.try_or_die(parse_documents(&payload))?;
+    let builder = transaction_instance_builder(context, transaction_term)?;
+    if builder.is_none() {
+        return context.raise_exception(&term! {context: error(builder_not_initialized, _)}?);
+    }
+    let builder = builder.unwrap();
+    let ids = context.try_or_die(context.try_or_die(builder.with_builder(|builder| {
+        insert_document_batch(
+            &document_context,
+            &mut **builder,
+            &documents,
+            raw_json,
+            mode,
+        )
+    }))?)?;
+
+    let ids: Vec<Atom> = ids.iter().map(|id| Atom::new(id)).collect();
+    ids_term.unify(ids.as_slice())
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn insert_documents(context, document_context_term, transaction_term, documents_term, raw_json_term, ids_term) {
+        write_documents_predicate(context, document_context_term, transaction_term, documents_term, raw_json_term, ids_term, WriteMode::Insert)
+    }
+
+    #[module("$doc")]
+    semidet fn replace_documents(context, document_context_term, transaction_term, documents_term, raw_json_term, create_term, ids_term) {
+        let create: bool = create_term.get_ex()?;
+        let mode = if create {
+            WriteMode::ReplaceOrCreate
+        } else {
+            WriteMode::Replace
+        };
+        write_documents_predicate(context, document_context_term, transaction_term, documents_term, raw_json_term, ids_term, mode)
+    }
+}
+
+pub fn register() {
+    register_insert_documents();
+    register_replace_documents();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_element_indexes() {
+        let value: Value = serde_json::from_str("[[1, 2], [3, null, 5]]").unwrap();
+        let elements = array_elements(&value, 2).unwrap();
+        let indexes: Vec<_> = elements.iter().map(|(i, _)| i.clone()).collect();
+        assert_eq!(
+            vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 2]],
+            indexes
+        );
+
+        assert!(array_elements(&value, 3).is_err());
+        assert_eq!(2, array_elements(&value, 1).unwrap().len());
+    }
+
+    fn cell_of(triples: &[ValueTriple], predicate: &str, value: &str) -> String {
+        let entry = ObjectType::Value(String::make_entry(value));
+        triples
+            .iter()
+            .find(|t| t.predicate == predicate && t.object == entry)
+            .map(|t| t.subject.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn unchanged_cells_are_reused() {
+        let model = SchemaModel::default();
+        let class = ClassDefinition {
+            id: "terminusdb:///schema#Thing".to_string(),
+            kind: ClassKind::Class,
+            subdocument: false,
+            abstract_: false,
+            unfoldable: false,
+            key: KeyStrategy::Random,
+            key_base: "Thing/".to_string(),
+            parents: Vec::new(),
+            fields: Default::default(),
+            one_of: Vec::new(),
+            on_delete: Default::default(),
+        };
+        let id = "terminusdb:///data/Thing/1";
+        let write = |kind: FieldKind, value: &str, cells: ExistingCells| {
+            let field = FieldDefinition {
+                kind,
+                range: XSD_STRING.to_string(),
+            };
+            let value: Value = serde_json::from_str(value).unwrap();
+            let mut writer = TripleWriter::reusing(&model, cells);
+            writer
+                .field(id, &class, "terminusdb:///schema#p", &field, &value)
+                .unwrap();
+            writer.triples
+        };
+
+        let old = write(
+            FieldKind::List,
+            r#"["a", "b", "c"]"#,
+            ExistingCells::default(),
+        );
+        let new = write(
+            FieldKind::List,
+            r#"["a", "x", "c"]"#,
+            ExistingCells::from_triples(&old),
+        );
+        assert_eq!(cell_of(&old, RDF_FIRST, "a"), cell_of(&new, RDF_FIRST, "a"));
+        assert_eq!(cell_of(&old, RDF_FIRST, "c"), cell_of(&new, RDF_FIRST, "c"));
+        assert_ne!(cell_of(&old, RDF_FIRST, "b"), cell_of(&new, RDF_FIRST, "x"));
+
+        let old = write(
+            FieldKind::Array(1),
+            r#"["a", "b"]"#,
+            ExistingCells::default(),
+        );
+        let new = write(
+            FieldKind::Array(1),
+            r#"["a", "x"]"#,
+            ExistingCells::from_triples(&old),
+        );
+        assert_eq!(cell_of(&old, SYS_VALUE, "a"), cell_of(&new, SYS_VALUE, "a"));
+        assert_ne!(cell_of(&old, SYS_VALUE, "b"), cell_of(&new, SYS_VALUE, "x"));
+        let unchanged = old.iter().filter(|t| new.contains(t)).count();
+        // the head link, type, index and value of the first cell
+        assert_eq!(4, unchanged);
+    }
+
+    #[test]
+    fn payloads_are_read_as_a_stream() {
+        let documents = parse_documents("{\"a\": 1}\n[{\"b\": 2}, {\"c\": 3}] {\"d\": 4}").unwrap();
+        assert_eq!(4, documents.len());
+        assert!(parse_documents("{\"a\": 1} {").is_err());
+    }
+
+    #[test]
+    fn json_scalars_hash_like_prolog() {
+        assert_eq!(
+            "\"Gavin\"",
+            quoted_json_scalar(&Value::String("Gavin".into()))
+        );
+        assert_eq!("null", quoted_json_scalar(&Value::Null));
+        assert_eq!("true", quoted_json_scalar(&Value::Bool(true)));
+        assert_eq!(
+            "\"say \\\"hi\\\"\"",
+            quoted_json_scalar(&Value::String("say \"hi\"".into()))
+        );
+    }
+
+    #[test]
+    fn json_nodes_are_content_addressed() {
+        let model = SchemaModel::default();
+        let value: Value = serde_json::from_str(r#"{"b": [1, 2], "a": {"c": null}}"#).unwrap();
+        let reordered: Value = serde_json::from_str(r#"{"a": {"c": null}, "b": [1, 2]}"#).unwrap();
+
+        let mut writer = TripleWriter::new(&model);
+        let (hash1, _) = writer.json(&value).unwrap();
+        let mut writer = TripleWriter::new(&model);
+        let (hash2, object) = writer.json(&reordered).unwrap();
+        assert_eq!(hash1, hash2);
+        match object {
+            Object::Node(node) => {
+                assert_eq!(format!("{JSON_DATA_PREFIX}JSON/SHA1/{hash1}"), node)
+            }
+            _ => panic!("expected a json node"),
+        }
+        // one dict with two members, one inner dict with one member, and
+        // two cons cells of three triples each
+        assert_eq!(3 + 2 + 6, writer.triples.len());
+    }
+
+    #[test]
+    fn base_type_entries() {
+        let entry = json_to_entry(&format!("{XSD_PREFIX}decimal"), &serde_json::json!(1.5));
+        assert_eq!(
+            Some(Decimal::make_entry(&Decimal::new("1.5".into()).unwrap())),
+            entry
+        );
+        let entry = json_to_entry(
+            &format!("{XSD_PREFIX}unsignedLong"),
+            &serde_json::json!("18446744073709551615"),
+        );
+        assert_eq!(Some(u64::make_entry(&u64::MAX)), entry);
+        assert!(json_to_entry(
+            &format!("{XSD_PREFIX}positiveInteger"),
+            &serde_json::json!(0)
+        )
+        .is_none());
+        assert!(
+            json_to_entry(&format!("{XSD_PREFIX}boolean"), &serde_json::json!("yes")).is_none()
+        );
+    }
+}
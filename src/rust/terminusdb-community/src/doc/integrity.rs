@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use terminusdb_store_prolog::layer::WrappedLayer;
+use terminusdb_store_prolog::terminus_store::layer::LayerBuilder;
+
+use super::model::*;
+use super::*;
+use crate::types::{
+    transaction_instance_builder, transaction_instance_layer, transaction_schema_layer,
+};
+
+/// What is wrong with a reference.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "@type", rename_all = "snake_case")]
+pub enum ReferenceProblem {
+    /// The referenced node has no type, which usually means the
+    /// document it pointed at has been deleted.
+    UntypedObject,
+    /// The referenced node is typed, but not as the range of the
+    /// property holding it.
+    WrongObjectType { expected: String, actual: String },
+}
+
+/// A node-valued property that does not point at a document of the
+/// class the schema expects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BrokenReference {
+    pub subject: String,
+    pub predicate: String,
+    pub object: String,
+    #[serde(flatten)]
+    pub problem: ReferenceProblem,
+    /// Whether the reference can be removed without breaking the
+    /// structure it is part of. References held by list or array
+    /// elements are only reported, as dropping the element would
+    /// shift or break the collection.
+    pub repairable: bool,
+    /// The id of the subject holding the property.
+    #[serde(skip)]
+    pub subject_id: u64,
+    /// The triple holding the reference itself, which for list and
+    /// array elements is the one of the cell.
+    #[serde(skip)]
+    pub triple: IdTriple,
+}
+
+struct Scanner<'a> {
+    model: &'a SchemaModel,
+    layer: &'a SyncStoreLayer,
+    rdf_type_id: u64,
+    rdf_first_id: Option<u64>,
+    rdf_rest_id: Option<u64>,
+    rdf_nil_id: Option<u64>,
+    sys_value_id: Option<u64>,
+}
+
+impl<'a> Scanner<'a> {
+    fn type_of(&self, id: u64) -> Option<String> {
+        let triple = self.layer.single_triple_sp(id, self.rdf_type_id)?;
+        self.layer.id_object_node(triple.object)
+    }
+
+    fn scan_subject(&self, id: u64) -> Vec<BrokenReference> {
+        let mut broken = Vec::new();
+        let class = match self.type_of(id).and_then(|typ| self.model.class(&typ)) {
+            Some(class) => class,
+            None => return broken,
+        };
+        if class.kind == ClassKind::Foreign {
+            return broken;
+        }
+
+        for t in self.layer.triples_s(id) {
+            if t.predicate == self.rdf_type_id
+                || self.layer.id_object_is_node(t.object) != Some(true)
+            {
+                continue;
+            }
+            let predicate = self.layer.id_predicate(t.predicate).unwrap();
+            // predicates the class doesn't have are reported by the
+            // schema check instead
+            let field = match class.field(&predicate) {
+                Some(field) => field,
+                None => continue,
+            };
+            match field.kind {
+                FieldKind::List => {
+                    let mut visited = HashSet::new();
+                    let mut cur = t.object;
+                    while Some(cur) != self.rdf_nil_id && visited.insert(cur) {
+                        if let Some(first) = self
+                            .rdf_first_id
+                            .and_then(|p| self.layer.single_triple_sp(cur, p))
+                        {
+                            self.check(id, &predicate, &field.range, first, false, &mut broken);
+                        }
+                        match self
+                            .rdf_rest_id
+                            .and_then(|p| self.layer.single_triple_sp(cur, p))
+                        {
+                            Some(rest) => cur = rest.object,
+                            None => break,
+                        }
+                    }
+                }
+                FieldKind::Array(_) => {
+                    if let Some(value) = self
+                        .sys_value_id
+                        .and_then(|p| self.layer.single_triple_sp(t.object, p))
+                    {
+                        self.check(id, &predicate, &field.range, value, false, &mut broken);
+                    }
+                }
+                _ => self.check(id, &predicate, &field.range, t, true, &mut broken),
+            }
+        }
+
+        broken
+    }
+
+    fn check(
+        &self,
+        subject_id: u64,
+        predicate: &str,
+        range: &str,
+        triple: IdTriple,
+        repairable: bool,
+        broken: &mut Vec<BrokenReference>,
+    ) {
+        // only ranges that are documents can be referenced. Enums,
+        // foreign classes, json and base types aren't.
+        match self.model.class(range) {
+            Some(class) if !matches!(class.kind, ClassKind::Enum(_) | ClassKind::Foreign) => {}
+            _ => return,
+        }
+        if self.layer.id_object_is_node(triple.object) != Some(true) {
+            return;
+        }
+
+        let problem = match self.type_of(triple.object) {
+            None => ReferenceProblem::UntypedObject,
+            Some(actual) if !self.model.is_subclass_of(&actual, range) => {
+                ReferenceProblem::WrongObjectType {
+                    expected: range.to_string(),
+                    actual,
+                }
+            }
+            Some(_) => return,
+        };
+        broken.push(BrokenReference {
+            subject: self.layer.id_subject(subject_id).unwrap(),
+            predicate: predicate.to_string(),
+            object: self.layer.id_object_node(triple.object).unwrap(),
+            problem,
+            repairable,
+            subject_id,
+            triple,
+        });
+    }
+}
+
+/// Walk all typed subjects of an instance layer and report every
+/// node-valued property whose object is untyped, or typed as something
+/// other than the range the schema gives for the property.
+///
+/// Unlike layer validation, this looks at the whole layer rather than
+/// just at what it changed, so it also catches links broken by raw
+/// triple writes, applied deltas or imported packs.
+pub fn find_broken_references(model: &SchemaModel, layer: &SyncStoreLayer) -> Vec<BrokenReference> {
+    let rdf_type_id = match layer.predicate_id(RDF_TYPE) {
+        Some(id) => id,
+        None => return Vec::new(),
+    };
+    let scanner = Scanner {
+        model,
+        layer,
+        rdf_type_id,
+        rdf_first_id: layer.predicate_id(RDF_FIRST),
+        rdf_rest_id: layer.predicate_id(RDF_REST),
+        rdf_nil_id: layer.object_node_id(RDF_NIL),
+        sys_value_id: layer.predicate_id(SYS_VALUE),
+    };
+
+    let subjects: Vec<u64> = layer.triples_p(rdf_type_id).map(|t| t.subject).collect();
+    subjects
+        .par_iter()
+        .flat_map_iter(|id| scanner.scan_subject(*id))
+        .collect()
+}
+
+/// Remove the repairable broken references, returning how many were
+/// removed.
+pub fn repair_references(builder: &mut dyn LayerBuilder, broken: &[BrokenReference]) -> usize {
+    let mut removed = 0;
+    for reference in broken.iter().filter(|r| r.repairable) {
+        builder.remove_id_triple(reference.triple);
+        removed += 1;
+    }
+
+    removed
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn check_referential_integrity(context, schema_term, layer_term, report_term) {
+        let schema: WrappedLayer = schema_term.get_ex()?;
+        let layer: WrappedLayer = layer_term.get_ex()?;
+        let model = SchemaModel::from_layer(&*schema);
+        let broken = find_broken_references(&model, &layer);
+
+        let report: Vec<String> = broken
+            .iter()
+            .map(|b| serde_json::to_string(b).unwrap())
+            .collect();
+        report_term.unify(report.as_slice())
+    }
+
+    #[module("$doc")]
+    semidet fn repair_referential_integrity(context, transaction_term, report_term) {
+        let layer = transaction_instance_layer(context, transaction_term)?;
+        let schema = transaction_schema_layer(context, transaction_term)?;
+        let (layer, schema) = match (layer, schema) {
+            (Some(layer), Some(schema)) => (layer, schema),
+            // nothing to check without instance data or a schema
+            _ => return report_term.unify(&[] as &[String]),
+        };
+        let builder = transaction_instance_builder(context, transaction_term)?;
+        if builder.is_none() {
+            return context.raise_exception(&term! {context: error(builder_not_initialized, _)}?);
+        }
+        let builder = builder.unwrap();
+        let model = SchemaModel::from_layer(&schema);
+        let broken = find_broken_references(&model, &layer);
+        context.try_or_die(builder.with_builder(|builder| {
+            repair_references(&mut **builder, &broken);
+        }))?;
+
+        let report: Vec<String> = broken
+            .iter()
+            .map(|b| serde_json::to_string(b).unwrap())
+            .collect();
+        report_term.unify(report.as_slice())
+    }
+}
+
+pub fn register() {
+    register_check_referential_integrity();
+    register_repair_referential_integrity();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminusdb_store_prolog::terminus_store::open_sync_memory_store;
+
+    const PERSON: &str = "terminusdb:///schema#Person";
+    const PET: &str = "terminusdb:///schema#Pet";
+    const FRIEND: &str = "terminusdb:///schema#friend";
+
+    fn schema_layer() -> SyncStoreLayer {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let triples = [
+            ValueTriple::new_node(PERSON, RDF_TYPE, SYS_CLASS),
+            ValueTriple::new_node(PERSON, FRIEND, "terminusdb:///schema#Person/friend"),
+            ValueTriple::new_node("terminusdb:///schema#Person/friend", RDF_TYPE, SYS_SET),
+            ValueTriple::new_node(
+                "terminusdb:///schema#Person/friend",
+                SYS_CLASS_PREDICATE,
+                PERSON,
+            ),
+            ValueTriple::new_node(PET, RDF_TYPE, SYS_CLASS),
+        ];
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+
+        builder.commit().unwrap()
+    }
+
+    #[test]
+    fn untyped_and_mistyped_objects_are_reported() {
+        let model = SchemaModel::from_layer(&schema_layer());
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let triples = [
+            ValueTriple::new_node("terminusdb:///data/Person/a", RDF_TYPE, PERSON),
+            ValueTriple::new_node("terminusdb:///data/Person/b", RDF_TYPE, PERSON),
+            ValueTriple::new_node("terminusdb:///data/Pet/p", RDF_TYPE, PET),
+            ValueTriple::new_node(
+                "terminusdb:///data/Person/a",
+                FRIEND,
+                "terminusdb:///data/Person/b",
+            ),
+            ValueTriple::new_node(
+                "terminusdb:///data/Person/a",
+                FRIEND,
+                "terminusdb:///data/Person/gone",
+            ),
+            ValueTriple::new_node(
+                "terminusdb:///data/Person/a",
+                FRIEND,
+                "terminusdb:///data/Pet/p",
+            ),
+        ];
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+        let layer = builder.commit().unwrap();
+
+        let mut broken = find_broken_references(&model, &layer);
+        broken.sort_by(|a, b| a.object.cmp(&b.object));
+        let problems: Vec<_> = broken
+            .iter()
+            .map(|b| (b.object.as_str(), b.problem.clone(), b.repairable))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "terminusdb:///data/Person/gone",
+                    ReferenceProblem::UntypedObject,
+                    true
+                ),
+                (
+                    "terminusdb:///data/Pet/p",
+                    ReferenceProblem::WrongObjectType {
+                        expected: PERSON.to_string(),
+                        actual: PET.to_string(),
+                    },
+                    true
+                ),
+            ],
+            problems
+        );
+
+        let builder = layer.open_write().unwrap();
+        for reference in broken.iter() {
+            builder.remove_id_triple(reference.triple).unwrap();
+        }
+        let repaired = builder.commit().unwrap();
+        assert!(find_broken_references(&model, &repaired).is_empty());
+    }
+
+    #[test]
+    fn report_serializes_flat() {
+        let reference = BrokenReference {
+            subject: "Person/a".to_string(),
+            predicate: "friend".to_string(),
+            object: "Person/gone".to_string(),
+            problem: ReferenceProblem::UntypedObject,
+            repairable: true,
+            subject_id: 1,
+            triple: IdTriple::new(1, 2, 3),
+        };
+        assert_eq!(
+            r#"{"subject":"Person/a","predicate":"friend","object":"Person/gone","@type":"untyped_object","repairable":true}"#,
+            serde_json::to_string(&reference).unwrap()
+        );
+    }
+}
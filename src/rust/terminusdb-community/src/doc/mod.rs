@@ -1,4 +1,12 @@
+pub mod canonical;
 mod delete;
+pub mod idgen;
+mod insert;
+pub mod integrity;
+pub mod model;
+pub mod patch;
+pub mod shape;
+mod validate;
 
 use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap, HashSet};
@@ -18,6 +26,7 @@ use super::consts::*;
 use super::prefix::PrefixContracter;
 use super::schema::*;
 use super::value::*;
+use shape::DocumentShape;
 
 use lazy_static::lazy_static;
 use rayon::prelude::*;
@@ -28,6 +37,7 @@ pub struct DocumentContext<L: Layer + Clone> {
     layer: Option<L>,
     prefixes: Lazy<PrefixContracter>,
     default_prefixes: Lazy<PrefixContracter>,
+    model: Lazy<model::SchemaModel>,
     types: HashSet<u64>,
     subtypes: HashMap<String, HashSet<u64>>,
     document_types: HashSet<u64>,
@@ -151,6 +161,7 @@ impl<L: Layer + Clone> DocumentContext<L> {
 
             prefixes: Lazy::new(),
             default_prefixes: Lazy::new(),
+            model: Lazy::new(),
             types,
             subtypes,
             document_types,
@@ -173,6 +184,7 @@ impl<L: Layer + Clone> DocumentContext<L> {
 
             default_prefixes: Lazy::new(),
             prefixes: Lazy::new(),
+            model: Lazy::new(),
             types: HashSet::with_capacity(0),
             subtypes: HashMap::with_capacity(0),
             document_types: HashSet::with_capacity(0),
@@ -204,16 +216,34 @@ impl<L: Layer + Clone> DocumentContext<L> {
         }
     }
 
+    /// The schema as resolved for writing documents.
+    pub fn model(&self) -> &model::SchemaModel {
+        self.model.get_or_create(|| match self.schema.as_ref() {
+            Some(schema) => model::SchemaModel::from_layer(schema),
+            None => model::SchemaModel::default(),
+        })
+    }
+
     pub fn get_document(
         &self,
         iri: &str,
         compress: bool,
         unfold: bool,
+    ) -> Result<Option<Map<String, Value>>, DocRetrievalError> {
+        self.get_document_shaped(iri, compress, unfold, &DocumentShape::default())
+    }
+
+    pub fn get_document_shaped(
+        &self,
+        iri: &str,
+        compress: bool,
+        unfold: bool,
+        shape: &DocumentShape,
     ) -> Result<Option<Map<String, Value>>, DocRetrievalError> {
         match self.layer.as_ref().and_then(|layer| {
             layer
                 .subject_id(iri)
-                .map(|id| self.get_id_document(id, compress, unfold))
+                .map(|id| self.get_id_document_shaped(id, compress, unfold, shape))
         }) {
             Some(Ok(x)) => Ok(x),
             Some(Err(e)) => Err(e),
@@ -402,6 +432,19 @@ impl<L: Layer + Clone> DocumentContext<L> {
         id: u64,
         compress: bool,
         unfold: bool,
+    ) -> Result<Option<Map<String, Value>>, DocRetrievalError> {
+        self.get_id_document_shaped(id, compress, unfold, &DocumentShape::default())
+    }
+
+    /// Retrieve a document, inlining unfoldable documents only up to
+    /// the maximum depth of the shape and keeping only the fields its
+    /// projection selects.
+    pub fn get_id_document_shaped(
+        &self,
+        id: u64,
+        compress: bool,
+        unfold: bool,
+        shape: &DocumentShape,
     ) -> Result<Option<Map<String, Value>>, DocRetrievalError> {
         if self.layer.is_none() {
             panic!("expected id to point at document: {}", id);
@@ -468,7 +511,15 @@ impl<L: Layer + Clone> DocumentContext<L> {
                 }
 
                 // it's not one of the special types, treat it as an ordinary field.
-                match self.get_field(next_obj, compress, unfold) {
+                // Only unfold further if we haven't yet inlined as many
+                // documents deep as the shape allows.
+                let depth = stack
+                    .iter()
+                    .skip(1)
+                    .filter(|entry| self.is_unfolded_document(entry))
+                    .count();
+                let unfold_here = unfold && shape.unfolds_at(depth);
+                match self.get_field(next_obj, compress, unfold_here) {
                     Ok(val) => {
                         cur.integrate_value(self, val, compress);
                     }
@@ -503,7 +554,10 @@ impl<L: Layer + Clone> DocumentContext<L> {
                 } else {
                     // we're done, this was the root, time to return!
                     match cur {
-                        StackEntry::Document { doc, .. } => return Ok(Some(doc)),
+                        StackEntry::Document { mut doc, .. } => {
+                            shape.apply(&mut doc);
+                            return Ok(Some(doc));
+                        }
                         _ => panic!("unexpected element at stack top"),
                     }
                 }
@@ -521,6 +575,18 @@ impl<L: Layer + Clone> DocumentContext<L> {
         Err(DocRetrievalError::LimitExceeded(iri))
     }
 
+    /// Whether a stack entry is a document that was inlined into its
+    /// parent, as opposed to a subdocument or collection.
+    fn is_unfolded_document(&self, entry: &StackEntry<L>) -> bool {
+        match entry {
+            StackEntry::Document {
+                type_id: Some(type_id),
+                ..
+            } => self.document_types.contains(type_id),
+            _ => false,
+        }
+    }
+
     fn get_subtypes_for(&self, type_name: &str) -> Vec<u64> {
         let mut types: Vec<u64> = Vec::new();
         if let Some(type_id) = self.layer().object_node_id(type_name) {
@@ -835,11 +901,16 @@ wrapped_arc_blob!(
 );
 
 fn map_to_writer<W: Write>(
-    writer: W,
+    mut writer: W,
     m: Map<String, Value>,
     pretty: bool,
+    canonical: bool,
 ) -> serde_json::Result<()> {
-    if pretty {
+    if canonical {
+        writer
+            .write_all(canonical::canonical_json(&Value::Object(m)).as_bytes())
+            .map_err(serde_json::Error::io)
+    } else if pretty {
         serde_json::to_writer_pretty(writer, &Value::Object(m))
     } else {
         serde_json::to_writer(writer, &Value::Object(m))
@@ -852,6 +923,7 @@ fn print_document<C: QueryableContextType>(
     doc: Map<String, Value>,
     as_list: bool,
     minimized: bool,
+    canonical: bool,
     stream_started: &mut bool,
 ) -> PrologResult<()> {
     if as_list && *stream_started {
@@ -859,7 +931,7 @@ fn print_document<C: QueryableContextType>(
     }
     *stream_started = true;
 
-    context.try_or_die_generic(map_to_writer(&mut *stream, doc, !minimized))?;
+    context.try_or_die_generic(map_to_writer(&mut *stream, doc, !minimized, canonical))?;
 
     if !as_list {
         context.try_or_die(stream.write_all(b"\n"))?;
@@ -886,6 +958,7 @@ fn print_documents_of_types<
     compress: bool,
     unfold: bool,
     minimize: bool,
+    shape: &DocumentShape,
 ) -> PrologResult<()> {
     let mut stream: WritablePrologStream = stream_term.get_ex()?;
     let mut skip: u64 = skip_term.get_ex()?;
@@ -912,9 +985,17 @@ fn print_documents_of_types<
             }
 
             let map = context
-                .try_or_die(doc_context.get_id_document(t.subject, compress, unfold))?
+                .try_or_die(doc_context.get_id_document_shaped(t.subject, compress, unfold, shape))?
                 .expect("expected document lookup by type to succeed as ids were prefetched");
-            print_document(context, &mut stream, map, as_list, minimize, &mut started)?;
+            print_document(
+                context,
+                &mut stream,
+                map,
+                as_list,
+                minimize,
+                shape.canonical,
+                &mut started,
+            )?;
         }
     }
 
@@ -932,6 +1013,7 @@ fn par_print_documents_of_types<C: QueryableContextType, L: Layer + Clone + 'sta
     compress: bool,
     unfold: bool,
     minimize: bool,
+    shape: DocumentShape,
 ) -> PrologResult<()> {
     let mut stream: WritablePrologStream = stream_term.get_ex()?;
 
@@ -959,12 +1041,13 @@ fn par_print_documents_of_types<C: QueryableContextType, L: Layer + Clone + 'sta
         itertools::Either::Right(iter)
     };
 
+    let canonical = shape.canonical;
     rayon::spawn(move || {
         let _result = iter
             .enumerate()
             .par_bridge()
             .try_for_each_with(sender, |sender, (ix, t)| {
-                let map = doc_context2.get_id_document(t.subject, compress, unfold);
+                let map = doc_context2.get_id_document_shaped(t.subject, compress, unfold, &shape);
                 sender.send((ix, map)) // failure will kill the task
             });
     });
@@ -978,7 +1061,15 @@ fn par_print_documents_of_types<C: QueryableContextType, L: Layer + Clone + 'sta
             .try_or_die(map)?
             .expect("expected parallel document lookup by type to succeed as ids were prefetched");
         if ix == cur {
-            print_document(context, &mut stream, map, as_list, minimize, &mut started)?;
+            print_document(
+                context,
+                &mut stream,
+                map,
+                as_list,
+                minimize,
+                canonical,
+                &mut started,
+            )?;
 
             cur += 1;
             while result
@@ -991,7 +1082,15 @@ fn par_print_documents_of_types<C: QueryableContextType, L: Layer + Clone + 'sta
                     value,
                 } = result.pop().unwrap();
 
-                print_document(context, &mut stream, value, as_list, minimize, &mut started)?;
+                print_document(
+                    context,
+                    &mut stream,
+                    value,
+                    as_list,
+                    minimize,
+                    canonical,
+                    &mut started,
+                )?;
 
                 cur += 1;
             }
@@ -1019,6 +1118,7 @@ fn print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static>(
     compress: bool,
     unfold: bool,
     minimize: bool,
+    shape: &DocumentShape,
 ) -> PrologResult<()> {
     let mut stream: WritablePrologStream = stream_term.get_ex()?;
 
@@ -1029,7 +1129,9 @@ fn print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static>(
     let mut started = false;
     for iri_term in context.term_list_iter(iris_term) {
         let iri: PrologText = iri_term.get_ex()?;
-        if let Some(doc) = context.try_or_die(doc_context.get_document(&iri, compress, unfold))? {
+        if let Some(doc) =
+            context.try_or_die(doc_context.get_document_shaped(&iri, compress, unfold, shape))?
+        {
             if skip > 0 {
                 skip -= 1;
                 continue;
@@ -1040,7 +1142,15 @@ fn print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static>(
                 }
                 *count -= 1;
             }
-            print_document(context, &mut stream, doc, as_list, minimize, &mut started)?;
+            print_document(
+                context,
+                &mut stream,
+                doc,
+                as_list,
+                minimize,
+                shape.canonical,
+                &mut started,
+            )?;
         }
     }
 
@@ -1058,6 +1168,7 @@ fn par_print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static
     compress: bool,
     unfold: bool,
     minimize: bool,
+    shape: DocumentShape,
 ) -> PrologResult<()> {
     let mut stream: WritablePrologStream = stream_term.get_ex()?;
 
@@ -1083,12 +1194,13 @@ fn par_print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static
         Some(count) => itertools::Either::Left(iter.take(count as usize)),
         None => itertools::Either::Right(iter),
     };
+    let canonical = shape.canonical;
     rayon::spawn(move || {
         let _result =
             iter.enumerate()
                 .par_bridge()
                 .try_for_each_with(sender, |sender, (ix, iri)| {
-                    let map = doc_context2.get_document(&iri, compress, unfold);
+                    let map = doc_context2.get_document_shaped(&iri, compress, unfold, &shape);
                     sender.send((ix, map)) // failure will kill the task
                 });
     });
@@ -1101,7 +1213,15 @@ fn par_print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static
             .try_or_die(map)?
             .expect("expected document to exist");
         if ix == cur {
-            print_document(context, &mut stream, map, as_list, minimize, &mut started)?;
+            print_document(
+                context,
+                &mut stream,
+                map,
+                as_list,
+                minimize,
+                canonical,
+                &mut started,
+            )?;
 
             cur += 1;
             while result
@@ -1114,7 +1234,15 @@ fn par_print_documents_by_id<C: QueryableContextType, L: Layer + Clone + 'static
                     value,
                 } = result.pop().unwrap();
 
-                print_document(context, &mut stream, value, as_list, minimize, &mut started)?;
+                print_document(
+                    context,
+                    &mut stream,
+                    value,
+                    as_list,
+                    minimize,
+                    canonical,
+                    &mut started,
+                )?;
 
                 cur += 1;
             }
@@ -1143,7 +1271,7 @@ predicates! {
     }
 
     #[module("$doc")]
-    semidet fn print_document_json(context, stream_term, get_context_term, doc_name_term, as_list_term, compress_term, unfold_term, minimize_term, started_term) {
+    semidet fn print_document_json(context, stream_term, get_context_term, doc_name_term, as_list_term, compress_term, unfold_term, minimize_term, started_term, shape_term) {
         let mut stream: WritablePrologStream = stream_term.get_ex()?;
         if !doc_name_term.is_string() && !doc_name_term.is_atom() {
             return fail();
@@ -1156,12 +1284,13 @@ predicates! {
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
         let started: bool = started_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
-        if let Some(result) = context.try_or_die(doc_context.get_document(&s, compress, unfold))? {
+        if let Some(result) = context.try_or_die(doc_context.get_document_shaped(&s, compress, unfold, &shape))? {
             if as_list && started {
                 context.try_or_die_generic(stream.write_all(b",\n"))?;
             }
-            context.try_or_die_generic(map_to_writer(&mut stream, result, !minimize))?;
+            context.try_or_die_generic(map_to_writer(&mut stream, result, !minimize, shape.canonical))?;
             if !as_list {
                 context.try_or_die(stream.write_all(b"\n"))?;
             }
@@ -1174,7 +1303,7 @@ predicates! {
     }
 
     #[module("$doc")]
-    semidet fn print_all_documents_json_by_type(context, stream_term, get_context_term, type_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn print_all_documents_json_by_type(context, stream_term, get_context_term, type_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1185,17 +1314,18 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
         if types.is_empty() {
             // no type found that is subsumed by the given type, so we're done
             return Ok(())
         }
 
-        print_documents_of_types(context, &doc_context, stream_term, skip_term, count_term, as_list_term, types.as_slice(), compress, unfold, minimize)
+        print_documents_of_types(context, &doc_context, stream_term, skip_term, count_term, as_list_term, types.as_slice(), compress, unfold, minimize, &shape)
     }
 
     #[module("$doc")]
-    semidet fn par_print_all_documents_json_by_type(context, stream_term, get_context_term, type_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn par_print_all_documents_json_by_type(context, stream_term, get_context_term, type_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1206,17 +1336,18 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
         if types.is_empty() {
             // no type found that is subsumed by the given type, so we're done
             return Ok(())
         }
 
-        par_print_documents_of_types(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, types, compress, unfold, minimize)
+        par_print_documents_of_types(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, types, compress, unfold, minimize, shape)
     }
 
     #[module("$doc")]
-    semidet fn print_all_documents_json(context, stream_term, get_context_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn print_all_documents_json(context, stream_term, get_context_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1224,6 +1355,7 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
         let mut types: Vec<u64> = match unfold {
             true => doc_context.document_types.iter().cloned().collect(),
@@ -1231,11 +1363,11 @@ predicates! {
         };
         types.sort();
 
-        print_documents_of_types(context, &doc_context, stream_term, skip_term, count_term, as_list_term, types.iter(), compress, unfold, minimize)
+        print_documents_of_types(context, &doc_context, stream_term, skip_term, count_term, as_list_term, types.iter(), compress, unfold, minimize, &shape)
     }
 
     #[module("$doc")]
-    semidet fn par_print_all_documents_json(context, stream_term, get_context_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn par_print_all_documents_json(context, stream_term, get_context_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1243,6 +1375,7 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
         // We either iterate over the document types, or if unfold is false, we iterate over all types
         let mut types: Vec<u64> = match unfold {
@@ -1251,11 +1384,11 @@ predicates! {
         };
         types.sort();
 
-        par_print_documents_of_types(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, types, compress, unfold, minimize)
+        par_print_documents_of_types(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, types, compress, unfold, minimize, shape)
     }
 
     #[module("$doc")]
-    semidet fn print_documents_json_by_id(context, stream_term, get_context_term, ids_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn print_documents_json_by_id(context, stream_term, get_context_term, ids_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1263,12 +1396,13 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
-        print_documents_by_id(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, ids_term, compress, unfold, minimize)
+        print_documents_by_id(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, ids_term, compress, unfold, minimize, &shape)
     }
 
     #[module("$doc")]
-    semidet fn par_print_documents_json_by_id(context, stream_term, get_context_term, ids_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term) {
+    semidet fn par_print_documents_json_by_id(context, stream_term, get_context_term, ids_term, skip_term, count_term, as_list_term, compress_term, unfold_term, minimize_term, shape_term) {
         let doc_context: DocumentContextBlob = get_context_term.get()?;
         if doc_context.layer.is_none() {
             return Ok(());
@@ -1276,8 +1410,9 @@ predicates! {
         let compress: bool = compress_term.get()?;
         let unfold: bool = unfold_term.get()?;
         let minimize: bool = minimize_term.get()?;
+        let shape = DocumentShape::from_term(context, shape_term)?;
 
-        par_print_documents_by_id(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, ids_term, compress, unfold, minimize)
+        par_print_documents_by_id(context, &doc_context.0, stream_term, skip_term, count_term, as_list_term, ids_term, compress, unfold, minimize, shape)
     }
 }
 
@@ -1316,7 +1451,13 @@ pub fn register() {
     register_print_documents_json_by_id();
     register_par_print_documents_json_by_id();
 
+    canonical::register();
     delete::register();
+    insert::register();
+    idgen::register();
+    integrity::register();
+    patch::register();
+    validate::register();
 }
 
 #[cfg(test)]
@@ -0,0 +1,604 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::terminus_store::*;
+
+use super::super::consts::*;
+use super::super::schema::RdfListIterator;
+use super::super::value::{value_to_bigint, value_to_string};
+
+/// The way a class mints ids for documents that are submitted
+/// without one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStrategy {
+    Random,
+    Lexical(Vec<String>),
+    Hash(Vec<String>),
+    ValueHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClassKind {
+    Class,
+    TaggedUnion,
+    Foreign,
+    Enum(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+    Required,
+    Optional,
+    Set,
+    List,
+    Array(usize),
+    Cardinality { min: usize, max: Option<usize> },
+}
+
+/// What happens to a reference held in a property when the document
+/// it points at gets deleted, as set through the `@onDelete` key of a
+/// class' `@metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletePolicy {
+    /// Delete the referring document as well.
+    Cascade,
+    /// Refuse to delete the referenced document.
+    Restrict,
+    /// Remove the reference, leaving the referring document in place.
+    SetNull,
+}
+
+impl DeletePolicy {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "cascade" => Some(Self::Cascade),
+            "restrict" => Some(Self::Restrict),
+            "setNull" | "unlink" => Some(Self::SetNull),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDefinition {
+    pub kind: FieldKind,
+    pub range: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassDefinition {
+    pub id: String,
+    pub kind: ClassKind,
+    pub subdocument: bool,
+    pub abstract_: bool,
+    pub unfoldable: bool,
+    pub key: KeyStrategy,
+    /// The path segment ids for this class are minted under, relative
+    /// to the instance base.
+    pub key_base: String,
+    pub parents: Vec<String>,
+    /// All fields of this class, including the inherited ones, keyed
+    /// by their expanded property IRI.
+    pub fields: BTreeMap<String, FieldDefinition>,
+    /// The `@oneOf` choices of this class, including the inherited
+    /// ones. Exactly one property of each choice has to be present.
+    pub one_of: Vec<BTreeMap<String, FieldDefinition>>,
+    /// The deletion policies of this class' properties, including the
+    /// inherited ones, keyed by their expanded property IRI.
+    pub on_delete: BTreeMap<String, DeletePolicy>,
+}
+
+impl ClassDefinition {
+    /// Look up a field, including the ones that are part of a
+    /// `@oneOf` choice.
+    pub fn field(&self, property: &str) -> Option<&FieldDefinition> {
+        self.fields
+            .get(property)
+            .or_else(|| self.one_of.iter().find_map(|choice| choice.get(property)))
+    }
+}
+
+/// A resolved view of the schema graph, with prefixes and inheritance
+/// already taken care of. This is what native document writes are
+/// checked and shaped against.
+#[derive(Debug)]
+pub struct SchemaModel {
+    pub base: String,
+    pub schema: String,
+    prefixes: HashMap<String, String>,
+    classes: HashMap<String, ClassDefinition>,
+    subclasses: HashMap<String, HashSet<String>>,
+}
+
+impl Default for SchemaModel {
+    fn default() -> Self {
+        Self {
+            base: "terminusdb:///data/".to_string(),
+            schema: "terminusdb:///schema#".to_string(),
+            prefixes: HashMap::new(),
+            classes: HashMap::new(),
+            subclasses: HashMap::new(),
+        }
+    }
+}
+
+fn builtin_prefix(prefix: &str) -> Option<&'static str> {
+    match prefix {
+        "rdf" => Some(RDF_PREFIX),
+        "rdfs" => Some(RDFS_PREFIX),
+        "xsd" => Some(XSD_PREFIX),
+        "xdd" => Some(XDD_PREFIX),
+        "owl" => Some(OWL_PREFIX),
+        "sys" => Some(SYS_NAMESPACE),
+        "json" => Some(SYS_JSON_PREFIX),
+        _ => None,
+    }
+}
+
+pub(super) fn has_protocol(s: &str) -> bool {
+    s.find("://")
+        .map(|pos| s[..pos].chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or(false)
+}
+
+struct SchemaReader<'a, L: Layer> {
+    layer: &'a L,
+}
+
+impl<'a, L: Layer> SchemaReader<'a, L> {
+    fn object_nodes(&self, subject: u64, predicate: &str) -> Vec<String> {
+        match self.layer.predicate_id(predicate) {
+            Some(predicate_id) => self
+                .layer
+                .triples_sp(subject, predicate_id)
+                .filter_map(|t| self.layer.id_object_node(t.object))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn object_node(&self, subject: u64, predicate: &str) -> Option<String> {
+        let predicate_id = self.layer.predicate_id(predicate)?;
+        let triple = self.layer.single_triple_sp(subject, predicate_id)?;
+        self.layer.id_object_node(triple.object)
+    }
+
+    fn object_value(&self, subject: u64, predicate: &str) -> Option<TypedDictEntry> {
+        let predicate_id = self.layer.predicate_id(predicate)?;
+        let triple = self.layer.single_triple_sp(subject, predicate_id)?;
+        self.layer.id_object_value(triple.object)
+    }
+
+    fn object_usize(&self, subject: u64, predicate: &str) -> Option<usize> {
+        self.object_value(subject, predicate)
+            .and_then(|v| value_to_bigint(&v).to_usize())
+    }
+
+    fn has_type(&self, subject: u64, typ: &str) -> bool {
+        self.object_nodes(subject, RDF_TYPE)
+            .iter()
+            .any(|t| t == typ)
+    }
+
+    fn list(&self, subject: u64) -> Vec<String> {
+        RdfListIterator {
+            layer: self.layer,
+            cur: subject,
+            rdf_first_id: self.layer.predicate_id(RDF_FIRST),
+            rdf_rest_id: self.layer.predicate_id(RDF_REST),
+            rdf_nil_id: self.layer.object_node_id(RDF_NIL),
+        }
+        .filter_map(|id| self.layer.id_object_node(id))
+        .collect()
+    }
+
+    fn field(&self, range: &str) -> FieldDefinition {
+        let range_id = match self.layer.subject_id(range) {
+            Some(id) => id,
+            None => {
+                return FieldDefinition {
+                    kind: FieldKind::Required,
+                    range: range.to_string(),
+                }
+            }
+        };
+        let kind = if self.has_type(range_id, SYS_OPTIONAL) {
+            FieldKind::Optional
+        } else if self.has_type(range_id, SYS_SET) {
+            FieldKind::Set
+        } else if self.has_type(range_id, SYS_LIST) || self.has_type(range_id, SYS_TABLE) {
+            FieldKind::List
+        } else if self.has_type(range_id, SYS_ARRAY) {
+            FieldKind::Array(self.object_usize(range_id, SYS_DIMENSIONS).unwrap_or(1))
+        } else if self.has_type(range_id, SYS_CARDINALITY) {
+            FieldKind::Cardinality {
+                min: self
+                    .object_usize(range_id, SYS_MIN_CARDINALITY)
+                    .unwrap_or(0),
+                max: self.object_usize(range_id, SYS_MAX_CARDINALITY),
+            }
+        } else {
+            return FieldDefinition {
+                kind: FieldKind::Required,
+                range: range.to_string(),
+            };
+        };
+
+        FieldDefinition {
+            kind,
+            range: self
+                .object_node(range_id, SYS_CLASS_PREDICATE)
+                .unwrap_or_else(|| range.to_string()),
+        }
+    }
+
+    /// The property-range pairs of a node, leaving out the sys ones.
+    fn properties(&self, subject: u64) -> BTreeMap<String, FieldDefinition> {
+        let mut fields = BTreeMap::new();
+        for t in self.layer.triples_s(subject) {
+            let predicate = self.layer.id_predicate(t.predicate).unwrap();
+            if predicate == RDF_TYPE || predicate.starts_with(SYS_NAMESPACE) {
+                continue;
+            }
+            if let Some(range) = self.layer.id_object_node(t.object) {
+                fields.insert(predicate, self.field(&range));
+            }
+        }
+
+        fields
+    }
+
+    /// The values of a `sys:JSON` node, keyed by their decoded key.
+    fn json_entries(&self, subject: u64) -> Vec<(String, u64)> {
+        self.layer
+            .triples_s(subject)
+            .filter_map(|t| {
+                let predicate = self.layer.id_predicate(t.predicate)?;
+                let key = predicate.strip_prefix(SYS_JSON_PREFIX)?;
+                let key = urlencoding::decode(key).ok()?.into_owned();
+                Some((key, t.object))
+            })
+            .collect()
+    }
+
+    /// The `@onDelete` policies found in the metadata of a class, keyed
+    /// by the property names as they were written.
+    fn on_delete(&self, class_id: u64) -> Vec<(String, DeletePolicy)> {
+        let metadata_id = match self
+            .object_node(class_id, SYS_METADATA)
+            .and_then(|metadata| self.layer.subject_id(&metadata))
+        {
+            Some(id) => id,
+            None => return Vec::new(),
+        };
+        let policies_id = match self
+            .json_entries(metadata_id)
+            .into_iter()
+            .find(|(key, _)| key == "@onDelete")
+            .and_then(|(_, object)| self.layer.id_object_node(object))
+            .and_then(|node| self.layer.subject_id(&node))
+        {
+            Some(id) => id,
+            None => return Vec::new(),
+        };
+
+        self.json_entries(policies_id)
+            .into_iter()
+            .filter_map(|(property, object)| {
+                let value = self.layer.id_object_value(object)?;
+                let policy = DeletePolicy::parse(&value_to_string(&value))?;
+                Some((property, policy))
+            })
+            .collect()
+    }
+
+    fn key(&self, class_id: u64) -> KeyStrategy {
+        let key_id = match self
+            .object_node(class_id, SYS_KEY)
+            .and_then(|key| self.layer.subject_id(&key))
+        {
+            Some(key_id) => key_id,
+            None => return KeyStrategy::Random,
+        };
+        let fields = || {
+            self.object_node(key_id, SYS_FIELDS)
+                .and_then(|list| self.layer.subject_id(&list))
+                .map(|list_id| self.list(list_id))
+                .unwrap_or_default()
+        };
+
+        if self.has_type(key_id, SYS_LEXICAL) {
+            KeyStrategy::Lexical(fields())
+        } else if self.has_type(key_id, SYS_HASH) {
+            KeyStrategy::Hash(fields())
+        } else if self.has_type(key_id, SYS_VALUE_HASH) {
+            KeyStrategy::ValueHash
+        } else {
+            KeyStrategy::Random
+        }
+    }
+
+    fn context_string(&self, predicate: &str) -> Option<String> {
+        let context_id = self.layer.subject_id(TDB_CONTEXT)?;
+        self.object_value(context_id, predicate)
+            .map(|v| value_to_string(&v).into_owned())
+    }
+
+    fn prefixes(&self) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        if let Some(context_id) = self.layer.subject_id(TDB_CONTEXT) {
+            for pair in self.object_nodes(context_id, SYS_PREFIX_PAIR) {
+                let pair_id = match self.layer.subject_id(&pair) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                if let (Some(prefix), Some(url)) = (
+                    self.object_value(pair_id, SYS_PREFIX),
+                    self.object_value(pair_id, SYS_URL),
+                ) {
+                    result.insert(
+                        value_to_string(&prefix).into_owned(),
+                        value_to_string(&url).into_owned(),
+                    );
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl SchemaModel {
+    pub fn from_layer<L: Layer>(layer: &L) -> SchemaModel {
+        let reader = SchemaReader { layer };
+        let mut model = SchemaModel {
+            prefixes: reader.prefixes(),
+            ..Default::default()
+        };
+        if let Some(base) = reader.context_string(SYS_BASE) {
+            model.base = base;
+        }
+        if let Some(schema) = reader.context_string(SYS_SCHEMA) {
+            model.schema = schema;
+        }
+
+        let rdf_type_id = match layer.predicate_id(RDF_TYPE) {
+            Some(id) => id,
+            None => return model,
+        };
+        let kinds = [SYS_CLASS, SYS_TAGGED_UNION, SYS_FOREIGN, SYS_ENUM];
+        let mut classes = Vec::new();
+        for kind in kinds {
+            if let Some(kind_id) = layer.object_node_id(kind) {
+                classes.extend(
+                    layer
+                        .triples_o(kind_id)
+                        .filter(|t| t.predicate == rdf_type_id)
+                        .map(|t| (t.subject, kind)),
+                );
+            }
+        }
+
+        let mut direct_fields: HashMap<String, BTreeMap<String, FieldDefinition>> = HashMap::new();
+        let mut direct_one_of: HashMap<String, Vec<BTreeMap<String, FieldDefinition>>> =
+            HashMap::new();
+        let mut direct_on_delete: HashMap<String, BTreeMap<String, DeletePolicy>> = HashMap::new();
+        for (class_id, kind) in classes {
+            let id = layer.id_subject(class_id).unwrap();
+            let kind = match kind {
+                SYS_TAGGED_UNION => ClassKind::TaggedUnion,
+                SYS_FOREIGN => ClassKind::Foreign,
+                SYS_ENUM => ClassKind::Enum(
+                    reader
+                        .object_node(class_id, SYS_VALUE)
+                        .and_then(|list| layer.subject_id(&list))
+                        .map(|list_id| reader.list(list_id))
+                        .unwrap_or_default(),
+                ),
+                _ => ClassKind::Class,
+            };
+
+            direct_fields.insert(id.clone(), reader.properties(class_id));
+            direct_one_of.insert(
+                id.clone(),
+                reader
+                    .object_nodes(class_id, SYS_ONE_OF)
+                    .iter()
+                    .filter_map(|choice| layer.subject_id(choice))
+                    .map(|choice_id| reader.properties(choice_id))
+                    .collect::<Vec<_>>(),
+            );
+            direct_on_delete.insert(
+                id.clone(),
+                reader
+                    .on_delete(class_id)
+                    .into_iter()
+                    .map(|(property, policy)| (model.expand_schema(&property), policy))
+                    .collect(),
+            );
+
+            let parents = reader.object_nodes(class_id, SYS_INHERITS);
+            let key_base = match reader.object_value(class_id, SYS_BASE) {
+                Some(base) => value_to_string(&base).into_owned(),
+                None => format!("{}/", model.compress_schema(&id)),
+            };
+
+            model.classes.insert(
+                id.clone(),
+                ClassDefinition {
+                    subdocument: layer
+                        .predicate_id(SYS_SUBDOCUMENT)
+                        .and_then(|p| layer.single_triple_sp(class_id, p))
+                        .is_some(),
+                    abstract_: layer
+                        .predicate_id(SYS_ABSTRACT)
+                        .and_then(|p| layer.single_triple_sp(class_id, p))
+                        .is_some(),
+                    unfoldable: layer
+                        .predicate_id(SYS_UNFOLDABLE)
+                        .and_then(|p| layer.single_triple_sp(class_id, p))
+                        .is_some(),
+                    key: reader.key(class_id),
+                    key_base,
+                    parents,
+                    fields: BTreeMap::new(),
+                    one_of: Vec::new(),
+                    on_delete: BTreeMap::new(),
+                    id,
+                    kind,
+                },
+            );
+        }
+
+        // Inheritance is resolved in a second pass, as parents may
+        // have been read after their children.
+        let ids: Vec<String> = model.classes.keys().cloned().collect();
+        for id in ids {
+            let ancestors = model.ancestors(&id);
+            let mut fields = BTreeMap::new();
+            let mut one_of = Vec::new();
+            let mut on_delete = BTreeMap::new();
+            let mut subdocument = false;
+            let mut unfoldable = false;
+            // ancestors are ordered from the class itself outwards, so
+            // walking them in reverse lets closer definitions win.
+            for ancestor in ancestors.iter().rev() {
+                if let Some(direct) = direct_fields.get(ancestor) {
+                    fields.extend(direct.iter().map(|(p, f)| (p.clone(), f.clone())));
+                }
+                if let Some(choices) = direct_one_of.get(ancestor) {
+                    one_of.extend(choices.iter().cloned());
+                }
+                if let Some(policies) = direct_on_delete.get(ancestor) {
+                    on_delete.extend(policies.iter().map(|(p, d)| (p.clone(), *d)));
+                }
+                if let Some(class) = model.classes.get(ancestor) {
+                    subdocument |= class.subdocument;
+                    unfoldable |= class.unfoldable;
+                }
+                if ancestor != &id {
+                    model
+                        .subclasses
+                        .entry(ancestor.clone())
+                        .or_default()
+                        .insert(id.clone());
+                }
+            }
+            let class = model.classes.get_mut(&id).unwrap();
+            class.fields = fields;
+            class.one_of = one_of;
+            class.on_delete = on_delete;
+            class.subdocument = subdocument;
+            class.unfoldable = unfoldable;
+        }
+
+        model
+    }
+
+    /// The class itself followed by all of its ancestors, without
+    /// duplicates.
+    fn ancestors(&self, id: &str) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        let mut work = vec![id.to_string()];
+        while let Some(cur) = work.pop() {
+            if result.contains(&cur) {
+                continue;
+            }
+            if let Some(class) = self.classes.get(&cur) {
+                work.extend(class.parents.iter().rev().cloned());
+            }
+            result.push(cur);
+        }
+
+        result
+    }
+
+    pub fn class(&self, id: &str) -> Option<&ClassDefinition> {
+        self.classes.get(id)
+    }
+
+    pub fn classes(&self) -> impl Iterator<Item = &ClassDefinition> {
+        self.classes.values()
+    }
+
+    /// Whether `sub` is `sup` or inherits from it.
+    pub fn is_subclass_of(&self, sub: &str, sup: &str) -> bool {
+        sub == sup
+            || self
+                .subclasses
+                .get(sup)
+                .map(|subs| subs.contains(sub))
+                .unwrap_or(false)
+    }
+
+    fn expand(&self, s: &str, default: &str) -> String {
+        if has_protocol(s) {
+            return s.to_string();
+        }
+        if let Some(pos) = s.find(':') {
+            let (prefix, rest) = (&s[..pos], &s[pos + 1..]);
+            if prefix == "@base" {
+                return format!("{}{}", self.base, rest);
+            } else if prefix == "@schema" {
+                return format!("{}{}", self.schema, rest);
+            } else if let Some(expansion) = self.prefixes.get(prefix) {
+                return format!("{expansion}{rest}");
+            } else if let Some(expansion) = builtin_prefix(prefix) {
+                return format!("{expansion}{rest}");
+            }
+        }
+
+        format!("{default}{s}")
+    }
+
+    /// Expand a document id against the instance base.
+    pub fn expand_instance(&self, s: &str) -> String {
+        self.expand(s, &self.base)
+    }
+
+    /// Expand a type or property name against the schema base.
+    pub fn expand_schema(&self, s: &str) -> String {
+        self.expand(s, &self.schema)
+    }
+
+    /// Contract a schema IRI by dropping the schema base, if it has
+    /// one.
+    pub fn compress_schema<'a>(&self, iri: &'a str) -> &'a str {
+        iri.strip_prefix(self.schema.as_str()).unwrap_or(iri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> SchemaModel {
+        let mut model = SchemaModel::default();
+        model
+            .prefixes
+            .insert("ex".to_string(), "http://example.com/".to_string());
+        model
+    }
+
+    #[test]
+    fn expand_names() {
+        let model = model();
+        assert_eq!(
+            "terminusdb:///data/Person/1",
+            model.expand_instance("Person/1")
+        );
+        assert_eq!("terminusdb:///schema#Person", model.expand_schema("Person"));
+        assert_eq!("http://example.com/thing", model.expand_schema("ex:thing"));
+        assert_eq!(
+            "http://www.w3.org/2001/XMLSchema#string",
+            model.expand_schema("xsd:string")
+        );
+        assert_eq!(
+            "http://elsewhere.com/x",
+            model.expand_instance("http://elsewhere.com/x")
+        );
+        assert_eq!(
+            "Person",
+            model.compress_schema("terminusdb:///schema#Person")
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use terminusdb_store_prolog::terminus_store::layer::LayerBuilder;
+
+use super::canonical::DocumentHashError;
+use super::delete::document_id_triples;
+use super::insert::{document_to_triples_reusing, ExistingCells, InsertError};
+use super::*;
+use crate::types::transaction_instance_builder;
+
+#[derive(Error, Debug)]
+pub enum PatchError {
+    #[error("document not found: {0}")]
+    DocumentNotFound(String),
+    #[error("invalid patch: {0}")]
+    InvalidPatch(String),
+    #[error("patch operation {0} failed: {1}")]
+    OperationFailed(usize, String),
+    #[error("patch changes the id of document {0} to {1}")]
+    IdChanged(String, String),
+    #[error(transparent)]
+    Insert(#[from] InsertError),
+    #[error(transparent)]
+    Hash(#[from] DocumentHashError),
+    #[error(transparent)]
+    Retrieval(#[from] DocRetrievalError),
+    #[error("submitted patches are not valid json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl IntoPrologException for PatchError {
+    fn into_prolog_exception<'a, T: QueryableContextType>(
+        self,
+        context: &'a Context<'_, T>,
+    ) -> PrologResult<Term<'a>> {
+        let term = match self {
+            PatchError::DocumentNotFound(id) => term! {context: error(document_not_found(#id), _)}?,
+            PatchError::InvalidPatch(msg) => term! {context: error(invalid_json_patch(#msg), _)}?,
+            PatchError::OperationFailed(index, msg) => {
+                let index = index as u64;
+                term! {context: error(json_patch_failed(#index, #msg), _)}?
+            }
+            PatchError::IdChanged(old, new) => {
+                term! {context: error(patch_changes_document_id(#old, #new), _)}?
+            }
+            PatchError::Insert(e) => return e.into_prolog_exception(context),
+            PatchError::Hash(e) => return e.into_prolog_exception(context),
+            PatchError::Retrieval(e) => return e.into_prolog_exception(context),
+            PatchError::Json(e) => {
+                let msg = e.to_string();
+                term! {context: error(malformed_json_payload(#msg), _)}?
+            }
+        };
+
+        context.raise_exception(&term)
+    }
+}
+
+/// A change to a single document. An RFC 6902 JSON Patch is a list of
+/// operations, while an RFC 7396 Merge Patch is an object, so the two
+/// are told apart by their shape.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentPatch {
+    Json(Vec<Value>),
+    Merge(Map<String, Value>),
+}
+
+impl DocumentPatch {
+    pub fn from_value(value: Value) -> Result<Self, PatchError> {
+        match value {
+            Value::Array(operations) => Ok(Self::Json(operations)),
+            Value::Object(merge) => Ok(Self::Merge(merge)),
+            _ => Err(PatchError::InvalidPatch(
+                "a patch should be a list of operations or a merge object".to_string(),
+            )),
+        }
+    }
+
+    /// Apply the patch. A JSON Patch is applied all or nothing, so on
+    /// failure the value is left as it was.
+    pub fn apply(&self, value: &mut Value) -> Result<(), PatchError> {
+        match self {
+            Self::Json(operations) => {
+                let mut patched = value.clone();
+                for (index, operation) in operations.iter().enumerate() {
+                    apply_operation(&mut patched, operation)
+                        .map_err(|e| PatchError::OperationFailed(index, e))?;
+                }
+                *value = patched;
+            }
+            Self::Merge(merge) => merge_patch(value, merge),
+        }
+
+        Ok(())
+    }
+}
+
+/// Apply an RFC 7396 Merge Patch. Null removes a member, objects are
+/// merged recursively and anything else replaces what was there.
+fn merge_patch(target: &mut Value, patch: &Map<String, Value>) {
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            Value::Object(nested) => {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), nested)
+            }
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn string_member<'a>(operation: &'a Map<String, Value>, key: &str) -> Result<&'a str, String> {
+    operation
+        .get(key)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("missing {key}"))
+}
+
+fn value_member(operation: &Map<String, Value>) -> Result<Value, String> {
+    operation
+        .get("value")
+        .cloned()
+        .ok_or_else(|| "missing value".to_string())
+}
+
+fn apply_operation(doc: &mut Value, operation: &Value) -> Result<(), String> {
+    let operation = operation
+        .as_object()
+        .ok_or_else(|| "operation is not an object".to_string())?;
+    let path = string_member(operation, "path")?;
+    match string_member(operation, "op")? {
+        "add" => add(doc, path, value_member(operation)?),
+        "remove" => remove(doc, path).map(|_| ()),
+        "replace" => {
+            let target = doc
+                .pointer_mut(path)
+                .ok_or_else(|| format!("{path} does not exist"))?;
+            *target = value_member(operation)?;
+            Ok(())
+        }
+        "move" => {
+            let from = string_member(operation, "from")?;
+            if path.len() > from.len()
+                && path.starts_with(from)
+                && path[from.len()..].starts_with('/')
+            {
+                return Err(format!("can't move {from} into one of its children"));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        "copy" => {
+            let from = string_member(operation, "from")?;
+            let value = doc
+                .pointer(from)
+                .cloned()
+                .ok_or_else(|| format!("{from} does not exist"))?;
+            add(doc, path, value)
+        }
+        "test" => {
+            let expected = value_member(operation)?;
+            match doc.pointer(path) {
+                Some(actual) if json_equal(actual, &expected) => Ok(()),
+                _ => Err(format!("test of {path} failed")),
+            }
+        }
+        op => Err(format!("unknown operation {op}")),
+    }
+}
+
+/// Split a json pointer into the pointer to its parent and the
+/// unescaped last reference token.
+fn split_pointer(path: &str) -> Result<(&str, String), String> {
+    let ix = path
+        .rfind('/')
+        .ok_or_else(|| format!("{path} is not a json pointer"))?;
+    let token = path[ix + 1..].replace("~1", "/").replace("~0", "~");
+
+    Ok((&path[..ix], token))
+}
+
+fn array_index(token: &str, len: usize, allow_end: bool) -> Result<usize, String> {
+    if allow_end && token == "-" {
+        return Ok(len);
+    }
+    let valid = !token.is_empty()
+        && token.chars().all(|c| c.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(ix) if valid && (ix < len || (allow_end && ix == len)) => Ok(ix),
+        _ => Err(format!("{token} is not a valid array index")),
+    }
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(elements)) => {
+            let ix = array_index(&token, elements.len(), true)?;
+            elements.insert(ix, value);
+            Ok(())
+        }
+        Some(_) => Err(format!("{parent} is neither an object nor an array")),
+        None => Err(format!("{parent} does not exist")),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    if path.is_empty() {
+        return Err("can't remove the whole document".to_string());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map
+            .remove(&token)
+            .ok_or_else(|| format!("{path} does not exist")),
+        Some(Value::Array(elements)) => {
+            let ix = array_index(&token, elements.len(), false)?;
+            Ok(elements.remove(ix))
+        }
+        _ => Err(format!("{path} does not exist")),
+    }
+}
+
+/// Compare json values the way RFC 6902 tests do, so numbers compare
+/// by value rather than by how they were written.
+fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a == b || a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(k, v)| b.get(k).map(|w| json_equal(v, w)).unwrap_or(false))
+        }
+        _ => a == b,
+    }
+}
+
+/// A patch for a stored document, as submitted in a list like
+/// `[{"@id": "Person/1", "patch": [...], "ifMatch": "..."}]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchRequest {
+    pub id: String,
+    pub patch: DocumentPatch,
+    /// The content hash the document should have before patching.
+    pub if_match: Option<String>,
+}
+
+impl PatchRequest {
+    fn from_value(value: Value) -> Result<Self, PatchError> {
+        let mut map = match value {
+            Value::Object(map) => map,
+            _ => {
+                return Err(PatchError::InvalidPatch(
+                    "a patch request should be an object".to_string(),
+                ))
+            }
+        };
+        let id = match map.remove("@id") {
+            Some(Value::String(id)) => id,
+            _ => return Err(PatchError::InvalidPatch("missing @id".to_string())),
+        };
+        let patch = map
+            .remove("patch")
+            .ok_or_else(|| PatchError::InvalidPatch(format!("missing patch for {id}")))?;
+        let if_match = match map.remove("ifMatch") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(hash)) => Some(hash),
+            Some(_) => {
+                return Err(PatchError::InvalidPatch(
+                    "ifMatch should be a string".to_string(),
+                ))
+            }
+        };
+        if let Some(key) = map.keys().next() {
+            return Err(PatchError::InvalidPatch(format!("unknown key {key}")));
+        }
+
+        Ok(Self {
+            id,
+            patch: DocumentPatch::from_value(patch)?,
+            if_match,
+        })
+    }
+}
+
+pub fn parse_patch_requests(s: &str) -> Result<Vec<PatchRequest>, PatchError> {
+    match serde_json::from_str(s)? {
+        Value::Array(requests) => requests.into_iter().map(PatchRequest::from_value).collect(),
+        request => Ok(vec![PatchRequest::from_value(request)?]),
+    }
+}
+
+/// Patch a stored document, writing only the triples that changed
+/// into the builder. Returns the id of the document.
+///
+/// The document is read the way it is returned to users, with
+/// compressed ids and linked documents not unfolded, so patch paths
+/// use the same names as the retrieved document.
+pub fn patch_document<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    request: &PatchRequest,
+) -> Result<String, PatchError> {
+    let iri = context.model().expand_instance(&request.id);
+    if let Some(expected) = request.if_match.as_ref() {
+        context.check_document_hash(&iri, expected)?;
+    }
+    let id = context
+        .layer
+        .as_ref()
+        .and_then(|layer| layer.subject_id(&iri))
+        .filter(|id| context.id_document_exists(*id))
+        .ok_or_else(|| PatchError::DocumentNotFound(iri.clone()))?;
+    let doc = context
+        .get_id_document(id, true, false)?
+        .ok_or_else(|| PatchError::DocumentNotFound(iri.clone()))?;
+
+    let layer = context.layer();
+    let old: HashMap<ValueTriple, IdTriple> = document_id_triples(context, id)
+        .into_iter()
+        .filter_map(|t| layer.id_triple_to_string(&t).map(|v| (v, t)))
+        .collect();
+    let cells = ExistingCells::from_triples(&old.keys().cloned().collect::<Vec<_>>());
+
+    let mut patched = Value::Object(doc);
+    request.patch.apply(&mut patched)?;
+    let document = document_to_triples_reusing(context.model(), &patched, cells)?;
+    if document.id != iri {
+        return Err(PatchError::IdChanged(iri, document.id));
+    }
+
+    let new: HashSet<ValueTriple> = document.triples.into_iter().collect();
+    for (value_triple, id_triple) in old.iter() {
+        if !new.contains(value_triple) {
+            builder.remove_id_triple(*id_triple);
+        }
+    }
+    for triple in new {
+        if !old.contains_key(&triple) {
+            builder.add_value_triple(triple);
+        }
+    }
+
+    Ok(iri)
+}
+
+pub fn patch_document_batch<L: Layer + Clone>(
+    context: &DocumentContext<L>,
+    builder: &mut dyn LayerBuilder,
+    requests: &[PatchRequest],
+) -> Result<Vec<String>, PatchError> {
+    let mut seen = HashSet::with_capacity(requests.len());
+    let mut ids = Vec::with_capacity(requests.len());
+    for request in requests {
+        // every patch reads the state from before the transaction, so
+        // a second patch of the same document would undo the first
+        if !seen.insert(context.model().expand_instance(&request.id)) {
+            return Err(InsertError::DuplicateId(request.id.clone()).into());
+        }
+        ids.push(patch_document(context, builder, request)?);
+    }
+
+    Ok(ids)
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn patch_documents(context, document_context_term, transaction_term, patches_term, ids_term) {
+        let document_context: DocumentContextBlob = document_context_term.get_ex()?;
+        let payload: PrologText = patches_term.get_ex()?;
+        let requests = context.try_or_die(parse_patch_requests(&payload))?;
+        let builder = transaction_instance_builder(context, transaction_term)?;
+        if builder.is_none() {
+            return context.raise_exception(&term! {context: error(builder_not_initialized, _)}?);
+        }
+        let builder = builder.unwrap();
+        let ids = context.try_or_die(context.try_or_die(builder.with_builder(|builder| {
+            patch_document_batch(&document_context, &mut **builder, &requests)
+        }))?)?;
+
+        let ids: Vec<Atom> = ids.iter().map(|id| Atom::new(id)).collect();
+        ids_term.unify(ids.as_slice())
+    }
+}
+
+pub fn register() {
+    register_patch_documents();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(doc: Value, patch: Value) -> Result<Value, PatchError> {
+        let mut doc = doc;
+        DocumentPatch::from_value(patch)?.apply(&mut doc)?;
+        Ok(doc)
+    }
+
+    #[test]
+    fn json_patch_operations() {
+        let doc = json!({"@id": "Person/1", "name": "Jane", "tags": ["a", "b"], "address": {"city": "Delft"}});
+        let patched = patch(
+            doc,
+            json!([
+                {"op": "test", "path": "/name", "value": "Jane"},
+                {"op": "replace", "path": "/name", "value": "Joan"},
+                {"op": "add", "path": "/tags/-", "value": "c"},
+                {"op": "remove", "path": "/tags/0"},
+                {"op": "copy", "from": "/address/city", "path": "/birthplace"},
+                {"op": "move", "from": "/address", "path": "/home"}
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            json!({"@id": "Person/1", "name": "Joan", "tags": ["b", "c"], "birthplace": "Delft", "home": {"city": "Delft"}}),
+            patched
+        );
+    }
+
+    #[test]
+    fn json_patch_is_all_or_nothing() {
+        let doc = json!({"name": "Jane"});
+        let mut patched = doc.clone();
+        let result = DocumentPatch::from_value(json!([
+            {"op": "replace", "path": "/name", "value": "Joan"},
+            {"op": "test", "path": "/name", "value": "Jane"}
+        ]))
+        .unwrap()
+        .apply(&mut patched);
+        assert!(matches!(result, Err(PatchError::OperationFailed(1, _))));
+        assert_eq!(doc, patched);
+
+        assert!(patch(doc.clone(), json!([{"op": "remove", "path": "/age"}])).is_err());
+        assert!(patch(
+            doc.clone(),
+            json!([{"op": "add", "path": "/a/b", "value": 1}])
+        )
+        .is_err());
+        assert!(patch(
+            doc,
+            json!([{"op": "move", "from": "/name", "path": "/name/x"}])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let doc = json!({"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]});
+        let patched = patch(
+            doc,
+            json!({"a": "z", "c": {"f": null}, "h": [2], "i": {"j": 1}}),
+        )
+        .unwrap();
+        assert_eq!(
+            json!({"a": "z", "c": {"d": "e"}, "h": [2], "i": {"j": 1}}),
+            patched
+        );
+    }
+
+    #[test]
+    fn patch_requests_parse() {
+        let requests = parse_patch_requests(
+            r#"[{"@id": "Person/1", "patch": {"name": "Joan"}, "ifMatch": "abc"},
+                {"@id": "Person/2", "patch": [{"op": "remove", "path": "/name"}]}]"#,
+        )
+        .unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(Some("abc".to_string()), requests[0].if_match);
+        assert!(matches!(requests[0].patch, DocumentPatch::Merge(_)));
+        assert!(matches!(requests[1].patch, DocumentPatch::Json(_)));
+
+        assert!(parse_patch_requests(r#"{"@id": "Person/1", "patch": 1}"#).is_err());
+        assert!(parse_patch_requests(r#"{"patch": []}"#).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::*;
+
+#[derive(Error, Debug)]
+pub enum ShapeError {
+    #[error("document shape is not valid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("document shape is invalid: {0}")]
+    Invalid(String),
+}
+
+impl IntoPrologException for ShapeError {
+    fn into_prolog_exception<'a, T: QueryableContextType>(
+        self,
+        context: &'a Context<'_, T>,
+    ) -> PrologResult<Term<'a>> {
+        let msg = self.to_string();
+        let term = term! {context: error(invalid_document_shape(#msg), _)}?;
+
+        context.raise_exception(&term)
+    }
+}
+
+/// Which fields of a document to return. Fields are named the way
+/// they appear in the retrieved document, and nested fields are
+/// addressed with dotted paths, like `address.city`. The `@id` and
+/// `@type` keys are always kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Projection {
+    /// The fields to keep at this level, or `None` to keep all of them.
+    include: Option<BTreeSet<String>>,
+    exclude: BTreeSet<String>,
+    nested: BTreeMap<String, Projection>,
+}
+
+fn split_path(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    }
+}
+
+impl Projection {
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Self {
+        let mut projection = Self::default();
+        for path in include {
+            projection.add_include(path.as_ref());
+        }
+        for path in exclude {
+            projection.add_exclude(path.as_ref());
+        }
+
+        projection
+    }
+
+    fn add_include(&mut self, path: &str) {
+        let (head, rest) = split_path(path);
+        self.include
+            .get_or_insert_with(BTreeSet::new)
+            .insert(head.to_string());
+        if let Some(rest) = rest {
+            self.nested
+                .entry(head.to_string())
+                .or_default()
+                .add_include(rest);
+        }
+    }
+
+    fn add_exclude(&mut self, path: &str) {
+        match split_path(path) {
+            (head, None) => {
+                self.exclude.insert(head.to_string());
+            }
+            (head, Some(rest)) => self
+                .nested
+                .entry(head.to_string())
+                .or_default()
+                .add_exclude(rest),
+        }
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        key.starts_with('@')
+            || (self
+                .include
+                .as_ref()
+                .map(|include| include.contains(key))
+                .unwrap_or(true)
+                && !self.exclude.contains(key))
+    }
+
+    pub fn apply(&self, doc: &mut Map<String, Value>) {
+        doc.retain(|key, _| self.keeps(key));
+        for (key, nested) in self.nested.iter() {
+            if let Some(value) = doc.get_mut(key) {
+                nested.apply_value(value);
+            }
+        }
+    }
+
+    fn apply_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(doc) => self.apply(doc),
+            Value::Array(elements) => {
+                for element in elements {
+                    self.apply_value(element);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How much of a document to retrieve, and how to write it out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DocumentShape {
+    pub projection: Option<Projection>,
+    /// How many levels of unfoldable documents get inlined.
+    /// Subdocuments are always part of their document and don't count.
+    pub max_depth: Option<usize>,
+    /// Write documents in RFC 8785 canonical form, so that the output
+    /// is byte for byte stable and can be hashed.
+    pub canonical: bool,
+}
+
+impl DocumentShape {
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S], max_depth: Option<usize>) -> Self {
+        Self {
+            projection: (!include.is_empty() || !exclude.is_empty())
+                .then(|| Projection::new(include, exclude)),
+            max_depth,
+            canonical: false,
+        }
+    }
+
+    /// Parse a shape from its json form, which looks like
+    /// `{"include": ["name", "address.city"], "exclude": [], "maxDepth": 1, "canonical": true}`.
+    /// All keys are optional.
+    pub fn from_json(s: &str) -> Result<Self, ShapeError> {
+        let mut map: Map<String, Value> = serde_json::from_str(s)?;
+        let mut paths = |key: &str| -> Result<Vec<String>, ShapeError> {
+            match map.remove(key) {
+                None | Some(Value::Null) => Ok(Vec::new()),
+                Some(paths) => serde_json::from_value(paths).map_err(|_| {
+                    ShapeError::Invalid(format!("{key} should be a list of field paths"))
+                }),
+            }
+        };
+        let include = paths("include")?;
+        let exclude = paths("exclude")?;
+        let max_depth = match map.remove("maxDepth") {
+            None | Some(Value::Null) => None,
+            Some(depth) => Some(depth.as_u64().ok_or_else(|| {
+                ShapeError::Invalid("maxDepth should be a non-negative integer".to_string())
+            })? as usize),
+        };
+        let canonical = match map.remove("canonical") {
+            None | Some(Value::Null) => false,
+            Some(Value::Bool(canonical)) => canonical,
+            Some(_) => {
+                return Err(ShapeError::Invalid(
+                    "canonical should be a boolean".to_string(),
+                ))
+            }
+        };
+        if let Some(key) = map.keys().next() {
+            return Err(ShapeError::Invalid(format!("unknown key {key}")));
+        }
+
+        Ok(Self {
+            canonical,
+            ..Self::new(&include, &exclude, max_depth)
+        })
+    }
+
+    /// Read a shape from a prolog term, which is either the atom `none`
+    /// or the json form of the shape.
+    pub fn from_term<C: QueryableContextType>(
+        context: &Context<C>,
+        term: &Term,
+    ) -> PrologResult<Self> {
+        if attempt_opt(term.get::<Atom>())? == Some(atom!("none")) {
+            return Ok(Self::default());
+        }
+        let s: PrologText = term.get_ex()?;
+        context.try_or_die(Self::from_json(&s))
+    }
+
+    /// Whether unfoldable documents may still be inlined at the given
+    /// depth.
+    pub(super) fn unfolds_at(&self, depth: usize) -> bool {
+        self.max_depth.map(|max| depth < max).unwrap_or(true)
+    }
+
+    pub fn apply(&self, doc: &mut Map<String, Value>) {
+        if let Some(projection) = self.projection.as_ref() {
+            projection.apply(doc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Map<String, Value> {
+        serde_json::from_str(
+            r#"{"@id": "Person/1",
+                "@type": "Person",
+                "name": "Jane",
+                "age": 40,
+                "address": {"@id": "Person/1/address", "@type": "Address", "city": "Delft", "street": "Markt"},
+                "pets": [{"@id": "Pet/1", "@type": "Pet", "name": "Rex", "legs": 4}]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn nested_include_keeps_only_the_listed_fields() {
+        let mut doc = doc();
+        Projection::new(&["name", "address.city", "pets.name"], &[]).apply(&mut doc);
+        assert_eq!(
+            serde_json::json!({
+                "@id": "Person/1",
+                "@type": "Person",
+                "name": "Jane",
+                "address": {"@id": "Person/1/address", "@type": "Address", "city": "Delft"},
+                "pets": [{"@id": "Pet/1", "@type": "Pet", "name": "Rex"}]
+            }),
+            Value::Object(doc)
+        );
+    }
+
+    #[test]
+    fn exclude_drops_fields_at_any_level() {
+        let mut doc = doc();
+        Projection::new(&[], &["age", "address.street", "pets"]).apply(&mut doc);
+        assert_eq!(
+            serde_json::json!({
+                "@id": "Person/1",
+                "@type": "Person",
+                "name": "Jane",
+                "address": {"@id": "Person/1/address", "@type": "Address", "city": "Delft"}
+            }),
+            Value::Object(doc)
+        );
+    }
+
+    #[test]
+    fn shape_parses_from_json() {
+        let shape = DocumentShape::from_json(r#"{"include": ["name"], "maxDepth": 0}"#).unwrap();
+        assert_eq!(Some(0), shape.max_depth);
+        assert!(!shape.unfolds_at(0));
+        assert_eq!(Some(Projection::new(&["name"], &[])), shape.projection);
+        assert!(!shape.canonical);
+        assert!(
+            DocumentShape::from_json(r#"{"canonical": true}"#)
+                .unwrap()
+                .canonical
+        );
+
+        assert!(DocumentShape::from_json(r#"{"maxDepth": -1}"#).is_err());
+        assert!(DocumentShape::from_json(r#"{"fields": []}"#).is_err());
+    }
+}
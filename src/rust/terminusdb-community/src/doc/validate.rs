@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use serde::Serialize;
+use tdb_succinct::*;
+use terminusdb_store_prolog::layer::WrappedLayer;
+
+use crate::terminus_store::layer::ObjectType;
+
+use super::model::*;
+use super::*;
+
+/// A reason the instance graph does not conform to the schema, shaped
+/// like the witnesses the prolog validator reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "@type", rename_all = "snake_case")]
+pub enum Witness {
+    SubjectHasNoType {
+        subject: String,
+    },
+    UnknownClass {
+        subject: String,
+        class: String,
+    },
+    ReifyingAbstractClass {
+        subject: String,
+        class: String,
+    },
+    SubjectTypeHasChanged {
+        subject: String,
+        old_type: String,
+        new_type: String,
+    },
+    InvalidPredicate {
+        subject: String,
+        class: String,
+        predicate: String,
+    },
+    InstanceNotCardinalityOne {
+        instance: String,
+        class: String,
+        predicate: String,
+    },
+    InstanceHasWrongCardinality {
+        instance: String,
+        class: String,
+        predicate: String,
+        cardinality: usize,
+    },
+    NoChoiceIsCardinalityOne {
+        instance: String,
+        class: String,
+        choices: Vec<String>,
+    },
+    ForbiddenOneofPropertyPresent {
+        instance: String,
+        class: String,
+        predicate: String,
+    },
+    InstanceNotOfClass {
+        class: String,
+        instance: String,
+    },
+    NotAValidList {
+        class: String,
+        list: String,
+    },
+    ReferencesUntypedObject {
+        subject: String,
+        predicate: String,
+        object: String,
+    },
+    DeletedObjectStillReferenced {
+        subject: String,
+        predicate: String,
+        object: String,
+    },
+}
+
+/// The datatype a value of the given base type is stored as, if it is
+/// a base type we know about.
+fn base_datatype(range: &str) -> Option<Datatype> {
+    if range.starts_with(XDD_PREFIX) {
+        return Some(Datatype::String);
+    }
+    if range == format!("{RDF_PREFIX}langString") {
+        return Some(Datatype::LangString);
+    }
+    let datatype = match range.strip_prefix(XSD_PREFIX)? {
+        "boolean" => Datatype::Boolean,
+        "byte" => Datatype::Int8,
+        "short" => Datatype::Int16,
+        "int" => Datatype::Int32,
+        "long" => Datatype::Int64,
+        "unsignedByte" => Datatype::UInt8,
+        "unsignedShort" => Datatype::UInt16,
+        "unsignedInt" => Datatype::UInt32,
+        "unsignedLong" => Datatype::UInt64,
+        "integer" => Datatype::BigInt,
+        "positiveInteger" => Datatype::PositiveInteger,
+        "nonNegativeInteger" => Datatype::NonNegativeInteger,
+        "negativeInteger" => Datatype::NegativeInteger,
+        "nonPositiveInteger" => Datatype::NonPositiveInteger,
+        "float" => Datatype::Float32,
+        "double" => Datatype::Float64,
+        "decimal" => Datatype::Decimal,
+        "language" => Datatype::Language,
+        "normalizedString" => Datatype::NormalizedString,
+        "token" => Datatype::Token,
+        "NMTOKEN" => Datatype::NMToken,
+        "Name" => Datatype::Name,
+        "NCName" => Datatype::NCName,
+        "anyURI" => Datatype::AnyURI,
+        "anySimpleType" => Datatype::AnySimpleType,
+        "base64Binary" => Datatype::Base64Binary,
+        "hexBinary" => Datatype::HexBinary,
+        "string" => Datatype::String,
+        "dateTime" => Datatype::DateTime,
+        "dateTimeStamp" => Datatype::DateTimeStamp,
+        "date" => Datatype::Date,
+        "time" => Datatype::Time,
+        "gYear" => Datatype::GYear,
+        "gMonth" => Datatype::GMonth,
+        "gDay" => Datatype::GDay,
+        "gYearMonth" => Datatype::GYearMonth,
+        "gMonthDay" => Datatype::GMonthDay,
+        "duration" => Datatype::Duration,
+        "yearMonthDuration" => Datatype::YearMonthDuration,
+        "dayTimeDuration" => Datatype::DayTimeDuration,
+        _ => return None,
+    };
+
+    Some(datatype)
+}
+
+fn object_string(object: &ObjectType) -> String {
+    match object {
+        ObjectType::Node(node) => node.clone(),
+        ObjectType::Value(value) => match value_to_json(value) {
+            Value::String(s) => s,
+            v => v.to_string(),
+        },
+    }
+}
+
+struct Validator<'a> {
+    model: &'a SchemaModel,
+    base: Option<&'a SyncStoreLayer>,
+    layer: &'a SyncStoreLayer,
+    rdf_type_id: Option<u64>,
+}
+
+impl<'a> Validator<'a> {
+    fn type_of(&self, id: u64) -> Option<String> {
+        let triple = self.layer.single_triple_sp(id, self.rdf_type_id?)?;
+        self.layer.id_object_node(triple.object)
+    }
+
+    fn base_type_of(&self, subject: &str) -> Option<String> {
+        let base = self.base?;
+        let id = base.subject_id(subject)?;
+        let triple = base.single_triple_sp(id, base.predicate_id(RDF_TYPE)?)?;
+        base.id_object_node(triple.object)
+    }
+
+    fn check_subject(&self, id: u64) -> Vec<Witness> {
+        let mut witnesses = Vec::new();
+        let subject = match self.layer.id_subject(id) {
+            Some(subject) => subject,
+            None => return witnesses,
+        };
+        let mut objects: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for t in self.layer.triples_s(id) {
+            if Some(t.predicate) != self.rdf_type_id {
+                objects.entry(t.predicate).or_default().push(t.object);
+            }
+        }
+        let typ = match self.type_of(id) {
+            Some(typ) => typ,
+            // no triples left at all means the subject was deleted
+            None if objects.is_empty() => return witnesses,
+            None => {
+                witnesses.push(Witness::SubjectHasNoType { subject });
+                return witnesses;
+            }
+        };
+        // list and array cells are checked as part of the field they
+        // belong to, and json is not described by the schema at all
+        if [RDF_LIST, SYS_ARRAY, SYS_JSON, SYS_JSON_DOCUMENT].contains(&typ.as_str()) {
+            return witnesses;
+        }
+
+        let class = match self.model.class(&typ) {
+            Some(class) => class,
+            None => {
+                witnesses.push(Witness::UnknownClass {
+                    subject,
+                    class: typ,
+                });
+                return witnesses;
+            }
+        };
+        if let Some(old_type) = self.base_type_of(&subject).filter(|t| t != &typ) {
+            witnesses.push(Witness::SubjectTypeHasChanged {
+                subject: subject.clone(),
+                old_type,
+                new_type: typ.clone(),
+            });
+        }
+        if class.abstract_ {
+            witnesses.push(Witness::ReifyingAbstractClass {
+                subject,
+                class: typ,
+            });
+            return witnesses;
+        }
+        if class.kind == ClassKind::Foreign {
+            return witnesses;
+        }
+
+        let mut by_property: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        for (predicate_id, objects) in objects {
+            let predicate = self.layer.id_predicate(predicate_id).unwrap();
+            if class.field(&predicate).is_none() {
+                witnesses.push(Witness::InvalidPredicate {
+                    subject: subject.clone(),
+                    class: typ.clone(),
+                    predicate,
+                });
+                continue;
+            }
+            by_property.insert(predicate, objects);
+        }
+        let objects_of = |property: &str| -> &[u64] {
+            by_property
+                .get(property)
+                .map(|o| o.as_slice())
+                .unwrap_or(&[])
+        };
+
+        let mut choices: Vec<&BTreeMap<String, FieldDefinition>> = class.one_of.iter().collect();
+        if class.kind == ClassKind::TaggedUnion {
+            choices.push(&class.fields);
+        } else {
+            for (property, field) in class.fields.iter() {
+                if let Some(witness) =
+                    self.check_cardinality(&subject, property, field, objects_of(property))
+                {
+                    witnesses.push(witness);
+                }
+            }
+        }
+        for choice in choices {
+            let present: Vec<&String> = choice
+                .keys()
+                .filter(|property| !objects_of(property).is_empty())
+                .collect();
+            match present.as_slice() {
+                [] => witnesses.push(Witness::NoChoiceIsCardinalityOne {
+                    instance: subject.clone(),
+                    class: typ.clone(),
+                    choices: choice.keys().cloned().collect(),
+                }),
+                [chosen, rest @ ..] => {
+                    if objects_of(chosen).len() != 1 {
+                        witnesses.push(Witness::InstanceNotCardinalityOne {
+                            instance: subject.clone(),
+                            class: choice[*chosen].range.clone(),
+                            predicate: chosen.to_string(),
+                        });
+                    }
+                    for property in rest {
+                        witnesses.push(Witness::ForbiddenOneofPropertyPresent {
+                            instance: subject.clone(),
+                            class: typ.clone(),
+                            predicate: property.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        for (property, objects) in by_property.iter() {
+            let field = class.field(property).unwrap();
+            for object in objects {
+                self.check_field_object(&subject, property, field, *object, &mut witnesses);
+            }
+        }
+
+        witnesses
+    }
+
+    fn check_cardinality(
+        &self,
+        subject: &str,
+        property: &str,
+        field: &FieldDefinition,
+        objects: &[u64],
+    ) -> Option<Witness> {
+        let count = objects.len();
+        let wrong = |cardinality| Witness::InstanceHasWrongCardinality {
+            instance: subject.to_string(),
+            class: field.range.clone(),
+            predicate: property.to_string(),
+            cardinality,
+        };
+        match field.kind {
+            FieldKind::Required | FieldKind::List if count != 1 => {
+                Some(Witness::InstanceNotCardinalityOne {
+                    instance: subject.to_string(),
+                    class: field.range.clone(),
+                    predicate: property.to_string(),
+                })
+            }
+            FieldKind::Optional if count > 1 => Some(wrong(count)),
+            FieldKind::Cardinality { min, max }
+                if count < min || max.map(|max| count > max).unwrap_or(false) =>
+            {
+                Some(wrong(count))
+            }
+            _ => None,
+        }
+    }
+
+    fn check_field_object(
+        &self,
+        subject: &str,
+        property: &str,
+        field: &FieldDefinition,
+        object: u64,
+        witnesses: &mut Vec<Witness>,
+    ) {
+        match field.kind {
+            FieldKind::List => {
+                let head = self.layer.id_object_node(object);
+                let is_list = head.as_deref() == Some(RDF_NIL)
+                    || self.type_of(object).as_deref() == Some(RDF_LIST);
+                if !is_list {
+                    witnesses.push(Witness::NotAValidList {
+                        class: field.range.clone(),
+                        list: head.unwrap_or_default(),
+                    });
+                    return;
+                }
+                let elements = RdfListIterator {
+                    layer: self.layer,
+                    cur: object,
+                    rdf_first_id: self.layer.predicate_id(RDF_FIRST),
+                    rdf_rest_id: self.layer.predicate_id(RDF_REST),
+                    rdf_nil_id: self.layer.object_node_id(RDF_NIL),
+                };
+                for element in elements {
+                    self.check_element(subject, property, &field.range, element, witnesses);
+                }
+            }
+            FieldKind::Array(_) => {
+                let value = self
+                    .layer
+                    .predicate_id(SYS_VALUE)
+                    .and_then(|p| self.layer.single_triple_sp(object, p));
+                if let Some(value) = value {
+                    self.check_element(subject, property, &field.range, value.object, witnesses);
+                }
+            }
+            _ => self.check_element(subject, property, &field.range, object, witnesses),
+        }
+    }
+
+    fn check_element(
+        &self,
+        subject: &str,
+        property: &str,
+        range: &str,
+        object_id: u64,
+        witnesses: &mut Vec<Witness>,
+    ) {
+        if range == SYS_JSON {
+            return;
+        }
+        let object = match self.layer.id_object(object_id) {
+            Some(object) => object,
+            None => return,
+        };
+        let not_of_class = || Witness::InstanceNotOfClass {
+            class: range.to_string(),
+            instance: object_string(&object),
+        };
+        if range == SYS_UNIT {
+            if !matches!(&object, ObjectType::Node(n) if n == RDF_NIL) {
+                witnesses.push(not_of_class());
+            }
+            return;
+        }
+
+        match (self.model.class(range), &object) {
+            (Some(class), ObjectType::Node(node)) => match &class.kind {
+                ClassKind::Enum(values) => {
+                    if !values.contains(node) {
+                        witnesses.push(not_of_class());
+                    }
+                }
+                ClassKind::Foreign => {}
+                _ => match self.type_of(object_id) {
+                    Some(typ) => {
+                        if !self.model.is_subclass_of(&typ, range) {
+                            witnesses.push(not_of_class());
+                        }
+                    }
+                    None => witnesses.push(Witness::ReferencesUntypedObject {
+                        subject: subject.to_string(),
+                        predicate: property.to_string(),
+                        object: node.clone(),
+                    }),
+                },
+            },
+            (Some(_), ObjectType::Value(_)) => witnesses.push(not_of_class()),
+            (None, ObjectType::Value(value)) => {
+                if let Some(datatype) = base_datatype(range) {
+                    if value.datatype() != datatype {
+                        witnesses.push(not_of_class());
+                    }
+                }
+            }
+            (None, ObjectType::Node(_)) => {
+                if base_datatype(range).is_some() {
+                    witnesses.push(not_of_class());
+                }
+            }
+        }
+    }
+
+    /// Documents whose type was removed in this layer but that are
+    /// still linked to.
+    fn check_deleted(&self, id: u64) -> Vec<Witness> {
+        if self.type_of(id).is_some() {
+            return Vec::new();
+        }
+        let object = match self.layer.id_subject(id) {
+            Some(object) => object,
+            None => return Vec::new(),
+        };
+        self.layer
+            .triples_o(id)
+            .map(|t| Witness::DeletedObjectStillReferenced {
+                subject: self.layer.id_subject(t.subject).unwrap(),
+                predicate: self.layer.id_predicate(t.predicate).unwrap(),
+                object: object.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Validate the changes `layer` makes on top of its parent against the
+/// schema. Only the subjects that were touched are checked, along with
+/// any links left pointing at documents that were deleted.
+///
+/// `base` is the instance layer the changes are measured against,
+/// which is used to detect documents changing type. A builder has to
+/// be committed into a layer before it can be validated, which is what
+/// happens before validation in a transaction anyway.
+pub fn validate_layer(
+    schema: &SyncStoreLayer,
+    base: Option<&SyncStoreLayer>,
+    layer: &SyncStoreLayer,
+) -> io::Result<Vec<Witness>> {
+    let model = SchemaModel::from_layer(schema);
+    let validator = Validator {
+        model: &model,
+        base,
+        layer,
+        rdf_type_id: layer.predicate_id(RDF_TYPE),
+    };
+
+    let mut changed = BTreeSet::new();
+    let mut deleted = BTreeSet::new();
+    for t in layer.triple_additions()? {
+        changed.insert(t.subject);
+    }
+    for t in layer.triple_removals()? {
+        if Some(t.predicate) == validator.rdf_type_id {
+            deleted.insert(t.subject);
+        }
+        changed.insert(t.subject);
+    }
+
+    let changed: Vec<u64> = changed.into_iter().collect();
+    let deleted: Vec<u64> = deleted.into_iter().collect();
+    let mut witnesses: Vec<Witness> = changed
+        .par_iter()
+        .flat_map_iter(|id| validator.check_subject(*id))
+        .collect();
+    witnesses.extend(
+        deleted
+            .par_iter()
+            .flat_map_iter(|id| validator.check_deleted(*id))
+            .collect::<Vec<_>>(),
+    );
+
+    Ok(witnesses)
+}
+
+predicates! {
+    #[module("$doc")]
+    semidet fn validate_layer(context, schema_term, base_term, layer_term, witnesses_term) {
+        let schema: WrappedLayer = schema_term.get_ex()?;
+        let base: Option<WrappedLayer> = attempt_opt(base_term.get())?;
+        let layer: WrappedLayer = layer_term.get_ex()?;
+        let witnesses = context.try_or_die(validate_layer(&schema, base.as_deref(), &layer))?;
+
+        let witnesses: Vec<String> = witnesses
+            .iter()
+            .map(|w| serde_json::to_string(w).unwrap())
+            .collect();
+        witnesses_term.unify(witnesses.as_slice())
+    }
+}
+
+pub fn register() {
+    register_validate_layer();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminusdb_store_prolog::terminus_store::open_sync_memory_store;
+
+    const PERSON: &str = "terminusdb:///schema#Person";
+    const NAME: &str = "terminusdb:///schema#name";
+    const FRIEND: &str = "terminusdb:///schema#friend";
+
+    fn schema_layer() -> SyncStoreLayer {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let triples = [
+            ValueTriple::new_node(PERSON, RDF_TYPE, SYS_CLASS),
+            ValueTriple::new_node(PERSON, NAME, XSD_STRING),
+            ValueTriple::new_node(PERSON, FRIEND, "terminusdb:///schema#Person/friend"),
+            ValueTriple::new_node("terminusdb:///schema#Person/friend", RDF_TYPE, SYS_OPTIONAL),
+            ValueTriple::new_node(
+                "terminusdb:///schema#Person/friend",
+                SYS_CLASS_PREDICATE,
+                PERSON,
+            ),
+        ];
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+
+        builder.commit().unwrap()
+    }
+
+    #[test]
+    fn only_changed_subjects_are_checked() {
+        let schema = schema_layer();
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        // a pre-existing violation that the next layer doesn't touch
+        builder
+            .add_value_triple(ValueTriple::new_node(
+                "terminusdb:///data/Person/old",
+                RDF_TYPE,
+                PERSON,
+            ))
+            .unwrap();
+        let base = builder.commit().unwrap();
+
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node(
+                "terminusdb:///data/Person/new",
+                RDF_TYPE,
+                PERSON,
+            ))
+            .unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_value(
+                "terminusdb:///data/Person/new",
+                NAME,
+                String::make_entry("Jane"),
+            ))
+            .unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node(
+                "terminusdb:///data/Person/new",
+                FRIEND,
+                "terminusdb:///data/Person/missing",
+            ))
+            .unwrap();
+        let layer = builder.commit().unwrap();
+
+        let witnesses = validate_layer(&schema, Some(&base), &layer).unwrap();
+        assert_eq!(
+            vec![Witness::ReferencesUntypedObject {
+                subject: "terminusdb:///data/Person/new".to_string(),
+                predicate: FRIEND.to_string(),
+                object: "terminusdb:///data/Person/missing".to_string(),
+            }],
+            witnesses
+        );
+    }
+
+    #[test]
+    fn cardinality_and_range_are_checked() {
+        let schema = schema_layer();
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node(
+                "terminusdb:///data/Person/1",
+                RDF_TYPE,
+                PERSON,
+            ))
+            .unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_value(
+                "terminusdb:///data/Person/1",
+                FRIEND,
+                String::make_entry("nobody"),
+            ))
+            .unwrap();
+        let layer = builder.commit().unwrap();
+
+        let witnesses = validate_layer(&schema, None, &layer).unwrap();
+        assert_eq!(
+            vec![
+                Witness::InstanceNotCardinalityOne {
+                    instance: "terminusdb:///data/Person/1".to_string(),
+                    class: XSD_STRING.to_string(),
+                    predicate: NAME.to_string(),
+                },
+                Witness::InstanceNotOfClass {
+                    class: PERSON.to_string(),
+                    instance: "nobody".to_string(),
+                },
+            ],
+            witnesses
+        );
+    }
+
+    #[test]
+    fn witnesses_serialize_like_prolog() {
+        let witness = Witness::SubjectHasNoType {
+            subject: "terminusdb:///data/x".to_string(),
+        };
+        assert_eq!(
+            r#"{"@type":"subject_has_no_type","subject":"terminusdb:///data/x"}"#,
+            serde_json::to_string(&witness).unwrap()
+        );
+    }
+}
@@ -0,0 +1,817 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use juniper::{InputValue, Value};
+use serde_json::Map;
+use terminusdb_store_prolog::terminus_store::store::sync::SyncStoreLayer;
+use terminusdb_store_prolog::terminus_store::Layer;
+
+use crate::consts::RDF_TYPE;
+use crate::path::{parse_path, Path, Pred};
+use crate::value::{base_type_kind, type_is_json, BaseTypeKind};
+
+use super::filter::FilterInputObject;
+use super::frame::{
+    node_variety, AllFrames, BaseOrDerived, FieldKind, GraphQLName, IriName, ShortName,
+};
+use super::naming::reverse_filter_to_inverted_field;
+use super::schema::{ids_from_restriction_name, TerminusContext, TerminusOrderBy};
+
+const SYSTEM_CAPABILITY: &str = "http://terminusdb.com/schema/system#capability";
+const SYSTEM_ROLE: &str = "http://terminusdb.com/schema/system#role";
+const SYSTEM_POLICY: &str = "http://terminusdb.com/schema/system#policy";
+const SYSTEM_POLICY_CLASS: &str = "http://terminusdb.com/schema/system#class";
+const SYSTEM_POLICY_HIDE: &str = "http://terminusdb.com/schema/system#hide";
+const SYSTEM_POLICY_MASK: &str = "http://terminusdb.com/schema/system#mask";
+const SYSTEM_POLICY_RESTRICTION: &str = "http://terminusdb.com/schema/system#restriction";
+
+/// The value returned in place of a masked property.
+pub const MASKED_VALUE: &str = "********";
+
+/// What a field policy does to a single property.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FieldAccess {
+    Visible,
+    Masked,
+    Hidden,
+}
+
+/// The combined read policy for a single class.
+///
+/// Policies from all roles of the user accumulate, so a property that
+/// is hidden by any role is hidden, and a document has to satisfy the
+/// restrictions of every role to be visible.
+#[derive(Debug, Default)]
+pub struct ClassPolicy {
+    pub hidden: HashSet<ShortName>,
+    pub masked: HashSet<ShortName>,
+    pub restrictions: Vec<ShortName>,
+}
+
+impl ClassPolicy {
+    pub fn property_access(&self, property: &ShortName) -> FieldAccess {
+        if self.hidden.contains(property) {
+            FieldAccess::Hidden
+        } else if self.masked.contains(property) {
+            FieldAccess::Masked
+        } else {
+            FieldAccess::Visible
+        }
+    }
+
+    fn merge(&mut self, other: &ClassPolicy) {
+        self.hidden.extend(other.hidden.iter().cloned());
+        self.masked.extend(other.masked.iter().cloned());
+        for restriction in other.restrictions.iter() {
+            if !self.restrictions.contains(restriction) {
+                self.restrictions.push(restriction.clone());
+            }
+        }
+    }
+}
+
+/// The read policies that apply to the current user, keyed by the
+/// GraphQL name of the class they apply to.
+///
+/// A policy on a class also applies to all of its subclasses.
+#[derive(Default)]
+pub struct AccessPolicies {
+    classes: HashMap<GraphQLName<'static>, ClassPolicy>,
+    restriction_cache: RefCell<HashMap<ShortName, Rc<HashSet<u64>>>>,
+}
+
+impl AccessPolicies {
+    pub fn from_system(system: &SyncStoreLayer, user: &str, allframes: &AllFrames) -> Self {
+        let mut classes: HashMap<GraphQLName<'static>, ClassPolicy> = HashMap::new();
+        for (class, policy) in policies_for_user(system, user) {
+            let class_name = match allframes.short_to_graphql_name_opt(&class) {
+                Some(class_name) => class_name,
+                // a policy on a class which is not in this schema
+                None => continue,
+            };
+            for sub_class in allframes.subsumed(&class_name) {
+                classes
+                    .entry(sub_class.as_static())
+                    .or_default()
+                    .merge(&policy);
+            }
+        }
+
+        Self {
+            classes,
+            restriction_cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    pub fn class_policy(&self, class: &GraphQLName) -> Option<&ClassPolicy> {
+        self.classes.get(&class.as_static())
+    }
+
+    /// Determine how a GraphQL field of a class may be read.
+    pub fn field_access(
+        &self,
+        allframes: &AllFrames,
+        class: &GraphQLName,
+        field: &GraphQLName,
+    ) -> FieldAccess {
+        match self.class_policy(class) {
+            None => FieldAccess::Visible,
+            Some(policy) => match allframes
+                .frames
+                .get(class)
+                .filter(|t| t.is_document_type())
+                .and_then(|t| {
+                    t.as_class_definition()
+                        .graphql_to_short_name
+                        .get_by_left(&field.as_static())
+                }) {
+                Some(property) => policy.property_access(property),
+                None => FieldAccess::Visible,
+            },
+        }
+    }
+
+    /// Determine whether the document with the given id may be seen
+    /// by the current user.
+    pub fn document_visible(&self, context: &TerminusContext<'static>, id: u64) -> bool {
+        if self.classes.is_empty() {
+            return true;
+        }
+        let instance = match context.instance.as_ref() {
+            Some(instance) => instance,
+            None => return true,
+        };
+        let class = instance
+            .predicate_id(RDF_TYPE)
+            .and_then(|rdf_type| instance.single_triple_sp(id, rdf_type))
+            .and_then(|t| instance.id_object_node(t.object))
+            .and_then(|ty| {
+                context
+                    .type_collection
+                    .allframes
+                    .iri_to_graphql_name_opt(&IriName(ty))
+                    .map(|c| c.as_static())
+            });
+        match class {
+            Some(class) => self.class_document_visible(context, &class, id),
+            None => true,
+        }
+    }
+
+    fn class_document_visible(
+        &self,
+        context: &TerminusContext<'static>,
+        class: &GraphQLName,
+        id: u64,
+    ) -> bool {
+        match self.class_policy(class) {
+            None => true,
+            Some(policy) => policy
+                .restrictions
+                .iter()
+                .all(|restriction| self.restriction_ids(context, restriction).contains(&id)),
+        }
+    }
+
+    fn restriction_ids(
+        &self,
+        context: &TerminusContext<'static>,
+        restriction: &ShortName,
+    ) -> Rc<HashSet<u64>> {
+        if let Some(ids) = self.restriction_cache.borrow().get(restriction) {
+            return ids.clone();
+        }
+        // A restriction which can not be evaluated hides everything
+        // rather than nothing.
+        let ids: HashSet<u64> = ids_from_restriction_name(context, restriction)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let ids = Rc::new(ids);
+        self.restriction_cache
+            .borrow_mut()
+            .insert(restriction.clone(), ids.clone());
+
+        ids
+    }
+
+    /// Remove hidden and mask masked properties in a retrieved JSON
+    /// document, including in any subdocuments or unfolded documents
+    /// it contains. Unfolded documents that are not visible are
+    /// replaced by their id.
+    pub fn redact_document(
+        &self,
+        context: &TerminusContext<'static>,
+        doc: &mut Map<String, serde_json::Value>,
+    ) {
+        if self.classes.is_empty() {
+            return;
+        }
+        let policy = doc
+            .get("@type")
+            .and_then(|ty| ty.as_str())
+            .and_then(|ty| {
+                context
+                    .type_collection
+                    .allframes
+                    .short_to_graphql_name_opt(&ShortName(ty.to_string()))
+            })
+            .and_then(|class| self.class_policy(&class));
+        if let Some(policy) = policy {
+            doc.retain(|key, _| !policy.hidden.contains(&ShortName(key.to_string())));
+            for (key, value) in doc.iter_mut() {
+                if policy.masked.contains(&ShortName(key.to_string())) {
+                    mask_json_value(value);
+                }
+            }
+        }
+
+        for (key, value) in doc.iter_mut() {
+            if key.starts_with('@') {
+                continue;
+            }
+            self.redact_json_value(context, value);
+        }
+    }
+
+    fn redact_json_value(&self, context: &TerminusContext<'static>, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Array(elements) => {
+                for element in elements.iter_mut() {
+                    self.redact_json_value(context, element);
+                }
+            }
+            serde_json::Value::Object(obj) => {
+                if obj.contains_key("@type") {
+                    let invisible_id = obj
+                        .get("@id")
+                        .and_then(|id| id.as_str())
+                        .and_then(|id| {
+                            let expanded = context
+                                .type_collection
+                                .allframes
+                                .context
+                                .expand_instance(&node_variety(id));
+                            context
+                                .instance
+                                .as_ref()
+                                .and_then(|instance| instance.subject_id(expanded.as_str()))
+                        })
+                        .filter(|id| !self.document_visible(context, *id))
+                        .map(|_| obj["@id"].clone());
+                    if let Some(id) = invisible_id {
+                        *value = id;
+                    } else {
+                        self.redact_document(context, obj);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Check that a query does not filter or order on properties that
+    /// are hidden or masked for the current user, as that would reveal
+    /// their values.
+    pub fn check_query_arguments(
+        &self,
+        allframes: &AllFrames,
+        class: &GraphQLName,
+        arguments: &juniper::Arguments,
+    ) -> Result<(), juniper::FieldError> {
+        if self.classes.is_empty() {
+            return Ok(());
+        }
+        if let Some(TerminusOrderBy { fields }) = arguments.get::<TerminusOrderBy>("orderBy") {
+            for field in fields.iter() {
+                let mut class = class.as_static();
+                for step in field.path.iter() {
+                    self.check_field_readable(allframes, &class, step)?;
+                    if let Some(next) = allframes
+                        .frames
+                        .get(&class)
+                        .filter(|t| t.is_document_type())
+                        .and_then(|t| t.as_class_definition().fields.get(step))
+                        .and_then(|f| f.document_type(allframes))
+                    {
+                        class = next.as_static();
+                    }
+                }
+            }
+        }
+        if let Some(filter) = arguments.get::<FilterInputObject>("filter") {
+            let edges: Vec<_> = filter
+                .edges
+                .iter()
+                .map(|(k, v)| (k.item.as_str(), &v.item))
+                .collect();
+            self.check_filter_edges(allframes, class, &edges)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_field_readable(
+        &self,
+        allframes: &AllFrames,
+        class: &GraphQLName,
+        field: &GraphQLName,
+    ) -> Result<(), juniper::FieldError> {
+        if self.field_access(allframes, class, field) == FieldAccess::Visible {
+            Ok(())
+        } else {
+            Err(field_not_accessible(class, field))
+        }
+    }
+
+    fn check_filter_edges(
+        &self,
+        allframes: &AllFrames,
+        class: &GraphQLName,
+        edges: &[(&str, &InputValue)],
+    ) -> Result<(), juniper::FieldError> {
+        for (field_name, value) in edges.iter() {
+            match *field_name {
+                "_and" | "_or" => {
+                    for elt in value.to_list_value().into_iter().flatten() {
+                        if let InputValue::Object(o) = elt {
+                            let sub_edges: Vec<_> =
+                                o.iter().map(|(k, v)| (k.item.as_str(), &v.item)).collect();
+                            self.check_filter_edges(allframes, class, &sub_edges)?;
+                        }
+                    }
+                }
+                "_not" => {
+                    if let InputValue::Object(o) = value {
+                        let sub_edges: Vec<_> =
+                            o.iter().map(|(k, v)| (k.item.as_str(), &v.item)).collect();
+                        self.check_filter_edges(allframes, class, &sub_edges)?;
+                    }
+                }
+                "_restriction" | "_id" | "_ids" | "_isNull" => {}
+                "_path" => {
+                    // the path walks raw predicates, which must not go
+                    // over restricted properties, and anything we filter
+                    // on at the end of it has to be readable.
+                    if let InputValue::Object(o) = value {
+                        for (target, target_filter) in o.iter() {
+                            if target.item == "path" {
+                                if let Some(Ok((_, path))) =
+                                    target_filter.item.as_string_value().map(parse_path)
+                                {
+                                    self.check_path(allframes, &path)?;
+                                }
+                                continue;
+                            }
+                            if let InputValue::Object(t) = &target_filter.item {
+                                let target_class = GraphQLName(target.item.as_str().into());
+                                let sub_edges: Vec<_> =
+                                    t.iter().map(|(k, v)| (k.item.as_str(), &v.item)).collect();
+                                self.check_filter_edges(allframes, &target_class, &sub_edges)?;
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    let field = GraphQLName((*field_name).into());
+                    if let Some(inverted) = reverse_filter_to_inverted_field(&field) {
+                        let link = match allframes.reverse_link(class, &inverted) {
+                            Some(link) => link,
+                            None => continue,
+                        };
+                        self.check_field_readable(allframes, &link.class, &link.property)?;
+                        if let InputValue::Object(o) = value {
+                            for (_, sub_filter) in o.iter() {
+                                if let InputValue::Object(f) = &sub_filter.item {
+                                    let sub_edges: Vec<_> =
+                                        f.iter().map(|(k, v)| (k.item.as_str(), &v.item)).collect();
+                                    self.check_filter_edges(allframes, &link.class, &sub_edges)?;
+                                }
+                            }
+                        }
+                    } else {
+                        self.check_field_readable(allframes, class, &field)?;
+                        self.check_nested_filter(allframes, class, &field, value)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that a path does not walk over a property that is hidden
+    /// or masked in any class, as the documents it reaches would
+    /// reveal the links. A wildcard may walk over any property, so it
+    /// can only be used if no property is restricted at all.
+    fn check_path(&self, allframes: &AllFrames, path: &Path) -> Result<(), juniper::FieldError> {
+        let restricted: HashSet<IriName> = self
+            .classes
+            .values()
+            .flat_map(|policy| policy.hidden.iter().chain(policy.masked.iter()))
+            .map(|property| allframes.context.expand_schema(&property.into()))
+            .collect();
+        if restricted.is_empty() {
+            return Ok(());
+        }
+        let mut predicates = Vec::new();
+        path_predicates(path, &mut predicates);
+        for predicate in predicates {
+            match predicate {
+                Pred::Any => {
+                    return Err(juniper::FieldError::new(
+                        "Paths can not use wildcards when properties are not accessible",
+                        Value::Null,
+                    ))
+                }
+                Pred::Named(name) => {
+                    if restricted.contains(&allframes.context.expand_schema(&node_variety(name))) {
+                        return Err(juniper::FieldError::new(
+                            format!("The property '{name}' is not accessible in a path"),
+                            Value::Null,
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_nested_filter(
+        &self,
+        allframes: &AllFrames,
+        class: &GraphQLName,
+        field: &GraphQLName,
+        value: &InputValue,
+    ) -> Result<(), juniper::FieldError> {
+        let field_definition = match allframes.frames.get(class) {
+            Some(t) if t.is_document_type() => t.as_class_definition().resolve_field(field),
+            _ => return Ok(()),
+        };
+        let range = match field_definition.range() {
+            BaseOrDerived::Derived(range) if !allframes.is_foreign(range) => range.as_static(),
+            _ => return Ok(()),
+        };
+        if allframes.document_type(&range).is_none() {
+            return Ok(());
+        }
+        let sub_filters: Vec<&InputValue> = if field_definition.kind().is_collection() {
+            match value {
+                InputValue::Object(o) => o.iter().map(|(_, v)| &v.item).collect(),
+                _ => vec![],
+            }
+        } else {
+            vec![value]
+        };
+        for sub_filter in sub_filters {
+            if let InputValue::Object(o) = sub_filter {
+                let sub_edges: Vec<_> = o.iter().map(|(k, v)| (k.item.as_str(), &v.item)).collect();
+                self.check_filter_edges(allframes, &range, &sub_edges)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect the policies of all roles of all capabilities of the given
+/// user, together with the (short) name of the class they apply to.
+fn policies_for_user(system: &SyncStoreLayer, user: &str) -> Vec<(ShortName, ClassPolicy)> {
+    let mut result = Vec::new();
+    let lookup = || {
+        Some((
+            system.subject_id(user)?,
+            system.predicate_id(SYSTEM_CAPABILITY)?,
+            system.predicate_id(SYSTEM_ROLE)?,
+            system.predicate_id(SYSTEM_POLICY)?,
+            system.predicate_id(SYSTEM_POLICY_CLASS)?,
+        ))
+    };
+    let (user_id, capability_id, role_id, policy_id, class_id) = match lookup() {
+        Some(ids) => ids,
+        // either no such user or no policies in the system graph at all
+        None => return result,
+    };
+    let hide_id = system.predicate_id(SYSTEM_POLICY_HIDE);
+    let mask_id = system.predicate_id(SYSTEM_POLICY_MASK);
+    let restriction_id = system.predicate_id(SYSTEM_POLICY_RESTRICTION);
+
+    let strings = |subject: u64, predicate: Option<u64>| -> Vec<ShortName> {
+        predicate
+            .into_iter()
+            .flat_map(|p| system.triples_sp(subject, p))
+            .filter_map(|t| system.id_object_value(t.object))
+            .map(|v| ShortName(v.as_val::<String, String>()))
+            .collect()
+    };
+
+    let mut seen_roles = HashSet::new();
+    for capability in system.triples_sp(user_id, capability_id) {
+        for role in system.triples_sp(capability.object, role_id) {
+            if !seen_roles.insert(role.object) {
+                continue;
+            }
+            for policy in system.triples_sp(role.object, policy_id) {
+                let class = strings(policy.object, Some(class_id)).pop();
+                if let Some(class) = class {
+                    let class_policy = ClassPolicy {
+                        hidden: strings(policy.object, hide_id).into_iter().collect(),
+                        masked: strings(policy.object, mask_id).into_iter().collect(),
+                        restrictions: strings(policy.object, restriction_id),
+                    };
+                    result.push((class, class_policy));
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn path_predicates<'a>(path: &'a Path, predicates: &mut Vec<&'a Pred>) {
+    match path {
+        Path::Positive(p) | Path::Negative(p) => predicates.push(p),
+        Path::Plus(p) | Path::Star(p) | Path::Times(p, _, _) => path_predicates(p, predicates),
+        Path::Seq(paths) | Path::Choice(paths) | Path::Branch(paths) | Path::Collide(paths) => {
+            for p in paths {
+                path_predicates(p, predicates);
+            }
+        }
+    }
+}
+
+fn mask_json_value(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(elements) => {
+            for element in elements.iter_mut() {
+                mask_json_value(element);
+            }
+        }
+        serde_json::Value::Null => {}
+        _ => *value = serde_json::Value::String(MASKED_VALUE.to_string()),
+    }
+}
+
+pub fn field_not_accessible(class: &GraphQLName, field: &GraphQLName) -> juniper::FieldError {
+    juniper::FieldError::new(
+        format!("The field '{field}' of '{class}' is not accessible"),
+        Value::Null,
+    )
+}
+
+/// The value a hidden or masked field resolves to. Masked string
+/// fields keep their shape and show a mask, everything else that is
+/// not visible resolves to nothing, just like a required field
+/// without a value would.
+pub fn restricted_field_value(
+    access: FieldAccess,
+    kind: FieldKind,
+    base_type: Option<&str>,
+    object_count: usize,
+) -> Value {
+    let maskable = access == FieldAccess::Masked
+        && base_type
+            .map(|t| {
+                matches!(
+                    base_type_kind(t),
+                    BaseTypeKind::String | BaseTypeKind::Temporal(_)
+                ) && !type_is_json(t)
+            })
+            .unwrap_or(false);
+    match kind {
+        FieldKind::Required | FieldKind::Optional if maskable && object_count != 0 => {
+            Value::scalar(MASKED_VALUE.to_string())
+        }
+        FieldKind::Required | FieldKind::Optional => Value::Null,
+        _ if maskable => Value::List(
+            (0..object_count)
+                .map(|_| Value::scalar(MASKED_VALUE.to_string()))
+                .collect(),
+        ),
+        _ => Value::List(vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::Spanning;
+    use serde_json::json;
+    use swipl::prelude::*;
+
+    use crate::graphql::frame::UncleanAllFrames;
+
+    fn person_frames() -> AllFrames {
+        let engine = Engine::new();
+        let activation = engine.activate();
+        let context: Context<_> = activation.into();
+
+        let term = r#"json{
+                   '@context': json{'@base':"terminusdb:///data/",'@schema':"terminusdb:///schema#",'@type':'Context'},
+                   'Person' : json{ '@type' : "Class",
+                                    name : "xsd:string",
+                                    salary : "xsd:decimal",
+                                    boss : json{ '@type' : "Optional", '@class' : "Person"},
+                                    mentor : json{ '@type' : "Optional", '@class' : "Person"}}}"#;
+        let term = unwrap_result(&context, context.term_from_string(term));
+        let pre_allframes: UncleanAllFrames = context.deserialize_from_term(&term).unwrap();
+        pre_allframes.finalize()
+    }
+
+    fn person_policies() -> AccessPolicies {
+        let policy = ClassPolicy {
+            hidden: [ShortName("salary".to_string())].into_iter().collect(),
+            masked: [ShortName("mentor".to_string())].into_iter().collect(),
+            restrictions: Vec::new(),
+        };
+        AccessPolicies {
+            classes: [(GraphQLName("Person".into()), policy)]
+                .into_iter()
+                .collect(),
+            restriction_cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn object(fields: Vec<(&str, InputValue)>) -> InputValue {
+        InputValue::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (Spanning::unlocated(k.to_string()), Spanning::unlocated(v)))
+                .collect(),
+        )
+    }
+
+    fn check(
+        allframes: &AllFrames,
+        policies: &AccessPolicies,
+        field: &str,
+        value: InputValue,
+    ) -> bool {
+        policies
+            .check_filter_edges(allframes, &GraphQLName("Person".into()), &[(field, &value)])
+            .is_ok()
+    }
+
+    #[test]
+    fn hidden_fields_can_not_be_filtered_on() {
+        let allframes = person_frames();
+        let policies = person_policies();
+        let eq = |v: InputValue| object(vec![("eq", v)]);
+
+        assert!(check(
+            &allframes,
+            &policies,
+            "name",
+            eq(InputValue::scalar("a"))
+        ));
+        assert!(!check(
+            &allframes,
+            &policies,
+            "salary",
+            eq(InputValue::scalar(10))
+        ));
+        assert!(!check(
+            &allframes,
+            &policies,
+            "boss",
+            object(vec![("salary", eq(InputValue::scalar(10)))])
+        ));
+    }
+
+    #[test]
+    fn paths_can_not_walk_restricted_properties() {
+        let allframes = person_frames();
+        let policies = person_policies();
+        let path = |p: &str| object(vec![("path", InputValue::scalar(p))]);
+
+        assert!(check(&allframes, &policies, "_path", path("boss+")));
+        assert!(!check(&allframes, &policies, "_path", path("boss,salary")));
+        assert!(!check(&allframes, &policies, "_path", path("(<mentor)+")));
+        assert!(!check(&allframes, &policies, "_path", path("boss,.")));
+        assert!(!check(
+            &allframes,
+            &policies,
+            "_path",
+            object(vec![
+                ("path", InputValue::scalar("boss")),
+                (
+                    "Person",
+                    object(vec![(
+                        "salary",
+                        object(vec![("eq", InputValue::scalar(10))])
+                    )])
+                ),
+            ])
+        ));
+    }
+
+    #[test]
+    fn reverse_filters_can_not_use_restricted_properties() {
+        let allframes = person_frames();
+        let policies = person_policies();
+        let some_have = |f: InputValue| object(vec![("someHave", f)]);
+
+        assert!(check(
+            &allframes,
+            &policies,
+            "_reverse_boss_of_Person",
+            some_have(object(vec![(
+                "name",
+                object(vec![("eq", InputValue::scalar("a"))])
+            )]))
+        ));
+        assert!(!check(
+            &allframes,
+            &policies,
+            "_reverse_boss_of_Person",
+            some_have(object(vec![(
+                "salary",
+                object(vec![("eq", InputValue::scalar(10))])
+            )]))
+        ));
+        assert!(!check(
+            &allframes,
+            &policies,
+            "_reverse_mentor_of_Person",
+            some_have(object(vec![]))
+        ));
+    }
+
+    #[test]
+    fn restricted_required_fields_resolve_to_null() {
+        assert_eq!(
+            Value::Null,
+            restricted_field_value(
+                FieldAccess::Hidden,
+                FieldKind::Required,
+                Some("xsd:string"),
+                1
+            )
+        );
+        assert_eq!(
+            Value::Null,
+            restricted_field_value(
+                FieldAccess::Masked,
+                FieldKind::Required,
+                Some("xsd:decimal"),
+                1
+            )
+        );
+        assert_eq!(
+            Value::scalar(MASKED_VALUE.to_string()),
+            restricted_field_value(
+                FieldAccess::Masked,
+                FieldKind::Required,
+                Some("xsd:string"),
+                1
+            )
+        );
+        assert_eq!(
+            Value::List(vec![]),
+            restricted_field_value(FieldAccess::Hidden, FieldKind::Set, Some("xsd:string"), 2)
+        );
+    }
+
+    #[test]
+    fn mask_nested_values() {
+        let mut value = json!(["a", 3, null, ["b"]]);
+        mask_json_value(&mut value);
+        assert_eq!(
+            json!([MASKED_VALUE, MASKED_VALUE, null, [MASKED_VALUE]]),
+            value
+        );
+    }
+
+    #[test]
+    fn policies_accumulate() {
+        let mut policy = ClassPolicy::default();
+        policy.masked.insert(ShortName("name".to_string()));
+        let other = ClassPolicy {
+            hidden: [ShortName("salary".to_string())].into_iter().collect(),
+            masked: HashSet::new(),
+            restrictions: vec![ShortName("Public".to_string())],
+        };
+        policy.merge(&other);
+        policy.merge(&other);
+
+        assert_eq!(
+            FieldAccess::Hidden,
+            policy.property_access(&ShortName("salary".to_string()))
+        );
+        assert_eq!(
+            FieldAccess::Masked,
+            policy.property_access(&ShortName("name".to_string()))
+        );
+        assert_eq!(
+            FieldAccess::Visible,
+            policy.property_access(&ShortName("age".to_string()))
+        );
+        assert_eq!(vec![ShortName("Public".to_string())], policy.restrictions);
+    }
+}
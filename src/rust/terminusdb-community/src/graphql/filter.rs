@@ -5,13 +5,20 @@ use juniper::{
     Registry, ID,
 };
 
-use crate::value::{base_type_kind, BaseTypeKind};
+use crate::value::{base_type_kind, BaseTypeKind, TemporalKind};
 
 use super::{
-    frame::{AllFrames, BaseOrDerived, GraphQLName, TypeDefinition},
-    naming::{collection_filter_name, enum_filter_name, filter_name, restriction_name},
+    frame::{AllFrames, BaseOrDerived, FieldKind, GraphQLName, TypeDefinition},
+    naming::{
+        collection_filter_name, enum_filter_name, filter_name, optional_enum_filter_name,
+        optional_filter_name, restriction_name, reverse_filter_name,
+    },
     query::EnumOperation,
     schema::{BigFloat, BigInt, DateTime, GeneratedEnum, GeneratedEnumTypeInfo, TerminusEnum},
+    temporal::{
+        XsdDate, XsdDayTimeDuration, XsdDuration, XsdGDay, XsdGMonth, XsdGMonthDay, XsdGYear,
+        XsdGYearMonth, XsdTime, XsdYearMonthDuration,
+    },
 };
 
 pub struct FilterInputObject {
@@ -22,6 +29,7 @@ pub struct FilterInputObjectTypeInfo {
     filter_type_name: GraphQLName<'static>,
     type_name: GraphQLName<'static>,
     frames: Arc<AllFrames>,
+    optional: bool,
 }
 
 impl FilterInputObjectTypeInfo {
@@ -30,6 +38,18 @@ impl FilterInputObjectTypeInfo {
             filter_type_name: filter_name(&type_name),
             type_name: type_name.as_static(),
             frames: all_frames.clone(),
+            optional: false,
+        }
+    }
+
+    /// The filter for an optional field linking to this type, which
+    /// can also check whether the field is absent.
+    pub fn new_optional(type_name: &GraphQLName, all_frames: &Arc<AllFrames>) -> Self {
+        Self {
+            filter_type_name: optional_filter_name(&type_name),
+            type_name: type_name.as_static(),
+            frames: all_frames.clone(),
+            optional: true,
         }
     }
 }
@@ -56,8 +76,8 @@ impl GraphQLType for FilterInputObject {
                         if kind.is_collection() {
                             match field_definition.range() {
                                 BaseOrDerived::Base(base_type) => {
-                                    let kind = base_type_kind(base_type);
-                                    match kind {
+                                    let base_kind = base_type_kind(base_type);
+                                    match base_kind {
                                         BaseTypeKind::String => registry.arg::<Option<
                                             CollectionStringFilterInputObject,
                                         >>(
@@ -93,6 +113,12 @@ impl GraphQLType for FilterInputObject {
                                         >>(
                                             name.as_str(), &()
                                         ),
+                                        BaseTypeKind::Temporal(temporal) => temporal_filter_arg(
+                                            registry,
+                                            name.as_str(),
+                                            temporal,
+                                            kind,
+                                        ),
                                     }
                                 }
                                 BaseOrDerived::Derived(c) => {
@@ -114,29 +140,51 @@ impl GraphQLType for FilterInputObject {
                                 }
                             }
                         } else if let Some(base_type) = field_definition.base_type() {
-                            let kind = base_type_kind(base_type);
-                            match kind {
-                                BaseTypeKind::String => registry
-                                    .arg::<Option<StringFilterInputObject>>(name.as_str(), &()),
+                            let optional = kind == FieldKind::Optional;
+                            macro_rules! arg {
+                                ($filter:ty, $optional:ty) => {
+                                    if optional {
+                                        registry.arg::<Option<$optional>>(name.as_str(), &())
+                                    } else {
+                                        registry.arg::<Option<$filter>>(name.as_str(), &())
+                                    }
+                                };
+                            }
+                            match base_type_kind(base_type) {
+                                BaseTypeKind::String => {
+                                    arg!(StringFilterInputObject, OptionalStringFilterInputObject)
+                                }
                                 BaseTypeKind::SmallInteger => {
-                                    registry.arg::<Option<IntFilterInputObject>>(name.as_str(), &())
+                                    arg!(IntFilterInputObject, OptionalIntFilterInputObject)
+                                }
+                                BaseTypeKind::BigIntger => {
+                                    arg!(BigIntFilterInputObject, OptionalBigIntFilterInputObject)
+                                }
+                                BaseTypeKind::Boolean => {
+                                    arg!(BooleanFilterInputObject, OptionalBooleanFilterInputObject)
+                                }
+                                BaseTypeKind::Float => {
+                                    arg!(FloatFilterInputObject, OptionalFloatFilterInputObject)
+                                }
+                                BaseTypeKind::Decimal => arg!(
+                                    BigFloatFilterInputObject,
+                                    OptionalBigFloatFilterInputObject
+                                ),
+                                BaseTypeKind::DateTime => arg!(
+                                    DateTimeFilterInputObject,
+                                    OptionalDateTimeFilterInputObject
+                                ),
+                                BaseTypeKind::Temporal(temporal) => {
+                                    temporal_filter_arg(registry, name.as_str(), temporal, kind)
                                 }
-                                BaseTypeKind::BigIntger => registry
-                                    .arg::<Option<BigIntFilterInputObject>>(name.as_str(), &()),
-                                BaseTypeKind::Boolean => registry
-                                    .arg::<Option<BooleanFilterInputObject>>(name.as_str(), &()),
-                                BaseTypeKind::Float => registry
-                                    .arg::<Option<FloatFilterInputObject>>(name.as_str(), &()),
-                                BaseTypeKind::Decimal => registry
-                                    .arg::<Option<BigFloatFilterInputObject>>(name.as_str(), &()),
-                                BaseTypeKind::DateTime => registry
-                                    .arg::<Option<DateTimeFilterInputObject>>(name.as_str(), &()),
                             }
                         } else if let Some(enum_type) = field_definition.enum_type(&info.frames) {
-                            registry.arg::<Option<EnumFilterInputObject>>(
-                                name.as_str(),
-                                &EnumFilterInputObjectTypeInfo::new(enum_type, &info.frames),
-                            )
+                            let type_info = if kind == FieldKind::Optional {
+                                EnumFilterInputObjectTypeInfo::new_optional(enum_type, &info.frames)
+                            } else {
+                                EnumFilterInputObjectTypeInfo::new(enum_type, &info.frames)
+                            };
+                            registry.arg::<Option<EnumFilterInputObject>>(name.as_str(), &type_info)
                         } else {
                             match field_definition.range() {
                                 BaseOrDerived::Base(_) => {
@@ -144,12 +192,26 @@ impl GraphQLType for FilterInputObject {
                                 }
                                 BaseOrDerived::Derived(c) => {
                                     if info.frames.is_foreign(c) {
-                                        registry
-                                            .arg::<Option<IdFilterInputObject>>(name.as_str(), &())
+                                        if kind == FieldKind::Optional {
+                                            registry.arg::<Option<OptionalIdFilterInputObject>>(
+                                                name.as_str(),
+                                                &(),
+                                            )
+                                        } else {
+                                            registry.arg::<Option<IdFilterInputObject>>(
+                                                name.as_str(),
+                                                &(),
+                                            )
+                                        }
                                     } else {
+                                        let type_info = if kind == FieldKind::Optional {
+                                            FilterInputObjectTypeInfo::new_optional(c, &info.frames)
+                                        } else {
+                                            FilterInputObjectTypeInfo::new(c, &info.frames)
+                                        };
                                         registry.arg::<Option<FilterInputObject>>(
                                             name.as_str(),
-                                            &FilterInputObjectTypeInfo::new(c, &info.frames),
+                                            &type_info,
                                         )
                                     }
                                 }
@@ -194,6 +256,39 @@ impl GraphQLType for FilterInputObject {
                 &FilterInputObjectTypeInfo::new(&info.type_name, &info.frames),
             ));
 
+            if let Some(inverted) = info.frames.inverted.classes.get(&info.type_name) {
+                for (field_name, inverted_field) in inverted.domain.iter() {
+                    if !info.frames.frames[&inverted_field.class].is_document_type() {
+                        continue;
+                    }
+                    args.push(
+                        registry
+                            .arg::<Option<CollectionFilterInputObject>>(
+                                reverse_filter_name(field_name).as_str(),
+                                &CollectionFilterInputObjectTypeInfo::new(
+                                    &inverted_field.class,
+                                    &info.frames,
+                                ),
+                            )
+                            .description(&format!(
+                                "filter on the {} documents linking here through {}",
+                                inverted_field.class, inverted_field.property
+                            )),
+                    );
+                }
+            }
+
+            args.push(registry.arg::<Option<PathFilterInputObject>>(
+                "_path",
+                &PathFilterInputObjectTypeInfo::new(&info.frames),
+            ));
+
+            if info.optional {
+                args.push(registry.arg::<Option<bool>>("_isNull", &()).description(
+                    "keep documents where the linking field is absent (true) or present (false)",
+                ));
+            }
+
             registry
                 .build_input_object_type::<FilterInputObject>(&info, &args)
                 .into_meta()
@@ -254,7 +349,7 @@ impl GraphQLType for CollectionFilterInputObject {
     where
         DefaultScalarValue: 'r,
     {
-        let mut args: Vec<_> = Vec::with_capacity(2);
+        let mut args: Vec<_> = Vec::with_capacity(4);
         let type_definition = &info.frames.frames[&info.type_name];
         match type_definition {
             TypeDefinition::Class(_) => {
@@ -266,6 +361,11 @@ impl GraphQLType for CollectionFilterInputObject {
                     "allHave",
                     &FilterInputObjectTypeInfo::new(&info.type_name, &info.frames),
                 ));
+                args.push(registry.arg::<Option<FilterInputObject>>(
+                    "noneHave",
+                    &FilterInputObjectTypeInfo::new(&info.type_name, &info.frames),
+                ));
+                args.push(registry.arg::<Option<bool>>("_isNull", &()));
                 registry
                     .build_input_object_type::<CollectionFilterInputObject>(info, &args)
                     .into_meta()
@@ -279,6 +379,11 @@ impl GraphQLType for CollectionFilterInputObject {
                     "allHave",
                     &EnumFilterInputObjectTypeInfo::new(&info.type_name, &info.frames),
                 ));
+                args.push(registry.arg::<Option<EnumFilterInputObject>>(
+                    "noneHave",
+                    &EnumFilterInputObjectTypeInfo::new(&info.type_name, &info.frames),
+                ));
+                args.push(registry.arg::<Option<bool>>("_isNull", &()));
                 registry
                     .build_input_object_type::<CollectionFilterInputObject>(info, &args)
                     .into_meta()
@@ -306,15 +411,103 @@ impl GraphQLValue for CollectionFilterInputObject {
     }
 }
 
+pub struct PathFilterInputObject {
+    pub path: String,
+    pub targets: Vec<(juniper::Spanning<String>, juniper::Spanning<InputValue>)>,
+}
+
+pub struct PathFilterInputObjectTypeInfo {
+    frames: Arc<AllFrames>,
+}
+
+impl PathFilterInputObjectTypeInfo {
+    pub fn new(all_frames: &Arc<AllFrames>) -> Self {
+        Self {
+            frames: all_frames.clone(),
+        }
+    }
+}
+
+impl GraphQLType for PathFilterInputObject {
+    fn name(_info: &Self::TypeInfo) -> Option<&str> {
+        Some("PathFilter")
+    }
+
+    fn meta<'r>(
+        info: &Self::TypeInfo,
+        registry: &mut Registry<'r, DefaultScalarValue>,
+    ) -> juniper::meta::MetaType<'r, DefaultScalarValue>
+    where
+        DefaultScalarValue: 'r,
+    {
+        let mut args = vec![registry
+            .arg::<String>("path", &())
+            .description("the path to follow from the document")];
+        for (class, typedef) in info.frames.frames.iter() {
+            if !typedef.is_document_type() {
+                continue;
+            }
+            args.push(
+                registry
+                    .arg::<Option<FilterInputObject>>(
+                        class.as_str(),
+                        &FilterInputObjectTypeInfo::new(class, &info.frames),
+                    )
+                    .description(&format!("a {class} reached through the path matches this")),
+            );
+        }
+        registry
+            .build_input_object_type::<PathFilterInputObject>(info, &args)
+            .description(
+                "Keep documents from which the path reaches a node of one of the given classes matching its filter. Without any class, reaching any node is enough.",
+            )
+            .into_meta()
+    }
+}
+
+impl FromInputValue for PathFilterInputObject {
+    fn from_input_value(v: &InputValue<DefaultScalarValue>) -> Option<Self> {
+        match v {
+            InputValue::Object(o) => {
+                let mut path = None;
+                let mut targets = Vec::new();
+                for (key, value) in o.iter() {
+                    if key.item == "path" {
+                        path = value.item.as_string_value().map(|p| p.to_string());
+                    } else if !value.item.is_null() {
+                        targets.push((key.clone(), value.clone()));
+                    }
+                }
+                Some(Self {
+                    path: path?,
+                    targets,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl GraphQLValue for PathFilterInputObject {
+    type Context = ();
+
+    type TypeInfo = PathFilterInputObjectTypeInfo;
+
+    fn type_name<'i>(&self, _info: &'i Self::TypeInfo) -> Option<&'i str> {
+        Some("PathFilter")
+    }
+}
+
 pub struct EnumFilterInputObject {
     pub op: EnumOperation,
-    pub enum_value: TerminusEnum,
+    pub enum_values: Vec<TerminusEnum>,
 }
 
 pub struct EnumFilterInputObjectTypeInfo {
     filter_type_name: GraphQLName<'static>,
     type_name: GraphQLName<'static>,
     frames: Arc<AllFrames>,
+    optional: bool,
 }
 
 impl EnumFilterInputObjectTypeInfo {
@@ -323,6 +516,16 @@ impl EnumFilterInputObjectTypeInfo {
             filter_type_name: enum_filter_name(type_name),
             type_name: type_name.as_static(),
             frames: all_frames.clone(),
+            optional: false,
+        }
+    }
+
+    pub fn new_optional(type_name: &GraphQLName, all_frames: &Arc<AllFrames>) -> Self {
+        Self {
+            filter_type_name: optional_enum_filter_name(type_name),
+            type_name: type_name.as_static(),
+            frames: all_frames.clone(),
+            optional: true,
         }
     }
 }
@@ -339,10 +542,15 @@ impl GraphQLType for EnumFilterInputObject {
     where
         DefaultScalarValue: 'r,
     {
-        let mut args: Vec<_> = Vec::with_capacity(2);
+        let mut args: Vec<_> = Vec::with_capacity(5);
         let type_info = (info.type_name.clone(), info.frames.clone());
         args.push(registry.arg::<Option<TerminusEnum>>("eq", &type_info));
         args.push(registry.arg::<Option<TerminusEnum>>("ne", &type_info));
+        args.push(registry.arg::<Option<Vec<TerminusEnum>>>("in", &type_info));
+        args.push(registry.arg::<Option<Vec<TerminusEnum>>>("notIn", &type_info));
+        if info.optional {
+            args.push(registry.arg::<Option<bool>>("_isNull", &()));
+        }
         registry
             .build_input_object_type::<EnumFilterInputObject>(info, &args)
             .into_meta()
@@ -353,15 +561,32 @@ impl FromInputValue for EnumFilterInputObject {
     fn from_input_value(v: &InputValue<DefaultScalarValue>) -> Option<Self> {
         match v {
             InputValue::Object(o) => {
-                if let Some((key, val)) = o.first() {
-                    let op = match key.item.as_ref() {
-                        "eq" => EnumOperation::Eq,
-                        "ne" => EnumOperation::Ne,
-                        unknown => panic!("unknown enum operation {unknown}"),
+                // `_isNull` is checked on the field itself, not here
+                let operation = o
+                    .iter()
+                    .find(|(key, val)| key.item != "_isNull" && !val.item.is_null());
+                if let Some((key, val)) = operation {
+                    let (op, enum_values) = match key.item.as_ref() {
+                        "eq" => (
+                            EnumOperation::Eq,
+                            vec![TerminusEnum::from_input_value(&val.item)?],
+                        ),
+                        "ne" => (
+                            EnumOperation::Ne,
+                            vec![TerminusEnum::from_input_value(&val.item)?],
+                        ),
+                        "in" => (
+                            EnumOperation::Eq,
+                            Vec::<TerminusEnum>::from_input_value(&val.item)?,
+                        ),
+                        "notIn" => (
+                            EnumOperation::Ne,
+                            Vec::<TerminusEnum>::from_input_value(&val.item)?,
+                        ),
+                        _ => return None,
                     };
 
-                    let enum_value = TerminusEnum::from_input_value(&val.item);
-                    enum_value.map(|e| Self { op, enum_value: e })
+                    Some(Self { op, enum_values })
                 } else {
                     None
                 }
@@ -381,28 +606,89 @@ impl GraphQLValue for EnumFilterInputObject {
     }
 }
 
+/// Declare a filter on values, along with the filter used for optional
+/// fields, which can also check whether the field is absent.
+macro_rules! value_filter {
+    ($filter:ident, $filter_name:literal, $optional:ident, $optional_name:literal, { $($fields:tt)* }) => {
+        #[derive(GraphQLInputObject)]
+        #[graphql(name = $filter_name)]
+        #[allow(non_snake_case)]
+        pub struct $filter {
+            $($fields)*
+        }
+
+        #[derive(GraphQLInputObject)]
+        #[graphql(name = $optional_name)]
+        #[allow(non_snake_case)]
+        pub struct $optional {
+            $($fields)*
+            #[graphql(name = "_isNull")]
+            pub is_null: Option<bool>,
+        }
+    };
+}
+
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionStringFilter")]
 #[allow(non_snake_case)]
 pub struct CollectionStringFilterInputObject {
     pub someHave: Option<StringFilterInputObject>,
     pub allHave: Option<StringFilterInputObject>,
+    pub noneHave: Option<StringFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
+value_filter!(
+    StringFilterInputObject,
+    "StringFilter",
+    OptionalStringFilterInputObject,
+    "OptionalStringFilter",
+    {
+        pub eq: Option<String>,
+        pub ne: Option<String>,
+        pub lt: Option<String>,
+        pub le: Option<String>,
+        pub gt: Option<String>,
+        pub ge: Option<String>,
+        pub regex: Option<String>,
+        pub startsWith: Option<String>,
+        pub allOfTerms: Option<Vec<String>>,
+        pub anyOfTerms: Option<Vec<String>>,
+        pub fuzzy: Option<FuzzyInputObject>,
+        pub similarTo: Option<SimilarToInputObject>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<String>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<String>>,
+    }
+);
+
 #[derive(GraphQLInputObject)]
 #[allow(non_snake_case)]
-#[graphql(name = "StringFilter")]
-pub struct StringFilterInputObject {
-    pub eq: Option<String>,
-    pub ne: Option<String>,
-    pub lt: Option<String>,
-    pub le: Option<String>,
-    pub gt: Option<String>,
-    pub ge: Option<String>,
-    pub regex: Option<String>,
-    pub startsWith: Option<String>,
-    pub allOfTerms: Option<Vec<String>>,
-    pub anyOfTerms: Option<Vec<String>>,
+#[graphql(
+    name = "FuzzyMatch",
+    description = "Match strings within an edit distance of the given value"
+)]
+pub struct FuzzyInputObject {
+    pub value: String,
+    #[graphql(description = "the maximum number of edits, defaults to 2")]
+    pub maxDistance: Option<i32>,
+    #[graphql(
+        description = "whether swapping adjacent characters is a single edit, defaults to true"
+    )]
+    pub transpositions: Option<bool>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(
+    name = "SimilarTo",
+    description = "Match strings sharing enough trigrams with the given value"
+)]
+pub struct SimilarToInputObject {
+    pub value: String,
+    #[graphql(description = "the minimum similarity between 0 and 1, defaults to 0.3")]
+    pub threshold: Option<f64>,
 }
 
 #[derive(GraphQLInputObject)]
@@ -411,18 +697,29 @@ pub struct StringFilterInputObject {
 pub struct CollectionBigIntFilterInputObject {
     pub someHave: Option<BigIntFilterInputObject>,
     pub allHave: Option<BigIntFilterInputObject>,
+    pub noneHave: Option<BigIntFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "BigIntFilter")]
-pub struct BigIntFilterInputObject {
-    pub eq: Option<BigInt>,
-    pub ne: Option<BigInt>,
-    pub lt: Option<BigInt>,
-    pub le: Option<BigInt>,
-    pub gt: Option<BigInt>,
-    pub ge: Option<BigInt>,
-}
+value_filter!(
+    BigIntFilterInputObject,
+    "BigIntFilter",
+    OptionalBigIntFilterInputObject,
+    "OptionalBigIntFilter",
+    {
+        pub eq: Option<BigInt>,
+        pub ne: Option<BigInt>,
+        pub lt: Option<BigInt>,
+        pub le: Option<BigInt>,
+        pub gt: Option<BigInt>,
+        pub ge: Option<BigInt>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<BigInt>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<BigInt>>,
+    }
+);
 
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionIntFilter")]
@@ -430,18 +727,29 @@ pub struct BigIntFilterInputObject {
 pub struct CollectionIntFilterInputObject {
     pub someHave: Option<IntFilterInputObject>,
     pub allHave: Option<IntFilterInputObject>,
+    pub noneHave: Option<IntFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "IntFilter")]
-pub struct IntFilterInputObject {
-    pub eq: Option<i32>,
-    pub ne: Option<i32>,
-    pub lt: Option<i32>,
-    pub le: Option<i32>,
-    pub gt: Option<i32>,
-    pub ge: Option<i32>,
-}
+value_filter!(
+    IntFilterInputObject,
+    "IntFilter",
+    OptionalIntFilterInputObject,
+    "OptionalIntFilter",
+    {
+        pub eq: Option<i32>,
+        pub ne: Option<i32>,
+        pub lt: Option<i32>,
+        pub le: Option<i32>,
+        pub gt: Option<i32>,
+        pub ge: Option<i32>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<i32>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<i32>>,
+    }
+);
 
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionBigFloatFilter")]
@@ -449,18 +757,29 @@ pub struct IntFilterInputObject {
 pub struct CollectionBigFloatFilterInputObject {
     pub someHave: Option<BigFloatFilterInputObject>,
     pub allHave: Option<BigFloatFilterInputObject>,
+    pub noneHave: Option<BigFloatFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "BigFloatFilter")]
-pub struct BigFloatFilterInputObject {
-    pub eq: Option<BigFloat>,
-    pub ne: Option<BigFloat>,
-    pub lt: Option<BigFloat>,
-    pub le: Option<BigFloat>,
-    pub gt: Option<BigFloat>,
-    pub ge: Option<BigFloat>,
-}
+value_filter!(
+    BigFloatFilterInputObject,
+    "BigFloatFilter",
+    OptionalBigFloatFilterInputObject,
+    "OptionalBigFloatFilter",
+    {
+        pub eq: Option<BigFloat>,
+        pub ne: Option<BigFloat>,
+        pub lt: Option<BigFloat>,
+        pub le: Option<BigFloat>,
+        pub gt: Option<BigFloat>,
+        pub ge: Option<BigFloat>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<BigFloat>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<BigFloat>>,
+    }
+);
 
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionFloatFilter")]
@@ -468,18 +787,29 @@ pub struct BigFloatFilterInputObject {
 pub struct CollectionFloatFilterInputObject {
     pub someHave: Option<FloatFilterInputObject>,
     pub allHave: Option<FloatFilterInputObject>,
+    pub noneHave: Option<FloatFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "FloatFilter")]
-pub struct FloatFilterInputObject {
-    pub eq: Option<f64>,
-    pub ne: Option<f64>,
-    pub lt: Option<f64>,
-    pub le: Option<f64>,
-    pub gt: Option<f64>,
-    pub ge: Option<f64>,
-}
+value_filter!(
+    FloatFilterInputObject,
+    "FloatFilter",
+    OptionalFloatFilterInputObject,
+    "OptionalFloatFilter",
+    {
+        pub eq: Option<f64>,
+        pub ne: Option<f64>,
+        pub lt: Option<f64>,
+        pub le: Option<f64>,
+        pub gt: Option<f64>,
+        pub ge: Option<f64>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<f64>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<f64>>,
+    }
+);
 
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionBooleanFilter")]
@@ -487,14 +817,25 @@ pub struct FloatFilterInputObject {
 pub struct CollectionBooleanFilterInputObject {
     pub someHave: Option<BooleanFilterInputObject>,
     pub allHave: Option<BooleanFilterInputObject>,
+    pub noneHave: Option<BooleanFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "BooleanFilter")]
-pub struct BooleanFilterInputObject {
-    pub eq: Option<bool>,
-    pub ne: Option<bool>,
-}
+value_filter!(
+    BooleanFilterInputObject,
+    "BooleanFilter",
+    OptionalBooleanFilterInputObject,
+    "OptionalBooleanFilter",
+    {
+        pub eq: Option<bool>,
+        pub ne: Option<bool>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<bool>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<bool>>,
+    }
+);
 
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionDateTimeFilter")]
@@ -502,32 +843,273 @@ pub struct BooleanFilterInputObject {
 pub struct CollectionDateTimeFilterInputObject {
     pub someHave: Option<DateTimeFilterInputObject>,
     pub allHave: Option<DateTimeFilterInputObject>,
+    pub noneHave: Option<DateTimeFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
+value_filter!(
+    DateTimeFilterInputObject,
+    "DateTimeFilter",
+    OptionalDateTimeFilterInputObject,
+    "OptionalDateTimeFilter",
+    {
+        pub eq: Option<DateTime>,
+        pub ne: Option<DateTime>,
+        pub lt: Option<DateTime>,
+        pub le: Option<DateTime>,
+        pub gt: Option<DateTime>,
+        pub ge: Option<DateTime>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<DateTime>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<DateTime>>,
+        #[graphql(description = "Keep values between the given duration ago and now.")]
+        pub withinLast: Option<XsdDuration>,
+        #[graphql(description = "Keep values between now and the given duration from now.")]
+        pub withinNext: Option<XsdDuration>,
+        #[graphql(description = "Keep values from before the given duration ago.")]
+        pub olderThan: Option<XsdDuration>,
+    }
+);
+
 #[derive(GraphQLInputObject)]
-#[graphql(name = "DateTimeFilter")]
-pub struct DateTimeFilterInputObject {
-    pub eq: Option<DateTime>,
-    pub ne: Option<DateTime>,
-    pub lt: Option<DateTime>,
-    pub le: Option<DateTime>,
-    pub gt: Option<DateTime>,
-    pub ge: Option<DateTime>,
+#[graphql(name = "CollectionXsdDateFilter")]
+#[allow(non_snake_case)]
+pub struct CollectionDateFilterInputObject {
+    pub someHave: Option<DateFilterInputObject>,
+    pub allHave: Option<DateFilterInputObject>,
+    pub noneHave: Option<DateFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
 
-#[derive(GraphQLInputObject)]
-#[graphql(name = "IdFilter")]
-pub struct IdFilterInputObject {
-    #[graphql(name = "_id")]
-    pub id: Option<ID>,
-    #[graphql(name = "_ids")]
-    pub ids: Option<Vec<ID>>,
+value_filter!(
+    DateFilterInputObject,
+    "XsdDateFilter",
+    OptionalDateFilterInputObject,
+    "OptionalXsdDateFilter",
+    {
+        pub eq: Option<XsdDate>,
+        pub ne: Option<XsdDate>,
+        pub lt: Option<XsdDate>,
+        pub le: Option<XsdDate>,
+        pub gt: Option<XsdDate>,
+        pub ge: Option<XsdDate>,
+        #[graphql(name = "in")]
+        pub is_in: Option<Vec<XsdDate>>,
+        #[graphql(name = "notIn")]
+        pub not_in: Option<Vec<XsdDate>>,
+        #[graphql(description = "Keep dates between the given duration ago and today.")]
+        pub withinLast: Option<XsdDuration>,
+        #[graphql(description = "Keep dates between today and the given duration from today.")]
+        pub withinNext: Option<XsdDuration>,
+        #[graphql(description = "Keep dates from before the given duration ago.")]
+        pub olderThan: Option<XsdDuration>,
+    }
+);
+
+macro_rules! temporal_filter {
+    ($filter:ident, $filter_name:literal, $optional:ident, $optional_name:literal, $collection:ident, $collection_name:literal, $scalar:ty) => {
+        #[derive(GraphQLInputObject)]
+        #[graphql(name = $collection_name)]
+        #[allow(non_snake_case)]
+        pub struct $collection {
+            pub someHave: Option<$filter>,
+            pub allHave: Option<$filter>,
+            pub noneHave: Option<$filter>,
+            #[graphql(name = "_isNull")]
+            pub is_null: Option<bool>,
+        }
+
+        value_filter!($filter, $filter_name, $optional, $optional_name, {
+            pub eq: Option<$scalar>,
+            pub ne: Option<$scalar>,
+            pub lt: Option<$scalar>,
+            pub le: Option<$scalar>,
+            pub gt: Option<$scalar>,
+            pub ge: Option<$scalar>,
+            #[graphql(name = "in")]
+            pub is_in: Option<Vec<$scalar>>,
+            #[graphql(name = "notIn")]
+            pub not_in: Option<Vec<$scalar>>,
+        });
+    };
+}
+
+temporal_filter!(
+    TimeFilterInputObject,
+    "XsdTimeFilter",
+    OptionalTimeFilterInputObject,
+    "OptionalXsdTimeFilter",
+    CollectionTimeFilterInputObject,
+    "CollectionXsdTimeFilter",
+    XsdTime
+);
+temporal_filter!(
+    GYearFilterInputObject,
+    "XsdGYearFilter",
+    OptionalGYearFilterInputObject,
+    "OptionalXsdGYearFilter",
+    CollectionGYearFilterInputObject,
+    "CollectionXsdGYearFilter",
+    XsdGYear
+);
+temporal_filter!(
+    GYearMonthFilterInputObject,
+    "XsdGYearMonthFilter",
+    OptionalGYearMonthFilterInputObject,
+    "OptionalXsdGYearMonthFilter",
+    CollectionGYearMonthFilterInputObject,
+    "CollectionXsdGYearMonthFilter",
+    XsdGYearMonth
+);
+temporal_filter!(
+    GMonthFilterInputObject,
+    "XsdGMonthFilter",
+    OptionalGMonthFilterInputObject,
+    "OptionalXsdGMonthFilter",
+    CollectionGMonthFilterInputObject,
+    "CollectionXsdGMonthFilter",
+    XsdGMonth
+);
+temporal_filter!(
+    GDayFilterInputObject,
+    "XsdGDayFilter",
+    OptionalGDayFilterInputObject,
+    "OptionalXsdGDayFilter",
+    CollectionGDayFilterInputObject,
+    "CollectionXsdGDayFilter",
+    XsdGDay
+);
+temporal_filter!(
+    GMonthDayFilterInputObject,
+    "XsdGMonthDayFilter",
+    OptionalGMonthDayFilterInputObject,
+    "OptionalXsdGMonthDayFilter",
+    CollectionGMonthDayFilterInputObject,
+    "CollectionXsdGMonthDayFilter",
+    XsdGMonthDay
+);
+temporal_filter!(
+    DurationFilterInputObject,
+    "XsdDurationFilter",
+    OptionalDurationFilterInputObject,
+    "OptionalXsdDurationFilter",
+    CollectionDurationFilterInputObject,
+    "CollectionXsdDurationFilter",
+    XsdDuration
+);
+temporal_filter!(
+    YearMonthDurationFilterInputObject,
+    "XsdYearMonthDurationFilter",
+    OptionalYearMonthDurationFilterInputObject,
+    "OptionalXsdYearMonthDurationFilter",
+    CollectionYearMonthDurationFilterInputObject,
+    "CollectionXsdYearMonthDurationFilter",
+    XsdYearMonthDuration
+);
+temporal_filter!(
+    DayTimeDurationFilterInputObject,
+    "XsdDayTimeDurationFilter",
+    OptionalDayTimeDurationFilterInputObject,
+    "OptionalXsdDayTimeDurationFilter",
+    CollectionDayTimeDurationFilterInputObject,
+    "CollectionXsdDayTimeDurationFilter",
+    XsdDayTimeDuration
+);
+
+fn temporal_filter_arg<'r>(
+    registry: &mut Registry<'r, DefaultScalarValue>,
+    name: &str,
+    temporal: TemporalKind,
+    kind: FieldKind,
+) -> juniper::meta::Argument<'r, DefaultScalarValue> {
+    macro_rules! arg {
+        ($filter:ty, $optional:ty, $collection:ty) => {
+            if kind.is_collection() {
+                registry.arg::<Option<$collection>>(name, &())
+            } else if kind == FieldKind::Optional {
+                registry.arg::<Option<$optional>>(name, &())
+            } else {
+                registry.arg::<Option<$filter>>(name, &())
+            }
+        };
+    }
+    match temporal {
+        TemporalKind::Date => arg!(
+            DateFilterInputObject,
+            OptionalDateFilterInputObject,
+            CollectionDateFilterInputObject
+        ),
+        TemporalKind::Time => arg!(
+            TimeFilterInputObject,
+            OptionalTimeFilterInputObject,
+            CollectionTimeFilterInputObject
+        ),
+        TemporalKind::GYear => arg!(
+            GYearFilterInputObject,
+            OptionalGYearFilterInputObject,
+            CollectionGYearFilterInputObject
+        ),
+        TemporalKind::GYearMonth => arg!(
+            GYearMonthFilterInputObject,
+            OptionalGYearMonthFilterInputObject,
+            CollectionGYearMonthFilterInputObject
+        ),
+        TemporalKind::GMonth => arg!(
+            GMonthFilterInputObject,
+            OptionalGMonthFilterInputObject,
+            CollectionGMonthFilterInputObject
+        ),
+        TemporalKind::GDay => arg!(
+            GDayFilterInputObject,
+            OptionalGDayFilterInputObject,
+            CollectionGDayFilterInputObject
+        ),
+        TemporalKind::GMonthDay => arg!(
+            GMonthDayFilterInputObject,
+            OptionalGMonthDayFilterInputObject,
+            CollectionGMonthDayFilterInputObject
+        ),
+        TemporalKind::Duration => arg!(
+            DurationFilterInputObject,
+            OptionalDurationFilterInputObject,
+            CollectionDurationFilterInputObject
+        ),
+        TemporalKind::YearMonthDuration => arg!(
+            YearMonthDurationFilterInputObject,
+            OptionalYearMonthDurationFilterInputObject,
+            CollectionYearMonthDurationFilterInputObject
+        ),
+        TemporalKind::DayTimeDuration => arg!(
+            DayTimeDurationFilterInputObject,
+            OptionalDayTimeDurationFilterInputObject,
+            CollectionDayTimeDurationFilterInputObject
+        ),
+    }
 }
 
+value_filter!(
+    IdFilterInputObject,
+    "IdFilter",
+    OptionalIdFilterInputObject,
+    "OptionalIdFilter",
+    {
+        #[graphql(name = "_id")]
+        pub id: Option<ID>,
+        #[graphql(name = "_ids")]
+        pub ids: Option<Vec<ID>>,
+    }
+);
+
 #[derive(GraphQLInputObject)]
 #[graphql(name = "CollectionIdFilterInputObject")]
 #[allow(non_snake_case)]
 pub struct CollectionIdFilterInputObject {
     pub someHave: Option<IdFilterInputObject>,
     pub allHave: Option<IdFilterInputObject>,
+    pub noneHave: Option<IdFilterInputObject>,
+    #[graphql(name = "_isNull")]
+    pub is_null: Option<bool>,
 }
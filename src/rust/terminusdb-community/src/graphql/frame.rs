@@ -741,7 +741,27 @@ pub struct ClassDefinition {
     pub graphql_to_short_name: BiMap<GraphQLName<'static>, ShortName>,
 }
 
-static RESERVED_CLASSES: [&str; 4] = ["BigFloat", "DateTime", "BigInt", "JSON"];
+static RESERVED_CLASSES: [&str; 19] = [
+    "BigFloat",
+    "DateTime",
+    "BigInt",
+    "JSON",
+    "XsdDate",
+    "XsdTime",
+    "XsdGYear",
+    "XsdGYearMonth",
+    "XsdGMonth",
+    "XsdGDay",
+    "XsdGMonthDay",
+    "XsdDuration",
+    "XsdYearMonthDuration",
+    "XsdDayTimeDuration",
+    "PathFilter",
+    "FuzzyMatch",
+    "SimilarTo",
+    "NullOrdering",
+    "Collation",
+];
 
 impl UncleanClassDefinition {
     pub fn sanitize(self, prefixes: &Prefixes) -> ClassDefinition {
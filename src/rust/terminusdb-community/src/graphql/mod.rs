@@ -15,15 +15,19 @@ use std::{
 };
 use swipl::prelude::*;
 
+mod access;
 mod filter;
 pub mod frame;
 mod main_graph;
 mod mutation;
 mod naming;
+mod ordering;
 pub mod query;
 mod sanitize;
 pub mod schema;
+mod similarity;
 mod system;
+pub mod temporal;
 mod top;
 
 use crate::types::{transaction_instance_layer, transaction_schema_layer};
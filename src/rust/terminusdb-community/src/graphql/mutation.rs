@@ -6,6 +6,7 @@ use swipl::{
     term::Term,
 };
 
+use crate::doc::idgen::document_ids;
 use crate::graphql::schema::GraphQLJSON;
 
 use super::schema::{result_to_execution_result, GraphType, TerminusContext};
@@ -34,11 +35,19 @@ impl GraphQLType for TerminusMutationRoot {
             .argument(registry.arg::<GraphQLJSON>("json", &()))
             .argument(registry.arg::<Option<GraphType>>("graph_type", &()))
             .argument(registry.arg::<Option<bool>>("raw_json", &()))
-            .argument(registry.arg::<Option<bool>>("create", &()));
+            .argument(registry.arg::<Option<bool>>("create", &()))
+            .argument(registry.arg::<Option<String>>("ifMatch", &()));
+        let patch_documents_field = registry
+            .field::<Vec<ID>>("_patchDocuments", &())
+            .argument(registry.arg::<GraphQLJSON>("json", &()));
         let delete_documents_field = registry
             .field::<Vec<ID>>("_deleteDocuments", &())
             .argument(registry.arg::<Vec<ID>>("ids", &()))
-            .argument(registry.arg::<Option<GraphType>>("graph_type", &()));
+            .argument(registry.arg::<Option<GraphType>>("graph_type", &()))
+            .argument(registry.arg::<Option<Vec<String>>>("ifMatch", &()));
+        let document_ids_field = registry
+            .field::<Vec<ID>>("_documentIds", &())
+            .argument(registry.arg::<GraphQLJSON>("json", &()));
         let commit_info_field = registry
             .field::<bool>("_commitInfo", &())
             .argument(registry.arg::<Option<String>>("author", &()))
@@ -50,7 +59,9 @@ impl GraphQLType for TerminusMutationRoot {
                 &[
                     insert_documents_field,
                     replace_documents_field,
+                    patch_documents_field,
                     delete_documents_field,
+                    document_ids_field,
                     commit_info_field,
                 ],
             )
@@ -125,6 +136,7 @@ impl GraphQLValue for TerminusMutationRoot {
                 let graph_type = arguments
                     .get::<String>("graph_type")
                     .unwrap_or("InstanceGraph".to_string());
+                let if_match = arguments.get::<Vec<String>>("ifMatch");
                 result_to_execution_result(
                     prolog_context,
                     self.call_delete_doc(
@@ -132,6 +144,7 @@ impl GraphQLValue for TerminusMutationRoot {
                         &executor.context().transaction_term,
                         &ids,
                         &graph_type,
+                        if_match.as_deref(),
                     ),
                 )
             }
@@ -146,6 +159,7 @@ impl GraphQLValue for TerminusMutationRoot {
                     .unwrap_or("InstanceGraph".to_string());
                 let raw_json = arguments.get::<bool>("raw_json").unwrap_or(false);
                 let create = arguments.get::<bool>("create").unwrap_or(false);
+                let if_match = arguments.get::<String>("ifMatch");
                 result_to_execution_result(
                     prolog_context,
                     self.call_replace_doc(
@@ -155,9 +169,39 @@ impl GraphQLValue for TerminusMutationRoot {
                         &graph_type,
                         raw_json,
                         create,
+                        if_match.as_deref(),
                     ),
                 )
             }
+            "_patchDocuments" => {
+                let json = arguments.get::<String>("json");
+                if json.is_none() {
+                    return Err("no patches specified".into());
+                }
+                let json = json.unwrap();
+                result_to_execution_result(
+                    prolog_context,
+                    self.call_patch_doc(
+                        prolog_context,
+                        &executor.context().transaction_term,
+                        &json,
+                    ),
+                )
+            }
+            "_documentIds" => {
+                let json = arguments.get::<String>("json");
+                if json.is_none() {
+                    return Err("no documents specified".into());
+                }
+                let json = json.unwrap();
+                let model = executor.context().document_context().model();
+                match document_ids(model, &json) {
+                    Ok(ids) => Ok(juniper::Value::List(
+                        ids.into_iter().map(|id| id.into()).collect(),
+                    )),
+                    Err(e) => Err(e.to_string().into()),
+                }
+            }
             "_commitInfo" => {
                 if let Some(author) = arguments.get::<String>("author") {
                     result_to_execution_result(
@@ -228,9 +272,10 @@ impl TerminusMutationRoot {
         transaction_term: &Term,
         ids: &[String],
         graph_type: &str,
+        if_match: Option<&[String]>,
     ) -> PrologResult<juniper::Value> {
         let frame = context.open_frame();
-        let [graph_type_term, ids_term] = frame.new_term_refs();
+        let [graph_type_term, ids_term, if_match_term] = frame.new_term_refs();
 
         let graph_type_atom = if graph_type == "SchemaGraph" {
             atom!("schema")
@@ -240,9 +285,21 @@ impl TerminusMutationRoot {
 
         ids_term.unify(ids)?;
         graph_type_term.put(&graph_type_atom)?;
+        // left unbound when there are no hashes to check
+        if let Some(if_match) = if_match {
+            if_match_term.unify(if_match)?;
+        }
 
-        let delete_doc = pred!("api_document:api_delete_documents_by_ids/3");
-        frame.call_once(delete_doc, [transaction_term, &graph_type_term, &ids_term])?;
+        let delete_doc = pred!("api_document:api_delete_documents_by_ids/4");
+        frame.call_once(
+            delete_doc,
+            [
+                transaction_term,
+                &graph_type_term,
+                &ids_term,
+                &if_match_term,
+            ],
+        )?;
 
         frame.close();
         Ok(juniper::Value::List(
@@ -250,6 +307,26 @@ impl TerminusMutationRoot {
         ))
     }
 
+    fn call_patch_doc(
+        &self,
+        context: &GenericQueryableContext<'static>,
+        transaction_term: &Term,
+        json: &str,
+    ) -> PrologResult<juniper::Value> {
+        let frame = context.open_frame();
+        let [string_term, ids_term] = frame.new_term_refs();
+        string_term.put(json)?;
+
+        let patch_doc = pred!("api_document:api_patch_documents_core_string/3");
+        frame.call_once(patch_doc, [transaction_term, &string_term, &ids_term])?;
+
+        let ids: Vec<String> = ids_term.get_ex()?;
+        frame.close();
+        Ok(juniper::Value::List(
+            ids.into_iter().map(|id| id.to_string().into()).collect(),
+        ))
+    }
+
     fn call_replace_doc(
         &self,
         context: &GenericQueryableContext<'static>,
@@ -258,9 +335,10 @@ impl TerminusMutationRoot {
         graph_type: &str,
         raw_json: bool,
         create: bool,
+        if_match: Option<&str>,
     ) -> PrologResult<juniper::Value> {
         let frame = context.open_frame();
-        let [string_term, graph_type_term, raw_json_term, ids_term, create_term] =
+        let [string_term, graph_type_term, raw_json_term, ids_term, create_term, if_match_term] =
             frame.new_term_refs();
 
         let graph_type_atom = if graph_type == "SchemaGraph" {
@@ -273,8 +351,11 @@ impl TerminusMutationRoot {
         graph_type_term.put(&graph_type_atom)?;
         raw_json_term.put(&raw_json)?;
         create_term.put(&create)?;
+        if let Some(if_match) = if_match {
+            if_match_term.unify(if_match)?;
+        }
 
-        let replace_doc = pred!("api_document:api_replace_documents_core_string/6");
+        let replace_doc = pred!("api_document:api_replace_documents_core_string/7");
         frame.call_once(
             replace_doc,
             [
@@ -283,6 +364,7 @@ impl TerminusMutationRoot {
                 &graph_type_term,
                 &raw_json_term,
                 &create_term,
+                &if_match_term,
                 &ids_term,
             ],
         )?;
@@ -12,6 +12,10 @@ pub fn filter_name(type_name: &GraphQLName) -> GraphQLName<'static> {
     GraphQLName(format!("{type_name}_Filter").into())
 }
 
+pub fn optional_filter_name(type_name: &GraphQLName) -> GraphQLName<'static> {
+    GraphQLName(format!("{type_name}_Optional_Filter").into())
+}
+
 pub fn collection_filter_name(type_name: &GraphQLName) -> GraphQLName<'static> {
     GraphQLName(format!("{type_name}_Collection_Filter").into())
 }
@@ -20,6 +24,10 @@ pub fn enum_filter_name(type_name: &GraphQLName) -> GraphQLName<'static> {
     GraphQLName(format!("{type_name}_Enum_Filter").into())
 }
 
+pub fn optional_enum_filter_name(type_name: &GraphQLName) -> GraphQLName<'static> {
+    GraphQLName(format!("{type_name}_Optional_Enum_Filter").into())
+}
+
 pub fn path_field_to_class<'a>(field_name: &'a GraphQLName<'a>) -> Option<GraphQLName<'a>> {
     let field_name = field_name.as_str();
     if field_name.starts_with("_path_to_") {
@@ -36,6 +44,20 @@ pub fn inverse_field_name(property: &GraphQLName, class: &GraphQLName) -> GraphQ
     GraphQLName(field.into())
 }
 
+pub fn reverse_filter_name(inverted_field: &GraphQLName) -> GraphQLName<'static> {
+    GraphQLName(format!("_reverse{inverted_field}").into())
+}
+
+pub fn reverse_filter_to_inverted_field<'a>(
+    field_name: &'a GraphQLName<'a>,
+) -> Option<GraphQLName<'a>> {
+    field_name
+        .as_str()
+        .strip_prefix("_reverse")
+        .filter(|rest| rest.starts_with('_'))
+        .map(|rest| GraphQLName(rest.into()))
+}
+
 pub fn path_to_class_name(class: &GraphQLName) -> GraphQLName<'static> {
     GraphQLName(format!("_path_to_{class}").into())
 }
@@ -0,0 +1,584 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+
+use icu_collator::{Collator, CollatorOptions, Strength};
+use icu_locid::Locale;
+use tdb_succinct::{Datatype, TypedDictEntry};
+
+use crate::consts::{RDF_FIRST, RDF_NIL, RDF_REST, RDF_TYPE};
+use crate::schema::RdfListIterator;
+use crate::terminus_store::store::sync::SyncStoreLayer;
+use crate::terminus_store::*;
+use crate::value::value_to_string;
+
+use super::frame::{AllFrames, CollectionKind, GraphQLName, IriName};
+use super::schema::{TerminusNullOrdering, TerminusOrderField, TerminusOrdering};
+use super::similarity::TextMatcher;
+
+enum OrderTarget {
+    Id,
+    Type,
+    Value(Option<u64>),
+    Count(Option<u64>, CollectionKind),
+    Similarity(Vec<(Option<u64>, TextMatcher)>),
+}
+
+enum StringCollation {
+    CaseInsensitive,
+    Locale(Collator),
+}
+
+impl StringCollation {
+    fn new(case_insensitive: bool, locale: Option<&str>) -> Result<Option<Self>, String> {
+        match locale {
+            Some(locale) => {
+                let locale: Locale = locale
+                    .parse()
+                    .map_err(|_| format!("Not a valid collation locale: {locale}"))?;
+                let mut options = CollatorOptions::new();
+                if case_insensitive {
+                    options.strength = Some(Strength::Secondary);
+                }
+                let collator = Collator::try_new(&(&locale).into(), options)
+                    .map_err(|_| format!("No collation available for locale {locale}"))?;
+                Ok(Some(Self::Locale(collator)))
+            }
+            None if case_insensitive => Ok(Some(Self::CaseInsensitive)),
+            None => Ok(None),
+        }
+    }
+
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        match self {
+            // the values were already folded to lowercase
+            Self::CaseInsensitive => left.cmp(right),
+            Self::Locale(collator) => collator.compare(left, right),
+        }
+    }
+}
+
+/// A compiled order key, with all property names resolved to
+/// predicate ids in the instance layer.
+pub struct OrderSpec {
+    /// The document links to follow before arriving at the document
+    /// holding the target. `None` if the link predicate doesn't exist.
+    steps: Vec<Option<u64>>,
+    target: OrderTarget,
+    ordering: TerminusOrdering,
+    nulls: Option<TerminusNullOrdering>,
+    collation: Option<StringCollation>,
+}
+
+/// Compile the order keys for a query on `class_name`. The
+/// `similarity` conditions are the approximate string matches in the
+/// query filter, which `_similarity` orders by.
+pub fn compile_order_specs(
+    g: &SyncStoreLayer,
+    all_frames: &AllFrames,
+    class_name: &GraphQLName,
+    fields: &[TerminusOrderField],
+    similarity: &[(String, TextMatcher)],
+) -> Result<Vec<OrderSpec>, juniper::FieldError> {
+    fields
+        .iter()
+        .map(|field| {
+            let (last, links) = field
+                .path
+                .split_last()
+                .expect("order key should have at least one component");
+            let mut class = class_name.as_static();
+            let mut steps = Vec::with_capacity(links.len());
+            for link in links {
+                let class_definition = all_frames.frames[&class].as_class_definition();
+                let next_class = class_definition
+                    .resolve_field(link)
+                    .document_type(all_frames)
+                    .unwrap_or_else(|| panic!("Field {link} of {class} is not a document link"))
+                    .as_static();
+                steps.push(
+                    all_frames
+                        .graphql_property_to_iri(&class, link)
+                        .and_then(|p| g.predicate_id(p.as_str())),
+                );
+                class = next_class;
+            }
+            let target = match last.as_str() {
+                "_id" => OrderTarget::Id,
+                "_type" => OrderTarget::Type,
+                "_similarity" => {
+                    // the filter only has conditions on the documents
+                    // themselves, not on the documents they link to
+                    if !links.is_empty() {
+                        return Err("_similarity can only be ordered on at the top level".into());
+                    }
+                    OrderTarget::Similarity(
+                        similarity
+                            .iter()
+                            .map(|(predicate, matcher)| {
+                                (g.predicate_id(predicate), matcher.clone())
+                            })
+                            .collect(),
+                    )
+                }
+                _ => {
+                    let class_definition = all_frames.frames[&class].as_class_definition();
+                    let kind = class_definition.resolve_field(last).kind();
+                    let predicate_id = all_frames
+                        .graphql_property_to_iri(&class, last)
+                        .and_then(|p| g.predicate_id(p.as_str()));
+                    match CollectionKind::try_from(kind) {
+                        Ok(collection_kind) => OrderTarget::Count(predicate_id, collection_kind),
+                        Err(_) => OrderTarget::Value(predicate_id),
+                    }
+                }
+            };
+            let collation = match field.collation.as_ref() {
+                Some(c) => {
+                    StringCollation::new(c.caseInsensitive.unwrap_or(false), c.locale.as_deref())?
+                }
+                None => None,
+            };
+
+            Ok(OrderSpec {
+                steps,
+                target,
+                ordering: field.ordering,
+                nulls: field.nulls,
+                collation,
+            })
+        })
+        .collect()
+}
+
+enum OrderValue {
+    Entry(TypedDictEntry),
+    Text(String),
+    Collated(String),
+    Count(usize),
+    Score(f64),
+}
+
+impl OrderValue {
+    fn rank(&self) -> usize {
+        match self {
+            Self::Entry(_) => 0,
+            Self::Text(_) => 1,
+            Self::Collated(_) => 2,
+            Self::Count(_) => 3,
+            Self::Score(_) => 4,
+        }
+    }
+
+    fn compare(&self, other: &OrderValue, spec: &OrderSpec) -> Ordering {
+        match (self, other) {
+            (Self::Entry(e1), Self::Entry(e2)) => e1.cmp(e2),
+            (Self::Text(t1), Self::Text(t2)) => t1.cmp(t2),
+            (Self::Collated(t1), Self::Collated(t2)) => spec
+                .collation
+                .as_ref()
+                .expect("collated values require a collation")
+                .compare(t1, t2),
+            (Self::Count(c1), Self::Count(c2)) => c1.cmp(c2),
+            (Self::Score(s1), Self::Score(s2)) => s1.total_cmp(s2),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+fn is_collatable(tde: &TypedDictEntry) -> bool {
+    matches!(
+        tde.datatype(),
+        Datatype::String
+            | Datatype::NormalizedString
+            | Datatype::Token
+            | Datatype::Language
+            | Datatype::Name
+            | Datatype::NCName
+            | Datatype::NMToken
+            | Datatype::AnyURI
+    )
+}
+
+fn order_value(
+    g: &SyncStoreLayer,
+    all_frames: &AllFrames,
+    spec: &OrderSpec,
+    id: u64,
+) -> Option<OrderValue> {
+    let mut cur = id;
+    for step in spec.steps.iter() {
+        cur = g.single_triple_sp(cur, (*step)?)?.object;
+    }
+    match &spec.target {
+        OrderTarget::Id => g.id_subject(cur).map(OrderValue::Text),
+        OrderTarget::Type => {
+            let ty = g
+                .predicate_id(RDF_TYPE)
+                .and_then(|rdf_type| g.single_triple_sp(cur, rdf_type))
+                .and_then(|t| g.id_object_node(t.object))?;
+            let name = all_frames
+                .iri_to_graphql_name_opt(&IriName(ty.clone()))
+                .map(|n| n.to_string())
+                .unwrap_or(ty);
+            Some(OrderValue::Text(name))
+        }
+        OrderTarget::Value(predicate_id) => {
+            let tde = g
+                .single_triple_sp(cur, (*predicate_id)?)
+                .and_then(|t| g.id_object_value(t.object))?;
+            match &spec.collation {
+                Some(collation) if is_collatable(&tde) => {
+                    let s = value_to_string(&tde).into_owned();
+                    Some(OrderValue::Collated(match collation {
+                        StringCollation::CaseInsensitive => s.to_lowercase(),
+                        StringCollation::Locale(_) => s,
+                    }))
+                }
+                _ => Some(OrderValue::Entry(tde)),
+            }
+        }
+        OrderTarget::Count(predicate_id, kind) => {
+            let predicate_id = match predicate_id {
+                Some(predicate_id) => *predicate_id,
+                None => return Some(OrderValue::Count(0)),
+            };
+            let count = match kind {
+                CollectionKind::Property | CollectionKind::Array => {
+                    g.triples_sp(cur, predicate_id).count()
+                }
+                CollectionKind::List => match g.single_triple_sp(cur, predicate_id) {
+                    Some(t) => RdfListIterator {
+                        layer: g,
+                        cur: t.object,
+                        rdf_first_id: g.predicate_id(RDF_FIRST),
+                        rdf_rest_id: g.predicate_id(RDF_REST),
+                        rdf_nil_id: g.subject_id(RDF_NIL),
+                    }
+                    .count(),
+                    None => 0,
+                },
+            };
+            Some(OrderValue::Count(count))
+        }
+        OrderTarget::Similarity(conditions) => {
+            if conditions.is_empty() {
+                return None;
+            }
+            // the mean over all conditions, each scored by its best value
+            let total: f64 = conditions
+                .iter()
+                .map(|(predicate_id, matcher)| match predicate_id {
+                    Some(predicate_id) => g
+                        .triples_sp(cur, *predicate_id)
+                        .filter_map(|t| g.id_object_value(t.object))
+                        .map(|v| matcher.score(&value_to_string(&v)))
+                        .fold(0.0, f64::max),
+                    None => 0.0,
+                })
+                .sum();
+            Some(OrderValue::Score(total / conditions.len() as f64))
+        }
+    }
+}
+
+pub fn create_query_order_key<'a>(
+    g: &SyncStoreLayer,
+    all_frames: &AllFrames,
+    specs: &'a [OrderSpec],
+    id: u64,
+) -> QueryOrderKey<'a> {
+    let vec: Vec<_> = specs
+        .iter()
+        .map(|spec| (order_value(g, all_frames, spec, id), spec))
+        .collect();
+
+    QueryOrderKey { vec }
+}
+
+pub struct QueryOrderKey<'a> {
+    vec: Vec<(Option<OrderValue>, &'a OrderSpec)>,
+}
+
+impl<'a> PartialEq for QueryOrderKey<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<'a> Eq for QueryOrderKey<'a> {}
+
+impl<'a> Ord for QueryOrderKey<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for i in 0..self.vec.len() {
+            let (option_value, spec) = &self.vec[i];
+            let (other_value, _) = &other.vec[i];
+            let directed = |res: Ordering| match spec.ordering {
+                TerminusOrdering::Desc => res.reverse(),
+                TerminusOrdering::Asc => res,
+            };
+            let final_order = match (option_value, other_value) {
+                (Some(value), Some(other_value)) => directed(value.compare(other_value, spec)),
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => match spec.nulls {
+                    // without explicit placement, nulls are the smallest value
+                    None => directed(Ordering::Less),
+                    Some(TerminusNullOrdering::First) => Ordering::Less,
+                    Some(TerminusNullOrdering::Last) => Ordering::Greater,
+                },
+                (Some(_), None) => match spec.nulls {
+                    None => directed(Ordering::Greater),
+                    Some(TerminusNullOrdering::First) => Ordering::Greater,
+                    Some(TerminusNullOrdering::Last) => Ordering::Less,
+                },
+            };
+
+            if !final_order.is_eq() {
+                return final_order;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl<'a> PartialOrd for QueryOrderKey<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+lazy_static! {
+    /// The number of order keys that can be held in memory at once
+    /// when sorting without a limit. Past this, sorted runs get
+    /// spilled to disk.
+    static ref SORT_MEMORY_BUDGET: usize = {
+        std::env::var("TERMINUSDB_ORDER_MEMORY_BUDGET")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(1_000_000)
+    };
+    static ref SORT_SPILL_DIR: PathBuf = {
+        std::env::var("TERMINUSDB_ORDER_SPILL_DIR")
+            .ok()
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+    };
+}
+
+/// Sort the given ids by the order specs, and return the page
+/// described by offset and limit.
+///
+/// When a limit is given, only the best `offset + limit` keys are
+/// ever kept around. Otherwise, a full sort is done, spilling to disk
+/// when there are more results than fit in the memory budget. Failing
+/// to spill results in an error.
+pub fn order_ids<I: Iterator<Item = u64>>(
+    g: &SyncStoreLayer,
+    all_frames: &AllFrames,
+    specs: &[OrderSpec],
+    ids: I,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Vec<u64>, juniper::FieldError> {
+    let key_fn = |id| create_query_order_key(g, all_frames, specs, id);
+    let sorted = match limit {
+        Some(limit) => top_k(ids, offset.saturating_add(limit), key_fn),
+        None => external_sort(ids, *SORT_MEMORY_BUDGET, &SORT_SPILL_DIR, key_fn)
+            .map_err(|e| format!("Failed to spill sort run to disk: {e}"))?,
+    };
+
+    Ok(sorted.into_iter().skip(offset).collect())
+}
+
+/// Return the `k` smallest ids by key, in order. Ties keep the order
+/// in which they were encountered.
+fn top_k<K: Ord, I: Iterator<Item = u64>, F: Fn(u64) -> K>(
+    ids: I,
+    k: usize,
+    key_fn: F,
+) -> Vec<u64> {
+    if k == 0 {
+        return Vec::new();
+    }
+    // a max-heap, so the worst of the current best k is on top
+    let mut heap: BinaryHeap<(K, usize, u64)> = BinaryHeap::with_capacity(k);
+    for (seq, id) in ids.enumerate() {
+        let key = key_fn(id);
+        if heap.len() < k {
+            heap.push((key, seq, id));
+        } else if let Some(mut worst) = heap.peek_mut() {
+            // the sequence number only grows, so a tie is never better
+            if key < worst.0 {
+                *worst = (key, seq, id);
+            }
+        }
+    }
+
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|(_, _, id)| id)
+        .collect()
+}
+
+/// A sorted run of (sequence number, id) pairs spilled to disk.
+struct SpilledRun {
+    reader: BufReader<File>,
+}
+
+impl SpilledRun {
+    fn write(dir: &Path, entries: &[(usize, u64)]) -> io::Result<Self> {
+        let mut file = tempfile::tempfile_in(dir)?;
+        {
+            let mut writer = BufWriter::new(&mut file);
+            for (seq, id) in entries {
+                writer.write_all(&(*seq as u64).to_le_bytes())?;
+                writer.write_all(&id.to_le_bytes())?;
+            }
+            writer.flush()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+        })
+    }
+
+    fn next(&mut self) -> io::Result<Option<(usize, u64)>> {
+        let mut buf = [0; 16];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => {
+                let seq = u64::from_le_bytes(buf[..8].try_into().unwrap()) as usize;
+                let id = u64::from_le_bytes(buf[8..].try_into().unwrap());
+                Ok(Some((seq, id)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn sort_run<K: Ord, F: Fn(u64) -> K>(run: &mut Vec<(usize, u64)>, key_fn: &F) {
+    run.sort_by_cached_key(|(seq, id)| (key_fn(*id), *seq));
+}
+
+/// Sort ids by key, keeping at most `budget` keys in memory.
+///
+/// Runs of `budget` ids are sorted and written to anonymous temporary
+/// files in `dir`. Only the ids are written, as keys are cheap to
+/// recompute and hard to serialize. The runs are then merged, keeping
+/// just the key of the head of each run in memory.
+fn external_sort<K: Ord, I: Iterator<Item = u64>, F: Fn(u64) -> K>(
+    ids: I,
+    budget: usize,
+    dir: &Path,
+    key_fn: F,
+) -> io::Result<Vec<u64>> {
+    let budget = budget.max(1);
+    let mut runs: Vec<SpilledRun> = Vec::new();
+    let mut current: Vec<(usize, u64)> = Vec::new();
+    let mut total = 0;
+    for (seq, id) in ids.enumerate() {
+        current.push((seq, id));
+        total += 1;
+        if current.len() == budget {
+            sort_run(&mut current, &key_fn);
+            runs.push(SpilledRun::write(dir, &current)?);
+            current.clear();
+        }
+    }
+    sort_run(&mut current, &key_fn);
+    if runs.is_empty() {
+        return Ok(current.into_iter().map(|(_, id)| id).collect());
+    }
+    if !current.is_empty() {
+        runs.push(SpilledRun::write(dir, &current)?);
+    }
+    drop(current);
+
+    let mut result = Vec::with_capacity(total);
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (run_index, run) in runs.iter_mut().enumerate() {
+        if let Some((seq, id)) = run.next()? {
+            heap.push(Reverse((key_fn(id), seq, id, run_index)));
+        }
+    }
+    while let Some(Reverse((_, _, id, run_index))) = heap.pop() {
+        result.push(id);
+        if let Some((seq, id)) = runs[run_index].next()? {
+            heap.push(Reverse((key_fn(id), seq, id, run_index)));
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(id: u64) -> u64 {
+        // lots of ties, to check that sorting is stable
+        (id * 7) % 5
+    }
+
+    fn reference_sort(ids: &[u64]) -> Vec<u64> {
+        let mut sorted = ids.to_vec();
+        sorted.sort_by_key(|id| bucket(*id));
+        sorted
+    }
+
+    #[test]
+    fn top_k_matches_full_sort() {
+        let ids: Vec<u64> = (0..100).rev().collect();
+        let expected = reference_sort(&ids);
+        for k in [0, 1, 7, 50, 100, 200] {
+            let result = top_k(ids.iter().copied(), k, bucket);
+            assert_eq!(&expected[..k.min(100)], &result[..]);
+        }
+    }
+
+    #[test]
+    fn external_sort_matches_full_sort() {
+        let dir = std::env::temp_dir();
+        let ids: Vec<u64> = (0..1000).map(|i| (i * 37) % 1000).collect();
+        let expected = reference_sort(&ids);
+        for budget in [1, 3, 64, 1000, 5000] {
+            let result = external_sort(ids.iter().copied(), budget, &dir, bucket).unwrap();
+            assert_eq!(expected, result);
+        }
+    }
+
+    #[test]
+    fn external_sort_reports_spill_failures() {
+        let dir = std::env::temp_dir().join("terminusdb-no-such-spill-dir");
+        let ids: Vec<u64> = (0..10).collect();
+        assert!(external_sort(ids.iter().copied(), 3, &dir, bucket).is_err());
+        // nothing is spilled when everything fits in the budget
+        assert!(external_sort(ids.iter().copied(), 100, &dir, bucket).is_ok());
+    }
+
+    #[test]
+    fn case_insensitive_collation_folds_case() {
+        let collation = StringCollation::new(true, None).unwrap().unwrap();
+        assert!(matches!(collation, StringCollation::CaseInsensitive));
+        assert!(StringCollation::new(false, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn locale_collation_orders_accents() {
+        let collation = StringCollation::new(false, Some("de")).unwrap().unwrap();
+        // in byte order, 'ä' would sort after 'z'
+        assert_eq!(Ordering::Less, collation.compare("äpfel", "zebra"));
+        let collation = StringCollation::new(true, Some("en")).unwrap().unwrap();
+        assert_eq!(Ordering::Equal, collation.compare("Apple", "apple"));
+    }
+
+    #[test]
+    fn invalid_locale_is_an_error() {
+        assert!(StringCollation::new(false, Some("not a locale!")).is_err());
+    }
+}
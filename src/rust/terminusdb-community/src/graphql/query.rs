@@ -4,11 +4,15 @@ use juniper::{self, FromInputValue, InputValue, ID};
 use ordered_float::OrderedFloat;
 use regex::{Regex, RegexSet};
 use rug::Integer;
-use tdb_succinct::{Decimal, TdbDataType, TypedDictEntry};
+use tdb_succinct::{
+    AnySimpleType, AnyURI, Decimal, Language, NCName, NMToken, Name, NegativeInteger,
+    NonNegativeInteger, NonPositiveInteger, NormalizedString, PositiveInteger, TdbDataType, Token,
+    TypedDictEntry,
+};
 
 use crate::consts::{RDF_FIRST, RDF_NIL, RDF_REST, SYS_VALUE};
 use crate::path::iterator::{CachedClonableIterator, ClonableIterator};
-use crate::path::{Path, Pred};
+use crate::path::{parse_path, Path, Pred};
 use crate::schema::RdfListIterator;
 use crate::terminus_store::store::sync::SyncStoreLayer;
 
@@ -17,23 +21,27 @@ use crate::{consts::RDF_TYPE, terminus_store::*};
 
 use super::filter::{
     BigFloatFilterInputObject, BigIntFilterInputObject, BooleanFilterInputObject,
-    CollectionFilterInputObject, DateTimeFilterInputObject, EnumFilterInputObject,
-    FilterInputObject, FloatFilterInputObject, IdFilterInputObject, IntFilterInputObject,
-    StringFilterInputObject,
+    CollectionFilterInputObject, EnumFilterInputObject, FilterInputObject, FloatFilterInputObject,
+    IdFilterInputObject, IntFilterInputObject, PathFilterInputObject, StringFilterInputObject,
 };
 use super::frame::{
     AllFrames, BaseOrDerived, ClassDefinition, CollectionKind, FieldKind, GraphQLName, IriName,
     Prefixes, TypeDefinition,
 };
+use super::naming::reverse_filter_to_inverted_field;
+use super::ordering::{compile_order_specs, order_ids};
 use super::schema::{
-    id_matches_restriction, BigFloat, BigInt, DateTime, GeneratedEnum, NodeOrValue,
-    TerminusContext, TerminusOrderBy, TerminusOrdering,
+    id_matches_restriction, BigFloat, BigInt, GeneratedEnum, NodeOrValue, TerminusContext,
+    TerminusOrderBy,
 };
+use super::similarity::{trigram_index, TextMatcher};
+use super::temporal::{now_entry, parse_duration, parse_temporal_entry, relative_entry};
 
 use crate::path::compile::{compile_path, path_to_class};
 
+use std::cell::RefCell;
 use std::cmp::*;
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -58,12 +66,14 @@ enum TextOperation {
     StartsWith(String),
     AllOfTerms(Vec<String>),
     AnyOfTerms(Vec<String>),
+    Approximate(TextMatcher),
 }
 
 #[derive(Debug, Clone, Copy)]
 enum CollectionOperation {
     SomeHave,
     AllHave,
+    NoneHave,
 }
 
 #[derive(Debug, Clone)]
@@ -84,9 +94,24 @@ enum FilterScope {
     Required(FilterObjectType),
     // Optional(OptionalOperation),
     Collection(CollectionKind, CollectionOperation, FilterObjectType),
+    IsNull(CollectionKind, bool),
     And(Vec<Rc<FilterObject>>),
     Or(Vec<Rc<FilterObject>>),
     Not(Rc<FilterObject>),
+    // kind, operation, referring types, filter on the referring documents
+    Reverse(
+        CollectionKind,
+        CollectionOperation,
+        Vec<String>,
+        Rc<FilterObject>,
+    ),
+    Path(Rc<Path>, Rc<Vec<PathTarget>>),
+}
+
+#[derive(Debug)]
+struct PathTarget {
+    types: Vec<String>,
+    filter: Option<Rc<FilterObject>>,
 }
 
 #[derive(Debug, Clone)]
@@ -105,10 +130,16 @@ enum FilterValue {
     Boolean(GenericOperation, bool, String),
     BigInt(GenericOperation, BigInt, String),
     BigFloat(GenericOperation, BigFloat, String),
-    DateTime(GenericOperation, DateTime, String),
+    Temporal(Vec<(GenericOperation, TypedDictEntry)>, String),
     String(GenericOperation, String, String),
-    Enum { op: EnumOperation, value: IriName },
+    Enum {
+        op: EnumOperation,
+        values: Vec<IriName>,
+    },
     Foreign(IdOperation, String),
+    // negated, values
+    OneOf(bool, Vec<TypedDictEntry>),
+    All(Vec<FilterValue>),
 }
 
 fn ordering_matches_op(ord: Ordering, op: GenericOperation) -> bool {
@@ -135,8 +166,11 @@ FilterObject{ edges:
 }
 */
 
-fn compile_string_input_value(string_type: &str, value: StringFilterInputObject) -> FilterValue {
-    if let Some(val) = value.eq {
+fn compile_string_input_value(
+    string_type: &str,
+    value: StringFilterInputObject,
+) -> Result<FilterValue, juniper::FieldError> {
+    Ok(if let Some(val) = value.eq {
         FilterValue::String(GenericOperation::Eq, val, string_type.to_string())
     } else if let Some(val) = value.ne {
         FilterValue::String(GenericOperation::Ne, val, string_type.to_string())
@@ -157,9 +191,25 @@ fn compile_string_input_value(string_type: &str, value: StringFilterInputObject)
         FilterValue::Text(TextOperation::AllOfTerms(val), string_type.to_string())
     } else if let Some(val) = value.anyOfTerms {
         FilterValue::Text(TextOperation::AnyOfTerms(val), string_type.to_string())
+    } else if let Some(val) = value.fuzzy {
+        let max_distance = val.maxDistance.unwrap_or(2);
+        let max_distance = usize::try_from(max_distance)
+            .map_err(|_| format!("maxDistance can not be negative, but was {max_distance}"))?;
+        let matcher = TextMatcher::Fuzzy {
+            value: val.value,
+            max_distance,
+            transpositions: val.transpositions.unwrap_or(true),
+        };
+        FilterValue::Text(TextOperation::Approximate(matcher), string_type.to_string())
+    } else if let Some(val) = value.similarTo {
+        let matcher = TextMatcher::SimilarTo {
+            value: val.value,
+            threshold: val.threshold.unwrap_or(0.3),
+        };
+        FilterValue::Text(TextOperation::Approximate(matcher), string_type.to_string())
     } else {
         panic!("Unable to compile string input value to a filter")
-    }
+    })
 }
 
 fn compile_big_int_input_value(string_type: &str, value: BigIntFilterInputObject) -> FilterValue {
@@ -180,25 +230,64 @@ fn compile_big_int_input_value(string_type: &str, value: BigIntFilterInputObject
     }
 }
 
-fn compile_datetime_input_value(
-    string_type: &str,
-    value: DateTimeFilterInputObject,
-) -> FilterValue {
-    if let Some(val) = value.eq {
-        FilterValue::DateTime(GenericOperation::Eq, val, string_type.to_string())
-    } else if let Some(val) = value.ne {
-        FilterValue::DateTime(GenericOperation::Ne, val, string_type.to_string())
-    } else if let Some(val) = value.lt {
-        FilterValue::DateTime(GenericOperation::Lt, val, string_type.to_string())
-    } else if let Some(val) = value.le {
-        FilterValue::DateTime(GenericOperation::Le, val, string_type.to_string())
-    } else if let Some(val) = value.gt {
-        FilterValue::DateTime(GenericOperation::Gt, val, string_type.to_string())
-    } else if let Some(val) = value.ge {
-        FilterValue::DateTime(GenericOperation::Ge, val, string_type.to_string())
-    } else {
-        panic!("Unable to compile string input value to a filter")
+/// Compile a filter on a date, time or duration value. Unlike the
+/// other scalar filters, every operator given applies, so that
+/// `{ge: "2000-01-01", lt: "2001-01-01"}` selects a range.
+fn compile_temporal_input_value(
+    base_type: &str,
+    value: &InputValue,
+) -> Result<FilterValue, juniper::FieldError> {
+    let fields = value
+        .to_object_value()
+        .ok_or_else(|| format!("The filter on {base_type} is not an object"))?;
+    let now = chrono::Utc::now().naive_utc();
+    let mut comparisons = Vec::new();
+    for (operator, value) in fields {
+        if value.is_null() {
+            continue;
+        }
+        let value = value
+            .as_string_value()
+            .ok_or_else(|| format!("The value for {operator} is not a string"))?;
+        let op = match operator {
+            "eq" => Some(GenericOperation::Eq),
+            "ne" => Some(GenericOperation::Ne),
+            "lt" => Some(GenericOperation::Lt),
+            "le" => Some(GenericOperation::Le),
+            "gt" => Some(GenericOperation::Gt),
+            "ge" => Some(GenericOperation::Ge),
+            _ => None,
+        };
+        if let Some(op) = op {
+            let entry = parse_temporal_entry(base_type, value)
+                .ok_or_else(|| format!("'{value}' is not a valid {base_type}"))?;
+            comparisons.push((op, entry));
+            continue;
+        }
+
+        let duration =
+            parse_duration(value).ok_or_else(|| format!("'{value}' is not a valid duration"))?;
+        let unsupported = || format!("{operator} is not supported on {base_type}");
+        let relative =
+            |forward| relative_entry(base_type, now, &duration, forward).ok_or_else(unsupported);
+        let current = now_entry(base_type, now).ok_or_else(unsupported)?;
+        match operator {
+            "withinLast" => {
+                comparisons.push((GenericOperation::Ge, relative(false)?));
+                comparisons.push((GenericOperation::Le, current));
+            }
+            "withinNext" => {
+                comparisons.push((GenericOperation::Ge, current));
+                comparisons.push((GenericOperation::Le, relative(true)?));
+            }
+            "olderThan" => comparisons.push((GenericOperation::Lt, relative(false)?)),
+            _ => return Err(format!("Unknown temporal filter operator {operator}").into()),
+        }
+    }
+    if comparisons.is_empty() {
+        return Err(format!("The filter on {base_type} has no operator").into());
     }
+    Ok(FilterValue::Temporal(comparisons, base_type.to_string()))
 }
 
 fn compile_float_input_value(string_type: &str, value: FloatFilterInputObject) -> FilterValue {
@@ -268,70 +357,201 @@ fn compile_boolean_input_value(string_type: &str, value: BooleanFilterInputObjec
     }
 }
 
+fn compile_base_filter(
+    base_type: &str,
+    input_value: &InputValue,
+) -> Result<FilterValue, juniper::FieldError> {
+    Ok(match base_type_kind(base_type) {
+        BaseTypeKind::String => {
+            let value = StringFilterInputObject::from_input_value(input_value);
+            compile_string_input_value(base_type, value.unwrap())?
+        }
+        BaseTypeKind::SmallInteger => {
+            let value = IntFilterInputObject::from_input_value(input_value);
+            compile_small_integer_input_value(base_type, value.unwrap())
+        }
+        BaseTypeKind::BigIntger => {
+            let value = BigIntFilterInputObject::from_input_value(input_value);
+            compile_big_int_input_value(base_type, value.unwrap())
+        }
+        BaseTypeKind::Boolean => {
+            let value = BooleanFilterInputObject::from_input_value(input_value);
+            compile_boolean_input_value(base_type, value.unwrap())
+        }
+        BaseTypeKind::DateTime | BaseTypeKind::Temporal(_) => {
+            compile_temporal_input_value(base_type, input_value)?
+        }
+        BaseTypeKind::Float => {
+            let value = FloatFilterInputObject::from_input_value(input_value);
+            compile_float_input_value(base_type, value.unwrap())
+        }
+        BaseTypeKind::Decimal => {
+            let value = BigFloatFilterInputObject::from_input_value(input_value);
+            compile_decimal_input_value(base_type, value.unwrap())
+        }
+    })
+}
+
+/// Convert a GraphQL input value to the dictionary entry it would be
+/// stored as for the given base type.
+fn input_value_to_entry(base_type: &str, value: &InputValue) -> Option<TypedDictEntry> {
+    let string = || value.as_string_value().map(|s| s.to_string());
+    let int = || i32::from_input_value(value);
+    let integer = || string().and_then(|s| s.parse::<Integer>().ok());
+    match base_type {
+        "boolean" => bool::from_input_value(value).map(|b| bool::make_entry(&b)),
+        "byte" => int()
+            .and_then(|i| i8::try_from(i).ok())
+            .map(|i| i8::make_entry(&i)),
+        "short" => int()
+            .and_then(|i| i16::try_from(i).ok())
+            .map(|i| i16::make_entry(&i)),
+        "int" => int().map(|i| i32::make_entry(&i)),
+        "long" => int().map(|i| i64::make_entry(&(i as i64))),
+        "unsignedByte" => int()
+            .and_then(|i| u8::try_from(i).ok())
+            .map(|i| u8::make_entry(&i)),
+        "unsignedShort" => int()
+            .and_then(|i| u16::try_from(i).ok())
+            .map(|i| u16::make_entry(&i)),
+        "unsignedInt" => int()
+            .and_then(|i| u32::try_from(i).ok())
+            .map(|i| u32::make_entry(&i)),
+        "unsignedLong" => string()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(|i| u64::make_entry(&i)),
+        "integer" => integer().map(|i| Integer::make_entry(&i)),
+        "positiveInteger" => integer().map(|i| PositiveInteger::make_entry(&PositiveInteger(i))),
+        "nonNegativeInteger" => {
+            integer().map(|i| NonNegativeInteger::make_entry(&NonNegativeInteger(i)))
+        }
+        "negativeInteger" => integer().map(|i| NegativeInteger::make_entry(&NegativeInteger(i))),
+        "nonPositiveInteger" => {
+            integer().map(|i| NonPositiveInteger::make_entry(&NonPositiveInteger(i)))
+        }
+        "float" => f64::from_input_value(value).map(|f| f32::make_entry(&(f as f32))),
+        "double" => f64::from_input_value(value).map(|f| f64::make_entry(&f)),
+        "decimal" => string()
+            .and_then(|s| Decimal::new(s).ok())
+            .map(|d| Decimal::make_entry(&d)),
+        "language" => string().map(|s| Language::make_entry(&s)),
+        "normalizedString" => string().map(|s| NormalizedString::make_entry(&s)),
+        "token" => string().map(|s| Token::make_entry(&s)),
+        "NMTOKEN" => string().map(|s| NMToken::make_entry(&s)),
+        "Name" => string().map(|s| Name::make_entry(&s)),
+        "NCName" => string().map(|s| NCName::make_entry(&s)),
+        "anyURI" => string().map(|s| AnyURI::make_entry(&s)),
+        "anySimpleType" => string().map(|s| AnySimpleType::make_entry(&s)),
+        _ if matches!(
+            base_type_kind(base_type),
+            BaseTypeKind::DateTime | BaseTypeKind::Temporal(_)
+        ) =>
+        {
+            parse_temporal_entry(base_type, value.as_string_value()?)
+        }
+        _ => string().map(|s| String::make_entry(&s)),
+    }
+}
+
+/// Pull the given operators out of a filter object. The remainder is
+/// `None` when nothing but these operators was given.
+fn split_filter_operators<'v>(
+    value: &'v InputValue,
+    operators: &[&str],
+) -> (Vec<(&'v str, &'v InputValue)>, Option<InputValue>) {
+    match value {
+        InputValue::Object(o) => {
+            let (split, rest): (Vec<_>, Vec<_>) = o
+                .iter()
+                .partition(|(k, _)| operators.contains(&k.item.as_str()));
+            let split: Vec<_> = split
+                .into_iter()
+                .filter(|(_, v)| !v.item.is_null())
+                .map(|(k, v)| (k.item.as_str(), &v.item))
+                .collect();
+            if rest.is_empty() && !split.is_empty() {
+                (split, None)
+            } else {
+                (
+                    split,
+                    Some(InputValue::Object(rest.into_iter().cloned().collect())),
+                )
+            }
+        }
+        _ => (Vec::new(), Some(value.clone())),
+    }
+}
+
+fn compile_membership_filter(
+    base_type: &str,
+    operator: &str,
+    values: &InputValue,
+) -> Result<FilterValue, juniper::FieldError> {
+    let entries = values
+        .to_list_value()
+        .unwrap_or_else(|| vec![values])
+        .into_iter()
+        .map(|v| {
+            input_value_to_entry(base_type, v)
+                .ok_or_else(|| format!("{v} is not a valid {base_type} for {operator}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(FilterValue::OneOf(operator == "notIn", entries))
+}
+
 fn compile_typed_filter(
     range: &BaseOrDerived<GraphQLName>,
     all_frames: &AllFrames,
-    spanning_input_value: &juniper::Spanning<InputValue>,
-) -> FilterObjectType {
+    input_value: &InputValue,
+) -> Result<FilterObjectType, juniper::FieldError> {
     match range {
-        BaseOrDerived::Base(base_type) => match base_type_kind(base_type) {
-            BaseTypeKind::String => {
-                let value = StringFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_string_input_value(base_type, value.unwrap()))
-            }
-            BaseTypeKind::SmallInteger => {
-                let value = IntFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_small_integer_input_value(
-                    base_type,
-                    value.unwrap(),
-                ))
-            }
-            BaseTypeKind::BigIntger => {
-                let value = BigIntFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_big_int_input_value(base_type, value.unwrap()))
-            }
-            BaseTypeKind::Boolean => {
-                let value = BooleanFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_boolean_input_value(base_type, value.unwrap()))
-            }
-            BaseTypeKind::DateTime => {
-                let value = DateTimeFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_datetime_input_value(base_type, value.unwrap()))
+        BaseOrDerived::Base(base_type) => {
+            let (membership, remainder) =
+                split_filter_operators(input_value, &["in", "notIn", "_isNull"]);
+            let mut filters: Vec<FilterValue> = Vec::with_capacity(membership.len() + 1);
+            for (operator, values) in membership {
+                if operator == "_isNull" {
+                    return Err("_isNull can only be used directly on a field filter".into());
+                }
+                filters.push(compile_membership_filter(base_type, operator, values)?);
             }
-            BaseTypeKind::Float => {
-                let value = FloatFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_float_input_value(base_type, value.unwrap()))
+            if let Some(remainder) = remainder {
+                filters.push(compile_base_filter(base_type, &remainder)?);
             }
-            BaseTypeKind::Decimal => {
-                let value = BigFloatFilterInputObject::from_input_value(&spanning_input_value.item);
-                FilterObjectType::Value(compile_decimal_input_value(base_type, value.unwrap()))
+            if filters.len() == 1 {
+                Ok(FilterObjectType::Value(filters.pop().unwrap()))
+            } else {
+                Ok(FilterObjectType::Value(FilterValue::All(filters)))
             }
-        },
+        }
         BaseOrDerived::Derived(range) => {
             match &all_frames.frames.get(range) {
                 Some(TypeDefinition::Class(class_definition)) => {
-                    if let InputValue::Object(edges) = &spanning_input_value.item {
+                    if let InputValue::Object(edges) = input_value {
                         let inner =
-                            compile_edges_to_filter(range, all_frames, class_definition, edges);
-                        FilterObjectType::Node(Rc::new(inner), range.to_string())
+                            compile_edges_to_filter(range, all_frames, class_definition, edges)?;
+                        Ok(FilterObjectType::Node(Rc::new(inner), range.to_string()))
                     } else {
                         panic!("object filter was not an object")
                     }
                 }
                 Some(TypeDefinition::Enum(_)) => {
-                    let value = EnumFilterInputObject::from_input_value(&spanning_input_value.item)
-                        .unwrap();
-                    let encoded =
-                        all_frames.graphql_enum_value_to_iri_name(range, &value.enum_value.value);
-                    FilterObjectType::Value(FilterValue::Enum {
+                    let value = EnumFilterInputObject::from_input_value(input_value)
+                        .ok_or("enum filter needs one of eq, ne, in or notIn")?;
+                    let values = value
+                        .enum_values
+                        .iter()
+                        .map(|v| all_frames.graphql_enum_value_to_iri_name(range, &v.value))
+                        .collect();
+                    Ok(FilterObjectType::Value(FilterValue::Enum {
                         op: value.op,
-                        value: encoded,
-                    })
+                        values,
+                    }))
                 }
                 None => {
                     // it's a foreign
-                    let IdFilterInputObject { id, ids } =
-                        IdFilterInputObject::from_input_value(&spanning_input_value.item).unwrap();
+                    let IdFilterInputObject { id, ids, .. } =
+                        IdFilterInputObject::from_input_value(input_value).unwrap();
                     let op = match (id, ids) {
                         (Some(id), _) => IdOperation::Equals(id.to_string()),
                         (_, Some(ids)) => {
@@ -342,28 +562,36 @@ fn compile_typed_filter(
                             panic!("input document does not have either an id or a list of ids")
                         }
                     };
-                    FilterObjectType::Value(FilterValue::Foreign(op, range.to_string()))
+                    Ok(FilterObjectType::Value(FilterValue::Foreign(
+                        op,
+                        range.to_string(),
+                    )))
                 }
             }
         }
     }
 }
 
+fn collection_operation(op: &str) -> CollectionOperation {
+    match op {
+        "someHave" => CollectionOperation::SomeHave,
+        "allHave" => CollectionOperation::AllHave,
+        "noneHave" => CollectionOperation::NoneHave,
+        _ => panic!("Unknown collection filter"),
+    }
+}
+
 fn compile_collection_filter(
     collection_filter: CollectionFilterInputObject,
     all_frames: &AllFrames,
     range: &BaseOrDerived<GraphQLName>,
     kind: CollectionKind,
-) -> FilterScope {
+) -> Result<FilterScope, juniper::FieldError> {
     let mut edges = collection_filter.edges;
     if let Some((op, next)) = edges.pop() {
-        let operation = match op.item.as_str() {
-            "someHave" => CollectionOperation::SomeHave,
-            "allHave" => CollectionOperation::AllHave,
-            _ => panic!("Unknown collection filter"),
-        };
-        let object_type = compile_typed_filter(range, all_frames, &next);
-        FilterScope::Collection(kind, operation, object_type)
+        let operation = collection_operation(op.item.as_str());
+        let object_type = compile_typed_filter(range, all_frames, &next.item)?;
+        Ok(FilterScope::Collection(kind, operation, object_type))
     } else {
         panic!("No operation for compiling collection filter")
     }
@@ -374,7 +602,7 @@ fn compile_edges_to_filter(
     all_frames: &AllFrames,
     class_definition: &ClassDefinition,
     edges: &Vec<(juniper::Spanning<String>, juniper::Spanning<InputValue>)>,
-) -> FilterObject {
+) -> Result<FilterObject, juniper::FieldError> {
     let mut result: Vec<(String, FilterScope)> = Vec::with_capacity(edges.len());
     let mut restriction = None;
     let mut ids = Vec::new();
@@ -394,7 +622,7 @@ fn compile_edges_to_filter(
                                     all_frames,
                                     class_definition,
                                     o,
-                                )))
+                                )?))
                             }
                             _ => panic!("We should not have a non object in And-clause"),
                         };
@@ -417,7 +645,7 @@ fn compile_edges_to_filter(
                                     all_frames,
                                     class_definition,
                                     o,
-                                )))
+                                )?))
                             }
                             _ => panic!("We should not have a non object in And-clause"),
                         };
@@ -436,7 +664,7 @@ fn compile_edges_to_filter(
                         all_frames,
                         class_definition,
                         o,
-                    ))),
+                    )?)),
                 )),
                 _ => panic!("We should not have a non object in And-clause"),
             }
@@ -471,40 +699,159 @@ fn compile_edges_to_filter(
                 .expect("id to match on should have been a stringy value")
                 .to_owned();
             ids.push(id);
+        } else if field_name.as_str() == "_isNull" {
+            return Err("_isNull can only be used on a filter for an optional or set field".into());
+        } else if field_name.as_str() == "_path" {
+            let path_filter = PathFilterInputObject::from_input_value(&spanning_input_value.item)
+                .ok_or("_path filter requires a path")?;
+            let path = match parse_path(&path_filter.path) {
+                Ok(("", path)) => path,
+                _ => return Err(format!("'{}' is not a valid path", path_filter.path).into()),
+            };
+            let targets = path_filter
+                .targets
+                .iter()
+                .map(|(class, target_filter)| -> Result<_, juniper::FieldError> {
+                    let target_class = GraphQLName(class.item.to_string().into());
+                    let types = subsumed_type_iris(all_frames, &target_class);
+                    let filter = match &target_filter.item {
+                        InputValue::Object(o) if !o.is_empty() => {
+                            Some(Rc::new(compile_edges_to_filter(
+                                &target_class,
+                                all_frames,
+                                all_frames.frames[&target_class].as_class_definition(),
+                                o,
+                            )?))
+                        }
+                        _ => None,
+                    };
+                    Ok(PathTarget { types, filter })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            result.push((
+                "_path".to_string(),
+                FilterScope::Path(Rc::new(path), Rc::new(targets)),
+            ));
+        } else if let Some(inverted_field) = reverse_filter_to_inverted_field(&field_name) {
+            let link = all_frames
+                .reverse_link(class_name, &inverted_field)
+                .expect("reverse filter on a field that does not link here");
+            let property = all_frames
+                .graphql_property_to_iri(&link.class, &link.property)
+                .expect("reverse filter from a class that is not a document");
+            let kind = match link.kind {
+                FieldKind::Required | FieldKind::Optional => CollectionKind::Property,
+                kind => CollectionKind::try_from(kind).unwrap(),
+            };
+            let types = subsumed_type_iris(all_frames, &link.class);
+            let referrer_definition = all_frames.frames[&link.class].as_class_definition();
+            let collection_filter =
+                CollectionFilterInputObject::from_input_value(&spanning_input_value.item)
+                    .ok_or("reverse filter should be a collection filter")?;
+            // null operations are left out, like for any other filter
+            for (op, next) in collection_filter
+                .edges
+                .iter()
+                .filter(|(_, next)| !next.item.is_null())
+            {
+                let (operation, sub_filter) = if op.item == "_isNull" {
+                    // nothing linking here is the same as none of the
+                    // referrers matching the empty filter.
+                    let is_null =
+                        bool::from_input_value(&next.item).ok_or("_isNull takes a boolean")?;
+                    let operation = if is_null {
+                        CollectionOperation::NoneHave
+                    } else {
+                        CollectionOperation::SomeHave
+                    };
+                    let empty = FilterObject {
+                        restriction: None,
+                        edges: Vec::new(),
+                        ids: Vec::new(),
+                    };
+                    (operation, empty)
+                } else {
+                    let edges = match &next.item {
+                        InputValue::Object(o) => o,
+                        _ => return Err("reverse filter operation expects a filter object".into()),
+                    };
+                    let sub_filter = compile_edges_to_filter(
+                        &link.class,
+                        all_frames,
+                        referrer_definition,
+                        edges,
+                    )?;
+                    (collection_operation(op.item.as_str()), sub_filter)
+                };
+                result.push((
+                    property.to_string(),
+                    FilterScope::Reverse(kind, operation, types.clone(), Rc::new(sub_filter)),
+                ));
+            }
         } else {
             let field = class_definition.resolve_field(&field_name);
             let prefixes = &all_frames.context;
             let property = class_definition.graphql_to_iri_name(prefixes, &field_name);
             let range = field.range();
             let kind = field.kind();
+            let (is_null, remainder) =
+                split_filter_operators(&spanning_input_value.item, &["_isNull"]);
+            for (_, is_null) in is_null {
+                let is_null = bool::from_input_value(is_null).ok_or("_isNull takes a boolean")?;
+                let collection_kind = match kind {
+                    FieldKind::Required => {
+                        return Err(
+                            "_isNull can only be used on a filter for an optional or set field"
+                                .into(),
+                        )
+                    }
+                    FieldKind::Optional => CollectionKind::Property,
+                    _ => CollectionKind::try_from(kind).unwrap(),
+                };
+                result.push((
+                    property.to_string(),
+                    FilterScope::IsNull(collection_kind, is_null),
+                ));
+            }
+            let remainder = match remainder {
+                Some(remainder) => remainder,
+                None => continue,
+            };
             match kind {
                 FieldKind::Required | FieldKind::Optional => {
-                    let res = compile_typed_filter(range, all_frames, spanning_input_value);
+                    let res = compile_typed_filter(range, all_frames, &remainder)?;
                     result.push((property.to_string(), FilterScope::Required(res)));
                 }
                 FieldKind::Set | FieldKind::List | FieldKind::Array | FieldKind::Cardinality => {
-                    let value =
-                        CollectionFilterInputObject::from_input_value(&spanning_input_value.item);
+                    let value = CollectionFilterInputObject::from_input_value(&remainder);
                     let kind = CollectionKind::try_from(kind).unwrap();
                     let filter_value =
-                        compile_collection_filter(value.unwrap(), all_frames, range, kind);
+                        compile_collection_filter(value.unwrap(), all_frames, range, kind)?;
                     result.push((property.to_string(), filter_value))
                 }
             }
         }
     }
-    FilterObject {
+    Ok(FilterObject {
         restriction,
         edges: result,
         ids,
-    }
+    })
+}
+
+fn subsumed_type_iris(all_frames: &AllFrames, class: &GraphQLName) -> Vec<String> {
+    all_frames
+        .subsumed(class)
+        .into_iter()
+        .map(|c| all_frames.graphql_to_iri_name(&c).to_string())
+        .collect()
 }
 
 fn compile_filter_object(
     class_name: &GraphQLName,
     all_frames: &AllFrames,
     filter_input: &FilterInputObject,
-) -> FilterObject {
+) -> Result<FilterObject, juniper::FieldError> {
     let class_definition: &ClassDefinition = all_frames.frames[class_name].as_class_definition();
     let edges = &filter_input.edges;
     compile_edges_to_filter(class_name, all_frames, class_definition, edges)
@@ -631,6 +978,24 @@ fn object_type_filter<'a>(
                     regexset.is_match(&string)
                 }))
             }
+            TextOperation::Approximate(matcher) => {
+                // Many documents share the same string, so remember
+                // the outcome per dictionary entry.
+                let seen: Rc<RefCell<HashMap<u64, bool>>> = Default::default();
+                let g = g.clone();
+                ClonableIterator::new(iter.filter(move |object| {
+                    if let Some(matches) = seen.borrow().get(object) {
+                        return *matches;
+                    }
+                    let string = g
+                        .id_object_value(*object)
+                        .unwrap()
+                        .as_val::<String, String>();
+                    let matches = matcher.matches(&string);
+                    seen.borrow_mut().insert(*object, matches);
+                    matches
+                }))
+            }
         },
         FilterValue::SmallInt(op, i, _) => {
             let op = *op;
@@ -686,15 +1051,13 @@ fn object_type_filter<'a>(
                 ordering_matches_op(cmp, op)
             }))
         }
-        FilterValue::DateTime(op, DateTime(val), _) => {
-            let op = *op;
-            let val = val.clone();
+        FilterValue::Temporal(comparisons, _) => {
             let g = g.clone();
             ClonableIterator::new(iter.filter(move |object| {
                 let object_value = g.id_object_value(*object).expect("Object value must exist");
-                let object_string = value_to_string(&object_value).to_string();
-                let cmp = object_string.cmp(&val);
-                ordering_matches_op(cmp, op)
+                comparisons
+                    .iter()
+                    .all(|(op, entry)| ordering_matches_op(object_value.cmp(entry), *op))
             }))
         }
         FilterValue::String(op, val, _) => {
@@ -708,19 +1071,49 @@ fn object_type_filter<'a>(
                 ordering_matches_op(cmp, op)
             }))
         }
-        FilterValue::Enum { op, value } => {
-            let op = *op;
-            match g.object_node_id(value.as_str()) {
-                Some(object_id) => ClonableIterator::new(iter.filter(move |object| match op {
-                    EnumOperation::Eq => *object == object_id,
-                    EnumOperation::Ne => *object != object_id,
-                })),
-                None => match op {
-                    EnumOperation::Eq => ClonableIterator::new(std::iter::empty()),
-                    EnumOperation::Ne => iter,
-                },
+        FilterValue::Enum { op, values } => {
+            let object_ids: Rc<HashSet<u64>> = Rc::new(
+                values
+                    .iter()
+                    .filter_map(|value| g.object_node_id(value.as_str()))
+                    .collect(),
+            );
+            match op {
+                EnumOperation::Eq if object_ids.is_empty() => {
+                    ClonableIterator::new(std::iter::empty())
+                }
+                EnumOperation::Eq => {
+                    ClonableIterator::new(iter.filter(move |object| object_ids.contains(object)))
+                }
+                EnumOperation::Ne if object_ids.is_empty() => iter,
+                EnumOperation::Ne => {
+                    ClonableIterator::new(iter.filter(move |object| !object_ids.contains(object)))
+                }
+            }
+        }
+        FilterValue::OneOf(negated, entries) => {
+            let negated = *negated;
+            let object_ids: Rc<HashSet<u64>> = Rc::new(
+                entries
+                    .iter()
+                    .filter_map(|entry| g.object_value_id(entry))
+                    .collect(),
+            );
+            if object_ids.is_empty() {
+                if negated {
+                    iter
+                } else {
+                    ClonableIterator::new(std::iter::empty())
+                }
+            } else {
+                ClonableIterator::new(
+                    iter.filter(move |object| object_ids.contains(object) != negated),
+                )
             }
         }
+        FilterValue::All(filters) => filters
+            .iter()
+            .fold(iter, |iter, filter| object_type_filter(g, filter, iter)),
         FilterValue::Foreign(op, _) => match op {
             IdOperation::Equals(val) => match g.object_node_id(val.as_str()) {
                 Some(object_id) => {
@@ -831,6 +1224,18 @@ fn compile_query<'a>(
                     return ClonableIterator::new(std::iter::empty());
                 }
             }
+            FilterScope::IsNull(kind, is_null) => {
+                let kind = *kind;
+                let is_null = *is_null;
+                if let Some(property_id) = g.predicate_id(predicate) {
+                    iter = ClonableIterator::new(iter.filter(move |subject| {
+                        let mut objects = collection_kind_iterator(g, kind, *subject, property_id);
+                        objects.next().is_none() == is_null
+                    }));
+                } else if !is_null {
+                    return ClonableIterator::new(std::iter::empty());
+                }
+            }
             FilterScope::Collection(kind, op, o) => {
                 let kind = *kind;
                 let maybe_property_id = g.predicate_id(predicate);
@@ -896,11 +1301,133 @@ fn compile_query<'a>(
                                 }));
                             }
                         },
+                        CollectionOperation::NoneHave => match o {
+                            FilterObjectType::Node(sub_filter, _) => {
+                                let sub_filter = sub_filter.clone();
+                                iter = ClonableIterator::new(iter.filter(move |subject| {
+                                    let objects =
+                                        collection_kind_iterator(g, kind, *subject, property_id);
+                                    compile_query(
+                                        context,
+                                        g,
+                                        all_frames,
+                                        sub_filter.clone(),
+                                        objects,
+                                    )
+                                    .next()
+                                    .is_none()
+                                }));
+                            }
+                            FilterObjectType::Value(filter_type) => {
+                                let filter_type = filter_type.clone();
+                                iter = ClonableIterator::new(iter.filter(move |subject| {
+                                    let objects =
+                                        collection_kind_iterator(g, kind, *subject, property_id);
+                                    object_type_filter(g, &filter_type, objects)
+                                        .next()
+                                        .is_none()
+                                }));
+                            }
+                        },
                     }
-                } else {
+                } else if !matches!(op, CollectionOperation::NoneHave) {
                     return ClonableIterator::new(std::iter::empty());
                 }
             }
+            FilterScope::Reverse(kind, op, types, sub_filter) => {
+                let kind = *kind;
+                let op = *op;
+                let rdf_type_id = match (g.predicate_id(RDF_TYPE), g.predicate_id(predicate)) {
+                    (Some(rdf_type_id), Some(_)) => rdf_type_id,
+                    _ => {
+                        // nothing links here at all
+                        if let CollectionOperation::SomeHave = op {
+                            return ClonableIterator::new(std::iter::empty());
+                        }
+                        continue;
+                    }
+                };
+                let type_ids: Vec<u64> = types.iter().flat_map(|t| g.subject_id(t)).collect();
+                let predicate = predicate.clone();
+                let sub_filter = sub_filter.clone();
+                iter = ClonableIterator::new(iter.filter(move |object| {
+                    let referrers: Vec<u64> = iterator_from_path_and_ids(
+                        g,
+                        &all_frames.context,
+                        vec![PathEdgeType::new(&predicate, kind)],
+                        std::iter::once(*object),
+                    )
+                    .filter(|referrer| {
+                        g.single_triple_sp(*referrer, rdf_type_id)
+                            .map(|t| type_ids.contains(&t.object))
+                            .unwrap_or(false)
+                    })
+                    // referrers we can't see can't be used to filter on
+                    .filter(|referrer| context.access_policies.document_visible(context, *referrer))
+                    .collect();
+                    let mut matching = compile_query(
+                        context,
+                        g,
+                        all_frames,
+                        sub_filter.clone(),
+                        ClonableIterator::new(referrers.clone().into_iter()),
+                    );
+                    match op {
+                        CollectionOperation::SomeHave => matching.next().is_some(),
+                        CollectionOperation::AllHave => matching.count() == referrers.len(),
+                        CollectionOperation::NoneHave => matching.next().is_none(),
+                    }
+                }));
+            }
+            FilterScope::Path(path, targets) => {
+                let path = path.clone();
+                let targets = targets.clone();
+                let rdf_type_id = g.predicate_id(RDF_TYPE);
+                let target_types: Vec<Vec<u64>> = targets
+                    .iter()
+                    .map(|target| target.types.iter().flat_map(|t| g.subject_id(t)).collect())
+                    .collect();
+                iter = ClonableIterator::new(iter.filter(move |subject| {
+                    let mut reached = compile_path(
+                        g,
+                        all_frames.context.clone(),
+                        (*path).clone(),
+                        ClonableIterator::new(std::iter::once(*subject)),
+                    )
+                    .unique()
+                    .filter(|node| context.access_policies.document_visible(context, *node));
+                    if targets.is_empty() {
+                        return reached.next().is_some();
+                    }
+                    let rdf_type_id = match rdf_type_id {
+                        Some(rdf_type_id) => rdf_type_id,
+                        None => return false,
+                    };
+                    reached.any(|node| {
+                        let node_type = match g.single_triple_sp(node, rdf_type_id) {
+                            Some(t) => t.object,
+                            None => return false,
+                        };
+                        targets
+                            .iter()
+                            .zip(target_types.iter())
+                            .any(|(target, types)| {
+                                types.contains(&node_type)
+                                    && target.filter.as_ref().map_or(true, |filter| {
+                                        compile_query(
+                                            context,
+                                            g,
+                                            all_frames,
+                                            filter.clone(),
+                                            ClonableIterator::new(std::iter::once(node)),
+                                        )
+                                        .next()
+                                        .is_some()
+                                    })
+                            })
+                    })
+                }));
+            }
         }
     }
     iter
@@ -1103,9 +1630,12 @@ impl FilterScope {
         match &self {
             FilterScope::Required(_) => Some(CollectionKind::Property),
             FilterScope::Collection(kind, _, _) => Some(*kind),
+            FilterScope::IsNull(_, _) => None,
             FilterScope::And(_) => None,
             FilterScope::Or(_) => None,
             FilterScope::Not(_) => None,
+            FilterScope::Reverse(_, _, _, _) => None,
+            FilterScope::Path(_, _) => None,
         }
     }
 }
@@ -1128,7 +1658,11 @@ fn generate_iterator_from_edges<'a, 'b>(
     for (name, e) in cur.1.edges.iter() {
         match e {
             FilterScope::Required(FilterObjectType::Value(value))
-            | FilterScope::Collection(_, _, FilterObjectType::Value(value)) => {
+            | FilterScope::Collection(
+                _,
+                CollectionOperation::SomeHave,
+                FilterObjectType::Value(value),
+            ) => {
                 let kind = e.kind().unwrap();
                 if let Some(entry) = filter_value_to_entry(value) {
                     let id_opt = g.object_value_id(&entry);
@@ -1145,6 +1679,44 @@ fn generate_iterator_from_edges<'a, 'b>(
                         components,
                         [id].into_iter(),
                     ));
+                } else if let FilterValue::Text(
+                    TextOperation::Approximate(TextMatcher::SimilarTo {
+                        value: similar_to,
+                        threshold,
+                    }),
+                    _,
+                ) = value
+                {
+                    let index = match g.predicate_id(name).and_then(|p| trigram_index(g, p)) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    let mut components = cur.0.clone();
+                    components.push(PathEdgeType::new(name, kind));
+
+                    return Some(iterator_from_path_and_ids(
+                        g,
+                        prefixes,
+                        components,
+                        index.similar_to(similar_to, *threshold).into_iter(),
+                    ));
+                } else if let FilterValue::OneOf(false, entries) = value {
+                    let ids: Vec<u64> = entries
+                        .iter()
+                        .filter_map(|entry| g.object_value_id(entry))
+                        .collect();
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    let mut components = cur.0.clone();
+                    components.push(PathEdgeType::new(name, kind));
+
+                    return Some(iterator_from_path_and_ids(
+                        g,
+                        prefixes,
+                        components,
+                        ids.into_iter(),
+                    ));
                 }
             }
             _ => {}
@@ -1214,11 +1786,18 @@ fn lookup_by_filter<'a>(
         zero_iter,
         includes_children,
     );
-    if let Some(continuation_filter) = continuation_filter_opt {
+    let iterator = if let Some(continuation_filter) = continuation_filter_opt {
         let continuation_filter = Rc::new(continuation_filter);
         compile_query(context, g, all_frames, continuation_filter, iterator)
     } else {
         iterator
+    };
+    if context.access_policies.is_empty() {
+        iterator
+    } else {
+        ClonableIterator::new(
+            iterator.filter(move |id| context.access_policies.document_visible(context, *id)),
+        )
     }
 }
 
@@ -1229,7 +1808,7 @@ pub fn run_filter_query<'a>(
     class_name: &'a GraphQLName<'a>,
     all_frames: &'a AllFrames,
     zero_iter: Option<ClonableIterator<'a, u64>>,
-) -> Vec<u64> {
+) -> Result<Vec<u64>, juniper::FieldError> {
     let new_zero_iter: Option<ClonableIterator<'a, u64>> =
         match (arguments.get::<ID>("id"), arguments.get::<Vec<ID>>("ids")) {
             (Some(id_string), None) => match zero_iter {
@@ -1291,142 +1870,417 @@ pub fn run_filter_query<'a>(
     let limit: Option<i32> = arguments.get("limit");
     let filter_arg_opt: Option<FilterInputObject> = arguments.get("filter");
     let filter = filter_arg_opt
-        .map(|filter_input| compile_filter_object(class_name, all_frames, &filter_input));
+        .map(|filter_input| compile_filter_object(class_name, all_frames, &filter_input))
+        .transpose()?;
     let includes_children = include_children(arguments);
-    let it: ClonableIterator<'a, u64> =
-        if let Some(TerminusOrderBy { fields }) = arguments.get::<TerminusOrderBy>("orderBy") {
-            let mut results: Vec<u64> = lookup_by_filter(
-                context,
-                g,
-                class_name,
-                all_frames,
-                filter,
-                new_zero_iter,
-                includes_children,
-            )
-            .unique()
-            .collect();
-            results.sort_by_cached_key(|id| {
-                create_query_order_key(g, all_frames, class_name, *id, &fields)
-            });
-            // Probs should not be into_iter(), done to satisfy both arms of let symmetry
-            // better to borrow in the other branch?
-            ClonableIterator::new(
-                results
-                    .into_iter()
-                    .skip(usize::try_from(offset).unwrap_or(0)),
-            )
-        } else {
-            ClonableIterator::new(
-                lookup_by_filter(
-                    context,
-                    g,
-                    class_name,
-                    all_frames,
-                    filter,
-                    new_zero_iter,
-                    includes_children,
-                )
-                .skip(usize::try_from(offset).unwrap_or(0)),
-            )
-        };
+    if let Some(TerminusOrderBy { fields }) = arguments.get::<TerminusOrderBy>("orderBy") {
+        let conditions = filter
+            .as_ref()
+            .map(similarity_conditions)
+            .unwrap_or_default();
+        let specs = compile_order_specs(g, all_frames, class_name, &fields, &conditions)?;
+        let ids = lookup_by_filter(
+            context,
+            g,
+            class_name,
+            all_frames,
+            filter,
+            new_zero_iter,
+            includes_children,
+        )
+        .unique();
+        return order_ids(
+            g,
+            all_frames,
+            &specs,
+            ids,
+            usize::try_from(offset).unwrap_or(0),
+            limit.map(|limit| usize::try_from(limit).unwrap_or(0)),
+        );
+    }
+
+    let it = lookup_by_filter(
+        context,
+        g,
+        class_name,
+        all_frames,
+        filter,
+        new_zero_iter,
+        includes_children,
+    )
+    .skip(usize::try_from(offset).unwrap_or(0));
 
     if let Some(limit) = limit {
-        it.take(usize::try_from(limit).unwrap_or(0)).collect()
+        Ok(it.take(usize::try_from(limit).unwrap_or(0)).collect())
     } else {
-        it.collect()
+        Ok(it.collect())
     }
 }
 
+/// Collect the approximate string matches on the document itself, to
+/// order by with `_similarity`.
+fn similarity_conditions(filter: &FilterObject) -> Vec<(String, TextMatcher)> {
+    let mut result = Vec::new();
+    for (predicate, scope) in filter.edges.iter() {
+        match scope {
+            FilterScope::Required(FilterObjectType::Value(FilterValue::Text(
+                TextOperation::Approximate(matcher),
+                _,
+            )))
+            | FilterScope::Collection(
+                CollectionKind::Property,
+                CollectionOperation::SomeHave,
+                FilterObjectType::Value(FilterValue::Text(TextOperation::Approximate(matcher), _)),
+            ) => result.push((predicate.clone(), matcher.clone())),
+            FilterScope::And(filters) => {
+                for filter in filters.iter() {
+                    result.extend(similarity_conditions(filter));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
 fn include_children(arguments: &juniper::Arguments) -> bool {
     arguments.get("include_children").unwrap_or(true)
 }
 
-fn create_query_order_key(
-    g: &SyncStoreLayer,
-    all_frames: &AllFrames,
-    class: &GraphQLName,
-    id: u64,
-    order_desc: &[(GraphQLName, TerminusOrdering)],
-) -> QueryOrderKey {
-    let vec: Vec<_> = order_desc
-        .iter()
-        .filter_map(|(property, ordering)| {
-            let predicate = all_frames.graphql_property_to_iri(class, property)?;
-            let predicate_id = g.predicate_id(predicate.as_str())?;
-            let res = g.single_triple_sp(id, predicate_id).map(move |t| {
-                g.id_object(t.object)
-                    .expect("This object must exist")
-                    .value()
-                    .unwrap()
-            });
-            Some((res, *ordering))
-        })
-        .collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::Spanning;
+    use swipl::prelude::*;
+    use terminusdb_store_prolog::terminus_store::open_sync_memory_store;
 
-    QueryOrderKey { vec }
-}
+    use crate::graphql::frame::UncleanAllFrames;
+    use crate::terminus_store::layer::ValueTriple;
 
-struct QueryOrderKey {
-    vec: Vec<(Option<TypedDictEntry>, TerminusOrdering)>,
-}
+    fn object(fields: Vec<(&str, InputValue)>) -> InputValue {
+        InputValue::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (Spanning::unlocated(k.to_string()), Spanning::unlocated(v)))
+                .collect(),
+        )
+    }
 
-impl PartialEq for QueryOrderKey {
-    fn eq(&self, other: &QueryOrderKey) -> bool {
-        for i in 0..self.vec.len() {
-            let (option_value, _) = &self.vec[i];
-            let (other_value, _) = &other.vec[i];
-            let res = match option_value {
-                Some(tde) => {
-                    Ordering::Equal
-                        == (match other_value {
-                            None => Ordering::Greater,
-                            Some(value) => tde.cmp(value),
-                        })
-                }
-                x => x == other_value,
-            };
-            if !res {
-                return false;
+    fn pet_frames() -> AllFrames {
+        let engine = Engine::new();
+        let activation = engine.activate();
+        let context: Context<_> = activation.into();
+
+        let term = r#"json{
+                   '@context': json{'@base':"terminusdb:///data/",'@schema':"terminusdb:///schema#",'@type':'Context'},
+                   'Colour' : json{ '@type' : "Enum", '@values' : [red, green]},
+                   'Pet' : json{ '@type' : "Class",
+                                 name : "xsd:string",
+                                 nickname : json{ '@type' : "Optional", '@class' : "xsd:string"},
+                                 colour : json{ '@type' : "Optional", '@class' : "Colour"},
+                                 toys : json{ '@type' : "Set", '@class' : "xsd:string"}},
+                   'Owner' : json{ '@type' : "Class",
+                                   pets : json{ '@type' : "Set", '@class' : "Pet"}}}"#;
+        let term = unwrap_result(&context, context.term_from_string(term));
+        let pre_allframes: UncleanAllFrames = context.deserialize_from_term(&term).unwrap();
+        pre_allframes.finalize()
+    }
+
+    fn compile_pet_filter(filter: InputValue) -> Result<FilterObject, juniper::FieldError> {
+        let allframes = pet_frames();
+        let class_name = GraphQLName("Pet".into());
+        let class_definition = allframes.frames[&class_name].as_class_definition();
+        match filter {
+            InputValue::Object(edges) => {
+                compile_edges_to_filter(&class_name, &allframes, class_definition, &edges)
             }
+            _ => panic!("filter should be an object"),
         }
-        true
     }
-}
 
-impl Eq for QueryOrderKey {}
-
-impl Ord for QueryOrderKey {
-    fn cmp(&self, other: &QueryOrderKey) -> Ordering {
-        for i in 0..self.vec.len() {
-            let (option_value, order) = &self.vec[i];
-            let (other_value, _) = &other.vec[i];
-            let res = match option_value {
-                Some(tde) => match other_value {
-                    None => Ordering::Greater,
-                    Some(value) => tde.cmp(value),
-                },
-                None => match other_value {
-                    Some(_) => Ordering::Less,
-                    None => Ordering::Equal,
-                },
-            };
+    #[test]
+    fn is_null_compiles_on_optional_and_set_fields() {
+        let is_null = || object(vec![("_isNull", InputValue::scalar(true))]);
+        for field in ["nickname", "colour", "toys"] {
+            let filter = compile_pet_filter(object(vec![(field, is_null())])).unwrap();
+            assert!(matches!(
+                filter.edges.as_slice(),
+                [(_, FilterScope::IsNull(CollectionKind::Property, true))]
+            ));
+        }
 
-            let final_order = match order {
-                TerminusOrdering::Desc => res.reverse(),
-                TerminusOrdering::Asc => res,
-            };
+        let filter = compile_pet_filter(object(vec![(
+            "colour",
+            object(vec![
+                ("_isNull", InputValue::scalar(false)),
+                ("eq", InputValue::enum_value("red")),
+            ]),
+        )]))
+        .unwrap();
+        assert_eq!(2, filter.edges.len());
+    }
+
+    #[test]
+    fn is_null_on_a_required_field_is_an_error() {
+        let is_null = || object(vec![("_isNull", InputValue::scalar(true))]);
+        assert!(compile_pet_filter(object(vec![("name", is_null())])).is_err());
+        assert!(compile_pet_filter(object(vec![("_isNull", InputValue::scalar(true))])).is_err());
+        assert!(compile_pet_filter(object(vec![(
+            "toys",
+            object(vec![("someHave", is_null())])
+        )]))
+        .is_err());
+    }
+
+    #[test]
+    fn null_is_null_filters_are_left_out() {
+        let is_null = || object(vec![("_isNull", InputValue::null())]);
+        assert!(compile_pet_filter(object(vec![("nickname", is_null())])).is_ok());
+        let filter =
+            compile_pet_filter(object(vec![("_reverse_pets_of_Owner", is_null())])).unwrap();
+        assert!(filter.edges.is_empty());
+
+        let filter = compile_pet_filter(object(vec![(
+            "_reverse_pets_of_Owner",
+            object(vec![("_isNull", InputValue::scalar(true))]),
+        )]))
+        .unwrap();
+        assert!(matches!(
+            filter.edges.as_slice(),
+            [(
+                _,
+                FilterScope::Reverse(_, CollectionOperation::NoneHave, _, _)
+            )]
+        ));
+        assert!(compile_pet_filter(object(vec![(
+            "_reverse_pets_of_Owner",
+            object(vec![("_isNull", InputValue::scalar("yes"))]),
+        )]))
+        .is_err());
+        assert!(compile_pet_filter(object(vec![(
+            "_reverse_pets_of_Owner",
+            object(vec![("someHave", InputValue::scalar(1))]),
+        )]))
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_paths_are_errors() {
+        let path_filter = |path: &str| {
+            compile_pet_filter(object(vec![(
+                "_path",
+                object(vec![
+                    ("path", InputValue::scalar(path)),
+                    ("Pet", object(vec![])),
+                ]),
+            )]))
+        };
+        assert!(path_filter("toys").is_ok());
+        assert_eq!(
+            "'(toys' is not a valid path",
+            path_filter("(toys").unwrap_err().message()
+        );
+        assert!(path_filter("toys)").is_err());
+        assert!(compile_pet_filter(object(vec![("_path", InputValue::scalar(1))])).is_err());
+    }
+
+    #[test]
+    fn enum_filter_ignores_is_null() {
+        let filter = object(vec![
+            ("_isNull", InputValue::scalar(false)),
+            ("ne", InputValue::enum_value("green")),
+        ]);
+        let value = EnumFilterInputObject::from_input_value(&filter).unwrap();
+        assert_eq!(EnumOperation::Ne, value.op);
+        assert!(EnumFilterInputObject::from_input_value(&object(vec![(
+            "_isNull",
+            InputValue::scalar(true)
+        )]))
+        .is_none());
+    }
+
+    fn pet_layer() -> SyncStoreLayer {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let pet = "terminusdb:///schema#Pet";
+        let toys = "terminusdb:///schema#toys";
+        let triples = [
+            ValueTriple::new_node("terminusdb:///data/Pet/rex", RDF_TYPE, pet),
+            ValueTriple::new_value(
+                "terminusdb:///data/Pet/rex",
+                toys,
+                String::make_entry("ball"),
+            ),
+            ValueTriple::new_node("terminusdb:///data/Pet/tom", RDF_TYPE, pet),
+            ValueTriple::new_value(
+                "terminusdb:///data/Pet/tom",
+                toys,
+                String::make_entry("mouse"),
+            ),
+        ];
+        for triple in triples {
+            builder.add_value_triple(triple).unwrap();
+        }
+
+        builder.commit().unwrap()
+    }
+
+    fn initial_pets(layer: &SyncStoreLayer, operation: &str) -> Vec<String> {
+        let allframes = pet_frames();
+        let class_name = GraphQLName("Pet".into());
+        let filter = compile_pet_filter(object(vec![(
+            "toys",
+            object(vec![(
+                operation,
+                object(vec![("eq", InputValue::scalar("ball"))]),
+            )]),
+        )]))
+        .unwrap();
+        let (_, iter) =
+            generate_initial_iterator(layer, &class_name, &allframes, Some(filter), None, false);
+        let mut pets: Vec<_> = iter.map(|id| layer.id_subject(id).unwrap()).collect();
+        pets.sort();
+        pets
+    }
 
-            if !final_order.is_eq() {
-                return final_order;
+    #[test]
+    fn only_positive_collection_filters_seed_the_iterator() {
+        let layer = pet_layer();
+        assert_eq!(
+            vec!["terminusdb:///data/Pet/rex"],
+            initial_pets(&layer, "someHave")
+        );
+        // seeding from the pets that do have a ball would leave nothing
+        // for noneHave to keep
+        assert_eq!(
+            vec!["terminusdb:///data/Pet/rex", "terminusdb:///data/Pet/tom"],
+            initial_pets(&layer, "noneHave")
+        );
+        assert_eq!(
+            vec!["terminusdb:///data/Pet/rex", "terminusdb:///data/Pet/tom"],
+            initial_pets(&layer, "allHave")
+        );
+    }
+
+    #[test]
+    fn invalid_temporal_filters_are_errors() {
+        let filter = |operator: &str, value: &str| {
+            let filter = object(vec![(operator, InputValue::scalar(value))]);
+            compile_temporal_input_value("date", &filter)
+        };
+        assert!(filter("ge", "2000-01-01").is_ok());
+        assert_eq!(
+            "'2000-13-01' is not a valid date",
+            filter("ge", "2000-13-01").unwrap_err().message()
+        );
+        assert!(filter("withinLast", "P1D").is_ok());
+        assert!(filter("withinLast", "one day").is_err());
+
+        let filter = object(vec![("withinLast", InputValue::scalar("P1Y"))]);
+        assert!(compile_temporal_input_value("gYear", &filter).is_err());
+    }
+
+    #[test]
+    fn negative_fuzzy_distance_is_an_error() {
+        let filter = |distance: i32| {
+            let filter = object(vec![(
+                "fuzzy",
+                object(vec![
+                    ("value", InputValue::scalar("colour")),
+                    ("maxDistance", InputValue::scalar(distance)),
+                ]),
+            )]);
+            compile_base_filter("string", &filter)
+        };
+        assert!(filter(1).is_ok());
+        assert!(filter(-1).is_err());
+    }
+
+    #[test]
+    fn split_membership_operators() {
+        let filter = object(vec![
+            (
+                "in",
+                InputValue::list(vec![InputValue::scalar(1), InputValue::scalar(2)]),
+            ),
+            ("lt", InputValue::scalar(3)),
+            ("notIn", InputValue::null()),
+        ]);
+        let (split, remainder) = split_filter_operators(&filter, &["in", "notIn"]);
+        assert_eq!(
+            vec!["in"],
+            split.iter().map(|(k, _)| *k).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(object(vec![("lt", InputValue::scalar(3))])), remainder);
+
+        let filter = object(vec![("_isNull", InputValue::scalar(true))]);
+        let (split, remainder) = split_filter_operators(&filter, &["_isNull"]);
+        assert_eq!(1, split.len());
+        assert_eq!(None, remainder);
+    }
+
+    #[test]
+    fn membership_values_use_the_stored_datatype() {
+        let value = InputValue::scalar(12);
+        assert_eq!(
+            Some(i64::make_entry(&12)),
+            input_value_to_entry("long", &value)
+        );
+        assert_eq!(
+            Some(u8::make_entry(&12)),
+            input_value_to_entry("unsignedByte", &value)
+        );
+        assert_eq!(
+            None,
+            input_value_to_entry("unsignedByte", &InputValue::scalar(-1))
+        );
+
+        let value = InputValue::scalar("foo");
+        assert_eq!(
+            Some(Token::make_entry(&"foo".to_string())),
+            input_value_to_entry("token", &value)
+        );
+        assert_eq!(
+            Some(String::make_entry(&"foo".to_string())),
+            input_value_to_entry("string", &value)
+        );
+    }
+
+    #[test]
+    fn membership_filter_can_be_negated() {
+        let values = InputValue::list(vec![InputValue::scalar("a"), InputValue::scalar("b")]);
+        match compile_membership_filter("string", "notIn", &values).unwrap() {
+            FilterValue::OneOf(negated, entries) => {
+                assert!(negated);
+                assert_eq!(2, entries.len());
             }
+            other => panic!("unexpected filter {other:?}"),
         }
-        Ordering::Equal
     }
-}
 
-impl PartialOrd for QueryOrderKey {
-    fn partial_cmp(&self, other: &QueryOrderKey) -> Option<Ordering> {
-        Some(self.cmp(other))
+    #[test]
+    fn membership_values_of_the_wrong_type_are_errors() {
+        let values = InputValue::list(vec![InputValue::scalar(1), InputValue::scalar(300)]);
+        assert!(compile_membership_filter("byte", "in", &values).is_err());
+        assert!(compile_membership_filter("short", "in", &values).is_ok());
+
+        let values = InputValue::list(vec![InputValue::scalar("ten")]);
+        assert!(compile_membership_filter("decimal", "notIn", &values).is_err());
+    }
+
+    #[test]
+    fn reverse_filter_names_round_trip() {
+        let inverted = GraphQLName("_author_of_Book".into());
+        let filter_name = super::super::naming::reverse_filter_name(&inverted);
+        assert_eq!(filter_name.as_str(), "_reverse_author_of_Book");
+        assert_eq!(
+            reverse_filter_to_inverted_field(&filter_name)
+                .unwrap()
+                .as_str(),
+            "_author_of_Book"
+        );
+        assert!(reverse_filter_to_inverted_field(&GraphQLName("_reverse".into())).is_none());
+        assert!(reverse_filter_to_inverted_field(&GraphQLName("reverse_x".into())).is_none());
     }
 }
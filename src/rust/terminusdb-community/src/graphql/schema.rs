@@ -4,8 +4,8 @@ use std::sync::Arc;
 
 use juniper::meta::{DeprecationStatus, EnumValue, Field};
 use juniper::{
-    graphql_value, DefaultScalarValue, FromInputValue, GraphQLEnum, GraphQLType, GraphQLValue,
-    InputValue, Registry, Value, ID,
+    graphql_value, DefaultScalarValue, FromInputValue, GraphQLEnum, GraphQLInputObject,
+    GraphQLType, GraphQLValue, InputValue, Registry, Value, ID,
 };
 use lazy_init::Lazy;
 use swipl::prelude::*;
@@ -14,19 +14,28 @@ use terminusdb_store_prolog::terminus_store::store::sync::SyncStoreLayer;
 use terminusdb_store_prolog::terminus_store::{IdTriple, Layer, ObjectType};
 
 use crate::consts::{RDF_FIRST, RDF_NIL, RDF_REST, RDF_TYPE, SYS_VALUE};
+use crate::doc::canonical::{content_hash, MerkleHasher};
+use crate::doc::integrity::find_broken_references;
+use crate::doc::shape::DocumentShape;
 use crate::doc::{retrieve_all_index_ids, ArrayIterator, DocumentContext};
 use crate::path::iterator::{CachedClonableIterator, ClonableIterator};
 use crate::schema::RdfListIterator;
 use crate::types::{transaction_instance_layer, transaction_schema_layer};
 use crate::value::{
     enum_node_to_value, type_is_big_integer, type_is_bool, type_is_datetime, type_is_decimal,
-    type_is_float, type_is_json, type_is_small_integer, value_to_graphql,
+    type_is_float, type_is_json, type_is_small_integer, type_temporal_kind, value_to_graphql,
+    TemporalKind,
 };
 
+use super::access::{restricted_field_value, AccessPolicies, FieldAccess};
 use super::filter::{FilterInputObject, FilterInputObjectTypeInfo};
 use super::frame::*;
 use super::naming::{ordering_name, path_field_to_class, path_to_class_name};
 use super::query::run_filter_query;
+use super::temporal::{
+    XsdDate, XsdDayTimeDuration, XsdDuration, XsdGDay, XsdGMonth, XsdGMonthDay, XsdGYear,
+    XsdGYearMonth, XsdTime, XsdYearMonthDuration,
+};
 
 pub enum NodeOrValue {
     Node(IriName),
@@ -54,6 +63,7 @@ pub struct TerminusContext<'a> {
     pub instance: Option<SyncStoreLayer>,
     pub type_collection: TerminusTypeCollectionInfo,
     pub document_context: Arc<Lazy<DocumentContext<SyncStoreLayer>>>,
+    pub access_policies: Rc<AccessPolicies>,
 }
 
 impl<'a> TerminusContext<'a> {
@@ -86,6 +96,9 @@ impl<'a> TerminusContext<'a> {
             transaction_schema_layer(&context, transaction_term)?.expect("missing schema layer");
         let instance = transaction_instance_layer(&context, transaction_term)?;
 
+        let access_policies =
+            AccessPolicies::from_system(&system, &user.to_string(), &type_collection.allframes);
+
         let context = Rc::new(context);
 
         Ok(TerminusContext {
@@ -104,6 +117,7 @@ impl<'a> TerminusContext<'a> {
             instance,
             type_collection,
             document_context: Arc::new(Lazy::new()),
+            access_policies: Rc::new(access_policies),
         })
     }
 
@@ -169,13 +183,10 @@ fn add_arguments<'r>(
 }
 
 fn must_generate_ordering(class_definition: &ClassDefinition) -> bool {
-    for (_, field) in class_definition.fields.iter() {
-        if field.base_type().is_some() {
-            return true;
-        }
-    }
-
-    false
+    // Base type fields are ordered on directly, collections by their
+    // size and document links through the fields of the linked
+    // document.
+    !class_definition.fields().is_empty()
 }
 
 impl GraphQLType for TerminusTypeCollection {
@@ -250,19 +261,68 @@ impl GraphQLType for TerminusTypeCollection {
 fn standard_collection_operators<'r>(
     registry: &mut juniper::Registry<'r, DefaultScalarValue>,
 ) -> impl Iterator<Item = Field<'r, DefaultScalarValue>> {
-    vec![registry
+    let get_document = registry
         .field::<GraphQLJSON>("_getDocument", &())
-        .argument(registry.arg::<String>("id", &()))]
+        .argument(registry.arg::<Option<String>>("id", &()))
+        .argument(registry.arg::<Option<Vec<String>>>("ids", &()));
+    vec![
+        add_shape_arguments(registry, get_document),
+        registry.field::<Vec<GraphQLJSON>>("_referentialIntegrity", &()),
+    ]
     .into_iter()
 }
 
+/// The broken references in the instance graph that are held by
+/// documents the user can see, as json.
+fn visible_broken_references(context: &TerminusContext) -> Vec<String> {
+    let instance = match context.instance.as_ref() {
+        Some(instance) => instance,
+        None => return Vec::new(),
+    };
+    find_broken_references(context.document_context().model(), instance)
+        .iter()
+        .filter(|b| {
+            context
+                .access_policies
+                .document_visible(context, b.subject_id)
+        })
+        .map(|b| serde_json::to_string(b).unwrap())
+        .collect()
+}
+
+/// Arguments that limit how much of a document gets retrieved.
+fn add_shape_arguments<'r>(
+    registry: &mut juniper::Registry<'r, DefaultScalarValue>,
+    field: Field<'r, DefaultScalarValue>,
+) -> Field<'r, DefaultScalarValue> {
+    field
+        .argument(registry.arg::<Option<Vec<String>>>("include", &()))
+        .argument(registry.arg::<Option<Vec<String>>>("exclude", &()))
+        .argument(registry.arg::<Option<i32>>("maxDepth", &()))
+}
+
+fn document_shape(arguments: &juniper::Arguments) -> Result<DocumentShape, juniper::FieldError> {
+    let include: Vec<String> = arguments.get("include").unwrap_or_default();
+    let exclude: Vec<String> = arguments.get("exclude").unwrap_or_default();
+    let max_depth = match arguments.get::<i32>("maxDepth") {
+        Some(depth) if depth < 0 => return Err("maxDepth should not be negative".into()),
+        depth => depth.map(|d| d as usize),
+    };
+
+    Ok(DocumentShape::new(&include, &exclude, max_depth))
+}
+
 fn standard_type_operators<'r>(
     registry: &mut juniper::Registry<'r, DefaultScalarValue>,
 ) -> impl Iterator<Item = Field<'r, DefaultScalarValue>> {
+    let json = registry.field::<GraphQLJSON>("_json", &());
     vec![
         registry.field::<ID>("_id", &()),
         registry.field::<ID>("_type", &()),
-        registry.field::<GraphQLJSON>("_json", &()),
+        add_shape_arguments(registry, json),
+        registry
+            .field::<Option<String>>("_hash", &())
+            .argument(registry.arg::<Option<bool>>("merkle", &())),
     ]
     .into_iter()
 }
@@ -285,13 +345,13 @@ pub fn result_to_execution_result<C: QueryableContextType, T>(
 
 fn pl_ids_from_restriction(
     context: &TerminusContext,
-    restriction: &RestrictionDefinition,
+    restriction: &ShortName,
 ) -> PrologResult<Vec<u64>> {
     let mut result = Vec::new();
     let prolog_context = &context.context;
     let frame = prolog_context.open_frame();
     let [restriction_term, id_term, reason_term] = frame.new_term_refs();
-    restriction_term.unify(restriction.original_id.as_str())?;
+    restriction_term.unify(restriction.as_str())?;
     let open_call = frame.open(
         pred!("query:ids_for_restriction/4"),
         [
@@ -312,6 +372,13 @@ fn pl_ids_from_restriction(
 fn ids_from_restriction(
     context: &TerminusContext,
     restriction: &RestrictionDefinition,
+) -> Result<Vec<u64>, juniper::FieldError> {
+    ids_from_restriction_name(context, &restriction.original_id)
+}
+
+pub fn ids_from_restriction_name(
+    context: &TerminusContext,
+    restriction: &ShortName,
 ) -> Result<Vec<u64>, juniper::FieldError> {
     let result = pl_ids_from_restriction(context, restriction).map(|mut r| {
         r.sort();
@@ -378,25 +445,43 @@ impl GraphQLValue for TerminusTypeCollection {
         match resolve_field_name {
             "_getDocument" => {
                 let context = executor.context();
-                let document_context = context.document_context();
-                let id: String = arguments.get("id").unwrap();
-                let id: NodeVariety = node_variety(&id);
-                let expanded_id = context
-                    .type_collection
-                    .allframes
-                    .context
-                    .expand_instance(&id);
-                let doc = document_context.get_document(expanded_id.as_str(), true, true)?;
-                match doc {
-                    Some(doc) => {
+                let shape = document_shape(arguments)?;
+                match (
+                    arguments.get::<String>("id"),
+                    arguments.get::<Vec<String>>("ids"),
+                ) {
+                    (Some(id), None) => match get_visible_document(context, &id, &shape)? {
+                        Some(doc) => {
+                            let json_string =
+                                serde_json::to_string_pretty(&serde_json::Value::Object(doc))
+                                    .unwrap();
+
+                            Ok(Value::Scalar(DefaultScalarValue::String(json_string)))
+                        }
+                        None => Err("No such document".into()),
+                    },
+                    (None, Some(ids)) => {
+                        // documents that don't exist or aren't visible are left out
+                        let mut docs = Vec::with_capacity(ids.len());
+                        for id in ids {
+                            if let Some(doc) = get_visible_document(context, &id, &shape)? {
+                                docs.push(serde_json::Value::Object(doc));
+                            }
+                        }
                         let json_string =
-                            serde_json::to_string_pretty(&serde_json::Value::Object(doc)).unwrap();
+                            serde_json::to_string_pretty(&serde_json::Value::Array(docs)).unwrap();
 
                         Ok(Value::Scalar(DefaultScalarValue::String(json_string)))
                     }
-                    None => Err("No such document".into()),
+                    _ => Err("_getDocument expects either an id or a list of ids".into()),
                 }
             }
+            "_referentialIntegrity" => Ok(Value::List(
+                visible_broken_references(executor.context())
+                    .into_iter()
+                    .map(|b| Value::Scalar(DefaultScalarValue::String(b)))
+                    .collect(),
+            )),
             _ => {
                 let zero_iter;
                 let type_name;
@@ -409,6 +494,11 @@ impl GraphQLValue for TerminusTypeCollection {
                     type_name = &field_name;
                     zero_iter = None;
                 }
+                executor.context().access_policies.check_query_arguments(
+                    &info.allframes,
+                    type_name,
+                    arguments,
+                )?;
                 let objects = match executor.context().instance.as_ref() {
                     Some(instance) => run_filter_query(
                         executor.context(),
@@ -417,7 +507,7 @@ impl GraphQLValue for TerminusTypeCollection {
                         type_name,
                         &info.allframes,
                         zero_iter,
-                    )
+                    )?
                     .into_iter()
                     .map(TerminusType::new)
                     .collect(),
@@ -464,6 +554,40 @@ impl TerminusType {
         }
     }
 
+    fn register_temporal_field<'r>(
+        registry: &mut Registry<'r, DefaultScalarValue>,
+        field_name: &str,
+        temporal_kind: TemporalKind,
+        kind: FieldKind,
+    ) -> Field<'r, DefaultScalarValue> {
+        match temporal_kind {
+            TemporalKind::Date => Self::register_field::<XsdDate>(registry, field_name, &(), kind),
+            TemporalKind::Time => Self::register_field::<XsdTime>(registry, field_name, &(), kind),
+            TemporalKind::GYear => {
+                Self::register_field::<XsdGYear>(registry, field_name, &(), kind)
+            }
+            TemporalKind::GYearMonth => {
+                Self::register_field::<XsdGYearMonth>(registry, field_name, &(), kind)
+            }
+            TemporalKind::GMonth => {
+                Self::register_field::<XsdGMonth>(registry, field_name, &(), kind)
+            }
+            TemporalKind::GDay => Self::register_field::<XsdGDay>(registry, field_name, &(), kind),
+            TemporalKind::GMonthDay => {
+                Self::register_field::<XsdGMonthDay>(registry, field_name, &(), kind)
+            }
+            TemporalKind::Duration => {
+                Self::register_field::<XsdDuration>(registry, field_name, &(), kind)
+            }
+            TemporalKind::YearMonthDuration => {
+                Self::register_field::<XsdYearMonthDuration>(registry, field_name, &(), kind)
+            }
+            TemporalKind::DayTimeDuration => {
+                Self::register_field::<XsdDayTimeDuration>(registry, field_name, &(), kind)
+            }
+        }
+    }
+
     fn generate_class_type<'r>(
         class_name: &GraphQLName,
         d: &ClassDefinition,
@@ -536,6 +660,13 @@ impl TerminusType {
                             &(),
                             field_definition.kind(),
                         )
+                    } else if let Some(kind) = type_temporal_kind(base_type) {
+                        Self::register_temporal_field(
+                            registry,
+                            field_name.as_str(),
+                            kind,
+                            field_definition.kind(),
+                        )
                     } else if type_is_decimal(base_type) {
                         Self::register_field::<BigFloat>(
                             registry,
@@ -740,9 +871,17 @@ impl GraphQLValue for TerminusType {
             }
             if field_name.as_str() == "_json" {
                 let document_context = executor.context().document_context();
-                let doc = document_context.get_id_document(self.id, true, true);
+                let shape = match document_shape(arguments) {
+                    Ok(shape) => shape,
+                    Err(e) => return Some(Err(e)),
+                };
+                let doc = document_context.get_id_document_shaped(self.id, true, true, &shape);
                 match doc {
-                    Ok(Some(doc)) => {
+                    Ok(Some(mut doc)) => {
+                        executor
+                            .context()
+                            .access_policies
+                            .redact_document(executor.context(), &mut doc);
                         let json_string =
                             serde_json::to_string_pretty(&serde_json::Value::Object(doc)).unwrap();
 
@@ -752,6 +891,43 @@ impl GraphQLValue for TerminusType {
                     Err(e) => return Some(Err(e.into())),
                 }
             }
+            if field_name.as_str() == "_hash" {
+                // the hash is over the stored document, so that it can be
+                // passed back as ifMatch on replace and delete. As that
+                // would give away the fields the user can't see, there is
+                // no hash for documents that get redacted.
+                let context = executor.context();
+                let document_context = context.document_context();
+                let merkle = arguments.get::<bool>("merkle").unwrap_or(false);
+                if !context.access_policies.is_empty() {
+                    match document_context.get_id_document(self.id, true, false) {
+                        Ok(Some(doc)) => {
+                            let mut redacted = doc.clone();
+                            context
+                                .access_policies
+                                .redact_document(context, &mut redacted);
+                            if redacted != doc {
+                                return Some(Ok(Value::Null));
+                            }
+                        }
+                        Ok(None) => return Some(Ok(Value::Null)),
+                        Err(e) => return Some(Err(e.into())),
+                    }
+                }
+                match document_context.get_id_document(self.id, false, false) {
+                    Ok(Some(doc)) => {
+                        let hash = if merkle {
+                            MerkleHasher::new().hash(&doc)
+                        } else {
+                            content_hash(&doc)
+                        };
+
+                        return Some(Ok(Value::Scalar(DefaultScalarValue::String(hash))));
+                    }
+                    Ok(None) => return Some(Ok(Value::Null)),
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
 
             let allframes = &info.allframes;
             let class = &info.class;
@@ -779,6 +955,15 @@ impl GraphQLValue for TerminusType {
                     .unwrap()
                     .clone();
                 let domain_iri: IriName = allframes.graphql_to_iri_name(domain);
+                if executor
+                    .context()
+                    .access_policies
+                    .field_access(allframes, domain, property)
+                    != FieldAccess::Visible
+                {
+                    // revealing the reverse link would reveal the property
+                    return Some(Ok(Value::List(vec![])));
+                }
                 let field_id = instance.predicate_id(property_iri.as_str())?;
                 // List and array are special since they are *deep* objects
                 match kind {
@@ -913,6 +1098,7 @@ impl GraphQLValue for TerminusType {
                 let enum_type;
                 let kind;
                 let is_json;
+                let base_type;
                 match frame {
                     TypeDefinition::Class(c) => {
                         let field = &c.resolve_field(&field_name);
@@ -923,10 +1109,27 @@ impl GraphQLValue for TerminusType {
                         enum_type = field.enum_type(allframes);
                         kind = field.kind();
                         is_json = field.is_json_type();
+                        base_type = field.base_type();
                     }
                     _ => panic!("expected only a class at this level"),
                 }
                 let field_id_opt = instance.predicate_id(field_name_expanded.as_str());
+                let access = executor.context().access_policies.field_access(
+                    allframes,
+                    &info.class,
+                    &field_name,
+                );
+                if access != FieldAccess::Visible {
+                    let object_count = field_id_opt
+                        .map(|field_id| instance.triples_sp(self.id, field_id).count())
+                        .unwrap_or(0);
+                    return Some(Ok(restricted_field_value(
+                        access,
+                        kind,
+                        base_type,
+                        object_count,
+                    )));
+                }
                 if field_id_opt.is_none() {
                     match kind {
                         FieldKind::Array
@@ -1044,6 +1247,10 @@ fn extract_fragment(
     is_json: bool,
 ) -> Option<Result<juniper::Value, juniper::FieldError>> {
     if let Some(doc_type) = doc_type {
+        let context = executor.context();
+        if !context.access_policies.document_visible(context, object_id) {
+            return None;
+        }
         Some(executor.resolve(
             &TerminusTypeInfo {
                 class: doc_type.as_static(),
@@ -1085,6 +1292,39 @@ fn extract_enum_fragment(
     ))
 }
 
+/// Look up a document by its (possibly prefixed) id, as long as the
+/// access policies allow it to be seen.
+fn get_visible_document(
+    context: &TerminusContext<'static>,
+    id: &str,
+    shape: &DocumentShape,
+) -> Result<Option<serde_json::Map<String, serde_json::Value>>, juniper::FieldError> {
+    let id: NodeVariety = node_variety(id);
+    let expanded_id = context
+        .type_collection
+        .allframes
+        .context
+        .expand_instance(&id);
+    let visible = context
+        .instance
+        .as_ref()
+        .and_then(|instance| instance.subject_id(expanded_id.as_str()))
+        .map(|id| context.access_policies.document_visible(context, id))
+        .unwrap_or(true);
+    if !visible {
+        return Ok(None);
+    }
+    let doc =
+        context
+            .document_context()
+            .get_document_shaped(expanded_id.as_str(), true, true, shape)?;
+
+    Ok(doc.map(|mut doc| {
+        context.access_policies.redact_document(context, &mut doc);
+        doc
+    }))
+}
+
 fn extract_json_fragment(
     instance: &SyncStoreLayer,
     object_id: u64,
@@ -1256,15 +1496,25 @@ fn collect_into_graphql_list<'a>(
     instance: &'a SyncStoreLayer,
 ) -> Option<Result<Value, juniper::FieldError>> {
     if let Some(doc_type) = doc_type {
+        if let Err(e) = executor.context().access_policies.check_query_arguments(
+            &info.allframes,
+            doc_type,
+            arguments,
+        ) {
+            return Some(Err(e));
+        }
         let object_ids = match executor.context().instance.as_ref() {
-            Some(instance) => run_filter_query(
+            Some(instance) => match run_filter_query(
                 executor.context(),
                 instance,
                 arguments,
                 doc_type,
                 &info.allframes,
                 Some(object_ids),
-            ),
+            ) {
+                Ok(object_ids) => object_ids,
+                Err(e) => return Some(Err(e)),
+            },
             None => vec![],
         };
         let subdocs: Vec<_> = object_ids.into_iter().map(TerminusType::new).collect();
@@ -1308,22 +1558,88 @@ pub enum TerminusOrdering {
     Desc,
 }
 
+#[derive(GraphQLEnum, Clone, Copy, Debug, Eq, PartialEq)]
+#[graphql(name = "NullOrdering")]
+pub enum TerminusNullOrdering {
+    First,
+    Last,
+}
+
+#[derive(GraphQLInputObject, Clone, Debug, Default)]
+#[graphql(name = "Collation")]
+#[allow(non_snake_case)]
+pub struct TerminusCollation {
+    /// compare strings without regard to case
+    pub caseInsensitive: Option<bool>,
+    /// a BCP 47 language tag whose collation rules should be used to compare strings
+    pub locale: Option<String>,
+}
+
+/// A single key to order by.
+///
+/// The path consists of zero or more single-valued document links,
+/// followed by the name of the field to order on. This final field
+/// can also be `_id` or `_type`.
+#[derive(Clone)]
+pub struct TerminusOrderField {
+    pub path: Vec<GraphQLName<'static>>,
+    pub ordering: TerminusOrdering,
+    pub nulls: Option<TerminusNullOrdering>,
+    pub collation: Option<Rc<TerminusCollation>>,
+}
+
 pub struct TerminusOrderBy {
-    pub fields: Vec<(GraphQLName<'static>, TerminusOrdering)>,
+    pub fields: Vec<TerminusOrderField>,
+}
+
+impl TerminusOrderBy {
+    fn collect_fields(
+        o: &[(juniper::Spanning<String>, juniper::Spanning<InputValue>)],
+        prefix: &[GraphQLName<'static>],
+        mut nulls: Option<TerminusNullOrdering>,
+        mut collation: Option<Rc<TerminusCollation>>,
+        fields: &mut Vec<TerminusOrderField>,
+    ) -> Option<()> {
+        // Null placement and collation apply to the level they are
+        // given at, and any nested level that doesn't override them.
+        for (k, v) in o.iter() {
+            match k.item.as_str() {
+                "_nulls" => nulls = Option::<TerminusNullOrdering>::from_input_value(&v.item)?,
+                "_collation" => {
+                    collation = Option::<TerminusCollation>::from_input_value(&v.item)?.map(Rc::new)
+                }
+                _ => {}
+            }
+        }
+        for (k, v) in o.iter() {
+            if k.item == "_nulls" || k.item == "_collation" {
+                continue;
+            }
+            let mut path = prefix.to_vec();
+            path.push(GraphQLName(Cow::Owned(k.item.to_owned())));
+            match &v.item {
+                InputValue::Null => {}
+                InputValue::Object(sub) => {
+                    Self::collect_fields(sub, &path, nulls, collation.clone(), fields)?
+                }
+                value => fields.push(TerminusOrderField {
+                    path,
+                    ordering: TerminusOrdering::from_input_value(value)?,
+                    nulls,
+                    collation: collation.clone(),
+                }),
+            }
+        }
+
+        Some(())
+    }
 }
 
 impl FromInputValue for TerminusOrderBy {
     fn from_input_value(v: &InputValue<DefaultScalarValue>) -> Option<Self> {
         if let InputValue::Object(o) = v {
-            let fields: Vec<_> = o
-                .iter()
-                .map(|(k, v)| {
-                    (
-                        GraphQLName(Cow::Owned(k.item.to_owned())),
-                        TerminusOrdering::from_input_value(&v.item).unwrap(),
-                    )
-                })
-                .collect();
+            let mut fields = Vec::new();
+            Self::collect_fields(o, &[], None, None, &mut fields)?;
 
             Some(Self { fields })
         } else {
@@ -1346,17 +1662,55 @@ impl GraphQLType for TerminusOrderBy {
     {
         let frames = &info.allframes;
         if let TypeDefinition::Class(d) = &frames.frames[&info.type_name] {
-            let arguments: Vec<_> = d
-                .fields
-                .iter()
+            let mut arguments: Vec<_> = d
+                .fields()
+                .into_iter()
                 .filter_map(|(field_name, field_definition)| {
-                    if field_definition.base_type().is_some() {
+                    if field_definition.kind().is_collection() {
+                        Some(
+                            registry
+                                .arg::<Option<TerminusOrdering>>(field_name.as_str(), &())
+                                .description("order by the number of elements"),
+                        )
+                    } else if field_definition.base_type().is_some() {
                         Some(registry.arg::<Option<TerminusOrdering>>(field_name.as_str(), &()))
+                    } else if let Some(document_type) = field_definition.document_type(frames) {
+                        Some(registry.arg::<Option<TerminusOrderBy>>(
+                            field_name.as_str(),
+                            &TerminusOrderingInfo::new(document_type, frames),
+                        ))
                     } else {
                         None
                     }
                 })
                 .collect();
+            arguments.push(
+                registry
+                    .arg::<Option<TerminusOrdering>>("_id", &())
+                    .description("order by document id"),
+            );
+            arguments.push(
+                registry
+                    .arg::<Option<TerminusOrdering>>("_type", &())
+                    .description("order by document type"),
+            );
+            arguments.push(
+                registry
+                    .arg::<Option<TerminusOrdering>>("_similarity", &())
+                    .description(
+                        "order by how closely the fuzzy and similarTo filters match, only at the top level",
+                    ),
+            );
+            arguments.push(
+                registry
+                    .arg::<Option<TerminusNullOrdering>>("_nulls", &())
+                    .description("whether missing values sort before or after all others"),
+            );
+            arguments.push(
+                registry
+                    .arg::<Option<TerminusCollation>>("_collation", &())
+                    .description("how to compare string values"),
+            );
 
             registry
                 .build_input_object_type::<TerminusOrderBy>(info, &arguments)
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use lru::LruCache;
+
+use crate::terminus_store::store::sync::SyncStoreLayer;
+use crate::terminus_store::*;
+use crate::value::value_to_string;
+
+/// An approximate string match, as given in a `fuzzy` or `similarTo`
+/// string filter.
+#[derive(Debug, Clone)]
+pub enum TextMatcher {
+    Fuzzy {
+        value: String,
+        max_distance: usize,
+        transpositions: bool,
+    },
+    SimilarTo {
+        value: String,
+        threshold: f64,
+    },
+}
+
+impl TextMatcher {
+    pub fn matches(&self, s: &str) -> bool {
+        match self {
+            Self::Fuzzy {
+                value,
+                max_distance,
+                transpositions,
+            } => bounded_edit_distance(value, s, *max_distance, *transpositions).is_some(),
+            Self::SimilarTo { value, threshold } => trigram_similarity(value, s) >= *threshold,
+        }
+    }
+
+    /// A score between 0 and 1 of how close `s` is to the value
+    /// matched against, with 1 an exact match.
+    pub fn score(&self, s: &str) -> f64 {
+        match self {
+            Self::Fuzzy {
+                value,
+                transpositions,
+                ..
+            } => {
+                let longest = value.chars().count().max(s.chars().count());
+                if longest == 0 {
+                    return 1.0;
+                }
+                let distance = edit_distance(value, s, *transpositions);
+                1.0 - distance as f64 / longest as f64
+            }
+            Self::SimilarTo { value, .. } => trigram_similarity(value, s),
+        }
+    }
+}
+
+/// The Levenshtein distance between two strings. With
+/// `transpositions`, swapping two adjacent characters counts as a
+/// single edit (optimal string alignment distance).
+pub fn edit_distance(a: &str, b: &str, transpositions: bool) -> usize {
+    bounded_edit_distance(a, b, usize::MAX, transpositions).unwrap()
+}
+
+/// Like [`edit_distance`], but gives up with `None` as soon as the
+/// distance is known to exceed `max`.
+pub fn bounded_edit_distance(a: &str, b: &str, max: usize, transpositions: bool) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        let mut row_min = current[0];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if transpositions && i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(before[j - 2] + 1);
+            }
+            current[j] = distance;
+            row_min = row_min.min(distance);
+        }
+        if row_min > max {
+            return None;
+        }
+        before = std::mem::replace(&mut previous, std::mem::take(&mut current));
+        current = vec![0; b.len() + 1];
+    }
+
+    let distance = previous[b.len()];
+    if distance > max {
+        None
+    } else {
+        Some(distance)
+    }
+}
+
+pub type Trigram = [char; 3];
+
+/// The trigrams of a string. Like postgres' pg_trgm, the string is
+/// lowercased and split into alphanumeric words, and each word is
+/// padded with two spaces in front and one behind.
+pub fn trigrams(s: &str) -> HashSet<Trigram> {
+    let mut result = HashSet::new();
+    for word in s
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        let padded: Vec<char> = "  "
+            .chars()
+            .chain(word.chars().flat_map(char::to_lowercase))
+            .chain(" ".chars())
+            .collect();
+        for window in padded.windows(3) {
+            result.insert([window[0], window[1], window[2]]);
+        }
+    }
+
+    result
+}
+
+/// The share of trigrams two strings have in common, from 0 (none)
+/// to 1 (all of them).
+pub fn trigram_similarity(a: &str, b: &str) -> f64 {
+    jaccard(&trigrams(a), &trigrams(b))
+}
+
+fn jaccard(a: &HashSet<Trigram>, b: &HashSet<Trigram>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.0;
+    }
+    let shared = a.intersection(b).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+/// An inverted index from trigrams to the string values a predicate
+/// points at.
+pub struct TrigramIndex {
+    postings: HashMap<Trigram, Vec<u64>>,
+    trigram_counts: HashMap<u64, usize>,
+}
+
+impl TrigramIndex {
+    pub fn build(g: &SyncStoreLayer, predicate_id: u64) -> Self {
+        let mut postings: HashMap<Trigram, Vec<u64>> = HashMap::new();
+        let mut trigram_counts = HashMap::new();
+        for t in g.triples_p(predicate_id) {
+            if trigram_counts.contains_key(&t.object) {
+                continue;
+            }
+            let value = match g.id_object_value(t.object) {
+                Some(value) => value,
+                None => continue,
+            };
+            let grams = trigrams(&value_to_string(&value));
+            trigram_counts.insert(t.object, grams.len());
+            for gram in grams {
+                postings.entry(gram).or_default().push(t.object);
+            }
+        }
+
+        Self {
+            postings,
+            trigram_counts,
+        }
+    }
+
+    /// The value ids whose trigram similarity to `value` is at least
+    /// `threshold`.
+    pub fn similar_to(&self, value: &str, threshold: f64) -> Vec<u64> {
+        let query = trigrams(value);
+        let mut shared: HashMap<u64, usize> = HashMap::new();
+        for gram in query.iter() {
+            for id in self.postings.get(gram).into_iter().flatten() {
+                *shared.entry(*id).or_default() += 1;
+            }
+        }
+
+        let mut result: Vec<u64> = shared
+            .into_iter()
+            .filter(|(id, shared)| {
+                let total = query.len() + self.trigram_counts[id] - shared;
+                *shared as f64 / total as f64 >= threshold
+            })
+            .map(|(id, _)| id)
+            .collect();
+        result.sort_unstable();
+        result
+    }
+}
+
+lazy_static! {
+    /// Predicates with at least this many triples get a trigram index
+    /// the first time a `similarTo` filter is run against them.
+    static ref TRIGRAM_INDEX_THRESHOLD: usize = {
+        std::env::var("TERMINUSDB_TRIGRAM_INDEX_THRESHOLD")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(100_000)
+    };
+    static ref TRIGRAM_INDEX_CACHE: Mutex<LruCache<([u32; 5], u64), Option<Arc<TrigramIndex>>>> =
+        Mutex::new(LruCache::new(NonZeroUsize::new(16).unwrap()));
+}
+
+/// Retrieve the trigram index for a predicate in this layer, building
+/// it if the predicate is large enough to warrant one.
+pub fn trigram_index(g: &SyncStoreLayer, predicate_id: u64) -> Option<Arc<TrigramIndex>> {
+    let key = (g.name(), predicate_id);
+    if let Some(index) = TRIGRAM_INDEX_CACHE.lock().unwrap().get(&key) {
+        return index.clone();
+    }
+
+    let large = g
+        .triples_p(predicate_id)
+        .nth(TRIGRAM_INDEX_THRESHOLD.saturating_sub(1))
+        .is_some();
+    let index = if large {
+        Some(Arc::new(TrigramIndex::build(g, predicate_id)))
+    } else {
+        None
+    };
+    TRIGRAM_INDEX_CACHE.lock().unwrap().put(key, index.clone());
+
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("kitten", "sitting", false), 3);
+        assert_eq!(edit_distance("", "abc", false), 3);
+        assert_eq!(edit_distance("abcd", "abdc", false), 2);
+        assert_eq!(edit_distance("abcd", "abdc", true), 1);
+        assert_eq!(bounded_edit_distance("kitten", "sitting", 2, false), None);
+        assert_eq!(
+            bounded_edit_distance("kitten", "sitting", 3, false),
+            Some(3)
+        );
+        assert_eq!(bounded_edit_distance("a", "abcdef", 2, true), None);
+    }
+
+    #[test]
+    fn trigram_similarities() {
+        assert_eq!(trigrams("cat").len(), 4);
+        assert!((trigram_similarity("Word", "word") - 1.0).abs() < f64::EPSILON);
+        assert_eq!(trigram_similarity("abc", "xyz"), 0.0);
+        let close = trigram_similarity("Jonathan", "Johnathan");
+        let far = trigram_similarity("Jonathan", "Jane");
+        assert!(close > far);
+        assert!(close > 0.5);
+    }
+
+    #[test]
+    fn fuzzy_scores() {
+        let matcher = TextMatcher::Fuzzy {
+            value: "Smith".to_string(),
+            max_distance: 1,
+            transpositions: true,
+        };
+        assert!(matcher.matches("Smiht"));
+        assert!(matcher.matches("Smyth"));
+        assert!(!matcher.matches("Smythe"));
+        assert_eq!(matcher.score("Smith"), 1.0);
+        assert!(matcher.score("Smyth") > matcher.score("Smythe"));
+    }
+}
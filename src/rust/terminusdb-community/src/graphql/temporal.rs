@@ -0,0 +1,463 @@
+use chrono::{Months, NaiveDate, NaiveDateTime, NaiveTime};
+use tdb_succinct::{
+    DateTimeStamp, DayTimeDuration, GDay, GMonth, GMonthDay, GYear, GYearMonth, TdbDataType,
+    TypedDictEntry, YearMonthDuration,
+};
+
+use crate::value::{type_temporal_kind, TemporalKind};
+
+macro_rules! temporal_scalar {
+    ($struct_name:ident, $name:literal, $description:literal) => {
+        #[derive(Debug, Clone)]
+        pub struct $struct_name(pub String);
+
+        #[juniper::graphql_scalar(name = $name, description = $description)]
+        impl<S> GraphQLScalar for $struct_name
+        where
+            S: juniper::ScalarValue,
+        {
+            fn resolve(&self) -> juniper::Value {
+                juniper::Value::scalar(self.0.to_owned())
+            }
+
+            fn from_input_value(value: &juniper::InputValue) -> Option<Self> {
+                value.as_string_value().map(|s| Self(s.to_owned()))
+            }
+
+            fn from_str<'a>(value: juniper::ScalarToken<'a>) -> juniper::ParseScalarResult<'a, S> {
+                <String as juniper::ParseScalarValue<S>>::from_str(value)
+            }
+        }
+    };
+}
+
+// These are prefixed so that they do not clash with user classes
+// named `Date`, `Time` or `Duration`.
+temporal_scalar!(
+    XsdDate,
+    "XsdDate",
+    "An `xsd:date`, such as `2023-04-01` or `2023-04-01+02:00`."
+);
+temporal_scalar!(
+    XsdTime,
+    "XsdTime",
+    "An `xsd:time`, such as `13:20:00` or `13:20:00.5Z`."
+);
+temporal_scalar!(XsdGYear, "XsdGYear", "An `xsd:gYear`, such as `2023`.");
+temporal_scalar!(
+    XsdGYearMonth,
+    "XsdGYearMonth",
+    "An `xsd:gYearMonth`, such as `2023-04`."
+);
+temporal_scalar!(XsdGMonth, "XsdGMonth", "An `xsd:gMonth`, such as `--04`.");
+temporal_scalar!(XsdGDay, "XsdGDay", "An `xsd:gDay`, such as `---01`.");
+temporal_scalar!(
+    XsdGMonthDay,
+    "XsdGMonthDay",
+    "An `xsd:gMonthDay`, such as `--04-01`."
+);
+temporal_scalar!(
+    XsdDuration,
+    "XsdDuration",
+    "An `xsd:duration` in ISO 8601 notation, such as `P1Y2M3DT4H5M6S`."
+);
+temporal_scalar!(
+    XsdYearMonthDuration,
+    "XsdYearMonthDuration",
+    "An `xsd:yearMonthDuration`, such as `P1Y2M`."
+);
+temporal_scalar!(
+    XsdDayTimeDuration,
+    "XsdDayTimeDuration",
+    "An `xsd:dayTimeDuration`, such as `P3DT4H`."
+);
+
+/// The components of an ISO 8601 duration.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DurationParts {
+    pub sign: i8,
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub minute: i64,
+    pub second: f64,
+}
+
+impl DurationParts {
+    fn has_date_part(&self) -> bool {
+        self.year != 0 || self.month != 0 || self.day != 0
+    }
+
+    fn has_time_part(&self) -> bool {
+        self.hour != 0 || self.minute != 0 || self.second != 0.0
+    }
+
+    fn to_duration(&self) -> Option<tdb_succinct::Duration> {
+        Some(tdb_succinct::Duration {
+            sign: self.sign,
+            year: self.year,
+            month: u8::try_from(self.month).ok()?,
+            day: u8::try_from(self.day).ok()?,
+            hour: u8::try_from(self.hour).ok()?,
+            minute: u8::try_from(self.minute).ok()?,
+            second: self.second,
+        })
+    }
+}
+
+pub fn parse_duration(s: &str) -> Option<DurationParts> {
+    let (sign, rest) = if let Some(rest) = s.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        (1, s.strip_prefix('+').unwrap_or(s))
+    };
+    let rest = rest.strip_prefix('P')?;
+    let (date_part, time_part) = match rest.split_once('T') {
+        Some((_, "")) => return None,
+        Some((date_part, time_part)) => (date_part, Some(time_part)),
+        None => (rest, None),
+    };
+
+    let mut parts = DurationParts {
+        sign,
+        ..Default::default()
+    };
+    let mut seen = false;
+    let mut number = String::new();
+    for c in date_part.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: i64 = number.parse().ok()?;
+        number.clear();
+        match c {
+            'Y' => parts.year = n,
+            'M' => parts.month = n,
+            'D' => parts.day = n,
+            _ => return None,
+        }
+        seen = true;
+    }
+    if !number.is_empty() {
+        return None;
+    }
+
+    for c in time_part.unwrap_or("").chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        match c {
+            'H' => parts.hour = number.parse().ok()?,
+            'M' => parts.minute = number.parse().ok()?,
+            'S' => parts.second = number.parse().ok()?,
+            _ => return None,
+        }
+        number.clear();
+        seen = true;
+    }
+    if !number.is_empty() || !seen {
+        return None;
+    }
+
+    Some(parts)
+}
+
+/// Split a trailing timezone off a lexical value, returning the
+/// offset in seconds.
+fn split_offset(s: &str) -> Option<(&str, i64)> {
+    if let Some(body) = s.strip_suffix('Z') {
+        return Some((body, 0));
+    }
+    if s.len() > 6 && s.is_char_boundary(s.len() - 6) {
+        let (body, zone) = s.split_at(s.len() - 6);
+        let zone = zone.as_bytes();
+        if (zone[0] == b'+' || zone[0] == b'-') && zone[3] == b':' {
+            let zone = std::str::from_utf8(&zone[1..]).ok()?;
+            let hours: i64 = zone[0..2].parse().ok()?;
+            let minutes: i64 = zone[3..5].parse().ok()?;
+            let offset = hours * 3600 + minutes * 60;
+            let sign = if s.as_bytes()[s.len() - 6] == b'-' {
+                -1
+            } else {
+                1
+            };
+            return Some((body, sign * offset));
+        }
+    }
+    Some((s, 0))
+}
+
+fn parse_two_digits(s: &str) -> Option<u8> {
+    if s.len() == 2 {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn parse_year(s: &str) -> Option<i64> {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if digits.len() < 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn parse_year_month(s: &str) -> Option<(i64, u8)> {
+    let (year, month) = s.rsplit_once('-')?;
+    Some((parse_year(year)?, parse_two_digits(month)?))
+}
+
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .ok()
+}
+
+fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(dt.naive_utc());
+    }
+    let (body, offset) = split_offset(s)?;
+    let naive = NaiveDateTime::parse_from_str(body, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(body, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()?;
+    naive.checked_sub_signed(chrono::Duration::seconds(offset))
+}
+
+// Offsets are stored in seconds, truncated the same way as values
+// coming in through the store, so that equal values compare equal.
+fn stored_offset(offset: i64) -> i16 {
+    offset as i16
+}
+
+fn date_entry(date: NaiveDate) -> TypedDictEntry {
+    use chrono::Datelike;
+    tdb_succinct::Date::make_entry(&tdb_succinct::Date {
+        year: date.year() as i64,
+        month: date.month() as u8,
+        day: date.day() as u8,
+        offset: 0,
+    })
+}
+
+fn temporal_entry(kind: TemporalKind, s: &str) -> Option<TypedDictEntry> {
+    match kind {
+        TemporalKind::Date => {
+            let (body, offset) = split_offset(s)?;
+            let mut parts = body.rsplitn(3, '-');
+            let day = parse_two_digits(parts.next()?)?;
+            let month = parse_two_digits(parts.next()?)?;
+            let year = parse_year(parts.next()?)?;
+            NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)?;
+            Some(tdb_succinct::Date::make_entry(&tdb_succinct::Date {
+                year,
+                month,
+                day,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::Time => {
+            let (body, _) = split_offset(s)?;
+            Some(NaiveTime::make_entry(&parse_time(body)?))
+        }
+        TemporalKind::GYear => {
+            let (body, offset) = split_offset(s)?;
+            Some(GYear::make_entry(&GYear {
+                year: parse_year(body)?,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::GYearMonth => {
+            let (body, offset) = split_offset(s)?;
+            let (year, month) = parse_year_month(body)?;
+            Some(GYearMonth::make_entry(&GYearMonth {
+                year,
+                month,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::GMonth => {
+            let (body, offset) = split_offset(s)?;
+            Some(GMonth::make_entry(&GMonth {
+                month: parse_two_digits(body.strip_prefix("--")?)?,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::GDay => {
+            let (body, offset) = split_offset(s)?;
+            Some(GDay::make_entry(&GDay {
+                day: parse_two_digits(body.strip_prefix("---")?)?,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::GMonthDay => {
+            let (body, offset) = split_offset(s)?;
+            let (month, day) = body.trim_start_matches('-').split_once('-')?;
+            Some(GMonthDay::make_entry(&GMonthDay {
+                month: parse_two_digits(month)?,
+                day: parse_two_digits(day)?,
+                offset: stored_offset(offset),
+            }))
+        }
+        TemporalKind::Duration => {
+            let duration = parse_duration(s)?.to_duration()?;
+            Some(tdb_succinct::Duration::make_entry(&duration))
+        }
+        TemporalKind::YearMonthDuration => {
+            let parts = parse_duration(s)?;
+            if parts.day != 0 || parts.has_time_part() {
+                return None;
+            }
+            Some(YearMonthDuration::make_entry(&YearMonthDuration(
+                parts.to_duration()?,
+            )))
+        }
+        TemporalKind::DayTimeDuration => {
+            let parts = parse_duration(s)?;
+            if parts.year != 0 || parts.month != 0 {
+                return None;
+            }
+            Some(DayTimeDuration::make_entry(&DayTimeDuration(
+                parts.to_duration()?,
+            )))
+        }
+    }
+}
+
+/// Parse the lexical form of a value of the given base type into a
+/// dictionary entry that can be compared against stored values.
+pub fn parse_temporal_entry(base_type: &str, s: &str) -> Option<TypedDictEntry> {
+    match base_type {
+        "dateTime" => Some(NaiveDateTime::make_entry(&parse_datetime(s)?)),
+        "dateTimeStamp" => Some(DateTimeStamp::make_entry(&DateTimeStamp(parse_datetime(
+            s,
+        )?))),
+        _ => temporal_entry(type_temporal_kind(base_type)?, s),
+    }
+}
+
+fn shift_datetime(
+    now: NaiveDateTime,
+    duration: &DurationParts,
+    forward: bool,
+) -> Option<NaiveDateTime> {
+    let forward = forward == (duration.sign >= 0);
+    let months = Months::new(u32::try_from(duration.year * 12 + duration.month).ok()?);
+    let delta = chrono::Duration::days(duration.day)
+        + chrono::Duration::hours(duration.hour)
+        + chrono::Duration::minutes(duration.minute)
+        + chrono::Duration::nanoseconds((duration.second * 1_000_000_000.0) as i64);
+    if forward {
+        now.checked_add_months(months)?.checked_add_signed(delta)
+    } else {
+        now.checked_sub_months(months)?.checked_sub_signed(delta)
+    }
+}
+
+/// The entry for the moment `duration` away from `now`, either in the
+/// future or in the past. Only dates and date times have a notion of
+/// "now", so this returns `None` for any other base type.
+pub fn relative_entry(
+    base_type: &str,
+    now: NaiveDateTime,
+    duration: &DurationParts,
+    forward: bool,
+) -> Option<TypedDictEntry> {
+    let moment = if duration.has_date_part() || duration.has_time_part() {
+        shift_datetime(now, duration, forward)?
+    } else {
+        now
+    };
+    match base_type {
+        "dateTime" => Some(NaiveDateTime::make_entry(&moment)),
+        "dateTimeStamp" => Some(DateTimeStamp::make_entry(&DateTimeStamp(moment))),
+        "date" => Some(date_entry(moment.date())),
+        _ => None,
+    }
+}
+
+/// The entry for `now` itself, truncated to the precision of the base
+/// type.
+pub fn now_entry(base_type: &str, now: NaiveDateTime) -> Option<TypedDictEntry> {
+    relative_entry(base_type, now, &DurationParts::default(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(
+            Some(DurationParts {
+                sign: 1,
+                year: 1,
+                month: 2,
+                day: 3,
+                hour: 4,
+                minute: 5,
+                second: 6.5,
+            }),
+            parse_duration("P1Y2M3DT4H5M6.5S")
+        );
+        assert_eq!(
+            Some(DurationParts {
+                sign: -1,
+                minute: 30,
+                ..Default::default()
+            }),
+            parse_duration("-PT30M")
+        );
+        assert_eq!(None, parse_duration("P"));
+        assert_eq!(None, parse_duration("P1DT"));
+        assert_eq!(None, parse_duration("1D"));
+    }
+
+    #[test]
+    fn temporal_entries_are_ordered() {
+        let entry = |ty, s| parse_temporal_entry(ty, s).unwrap();
+        assert!(entry("date", "1999-12-31") < entry("date", "2000-01-01"));
+        assert!(entry("gYear", "1999") < entry("gYear", "2000"));
+        assert!(entry("gYearMonth", "2000-01") < entry("gYearMonth", "2000-02"));
+        assert!(entry("time", "09:00:00") < entry("time", "13:20:00"));
+        assert!(entry("dayTimeDuration", "PT59M") < entry("dayTimeDuration", "PT1H"));
+        assert!(
+            entry("dateTime", "2000-01-01T00:00:00Z") < entry("dateTime", "2000-01-01T00:00:01Z")
+        );
+        assert_eq!(
+            entry("dateTime", "2000-01-01T02:00:00+02:00"),
+            entry("dateTime", "2000-01-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn invalid_temporal_values_are_rejected() {
+        assert!(parse_temporal_entry("date", "2000-02-30").is_none());
+        assert!(parse_temporal_entry("gMonth", "04").is_none());
+        assert!(parse_temporal_entry("yearMonthDuration", "P1D").is_none());
+        assert!(parse_temporal_entry("dayTimeDuration", "P1Y").is_none());
+        assert!(parse_temporal_entry("string", "2000").is_none());
+    }
+
+    #[test]
+    fn relative_entries_move_from_now() {
+        let now = NaiveDate::from_ymd_opt(2000, 3, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let week = parse_duration("P7D").unwrap();
+        assert_eq!(
+            parse_temporal_entry("date", "2000-02-23"),
+            relative_entry("date", now, &week, false)
+        );
+        assert_eq!(
+            parse_temporal_entry("dateTime", "2000-03-08T12:00:00Z"),
+            relative_entry("dateTime", now, &week, true)
+        );
+        assert_eq!(None, relative_entry("gYear", now, &week, true));
+    }
+}
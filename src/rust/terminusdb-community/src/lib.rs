@@ -78,20 +78,21 @@ predicates! {
     #[module("utils")]
     semidet fn random_base64(_context, size_term, s_term) {
         let size: u64 = size_term.get()?;
-        let mut buf = Vec::with_capacity(size as usize);
-        let mut rng = thread_rng();
+        let s = random_base64_string(size as usize);
 
-        for _ in 0..size {
-            let r = rng.gen_range(0..64);
-            let item = base64char(r);
-            buf.push(item)
-        }
+        s_term.unify(s.as_str())
+    }
 
-        let s = unsafe { std::str::from_utf8_unchecked(&buf) };
+}
 
-        s_term.unify(s)
-    }
+/// A random string of `size` characters from the url-safe base64
+/// alphabet, as used for generated document ids.
+pub(crate) fn random_base64_string(size: usize) -> String {
+    let mut rng = thread_rng();
 
+    (0..size)
+        .map(|_| base64char(rng.gen_range(0..64)) as char)
+        .collect()
 }
 
 // implements RFC4648 encoding
@@ -111,10 +111,28 @@ pub enum BaseTypeKind {
     BigIntger,
     Boolean,
     DateTime,
+    Temporal(TemporalKind),
     Float,
     Decimal,
 }
 
+/// The XSD date, time and duration types other than `dateTime` and
+/// `dateTimeStamp`, which are all ordered through their dictionary
+/// encoding.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TemporalKind {
+    Date,
+    Time,
+    GYear,
+    GYearMonth,
+    GMonth,
+    GDay,
+    GMonthDay,
+    Duration,
+    YearMonthDuration,
+    DayTimeDuration,
+}
+
 pub fn base_type_kind(s: &str) -> BaseTypeKind {
     if type_is_small_integer(s) {
         BaseTypeKind::SmallInteger
@@ -126,6 +144,8 @@ pub fn base_type_kind(s: &str) -> BaseTypeKind {
         BaseTypeKind::Float
     } else if type_is_datetime(s) {
         BaseTypeKind::DateTime
+    } else if let Some(kind) = type_temporal_kind(s) {
+        BaseTypeKind::Temporal(kind)
     } else if type_is_decimal(s) {
         BaseTypeKind::Decimal
     } else {
@@ -153,6 +173,22 @@ pub fn type_is_datetime(s: &str) -> bool {
     DATETIME_TYPES.contains(s)
 }
 
+pub fn type_temporal_kind(s: &str) -> Option<TemporalKind> {
+    match s {
+        "date" => Some(TemporalKind::Date),
+        "time" => Some(TemporalKind::Time),
+        "gYear" => Some(TemporalKind::GYear),
+        "gYearMonth" => Some(TemporalKind::GYearMonth),
+        "gMonth" => Some(TemporalKind::GMonth),
+        "gDay" => Some(TemporalKind::GDay),
+        "gMonthDay" => Some(TemporalKind::GMonthDay),
+        "duration" => Some(TemporalKind::Duration),
+        "yearMonthDuration" => Some(TemporalKind::YearMonthDuration),
+        "dayTimeDuration" => Some(TemporalKind::DayTimeDuration),
+        _ => None,
+    }
+}
+
 pub fn type_is_decimal(s: &str) -> bool {
     DECIMAL_TYPES.contains(s)
 }
@@ -0,0 +1,537 @@
+//! Archive stores whose layers are encrypted at rest.
+//!
+//! An [`EncryptedArchiveBackend`] sits between the archive layer store
+//! and a [`DirectoryArchiveBackend`]. Every layer archive is encrypted
+//! with ChaCha20-Poly1305 before it is written, and decrypted and
+//! authenticated when it is read back. Everything above the backend,
+//! including `SyncStore`, sees plain archives. Labels and rollup
+//! markers only hold layer ids, and are not encrypted.
+//!
+//! An encrypted archive is laid out as
+//!
+//! ```text
+//! TDBENCR1 <key id: u32> <nonce: 12 bytes> <ciphertext and tag>
+//! ```
+//!
+//! The header and the layer id are authenticated along with the
+//! contents, so an archive can't be passed off as another layer.
+//!
+//! Keys come from a key file, or from an environment variable with the
+//! same contents. Each key is given on its own line as
+//!
+//! ```text
+//! <key id>:<64 hex digits>
+//! ```
+//!
+//! In an environment variable, keys may also be separated by commas.
+//! New layers are encrypted with the last key. Older keys are only
+//! used to read layers written before a key rotation.
+//! [`reencrypt_archives`] rewrites those layers with the current key,
+//! after which the older keys can be dropped. It also encrypts the
+//! layers of a plaintext archive store, and should be run while the
+//! store is not opened.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use terminus_store::storage::archive::{
+    Archive, ArchiveBackend, ArchiveMetadataBackend, DirectoryArchiveBackend,
+};
+use terminus_store::storage::consts::LayerFileEnum;
+use terminus_store::storage::{name_to_string, string_to_name};
+use terminus_store::store::sync::task_sync;
+
+use crate::LayerId;
+
+const MAGIC: &[u8; 8] = b"TDBENCR1";
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+
+/// The extension of layer archives in a directory archive store.
+const ARCHIVE_EXTENSION: &str = "larch";
+
+fn invalid_data<E: ToString>(message: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Where the keys of an encrypted store come from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    File(PathBuf),
+    Env(String),
+}
+
+/// The keys an encrypted store can be read with, and the one new
+/// layers are written with.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: HashMap<u32, ChaCha20Poly1305>,
+    current: u32,
+}
+
+impl KeyRing {
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut keys = HashMap::new();
+        let mut current = None;
+        for entry in contents
+            .split(['\n', ','])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| invalid_data("key entries should look like <id>:<hex key>"))?;
+            let id: u32 = id.trim().parse().map_err(invalid_data)?;
+            let key = hex::decode(key.trim()).map_err(invalid_data)?;
+            if key.len() != 32 {
+                return Err(invalid_data(format!("key {} is not 32 bytes long", id)));
+            }
+            if keys
+                .insert(id, ChaCha20Poly1305::new(Key::from_slice(&key)))
+                .is_some()
+            {
+                return Err(invalid_data(format!("key {} is given more than once", id)));
+            }
+            current = Some(id);
+        }
+
+        match current {
+            Some(current) => Ok(Self { keys, current }),
+            None => Err(invalid_data("no encryption keys were given")),
+        }
+    }
+
+    pub fn load(source: &KeySource) -> io::Result<Self> {
+        match source {
+            KeySource::File(path) => Self::parse(&fs::read_to_string(path)?),
+            KeySource::Env(var) => match std::env::var(var) {
+                Ok(contents) => Self::parse(&contents),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("environment variable {} is not set", var),
+                )),
+            },
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    fn aad(key_id: u32, id: LayerId) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(MAGIC.len() + 4 + 20);
+        aad.extend_from_slice(MAGIC);
+        aad.extend_from_slice(&key_id.to_be_bytes());
+        for part in id {
+            aad.extend_from_slice(&part.to_be_bytes());
+        }
+
+        aad
+    }
+
+    /// Encrypt the archive of the given layer with the current key.
+    pub fn encrypt(&self, id: LayerId, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let cipher = &self.keys[&self.current];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plain,
+                    aad: &Self::aad(self.current, id),
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not encrypt layer"))?;
+
+        let mut result = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        result.extend_from_slice(MAGIC);
+        result.extend_from_slice(&self.current.to_be_bytes());
+        result.extend_from_slice(&nonce);
+        result.extend_from_slice(&ciphertext);
+
+        Ok(result)
+    }
+
+    /// The id of the key an archive was encrypted with, or None if it
+    /// is not encrypted.
+    pub fn key_id(data: &[u8]) -> Option<u32> {
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let mut key_id = [0; 4];
+        key_id.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + 4]);
+
+        Some(u32::from_be_bytes(key_id))
+    }
+
+    /// Decrypt and authenticate the archive of the given layer.
+    pub fn decrypt(&self, id: LayerId, data: &[u8]) -> io::Result<Vec<u8>> {
+        let key_id = Self::key_id(data).ok_or_else(|| {
+            invalid_data(format!("layer {} is not encrypted", name_to_string(id)))
+        })?;
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or_else(|| invalid_data(format!("encryption key {} is not known", key_id)))?;
+        let nonce = Nonce::from_slice(&data[MAGIC.len() + 4..HEADER_LEN]);
+
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &data[HEADER_LEN..],
+                    aad: &Self::aad(key_id, id),
+                },
+            )
+            .map_err(|_| {
+                invalid_data(format!(
+                    "layer {} could not be decrypted",
+                    name_to_string(id)
+                ))
+            })
+    }
+}
+
+/// An archive backend that encrypts the archives it stores in the
+/// backend it wraps.
+#[derive(Clone)]
+pub struct EncryptedArchiveBackend<B> {
+    inner: B,
+    keys: KeyRing,
+}
+
+impl<B: ArchiveBackend> EncryptedArchiveBackend<B> {
+    pub fn new(inner: B, keys: KeyRing) -> Self {
+        Self { inner, keys }
+    }
+
+    async fn archive(&self, id: LayerId) -> io::Result<Archive> {
+        let data = self.inner.get_layer_bytes(id).await?;
+        let plain = self.keys.decrypt(id, &data)?;
+
+        Ok(Archive::parse(Bytes::from(plain)))
+    }
+}
+
+#[async_trait]
+impl<B: ArchiveBackend> ArchiveBackend for EncryptedArchiveBackend<B> {
+    type Read = Cursor<Bytes>;
+
+    async fn get_layer_bytes(&self, id: LayerId) -> io::Result<Bytes> {
+        let data = self.inner.get_layer_bytes(id).await?;
+
+        Ok(Bytes::from(self.keys.decrypt(id, &data)?))
+    }
+
+    async fn get_layer_structure_bytes(
+        &self,
+        id: LayerId,
+        file_type: LayerFileEnum,
+    ) -> io::Result<Option<Bytes>> {
+        Ok(self.archive(id).await?.slice_for(file_type))
+    }
+
+    async fn store_layer_file<R: Buf + Send>(&self, id: LayerId, mut bytes: R) -> io::Result<()> {
+        let plain = bytes.copy_to_bytes(bytes.remaining());
+        let encrypted = self.keys.encrypt(id, &plain)?;
+
+        self.inner
+            .store_layer_file(id, Bytes::from(encrypted))
+            .await
+    }
+
+    async fn read_layer_structure_bytes_from(
+        &self,
+        id: LayerId,
+        file_type: LayerFileEnum,
+        read_from: usize,
+    ) -> io::Result<Self::Read> {
+        let mut bytes = self
+            .archive(id)
+            .await?
+            .slice_for(file_type)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "layer file not found in archive")
+            })?;
+        bytes.advance(read_from.min(bytes.len()));
+
+        Ok(Cursor::new(bytes))
+    }
+}
+
+#[async_trait]
+impl<B: ArchiveBackend + ArchiveMetadataBackend> ArchiveMetadataBackend
+    for EncryptedArchiveBackend<B>
+{
+    async fn get_layer_names(&self) -> io::Result<Vec<LayerId>> {
+        self.inner.get_layer_names().await
+    }
+
+    async fn layer_exists(&self, id: LayerId) -> io::Result<bool> {
+        self.inner.layer_exists(id).await
+    }
+
+    async fn layer_size(&self, id: LayerId) -> io::Result<u64> {
+        Ok(self.get_layer_bytes(id).await?.len() as u64)
+    }
+
+    async fn layer_file_exists(&self, id: LayerId, file_type: LayerFileEnum) -> io::Result<bool> {
+        Ok(self.archive(id).await?.slice_for(file_type).is_some())
+    }
+
+    async fn get_layer_structure_size(
+        &self,
+        id: LayerId,
+        file_type: LayerFileEnum,
+    ) -> io::Result<usize> {
+        self.archive(id).await?.size_of(file_type).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "layer file not found in archive")
+        })
+    }
+
+    async fn get_rollup(&self, id: LayerId) -> io::Result<Option<LayerId>> {
+        self.inner.get_rollup(id).await
+    }
+
+    async fn set_rollup(&self, id: LayerId, rollup: LayerId) -> io::Result<()> {
+        self.inner.set_rollup(id, rollup).await
+    }
+
+    async fn get_parent(&self, id: LayerId) -> io::Result<Option<LayerId>> {
+        match self.archive(id).await?.slice_for(LayerFileEnum::Parent) {
+            Some(parent) => {
+                let name = std::str::from_utf8(&parent).map_err(invalid_data)?;
+                Ok(Some(string_to_name(name.trim())?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The archive files of all layers of the store at the given path.
+///
+/// Archives are stored under a directory named after the first
+/// characters of their layer id. Rather than depend on the exact
+/// layout, any file with the archive extension named after a layer in
+/// the store directory or its prefix directory is included.
+fn archive_files(path: &Path) -> io::Result<Vec<(LayerId, PathBuf)>> {
+    let backend = DirectoryArchiveBackend::new(path.into());
+    let mut result = Vec::new();
+    for id in task_sync(backend.get_layer_names())? {
+        let name = name_to_string(id);
+        for dir in [path.to_path_buf(), path.join(&name[..3])] {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?.path();
+                let is_archive = entry
+                    .extension()
+                    .map(|e| e == ARCHIVE_EXTENSION)
+                    .unwrap_or(false);
+                let is_layer = entry
+                    .file_name()
+                    .map(|n| n.to_string_lossy().starts_with(&name))
+                    .unwrap_or(false);
+                if is_archive && is_layer {
+                    result.push((id, entry));
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Replace a file by writing to a temporary file first, so that a
+/// layer is never left half written.
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)
+}
+
+/// Whether any layer archive of the store at the given path is
+/// encrypted. A store that is only partly re-encrypted counts as
+/// encrypted.
+pub fn has_encrypted_archives(path: &Path) -> io::Result<bool> {
+    for (_, file) in archive_files(path)? {
+        let mut magic = [0; MAGIC.len()];
+        match File::open(&file)?.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => return Ok(true),
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Rewrite every layer archive of the store at the given path that is
+/// not encrypted with the current key, returning how many were
+/// rewritten. Plaintext archives are encrypted.
+pub fn reencrypt_archives(path: &Path, keys: &KeyRing) -> io::Result<usize> {
+    let mut count = 0;
+    for (id, file) in archive_files(path)? {
+        let data = fs::read(&file)?;
+        let plain = match KeyRing::key_id(&data) {
+            Some(key_id) if key_id == keys.current() => continue,
+            Some(_) => keys.decrypt(id, &data)?,
+            None => data,
+        };
+        replace_file(&file, &keys.encrypt(id, &plain)?)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+    use terminus_store::storage::archive::ArchiveLayerStore;
+    use terminus_store::storage::directory::DirectoryLabelStore;
+    use terminus_store::storage::{CachedLayerStore, LockingHashMapLayerCache};
+    use terminus_store::store::sync::*;
+    use terminus_store::store::Store;
+
+    const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "f0e0d0c0b0a090807060504030201000f0e0d0c0b0a090807060504030201000";
+    const LAYER: LayerId = [1, 2, 3, 4, 5];
+
+    fn key_ring(keys: &[(u32, &str)]) -> KeyRing {
+        let contents: Vec<String> = keys
+            .iter()
+            .map(|(id, key)| format!("{}:{}", id, key))
+            .collect();
+
+        KeyRing::parse(&contents.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn key_rings_use_the_last_key() {
+        let keys = KeyRing::parse(&format!("# rotated\n1:{}\n\n 2 : {} \n", KEY_1, KEY_2)).unwrap();
+        assert_eq!(2, keys.current());
+        let keys = KeyRing::parse(&format!("2:{},1:{}", KEY_2, KEY_1)).unwrap();
+        assert_eq!(1, keys.current());
+    }
+
+    #[test]
+    fn invalid_key_rings_are_rejected() {
+        for contents in [
+            String::new(),
+            "# no keys\n".to_string(),
+            KEY_1.to_string(),
+            format!("one:{}", KEY_1),
+            "1:not hex".to_string(),
+            format!("1:{}", &KEY_1[..62]),
+            format!("1:{}\n1:{}", KEY_1, KEY_2),
+        ] {
+            let error = KeyRing::parse(&contents).err().unwrap();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind(), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        let keys = key_ring(&[(1, KEY_1)]);
+        let encrypted = keys.encrypt(LAYER, b"archive").unwrap();
+        assert_eq!(Some(1), KeyRing::key_id(&encrypted));
+        assert_eq!(None, KeyRing::key_id(b"archive"));
+        assert_eq!(
+            b"archive".to_vec(),
+            keys.decrypt(LAYER, &encrypted).unwrap()
+        );
+
+        // an older key still decrypts after a rotation
+        let rotated = key_ring(&[(1, KEY_1), (2, KEY_2)]);
+        assert_eq!(
+            b"archive".to_vec(),
+            rotated.decrypt(LAYER, &encrypted).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrong_keys_dont_decrypt() {
+        let encrypted = key_ring(&[(1, KEY_1)]).encrypt(LAYER, b"archive").unwrap();
+
+        assert!(key_ring(&[(2, KEY_2)]).decrypt(LAYER, &encrypted).is_err());
+        assert!(key_ring(&[(1, KEY_2)]).decrypt(LAYER, &encrypted).is_err());
+        assert!(key_ring(&[(1, KEY_1)]).decrypt(LAYER, b"archive").is_err());
+    }
+
+    #[test]
+    fn tampered_archives_dont_decrypt() {
+        let keys = key_ring(&[(1, KEY_1), (2, KEY_2)]);
+        let encrypted = keys.encrypt(LAYER, b"archive").unwrap();
+
+        // the layer id is authenticated
+        assert!(keys.decrypt([1, 2, 3, 4, 6], &encrypted).is_err());
+
+        // so is the key id in the header
+        let mut tampered = encrypted.clone();
+        tampered[MAGIC.len() + 3] = 1;
+        assert!(keys.decrypt(LAYER, &tampered).is_err());
+
+        // and the contents
+        let mut tampered = encrypted;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(keys.decrypt(LAYER, &tampered).is_err());
+    }
+
+    fn open_encrypted_store(path: &Path, keys: KeyRing) -> SyncStore {
+        let backend = EncryptedArchiveBackend::new(DirectoryArchiveBackend::new(path.into()), keys);
+        SyncStore::wrap(Store::new(
+            DirectoryLabelStore::new(path),
+            CachedLayerStore::new(
+                ArchiveLayerStore::new(backend.clone(), backend),
+                LockingHashMapLayerCache::new(),
+            ),
+        ))
+    }
+
+    #[test]
+    fn plaintext_stores_are_encrypted_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_sync_archive_store(dir.path(), 16);
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("duck", "says", "quack"))
+            .unwrap();
+        let child = builder.commit().unwrap().name();
+        assert!(!has_encrypted_archives(dir.path()).unwrap());
+
+        let keys = key_ring(&[(1, KEY_1)]);
+        assert_eq!(2, reencrypt_archives(dir.path(), &keys).unwrap());
+        assert!(has_encrypted_archives(dir.path()).unwrap());
+        assert_eq!(0, reencrypt_archives(dir.path(), &keys).unwrap());
+
+        let rotated = key_ring(&[(1, KEY_1), (2, KEY_2)]);
+        assert_eq!(2, reencrypt_archives(dir.path(), &rotated).unwrap());
+
+        let store = open_encrypted_store(dir.path(), key_ring(&[(2, KEY_2)]));
+        let layer = store.get_layer_from_id(child).unwrap().unwrap();
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("cow", "says", "moo")));
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("duck", "says", "quack")));
+    }
+}
@@ -0,0 +1,496 @@
+//! Conversion of stores between the on-disk layouts of
+//! `open_directory_store`, `open_raw_archive_store` and
+//! `open_archive_store`.
+//!
+//! A directory store keeps every layer as a directory with one file per
+//! layer component, while (raw) archive stores keep every layer in a
+//! single archive file. Raw archive and archive stores share their
+//! layout, and only differ in how they cache layers once opened.
+//!
+//! Converting copies every layer into a store of the new layout,
+//! parents first, and then copies the rollups and the files in the
+//! root of the store directory, which hold the labels and their logs.
+//! Labels are copied as is, so they keep their versions. Afterwards
+//! the converted store is verified against the original: every layer
+//! has to load, with the same parent, the same rollup and the same
+//! number of additions and removals.
+//!
+//! A store is converted in place by converting it into a sibling
+//! directory first. Only after it was verified does it replace the
+//! original. The store must not be opened while it is converted.
+//!
+//! Encrypted archive stores are refused, as their layers can't be read
+//! without the keys.
+pub mod encryption;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use terminus_store::storage::archive::{ArchiveLayerStore, DirectoryArchiveBackend};
+use terminus_store::storage::directory::DirectoryLayerStore;
+use terminus_store::storage::{name_to_string, LayerStore, PersistentLayerStore};
+use terminus_store::store::sync::*;
+
+/// The number of layers kept in memory while converting from or to an
+/// archive store.
+const CACHE_SIZE: usize = 64;
+
+pub type LayerId = [u32; 5];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreLayout {
+    Directory,
+    RawArchive,
+    Archive,
+}
+
+impl FromStr for StoreLayout {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "directory" => Ok(StoreLayout::Directory),
+            "raw_archive" => Ok(StoreLayout::RawArchive),
+            "archive" => Ok(StoreLayout::Archive),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown store layout {}, expected directory, raw_archive or archive",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StoreLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreLayout::Directory => write!(f, "directory"),
+            StoreLayout::RawArchive => write!(f, "raw_archive"),
+            StoreLayout::Archive => write!(f, "archive"),
+        }
+    }
+}
+
+/// The layer store of a store, for the layer metadata that a
+/// `SyncStore` doesn't give access to.
+enum RawLayerStore {
+    Directory(DirectoryLayerStore),
+    Archive(ArchiveLayerStore<DirectoryArchiveBackend, DirectoryArchiveBackend>),
+}
+
+impl RawLayerStore {
+    fn open(path: &Path, layout: StoreLayout) -> Self {
+        match layout {
+            StoreLayout::Directory => RawLayerStore::Directory(DirectoryLayerStore::new(path)),
+            StoreLayout::RawArchive | StoreLayout::Archive => {
+                let backend = DirectoryArchiveBackend::new(path.into());
+                RawLayerStore::Archive(ArchiveLayerStore::new(backend.clone(), backend))
+            }
+        }
+    }
+
+    fn layers(&self) -> io::Result<Vec<LayerId>> {
+        match self {
+            RawLayerStore::Directory(store) => task_sync(LayerStore::layers(store)),
+            RawLayerStore::Archive(store) => task_sync(LayerStore::layers(store)),
+        }
+    }
+
+    fn parent(&self, id: LayerId) -> io::Result<Option<LayerId>> {
+        match self {
+            RawLayerStore::Directory(store) => {
+                task_sync(LayerStore::get_layer_parent_name(store, id))
+            }
+            RawLayerStore::Archive(store) => {
+                task_sync(LayerStore::get_layer_parent_name(store, id))
+            }
+        }
+    }
+
+    fn rollup(&self, id: LayerId) -> io::Result<Option<LayerId>> {
+        match self {
+            RawLayerStore::Directory(store) => {
+                if task_sync(store.layer_has_rollup(id))? {
+                    Ok(Some(task_sync(store.read_rollup_file(id))?))
+                } else {
+                    Ok(None)
+                }
+            }
+            RawLayerStore::Archive(store) => {
+                if task_sync(store.layer_has_rollup(id))? {
+                    Ok(Some(task_sync(store.read_rollup_file(id))?))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    fn set_rollup(&self, id: LayerId, rollup: LayerId) -> io::Result<()> {
+        match self {
+            RawLayerStore::Directory(store) => task_sync(store.write_rollup_file(id, rollup)),
+            RawLayerStore::Archive(store) => task_sync(store.write_rollup_file(id, rollup)),
+        }
+    }
+}
+
+fn open_store(path: &Path, layout: StoreLayout) -> SyncStore {
+    match layout {
+        StoreLayout::Directory => open_sync_directory_store(path),
+        StoreLayout::RawArchive | StoreLayout::Archive => open_sync_archive_store(path, CACHE_SIZE),
+    }
+}
+
+/// Load a layer, failing if the store doesn't have it.
+pub fn get_layer(store: &SyncStore, id: LayerId) -> io::Result<SyncStoreLayer> {
+    store.get_layer_from_id(id)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("layer {} not found", name_to_string(id)),
+        )
+    })
+}
+
+fn mismatch(id: LayerId, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "converted layer {} has a different {}",
+            name_to_string(id),
+            what
+        ),
+    )
+}
+
+/// Order the layers so that parents come before their children.
+fn parents_first(layers: &RawLayerStore, ids: Vec<LayerId>) -> io::Result<Vec<LayerId>> {
+    let all: HashSet<LayerId> = ids.iter().cloned().collect();
+    let mut ordered = Vec::with_capacity(ids.len());
+    let mut emitted = HashSet::with_capacity(ids.len());
+    for id in ids {
+        let mut chain = vec![id];
+        let mut cur = layers.parent(id)?;
+        while let Some(parent) = cur {
+            if !all.contains(&parent) || emitted.contains(&parent) {
+                break;
+            }
+            chain.push(parent);
+            cur = layers.parent(parent)?;
+        }
+        for id in chain.into_iter().rev() {
+            if emitted.insert(id) {
+                ordered.push(id);
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// Copy the regular files in the root of the store directory, which
+/// hold the labels, their logs and any unfinished label transaction.
+/// Files that belong to a layer were already copied along with it.
+fn copy_root_files(from: &Path, to: &Path, layers: &[LayerId]) -> io::Result<usize> {
+    let layer_names: Vec<String> = layers.iter().map(|id| name_to_string(*id)).collect();
+    let mut count = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = file_name.to_string_lossy();
+        if !entry.file_type()?.is_file() || layer_names.iter().any(|layer| name.starts_with(layer))
+        {
+            continue;
+        }
+        fs::copy(entry.path(), to.join(&file_name))?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[derive(Debug, Default)]
+pub struct ConversionReport {
+    pub layers: usize,
+    pub rollups: usize,
+    /// The number of files in the store root that were copied, which
+    /// are mostly labels.
+    pub files: usize,
+}
+
+fn copy_store(
+    from: &Path,
+    from_layout: StoreLayout,
+    to: &Path,
+    to_layout: StoreLayout,
+) -> io::Result<ConversionReport> {
+    let from_layers = RawLayerStore::open(from, from_layout);
+    let from_store = open_store(from, from_layout);
+    let to_layers = RawLayerStore::open(to, to_layout);
+    let to_store = open_store(to, to_layout);
+
+    let mut report = ConversionReport::default();
+    let ids = parents_first(&from_layers, from_layers.layers()?)?;
+    for id in ids.iter() {
+        let pack = from_store.export_layers(Box::new(iter::once(*id)))?;
+        to_store.import_layers(pack.as_slice(), Box::new(iter::once(*id)))?;
+        report.layers += 1;
+    }
+    for id in ids.iter() {
+        if let Some(rollup) = from_layers.rollup(*id)? {
+            to_layers.set_rollup(*id, rollup)?;
+            report.rollups += 1;
+        }
+    }
+    report.files = copy_root_files(from, to, &ids)?;
+
+    Ok(report)
+}
+
+/// Check that every layer of the original store loads in the converted
+/// store, with the same parent, rollup and number of changes.
+pub fn verify_conversion(
+    from: &Path,
+    from_layout: StoreLayout,
+    to: &Path,
+    to_layout: StoreLayout,
+) -> io::Result<()> {
+    let from_layers = RawLayerStore::open(from, from_layout);
+    let from_store = open_store(from, from_layout);
+    let to_layers = RawLayerStore::open(to, to_layout);
+    let to_store = open_store(to, to_layout);
+
+    let converted: HashSet<LayerId> = to_layers.layers()?.into_iter().collect();
+    for id in from_layers.layers()? {
+        if !converted.contains(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("layer {} was not converted", name_to_string(id)),
+            ));
+        }
+        let original = get_layer(&from_store, id)?;
+        let layer = get_layer(&to_store, id)?;
+        if layer.parent_name() != original.parent_name() {
+            return Err(mismatch(id, "parent"));
+        }
+        if to_layers.rollup(id)? != from_layers.rollup(id)? {
+            return Err(mismatch(id, "rollup"));
+        }
+        if layer.triple_layer_addition_count()? != original.triple_layer_addition_count()?
+            || layer.triple_layer_removal_count()? != original.triple_layer_removal_count()?
+        {
+            return Err(mismatch(id, "content"));
+        }
+    }
+
+    Ok(())
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(suffix);
+
+    PathBuf::from(sibling)
+}
+
+/// Fail on stores with encrypted layer archives, which would otherwise
+/// look corrupt.
+fn reject_encrypted(path: &Path) -> io::Result<()> {
+    if encryption::has_encrypted_archives(path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the store at {} is encrypted", path.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+fn create_empty_dir(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+    if fs::read_dir(path)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", path.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Convert the store at `from` into a store of another layout at `to`,
+/// which has to be empty or not exist yet, and verify the result.
+pub fn convert_store(
+    from: &Path,
+    from_layout: StoreLayout,
+    to: &Path,
+    to_layout: StoreLayout,
+) -> io::Result<ConversionReport> {
+    reject_encrypted(from)?;
+    create_empty_dir(to)?;
+    let report = copy_store(from, from_layout, to, to_layout)?;
+    verify_conversion(from, from_layout, to, to_layout)?;
+
+    Ok(report)
+}
+
+/// Convert the store at `path` into another layout in place.
+///
+/// The store is converted into a sibling directory first. Once that
+/// was verified, the original is moved aside, the converted store is
+/// moved into its place, and the original is removed. If this is
+/// interrupted, the original store is still in the `.unconverted`
+/// sibling directory, or still in place.
+pub fn convert_store_in_place(
+    path: &Path,
+    from_layout: StoreLayout,
+    to_layout: StoreLayout,
+) -> io::Result<ConversionReport> {
+    reject_encrypted(path)?;
+    let converted = sibling(path, ".converting");
+    let original = sibling(path, ".unconverted");
+    if converted.exists() {
+        fs::remove_dir_all(&converted)?;
+    }
+    if original.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} exists, an earlier conversion was interrupted",
+                original.display()
+            ),
+        ));
+    }
+
+    let report = convert_store(path, from_layout, &converted, to_layout)?;
+    fs::rename(path, &original)?;
+    fs::rename(&converted, path)?;
+    fs::remove_dir_all(&original)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+
+    fn create_store(path: &Path) -> LayerId {
+        let store = open_sync_directory_store(path);
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("duck", "says", "quack"))
+            .unwrap();
+        builder
+            .remove_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let child = builder.commit().unwrap();
+        let graph = store.create("animals").unwrap();
+        assert!(graph.set_head(&base).unwrap());
+        assert!(graph.set_head(&child).unwrap());
+
+        child.name()
+    }
+
+    fn assert_converted(path: &Path, layout: StoreLayout, head: LayerId) {
+        let store = open_store(path, layout);
+        let graph = store.open("animals").unwrap().unwrap();
+        let (layer, version) = graph.head_version().unwrap();
+        let layer = layer.unwrap();
+        assert_eq!(head, layer.name());
+        assert_eq!(2, version);
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("duck", "says", "quack")));
+        assert!(!layer.value_triple_exists(&ValueTriple::new_node("cow", "says", "moo")));
+    }
+
+    #[test]
+    fn convert_directory_to_archive_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().join("directory");
+        let archive = dir.path().join("archive");
+        let head = create_store(&directory);
+
+        let report = convert_store(
+            &directory,
+            StoreLayout::Directory,
+            &archive,
+            StoreLayout::Archive,
+        )
+        .unwrap();
+        assert_eq!(2, report.layers);
+        assert_converted(&archive, StoreLayout::Archive, head);
+
+        convert_store_in_place(&archive, StoreLayout::Archive, StoreLayout::Directory).unwrap();
+        assert_converted(&archive, StoreLayout::Directory, head);
+        assert!(!sibling(&archive, ".converting").exists());
+        assert!(!sibling(&archive, ".unconverted").exists());
+    }
+
+    #[test]
+    fn convert_refuses_nonempty_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().join("directory");
+        let archive = dir.path().join("archive");
+        create_store(&directory);
+        fs::create_dir_all(&archive).unwrap();
+        fs::write(archive.join("something"), b"").unwrap();
+
+        let error = convert_store(
+            &directory,
+            StoreLayout::Directory,
+            &archive,
+            StoreLayout::Archive,
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+    }
+
+    #[test]
+    fn convert_refuses_encrypted_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().join("directory");
+        let archive = dir.path().join("archive");
+        let head = create_store(&directory);
+        convert_store(
+            &directory,
+            StoreLayout::Directory,
+            &archive,
+            StoreLayout::Archive,
+        )
+        .unwrap();
+        let keys = encryption::KeyRing::parse(
+            "1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+        assert_eq!(2, encryption::reencrypt_archives(&archive, &keys).unwrap());
+
+        let error = convert_store(
+            &archive,
+            StoreLayout::Archive,
+            &dir.path().join("converted"),
+            StoreLayout::Directory,
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert!(!dir.path().join("converted").exists());
+
+        let error = convert_store_in_place(&archive, StoreLayout::Archive, StoreLayout::Directory)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert!(!sibling(&archive, ".converting").exists());
+        assert!(encryption::has_encrypted_archives(&archive).unwrap());
+        assert!(!encryption::has_encrypted_archives(&directory).unwrap());
+        assert_converted(&directory, StoreLayout::Directory, head);
+    }
+}
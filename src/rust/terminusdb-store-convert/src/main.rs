@@ -0,0 +1,98 @@
+//! Converts a store between the directory, raw archive and archive
+//! layouts, or re-encrypts the layers of an archive store.
+//!
+//! ```text
+//! terminusdb-store-convert <from layout> <to layout> <store> [<destination>]
+//! terminusdb-store-convert reencrypt <store> (--key-file <path> | --key-env <variable>)
+//! ```
+//!
+//! Without a destination, the store is converted in place.
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use terminusdb_store_convert::encryption::*;
+use terminusdb_store_convert::*;
+
+const USAGE: &str =
+    "usage: terminusdb-store-convert <from layout> <to layout> <store> [<destination>]
+       terminusdb-store-convert reencrypt <store> (--key-file <path> | --key-env <variable>)
+
+layouts are directory, raw_archive and archive. Without a destination,
+the store is converted in place.
+
+reencrypt rewrites every layer of an archive store that is not
+encrypted with the last of the given keys, including plaintext layers.
+
+The store must not be in use.";
+
+fn run(args: &[String]) -> io::Result<()> {
+    let from_layout: StoreLayout = args[0].parse()?;
+    let to_layout: StoreLayout = args[1].parse()?;
+    let path = Path::new(&args[2]);
+    let report = match args.get(3) {
+        Some(destination) => convert_store(path, from_layout, Path::new(destination), to_layout)?,
+        None => convert_store_in_place(path, from_layout, to_layout)?,
+    };
+
+    println!(
+        "converted {} from {} to {}: {} layers, {} rollups and {} label files",
+        path.display(),
+        from_layout,
+        to_layout,
+        report.layers,
+        report.rollups,
+        report.files
+    );
+
+    Ok(())
+}
+
+fn key_source(option: &str, value: &str) -> Option<KeySource> {
+    match option {
+        "--key-file" => Some(KeySource::File(PathBuf::from(value))),
+        "--key-env" => Some(KeySource::Env(value.to_string())),
+        _ => None,
+    }
+}
+
+fn reencrypt(path: &Path, source: &KeySource) -> io::Result<()> {
+    let keys = KeyRing::load(source)?;
+    let count = reencrypt_archives(path, &keys)?;
+
+    println!(
+        "re-encrypted {} layers of {} with key {}",
+        count,
+        path.display(),
+        keys.current()
+    );
+
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = if args.first().map(String::as_str) == Some("reencrypt") {
+        if args.len() != 4 {
+            usage();
+        }
+        let source = key_source(&args[2], &args[3]).unwrap_or_else(|| usage());
+        reencrypt(Path::new(&args[1]), &source)
+    } else {
+        if args.len() < 3 || args.len() > 4 {
+            usage();
+        }
+        run(&args)
+    };
+
+    if let Err(e) = result {
+        eprintln!("conversion failed: {}", e);
+        process::exit(1);
+    }
+}
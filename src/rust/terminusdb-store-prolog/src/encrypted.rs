@@ -0,0 +1,61 @@
+//! Archive stores whose layers are encrypted at rest.
+//!
+//! The encryption itself lives in
+//! [`terminusdb_store_convert::encryption`], so that stores can also be
+//! re-encrypted with `terminusdb-store-convert reencrypt` while the
+//! server is down. This module opens encrypted stores and re-encrypts
+//! them from Prolog.
+//!
+//! `verify_store` and `garbage_collect_layers` read archives without
+//! keys, and refuse encrypted stores with an error.
+use std::path::{Path, PathBuf};
+
+use swipl::prelude::*;
+use terminus_store::storage::archive::{
+    ArchiveLayerStore, DirectoryArchiveBackend, LruArchiveBackend,
+};
+use terminusdb_store_convert::encryption::*;
+
+use crate::store::*;
+
+fn key_ring_from_term<C: QueryableContextType>(
+    context: &Context<'_, C>,
+    keys_term: &Term,
+) -> PrologResult<KeyRing> {
+    let inner = context.new_term_ref();
+    let source = if attempt(keys_term.unify(term! {context: file(#&inner)}?))? {
+        let path: PrologText = inner.get_ex()?;
+        KeySource::File(PathBuf::from(&*path))
+    } else if attempt(keys_term.unify(term! {context: env(#&inner)}?))? {
+        let var: PrologText = inner.get_ex()?;
+        KeySource::Env(var.to_string())
+    } else {
+        return context.raise_exception(
+            &term! {context: error(domain_error(oneof([file(), env()]), #keys_term), _)}?,
+        );
+    };
+
+    context.try_or_die(KeyRing::load(&source))
+}
+
+predicates! {
+    pub semidet fn open_encrypted_archive_store(context, dir_term, keys_term, cache_size_term, out_term) {
+        let dir: PrologText = dir_term.get_ex()?;
+        let keys = key_ring_from_term(context, keys_term)?;
+        let cache_size: usize = cache_size_term.get_ex::<u64>()? as usize;
+        let directory_layer_backend = DirectoryArchiveBackend::new((&*dir).into());
+        let encrypted_layer_backend = EncryptedArchiveBackend::new(directory_layer_backend, keys);
+        let layer_backend = LruArchiveBackend::new(encrypted_layer_backend.clone(), encrypted_layer_backend, cache_size);
+        let layer_store = ArchiveLayerStore::new(layer_backend.clone(), layer_backend);
+        let store = context.try_or_die(LoggedStore::open(Some(&*dir), layer_store))?;
+        out_term.unify(&WrappedStore(store))
+    }
+
+    pub semidet fn reencrypt_archive_store(context, dir_term, keys_term, count_term) {
+        let dir: PrologText = dir_term.get_ex()?;
+        let keys = key_ring_from_term(context, keys_term)?;
+        let count = context.try_or_die(reencrypt_archives(Path::new(&*dir), &keys))?;
+
+        count_term.unify(count as u64)
+    }
+}
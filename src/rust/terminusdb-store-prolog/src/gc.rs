@@ -0,0 +1,506 @@
+//! Garbage collection of layers that can no longer be reached.
+//!
+//! Squashes, resets, deleted branches and merged base layers leave
+//! layers behind that nothing points at anymore. A layer is live if
+//!
+//! - a label points at it,
+//! - it is the parent or the rollup of a live layer, or
+//! - the content of a layer that a label points at, or that is
+//!   referenced this way itself, mentions its id as a
+//!   `layer:identifier`. This is how repository graphs point at commit
+//!   graphs, and how commits point at their instance and schema
+//!   layers.
+//!
+//! The layers recorded in the label logs count as labelled as well,
+//! so that a head that was moved away from can still be restored
+//! through the log after a collection. This only holds for changes
+//! made within the history period. Older entries expire, so that the
+//! layers left behind by old resets, squashes and deleted branches
+//! are eventually collected. Restoring a head to an expired entry
+//! fails once its layer is gone.
+//!
+//! The layers in the journal of an interrupted head transaction are
+//! live as well, as opening the store rolls the labels onto them, or
+//! back to the layers they pointed at before.
+//!
+//! Everything else is unreachable. Unreachable layers that were
+//! written within the grace period are left alone, as they may belong
+//! to a commit that is still in progress. Just before deleting
+//! anything, the labels are read again. If any of them moved while
+//! the live layers were being determined, the marking starts over, so
+//! a head that was set through the versioned `nb_set_head` in the
+//! meantime is taken into account.
+//!
+//! Encrypted archive stores are refused, as the references between
+//! layers can't be read without the keys.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use swipl::prelude::*;
+use tdb_succinct::Datatype;
+use terminus_store::layer::ObjectType;
+use terminus_store::storage::archive::{ArchiveLayerStore, DirectoryArchiveBackend};
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::{string_to_name, LabelStore, LayerStore, PersistentLayerStore};
+use terminus_store::store::sync::*;
+use terminus_store::Layer;
+
+use crate::label_log::LabelLog;
+use crate::layer_ids::*;
+use crate::named_graph::journaled_layers;
+use crate::verify::{
+    layer_entries, reject_encrypted, store_kind_from_term, StoreKind, VERIFY_CACHE_SIZE,
+};
+
+/// The predicate through which layers refer to other layers.
+pub const LAYER_IDENTIFIER: &str = "http://terminusdb.com/schema/layer#identifier";
+
+/// How often the marking is redone when labels keep moving.
+const MAX_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// The number of live layers.
+    pub live: usize,
+    /// Unreachable layers older than the grace period.
+    pub unreachable: Vec<LayerId>,
+    /// Unreachable layers that are still within the grace period.
+    pub in_grace: Vec<LayerId>,
+    /// The layers that were deleted.
+    pub deleted: Vec<LayerId>,
+}
+
+/// The layers that a layer refers to through `layer:identifier`.
+pub(crate) fn referenced_layers(layer: &SyncStoreLayer) -> Vec<LayerId> {
+    let predicate = match layer.predicate_id(LAYER_IDENTIFIER) {
+        Some(predicate) => predicate,
+        None => return Vec::new(),
+    };
+
+    layer
+        .triples_p(predicate)
+        .filter_map(|triple| match layer.id_object(triple.object) {
+            Some(ObjectType::Value(entry)) if entry.datatype() == Datatype::String => {
+                string_to_name(&entry.as_val::<String, String>()).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn label_heads(
+    labels: &DirectoryLabelStore,
+) -> io::Result<HashMap<String, (Option<LayerId>, u64)>> {
+    Ok(task_sync(labels.labels())?
+        .into_iter()
+        .map(|label| (label.name, (label.layer, label.version)))
+        .collect())
+}
+
+fn mark<S: LayerStore + PersistentLayerStore>(
+    layers: &S,
+    store: &SyncStore,
+    existing: &HashSet<LayerId>,
+    roots: impl Iterator<Item = LayerId>,
+) -> io::Result<HashSet<LayerId>> {
+    let mut live = HashSet::new();
+    let mut scanned = HashSet::new();
+    let mut to_scan: Vec<LayerId> = roots.collect();
+    while let Some(id) = to_scan.pop() {
+        if !existing.contains(&id) || !scanned.insert(id) {
+            continue;
+        }
+        if let Some(layer) = store.get_layer_from_id(id)? {
+            to_scan.extend(referenced_layers(&layer));
+        }
+
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            if !existing.contains(&id) || !live.insert(id) {
+                continue;
+            }
+            if let Some(parent) = task_sync(LayerStore::get_layer_parent_name(layers, id))? {
+                pending.push(parent);
+            }
+            if task_sync(layers.layer_has_rollup(id))? {
+                pending.push(task_sync(layers.read_rollup_file(id))?);
+            }
+        }
+    }
+
+    Ok(live)
+}
+
+/// The time a layer was last written to, which is the latest
+/// modification time of its files.
+fn last_modified(path: &Path, id: LayerId) -> io::Result<Option<SystemTime>> {
+    let mut latest = None;
+    for entry in layer_entries(path, id)? {
+        let modified = fs::metadata(entry)?.modified()?;
+        if latest.map(|latest| modified > latest).unwrap_or(true) {
+            latest = Some(modified);
+        }
+    }
+
+    Ok(latest)
+}
+
+/// The time in milliseconds since the unix epoch before which label
+/// log entries have expired.
+fn history_cutoff(history: Duration) -> u64 {
+    SystemTime::now()
+        .checked_sub(history)
+        .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+        .map(|cutoff| cutoff.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn collect<S: LayerStore + PersistentLayerStore>(
+    path: &Path,
+    layers: S,
+    store: SyncStore,
+    grace: Duration,
+    history: Duration,
+    dry_run: bool,
+) -> io::Result<GcReport> {
+    let labels = DirectoryLabelStore::new(path);
+    let label_log = LabelLog::directory(path);
+    for _ in 0..MAX_ATTEMPTS {
+        let heads = label_heads(&labels)?;
+        let existing: HashSet<LayerId> = task_sync(LayerStore::layers(&layers))?
+            .into_iter()
+            .collect();
+        let logged = label_log.logged_layers(history_cutoff(history))?;
+        let journaled = journaled_layers(path)?;
+        let roots = heads
+            .values()
+            .filter_map(|(layer, _)| *layer)
+            .chain(logged)
+            .chain(journaled);
+        let live = mark(&layers, &store, &existing, roots)?;
+
+        let now = SystemTime::now();
+        let mut report = GcReport {
+            live: live.len(),
+            ..Default::default()
+        };
+        let mut candidates: Vec<LayerId> = existing.difference(&live).cloned().collect();
+        candidates.sort();
+        for id in candidates {
+            let age = match last_modified(path, id)? {
+                Some(modified) => now.duration_since(modified).unwrap_or_default(),
+                None => Duration::default(),
+            };
+            if age < grace {
+                report.in_grace.push(id);
+            } else {
+                report.unreachable.push(id);
+            }
+        }
+
+        if dry_run {
+            return Ok(report);
+        }
+        if label_heads(&labels)? != heads {
+            continue;
+        }
+
+        for id in report.unreachable.iter() {
+            for entry in layer_entries(path, *id)? {
+                if entry.is_dir() {
+                    fs::remove_dir_all(entry)?;
+                } else {
+                    fs::remove_file(entry)?;
+                }
+            }
+            report.deleted.push(*id);
+        }
+
+        return Ok(report);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "labels kept changing during garbage collection",
+    ))
+}
+
+/// Find the unreachable layers of the store at the given path, and
+/// unless `dry_run` is set, delete those that are older than `grace`.
+/// Label log entries older than `history` don't keep layers alive.
+pub fn collect_garbage(
+    path: &Path,
+    kind: StoreKind,
+    grace: Duration,
+    history: Duration,
+    dry_run: bool,
+) -> io::Result<GcReport> {
+    reject_encrypted(path, kind)?;
+    match kind {
+        StoreKind::Directory => collect(
+            path,
+            DirectoryLayerStore::new(path),
+            open_sync_directory_store(path),
+            grace,
+            history,
+            dry_run,
+        ),
+        StoreKind::Archive => {
+            let backend = DirectoryArchiveBackend::new(path.into());
+            collect(
+                path,
+                ArchiveLayerStore::new(backend.clone(), backend),
+                open_sync_archive_store(path, VERIFY_CACHE_SIZE),
+                grace,
+                history,
+                dry_run,
+            )
+        }
+    }
+}
+
+predicates! {
+    pub semidet fn garbage_collect_layers(context, path_term, kind_term, grace_term, history_term, dry_run_term, unreachable_term, deleted_term) {
+        let path: PrologText = path_term.get_ex()?;
+        let kind = store_kind_from_term(context, kind_term)?;
+        let grace: u64 = grace_term.get_ex()?;
+        let history: u64 = history_term.get_ex()?;
+        let dry_run: bool = dry_run_term.get_ex()?;
+
+        let report = context.try_or_die(collect_garbage(Path::new(&*path), kind, Duration::from_secs(grace), Duration::from_secs(history), dry_run))?;
+
+        unify_layer_ids(unreachable_term, report.unreachable)?;
+        unify_layer_ids(deleted_term, report.deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label_log::LabelLogEntry;
+    use crate::named_graph::HEADS_JOURNAL;
+    use tdb_succinct::TdbDataType;
+    use terminus_store::layer::ValueTriple;
+    use terminus_store::storage::name_to_string;
+
+    const HISTORY: Duration = Duration::from_secs(3600);
+
+    fn base_layer(store: &SyncStore, triple: ValueTriple) -> SyncStoreLayer {
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple(triple).unwrap();
+
+        builder.commit().unwrap()
+    }
+
+    fn set_label(store: &SyncStore, label: &str, layer: &SyncStoreLayer) {
+        let graph = store.create(label).unwrap();
+        assert!(graph.set_head(layer).unwrap());
+    }
+
+    struct Layers {
+        base: LayerId,
+        child: LayerId,
+        repository: LayerId,
+        referenced: LayerId,
+        orphan: LayerId,
+    }
+
+    /// Create an archive store with a labelled stack of two layers, a
+    /// labelled layer referring to another layer through
+    /// `layer:identifier`, and a layer nothing points at.
+    fn create_store(path: &Path) -> Layers {
+        let store = open_sync_archive_store(path, VERIFY_CACHE_SIZE);
+        let base = base_layer(&store, ValueTriple::new_node("cow", "says", "moo"));
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("duck", "says", "quack"))
+            .unwrap();
+        let child = builder.commit().unwrap();
+        set_label(&store, "animals", &child);
+
+        let referenced = base_layer(&store, ValueTriple::new_node("pig", "says", "oink"));
+        let repository = base_layer(
+            &store,
+            ValueTriple::new_value(
+                "commit",
+                LAYER_IDENTIFIER,
+                String::make_entry(&name_to_string(referenced.name())),
+            ),
+        );
+        set_label(&store, "repository", &repository);
+
+        let orphan = base_layer(&store, ValueTriple::new_node("cat", "says", "meow"));
+
+        Layers {
+            base: base.name(),
+            child: child.name(),
+            repository: repository.name(),
+            referenced: referenced.name(),
+            orphan: orphan.name(),
+        }
+    }
+
+    #[test]
+    fn marks_parents_and_referenced_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+        let store = open_sync_archive_store(dir.path(), VERIFY_CACHE_SIZE);
+        let backend = DirectoryArchiveBackend::new(dir.path().into());
+        let layer_store = ArchiveLayerStore::new(backend.clone(), backend);
+        let existing: HashSet<LayerId> = task_sync(LayerStore::layers(&layer_store))
+            .unwrap()
+            .into_iter()
+            .collect();
+
+        let live = mark(
+            &layer_store,
+            &store,
+            &existing,
+            vec![layers.child, layers.repository].into_iter(),
+        )
+        .unwrap();
+        let expected: HashSet<LayerId> = vec![
+            layers.base,
+            layers.child,
+            layers.repository,
+            layers.referenced,
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, live);
+    }
+
+    #[test]
+    fn unreachable_layers_within_the_grace_period_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::from_secs(3600),
+            HISTORY,
+            false,
+        )
+        .unwrap();
+        assert_eq!(4, report.live);
+        assert!(report.unreachable.is_empty());
+        assert_eq!(vec![layers.orphan], report.in_grace);
+        assert!(report.deleted.is_empty());
+        assert!(!layer_entries(dir.path(), layers.orphan).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreachable_layers_past_the_grace_period_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::default(),
+            HISTORY,
+            true,
+        )
+        .unwrap();
+        assert_eq!(vec![layers.orphan], report.unreachable);
+        assert!(report.deleted.is_empty());
+        assert!(!layer_entries(dir.path(), layers.orphan).unwrap().is_empty());
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::default(),
+            HISTORY,
+            false,
+        )
+        .unwrap();
+        assert_eq!(vec![layers.orphan], report.deleted);
+        assert!(layer_entries(dir.path(), layers.orphan).unwrap().is_empty());
+        assert!(!layer_entries(dir.path(), layers.referenced)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Log that the label of the animals moved from the orphan to its
+    /// current head at the given time.
+    fn log_reset(path: &Path, layers: &Layers, timestamp: u64) {
+        LabelLog::directory(path)
+            .append(
+                "animals",
+                &LabelLogEntry {
+                    version: 2,
+                    previous: Some(layers.orphan),
+                    layer: Some(layers.child),
+                    timestamp,
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn logged_layers_are_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+        log_reset(dir.path(), &layers, history_cutoff(Duration::default()));
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::default(),
+            HISTORY,
+            false,
+        )
+        .unwrap();
+        assert_eq!(5, report.live);
+        assert!(report.deleted.is_empty());
+    }
+
+    #[test]
+    fn journaled_layers_are_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+        fs::write(
+            dir.path().join(HEADS_JOURNAL),
+            format!(
+                "1 {} {} animals\n",
+                name_to_string(layers.child),
+                name_to_string(layers.orphan)
+            ),
+        )
+        .unwrap();
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::default(),
+            HISTORY,
+            false,
+        )
+        .unwrap();
+        assert_eq!(5, report.live);
+        assert!(report.deleted.is_empty());
+    }
+
+    #[test]
+    fn layers_of_expired_log_entries_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = create_store(dir.path());
+        log_reset(dir.path(), &layers, history_cutoff(HISTORY * 2));
+
+        let report = collect_garbage(
+            dir.path(),
+            StoreKind::Archive,
+            Duration::default(),
+            HISTORY,
+            false,
+        )
+        .unwrap();
+        assert_eq!(4, report.live);
+        assert_eq!(vec![layers.orphan], report.deleted);
+        assert!(layer_entries(dir.path(), layers.orphan).unwrap().is_empty());
+    }
+}
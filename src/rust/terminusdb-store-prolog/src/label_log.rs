@@ -0,0 +1,490 @@
+//! A history of label changes.
+//!
+//! Every change of a label is appended to a log as the new version,
+//! the layer the label pointed at before, the layer it points at now,
+//! and the time of the change. This makes an accidental
+//! `nb_force_set_head` recoverable.
+//!
+//! For stores on disk the log of a label is kept in a `.reflog` file
+//! next to its `.label` file. Each line holds one change as
+//!
+//! ```text
+//! <version> <previous layer or -> <new layer or -> <milliseconds since epoch>
+//! ```
+//!
+//! Lines are only ever appended. A line that was only partially
+//! written, for example because the process died, is skipped when
+//! reading the log.
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use swipl::prelude::*;
+use terminus_store::storage::{name_to_string, Label, LabelStore};
+
+use crate::layer_ids::*;
+use crate::store::*;
+
+pub const LABEL_LOG_EXTENSION: &str = "reflog";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelLogEntry {
+    /// The version of the label after the change.
+    pub version: u64,
+    pub previous: Option<LayerId>,
+    pub layer: Option<LayerId>,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+impl LabelLogEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {}\n",
+            self.version,
+            layer_to_field(self.previous),
+            layer_to_field(self.layer),
+            self.timestamp
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 4 {
+            return None;
+        }
+
+        Some(Self {
+            version: fields[0].parse().ok()?,
+            previous: field_to_layer(fields[1])?,
+            layer: field_to_layer(fields[2])?,
+            timestamp: fields[3].parse().ok()?,
+        })
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Where label changes are recorded.
+#[derive(Clone)]
+pub enum LabelLog {
+    Directory(PathBuf),
+    Memory(Arc<Mutex<HashMap<String, Vec<LabelLogEntry>>>>),
+}
+
+impl LabelLog {
+    pub fn directory<P: Into<PathBuf>>(path: P) -> Self {
+        LabelLog::Directory(path.into())
+    }
+
+    pub fn memory() -> Self {
+        LabelLog::Memory(Default::default())
+    }
+
+    fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.{}", name, LABEL_LOG_EXTENSION))
+    }
+
+    pub fn append(&self, name: &str, entry: &LabelLogEntry) -> io::Result<()> {
+        match self {
+            LabelLog::Directory(dir) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(Self::path(dir, name))?;
+                // a single write, so that concurrent appends don't
+                // interleave within a line
+                file.write_all(entry.to_line().as_bytes())?;
+                file.sync_data()
+            }
+            LabelLog::Memory(logs) => {
+                logs.lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(entry.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// All recorded changes of a label, oldest first.
+    pub fn entries(&self, name: &str) -> io::Result<Vec<LabelLogEntry>> {
+        match self {
+            LabelLog::Directory(dir) => match fs::read_to_string(Self::path(dir, name)) {
+                Ok(contents) => Ok(contents
+                    .lines()
+                    .filter_map(LabelLogEntry::from_line)
+                    .collect()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(e),
+            },
+            LabelLog::Memory(logs) => {
+                Ok(logs.lock().unwrap().get(name).cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Every layer mentioned in a change made at or after `since`, in
+    /// milliseconds since the unix epoch, in the log of any label,
+    /// including the logs of labels that have since been deleted.
+    pub fn logged_layers(&self, since: u64) -> io::Result<Vec<LayerId>> {
+        let entries: Vec<LabelLogEntry> = match self {
+            LabelLog::Directory(dir) => {
+                let mut entries = Vec::new();
+                for file in fs::read_dir(dir)? {
+                    let path = file?.path();
+                    if path.extension().and_then(|e| e.to_str()) != Some(LABEL_LOG_EXTENSION) {
+                        continue;
+                    }
+                    if let Some(name) = path.file_stem().and_then(|n| n.to_str()) {
+                        entries.extend(self.entries(name)?);
+                    }
+                }
+
+                entries
+            }
+            LabelLog::Memory(logs) => logs.lock().unwrap().values().flatten().cloned().collect(),
+        };
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.timestamp >= since)
+            .flat_map(|entry| entry.previous.into_iter().chain(entry.layer))
+            .collect())
+    }
+
+    /// The layer a label pointed at right after the change that gave
+    /// it the given version.
+    pub fn layer_at_version(&self, name: &str, version: u64) -> io::Result<Option<LayerId>> {
+        self.entries(name)?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.version == version)
+            .map(|entry| entry.layer)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("label {} has no recorded version {}", name, version),
+                )
+            })
+    }
+
+    /// The layer a label pointed at at the given time, in milliseconds
+    /// since the unix epoch.
+    pub fn layer_at_time(&self, name: &str, timestamp: u64) -> io::Result<Option<LayerId>> {
+        self.entries(name)?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.timestamp <= timestamp)
+            .map(|entry| entry.layer)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("label {} has no recorded change before {}", name, timestamp),
+                )
+            })
+    }
+}
+
+/// A label store that records every change it makes in a label log.
+pub struct LoggingLabelStore<L> {
+    inner: L,
+    log: LabelLog,
+}
+
+impl<L: LabelStore> LoggingLabelStore<L> {
+    pub fn new(inner: L, log: LabelLog) -> Self {
+        Self { inner, log }
+    }
+}
+
+#[async_trait]
+impl<L: LabelStore> LabelStore for LoggingLabelStore<L> {
+    async fn labels(&self) -> io::Result<Vec<Label>> {
+        self.inner.labels().await
+    }
+
+    async fn create_label(&self, name: &str) -> io::Result<Label> {
+        self.inner.create_label(name).await
+    }
+
+    async fn get_label(&self, name: &str) -> io::Result<Option<Label>> {
+        self.inner.get_label(name).await
+    }
+
+    async fn set_label_option(
+        &self,
+        label: &Label,
+        layer: Option<[u32; 5]>,
+    ) -> io::Result<Option<Label>> {
+        let result = self.inner.set_label_option(label, layer).await?;
+        if let Some(updated) = result.as_ref() {
+            // the label has moved at this point, so failing here would
+            // make the caller believe it didn't. A missing entry only
+            // means this change can't be restored through the log.
+            let _ = self.log.append(
+                &label.name,
+                &LabelLogEntry {
+                    version: updated.version,
+                    previous: label.layer,
+                    layer,
+                    timestamp: now_millis(),
+                },
+            );
+        }
+
+        Ok(result)
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<bool> {
+        loop {
+            let label = match self.inner.get_label(name).await? {
+                Some(label) => label,
+                None => return Ok(false),
+            };
+            // clear the head through a versioned set first, so that the
+            // head that gets deleted is the one that is logged. A head
+            // set in between is logged by whoever set it.
+            if label.layer.is_some() && self.set_label_option(&label, None).await?.is_none() {
+                continue;
+            }
+
+            return self.inner.delete(name).await;
+        }
+    }
+}
+
+fn store_log<C: QueryableContextType>(
+    context: &Context<'_, C>,
+    store: &WrappedStore,
+) -> PrologResult<LabelLog> {
+    match store.label_log() {
+        Some(log) => Ok(log.clone()),
+        None => context.raise_exception(&term! {context: error(label_log_not_available, _)}?),
+    }
+}
+
+fn restore_head<C: QueryableContextType>(
+    context: &Context<'_, C>,
+    store: &WrappedStore,
+    name: &str,
+    layer: Option<LayerId>,
+) -> PrologResult<()> {
+    let layer = match layer {
+        Some(layer) => layer,
+        None => {
+            return context.raise_exception(&term! {context: error(label_had_no_layer(#name), _)}?)
+        }
+    };
+    let layer = match context.try_or_die(store.get_layer_from_id(layer))? {
+        Some(layer) => layer,
+        None => {
+            let id = name_to_string(layer);
+            return context.raise_exception(&term! {context: error(layer_not_found(#id), _)}?);
+        }
+    };
+    let graph = match context.try_or_die(store.open(name))? {
+        Some(graph) => graph,
+        None => context.try_or_die(store.create(name))?,
+    };
+
+    context.try_or_die(graph.force_set_head(&layer))?;
+
+    Ok(())
+}
+
+predicates! {
+    pub semidet fn label_history(context, store_term, graph_name_term, history_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let graph_name: PrologText = graph_name_term.get_ex()?;
+        let log = store_log(context, &store)?;
+        let entries = context.try_or_die(log.entries(&graph_name))?;
+
+        let none = Atom::new("none");
+        let mut entry_terms = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let previous = context.new_term_ref();
+            match entry.previous {
+                Some(layer) => previous.unify(name_to_string(layer))?,
+                None => previous.unify(&none)?,
+            }
+            let layer = context.new_term_ref();
+            match entry.layer {
+                Some(id) => layer.unify(name_to_string(id))?,
+                None => layer.unify(&none)?,
+            }
+            let version = entry.version;
+            let seconds = entry.timestamp as f64 / 1000.0;
+            entry_terms.push(term! {context: label_change(#version, #&previous, #&layer, #seconds)}?);
+        }
+
+        history_term.unify(entry_terms.as_slice())
+    }
+
+    pub semidet fn nb_restore_head_version(context, store_term, graph_name_term, version_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let graph_name: PrologText = graph_name_term.get_ex()?;
+        let version: u64 = version_term.get_ex()?;
+        let log = store_log(context, &store)?;
+        let layer = context.try_or_die(log.layer_at_version(&graph_name, version))?;
+
+        restore_head(context, &store, &graph_name, layer)
+    }
+
+    pub semidet fn nb_restore_head_at(context, store_term, graph_name_term, time_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let graph_name: PrologText = graph_name_term.get_ex()?;
+        let seconds: f64 = time_term.get_ex()?;
+        let log = store_log(context, &store)?;
+        let layer = context.try_or_die(log.layer_at_time(&graph_name, (seconds * 1000.0) as u64))?;
+
+        restore_head(context, &store, &graph_name, layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::storage::memory::MemoryLabelStore;
+    use terminus_store::store::sync::task_sync;
+
+    fn id(index: u32) -> LayerId {
+        [index, 0, 0, 0, 0]
+    }
+
+    fn entry(
+        version: u64,
+        previous: Option<u32>,
+        layer: Option<u32>,
+        timestamp: u64,
+    ) -> LabelLogEntry {
+        LabelLogEntry {
+            version,
+            previous: previous.map(id),
+            layer: layer.map(id),
+            timestamp,
+        }
+    }
+
+    fn without_timestamps(entries: Vec<LabelLogEntry>) -> Vec<LabelLogEntry> {
+        entries
+            .into_iter()
+            .map(|entry| LabelLogEntry {
+                timestamp: 0,
+                ..entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entries_round_trip_through_lines() {
+        for entry in [entry(1, None, Some(1), 10), entry(7, Some(2), None, 20)] {
+            assert_eq!(
+                Some(entry.clone()),
+                LabelLogEntry::from_line(entry.to_line().trim_end())
+            );
+        }
+        assert_eq!(None, LabelLogEntry::from_line("3 - "));
+        assert_eq!(None, LabelLogEntry::from_line("3 - nonsense 10"));
+    }
+
+    #[test]
+    fn partially_written_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = LabelLog::directory(dir.path());
+        log.append("graph", &entry(1, None, Some(1), 10)).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(LabelLog::path(dir.path(), "graph"))
+            .unwrap();
+        file.write_all(b"2 ").unwrap();
+
+        assert_eq!(
+            vec![entry(1, None, Some(1), 10)],
+            log.entries("graph").unwrap()
+        );
+        assert_eq!(vec![id(1)], log.logged_layers(0).unwrap());
+        assert!(log.logged_layers(11).unwrap().is_empty());
+    }
+
+    #[test]
+    fn layers_are_found_by_version_and_time() {
+        let log = LabelLog::memory();
+        log.append("graph", &entry(1, None, Some(1), 10)).unwrap();
+        log.append("graph", &entry(2, Some(1), Some(2), 20))
+            .unwrap();
+        log.append("graph", &entry(3, Some(2), None, 30)).unwrap();
+
+        assert_eq!(Some(id(1)), log.layer_at_version("graph", 1).unwrap());
+        assert_eq!(None, log.layer_at_version("graph", 3).unwrap());
+        assert!(log.layer_at_version("graph", 4).is_err());
+        assert_eq!(Some(id(2)), log.layer_at_time("graph", 25).unwrap());
+        assert_eq!(None, log.layer_at_time("graph", 30).unwrap());
+        assert!(log.layer_at_time("graph", 5).is_err());
+        assert!(log.entries("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn label_changes_are_logged() {
+        let log = LabelLog::memory();
+        let store = LoggingLabelStore::new(MemoryLabelStore::new(), log.clone());
+        let label = task_sync(store.create_label("graph")).unwrap();
+        let label = task_sync(store.set_label_option(&label, Some(id(1))))
+            .unwrap()
+            .unwrap();
+        task_sync(store.set_label_option(&label, Some(id(2))))
+            .unwrap()
+            .unwrap();
+        // a set against an outdated version changes nothing
+        assert!(task_sync(store.set_label_option(&label, Some(id(3))))
+            .unwrap()
+            .is_none());
+
+        assert_eq!(
+            vec![entry(1, None, Some(1), 0), entry(2, Some(1), Some(2), 0)],
+            without_timestamps(log.entries("graph").unwrap())
+        );
+    }
+
+    #[test]
+    fn deletes_log_the_deleted_head() {
+        let log = LabelLog::memory();
+        let store = LoggingLabelStore::new(MemoryLabelStore::new(), log.clone());
+        let label = task_sync(store.create_label("graph")).unwrap();
+        task_sync(store.set_label_option(&label, Some(id(1))))
+            .unwrap()
+            .unwrap();
+
+        assert!(task_sync(store.delete("graph")).unwrap());
+        assert!(!task_sync(store.delete("graph")).unwrap());
+        assert!(task_sync(store.get_label("graph")).unwrap().is_none());
+        assert_eq!(
+            vec![entry(1, None, Some(1), 0), entry(2, Some(1), None, 0)],
+            without_timestamps(log.entries("graph").unwrap())
+        );
+    }
+
+    #[test]
+    fn failing_log_appends_dont_fail_label_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = LabelLog::directory(dir.path().join("missing"));
+        let store = LoggingLabelStore::new(MemoryLabelStore::new(), log.clone());
+        let label = task_sync(store.create_label("graph")).unwrap();
+
+        let label = task_sync(store.set_label_option(&label, Some(id(1))))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(id(1)), label.layer);
+        assert!(log.entries("graph").unwrap().is_empty());
+    }
+}
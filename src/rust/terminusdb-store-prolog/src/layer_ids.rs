@@ -0,0 +1,33 @@
+//! Layer ids, and how they are written in the line based files that
+//! are kept next to the labels of a store: label logs, head journals
+//! and replication checkpoints.
+//!
+//! Within a line, a layer is written as its id, or as `-` where there
+//! is none, such as the head of a label that was cleared.
+use swipl::prelude::*;
+use terminus_store::storage::{name_to_string, string_to_name};
+
+pub use terminusdb_store_convert::{get_layer, LayerId};
+
+/// A layer as a field of a line.
+pub fn layer_to_field(layer: Option<LayerId>) -> String {
+    match layer {
+        Some(layer) => name_to_string(layer),
+        None => "-".to_string(),
+    }
+}
+
+/// The layer of a field of a line, or None if it is neither a layer id
+/// nor `-`.
+pub fn field_to_layer(field: &str) -> Option<Option<LayerId>> {
+    match field {
+        "-" => Some(None),
+        _ => string_to_name(field).ok().map(Some),
+    }
+}
+
+/// Unify a term with the given layers as a list of id strings.
+pub fn unify_layer_ids(term: &Term, layer_ids: Vec<LayerId>) -> PrologResult<()> {
+    let names: Vec<String> = layer_ids.into_iter().map(name_to_string).collect();
+    term.unify(names.as_slice())
+}
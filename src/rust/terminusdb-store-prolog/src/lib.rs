@@ -1,8 +1,16 @@
 pub mod builder;
+pub mod encrypted;
+pub mod gc;
+pub mod label_log;
 pub mod layer;
+pub mod layer_ids;
 pub mod named_graph;
+pub mod pack;
+pub mod replicate;
+pub mod rollup_policy;
 pub mod store;
 pub mod value;
+pub mod verify;
 
 pub use swipl;
 pub use terminus_store;
@@ -13,6 +21,7 @@ pub fn install(module: Option<&str>) {
     store::register_open_raw_archive_store_in_module(module);
     store::register_open_archive_store_in_module(module);
     store::register_open_grpc_store_in_module(module);
+    encrypted::register_open_encrypted_archive_store_in_module(module);
     named_graph::register_create_named_graph_in_module(module);
     named_graph::register_open_named_graph_in_module(module);
     named_graph::register_delete_named_graph_in_module(module);
@@ -21,6 +30,10 @@ pub fn install(module: Option<&str>) {
     named_graph::register_nb_set_head_in_module(module);
     named_graph::register_nb_force_set_head_in_module(module);
     named_graph::register_nb_force_set_head_version_in_module(module);
+    named_graph::register_nb_set_heads_in_module(module);
+    label_log::register_label_history_in_module(module);
+    label_log::register_nb_restore_head_version_in_module(module);
+    label_log::register_nb_restore_head_at_in_module(module);
     store::register_open_write_in_module(module);
     store::register_merge_base_layers_in_module(module);
     builder::register_nb_add_id_triple_in_module(module);
@@ -57,6 +70,16 @@ pub fn install(module: Option<&str>) {
     store::register_pack_export_in_module(module);
     store::register_pack_layerids_and_parents_in_module(module);
     store::register_pack_import_in_module(module);
+    pack::register_pack_export_to_stream_in_module(module);
+    pack::register_pack_export_delta_in_module(module);
+    pack::register_pack_export_to_file_in_module(module);
+    pack::register_pack_import_from_stream_in_module(module);
+    pack::register_pack_import_from_file_in_module(module);
+    verify::register_verify_store_in_module(module);
+    gc::register_garbage_collect_layers_in_module(module);
+    encrypted::register_reencrypt_archive_store_in_module(module);
+    replicate::register_replicate_store_in_module(module);
+    rollup_policy::register_optimize_layer7_in_module(module);
     layer::register_id_triple_in_module(module);
     layer::register_id_triple_addition_in_module(module);
     layer::register_id_triple_removal_in_module(module);
@@ -1,8 +1,428 @@
+//! Named graphs, and moving the heads of several of them at once.
+//!
+//! `nb_set_head` and `nb_force_set_head` move one label at a time. A
+//! commit that moves a branch and a system graph together would be
+//! left half applied if the process died between the two. The
+//! `nb_set_heads` predicate instead takes a list of heads to set, each
+//! with the label version it expects, and either moves all of them or
+//! none.
+//!
+//! This is done by a [`TransactionalLabelStore`] in front of the
+//! store's label store. Single label operations share a lock that a
+//! transaction takes exclusively, so nothing in this process sees or
+//! changes the labels halfway through a transaction. For stores on
+//! disk, the updates are first written to a journal in the store
+//! directory, along with the layers the labels pointed at before. If
+//! the process dies while applying them, the journal is rolled forward
+//! the next time the store is opened. If applying an update fails
+//! instead, the labels moved so far are set back right away.
+//!
+//! Other processes changing the labels of the same directory are not
+//! coordinated with. If one of them moved a label of an interrupted
+//! transaction in the meantime, the transaction is undone instead:
+//! the labels it already moved are set back, the journal is kept as
+//! `heads.journal.conflict`, and opening the store fails once with an
+//! error naming the labels that moved.
 use crate::layer::*;
+use crate::layer_ids::*;
 use crate::store::*;
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use swipl::prelude::*;
+use terminus_store::storage::{Label, LabelStore};
 use terminus_store::store::sync::*;
+use tokio::sync::RwLock;
+
+pub const HEADS_JOURNAL: &str = "heads.journal";
+
+/// The journal of an interrupted transaction that could not be
+/// completed is kept under this name.
+pub const CONFLICTED_HEADS_JOURNAL: &str = "heads.journal.conflict";
+
+/// A head to set as part of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadUpdate {
+    pub name: String,
+    /// The version the label is expected to be at.
+    pub version: u64,
+    /// The new head, or None to clear it.
+    pub layer: Option<LayerId>,
+}
+
+/// A head update as written to the journal, along with the layer the
+/// label pointed at before, so that it can be undone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct JournalEntry {
+    update: HeadUpdate,
+    previous: Option<LayerId>,
+}
+
+impl JournalEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {}\n",
+            self.update.version,
+            layer_to_field(self.previous),
+            layer_to_field(self.update.layer),
+            self.update.name
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, ' ');
+        let version = fields.next()?.parse().ok()?;
+        let previous = field_to_layer(fields.next()?)?;
+        let layer = field_to_layer(fields.next()?)?;
+        let name = fields.next()?.to_string();
+
+        Some(Self {
+            update: HeadUpdate {
+                name,
+                version,
+                layer,
+            },
+            previous,
+        })
+    }
+}
+
+fn conflict(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("label {} changed during a head transaction", name),
+    )
+}
+
+fn journal_path(dir: &Path) -> PathBuf {
+    dir.join(HEADS_JOURNAL)
+}
+
+/// Write the journal to a temporary file first, so that it either
+/// exists completely or not at all.
+fn write_journal(dir: &Path, entries: &[JournalEntry]) -> io::Result<()> {
+    let temp_path = dir.join(format!("{}.tmp", HEADS_JOURNAL));
+    let mut file = File::create(&temp_path)?;
+    for entry in entries {
+        file.write_all(entry.to_line().as_bytes())?;
+    }
+    file.sync_all()?;
+    fs::rename(&temp_path, journal_path(dir))?;
+
+    File::open(dir)?.sync_all()
+}
+
+fn read_journal_file(path: &Path) -> io::Result<Option<Vec<JournalEntry>>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .map(|line| {
+                JournalEntry::from_line(line).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid line in head journal: {}", line),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_journal(dir: &Path) -> io::Result<Option<Vec<JournalEntry>>> {
+    read_journal_file(&journal_path(dir))
+}
+
+/// The layers that the journals in the given store directory mention,
+/// both those that an interrupted transaction sets and those it sets
+/// back when undone.
+pub(crate) fn journaled_layers(dir: &Path) -> io::Result<Vec<LayerId>> {
+    let mut layers = Vec::new();
+    for name in [HEADS_JOURNAL, CONFLICTED_HEADS_JOURNAL] {
+        for entry in read_journal_file(&dir.join(name))?.unwrap_or_default() {
+            layers.extend(entry.previous.into_iter().chain(entry.update.layer));
+        }
+    }
+
+    Ok(layers)
+}
+
+/// The lock shared by all label stores in this process that are opened
+/// on the same directory.
+fn directory_lock(dir: &Path) -> Arc<RwLock<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<RwLock<()>>>>> = OnceLock::new();
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(dir)
+        .or_default()
+        .clone()
+}
+
+/// Set a head as part of a transaction, returning false if the label
+/// was moved by someone else.
+async fn apply_update<L: LabelStore>(labels: &L, update: &HeadUpdate) -> io::Result<bool> {
+    match labels.get_label(&update.name).await? {
+        Some(label) if label.version == update.version => Ok(labels
+            .set_label_option(&label, update.layer)
+            .await?
+            .is_some()),
+        _ => Ok(false),
+    }
+}
+
+/// How far a head update of an interrupted transaction got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateState {
+    /// The label is still at the version the update expects.
+    Pending,
+    /// The label is one version further and points at the new layer.
+    Applied,
+    /// The label was moved by someone else.
+    Conflicted,
+}
+
+async fn update_state<L: LabelStore>(labels: &L, update: &HeadUpdate) -> io::Result<UpdateState> {
+    Ok(match labels.get_label(&update.name).await? {
+        Some(label) if label.version == update.version => UpdateState::Pending,
+        Some(label) if label.version == update.version + 1 && label.layer == update.layer => {
+            UpdateState::Applied
+        }
+        _ => UpdateState::Conflicted,
+    })
+}
+
+/// Set the labels of the given updates back to the layers they pointed
+/// at before, as long as nobody moved them since the update.
+async fn undo<L: LabelStore>(labels: &L, entries: &[JournalEntry]) -> io::Result<()> {
+    for entry in entries.iter().rev() {
+        let undone = match labels.get_label(&entry.update.name).await? {
+            Some(label)
+                if label.version == entry.update.version + 1
+                    && label.layer == entry.update.layer =>
+            {
+                labels
+                    .set_label_option(&label, entry.previous)
+                    .await?
+                    .is_some()
+            }
+            _ => false,
+        };
+        if !undone {
+            return Err(conflict(&entry.update.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// A label store that can set several labels in one transaction.
+pub struct TransactionalLabelStore<L> {
+    inner: Arc<L>,
+    lock: Arc<RwLock<()>>,
+    journal_dir: Option<PathBuf>,
+}
+
+impl<L> Clone for TransactionalLabelStore<L> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            lock: self.lock.clone(),
+            journal_dir: self.journal_dir.clone(),
+        }
+    }
+}
+
+impl<L: LabelStore> TransactionalLabelStore<L> {
+    /// A transactional label store that lives in memory.
+    pub fn memory(inner: L) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            lock: Default::default(),
+            journal_dir: None,
+        }
+    }
+
+    /// A transactional label store for labels in the given directory.
+    /// A transaction that was interrupted is completed first.
+    pub fn directory<P: Into<PathBuf>>(inner: L, dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        let store = Self {
+            inner: Arc::new(inner),
+            lock: directory_lock(&dir),
+            journal_dir: Some(dir),
+        };
+        task_sync(store.clone().recover())?;
+
+        Ok(store)
+    }
+
+    /// Roll an interrupted transaction forward, or if any of its labels
+    /// was moved by someone else since, undo it and report the labels
+    /// that moved.
+    async fn recover(self) -> io::Result<()> {
+        let _guard = self.lock.write().await;
+
+        self.recover_locked().await
+    }
+
+    /// Recover while already holding the lock exclusively.
+    async fn recover_locked(&self) -> io::Result<()> {
+        let dir = match self.journal_dir.as_ref() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let entries = match read_journal(dir)? {
+            Some(entries) => entries,
+            None => return Ok(()),
+        };
+        let mut states = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            states.push(update_state(&*self.inner, &entry.update).await?);
+        }
+
+        let conflicted: Vec<&str> = entries
+            .iter()
+            .zip(states.iter())
+            .filter(|(_, state)| **state == UpdateState::Conflicted)
+            .map(|(entry, _)| entry.update.name.as_str())
+            .collect();
+        if conflicted.is_empty() {
+            for (entry, state) in entries.iter().zip(states) {
+                if state == UpdateState::Pending
+                    && !apply_update(&*self.inner, &entry.update).await?
+                {
+                    return Err(conflict(&entry.update.name));
+                }
+            }
+            return fs::remove_file(journal_path(dir));
+        }
+
+        let applied: Vec<JournalEntry> = entries
+            .iter()
+            .zip(states)
+            .filter(|(_, state)| *state == UpdateState::Applied)
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        undo(&*self.inner, &applied).await?;
+        let kept = dir.join(CONFLICTED_HEADS_JOURNAL);
+        fs::rename(journal_path(dir), &kept)?;
+
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "an interrupted head transaction was undone, as labels {} changed since it was started. Its journal was kept as {}",
+                conflicted.join(", "),
+                kept.display()
+            ),
+        ))
+    }
+
+    /// Set all the given heads, or none of them if any label is not at
+    /// its expected version.
+    pub async fn transact(self, updates: Vec<HeadUpdate>) -> io::Result<bool> {
+        let mut names = HashSet::new();
+        if let Some(update) = updates.iter().find(|update| !names.insert(&update.name)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("label {} is set more than once", update.name),
+            ));
+        }
+
+        let _guard = self.lock.write().await;
+        // a journal left behind by a transaction that could be neither
+        // completed nor undone is dealt with first, so it isn't
+        // overwritten by this one
+        self.recover_locked().await?;
+
+        let mut entries = Vec::with_capacity(updates.len());
+        for update in updates {
+            match self.inner.get_label(&update.name).await? {
+                Some(label) if label.version == update.version => entries.push(JournalEntry {
+                    update,
+                    previous: label.layer,
+                }),
+                _ => return Ok(false),
+            }
+        }
+
+        if let Some(dir) = self.journal_dir.as_ref() {
+            write_journal(dir, &entries)?;
+        }
+        for (applied, entry) in entries.iter().enumerate() {
+            let error = match apply_update(&*self.inner, &entry.update).await {
+                Ok(true) => continue,
+                Ok(false) => conflict(&entry.update.name),
+                Err(e) => e,
+            };
+            // the labels moved so far are set back right away, so that
+            // this process doesn't see a half applied transaction. If
+            // that fails too, the journal is left for recovery.
+            undo(&*self.inner, &entries[..applied]).await?;
+            if let Some(dir) = self.journal_dir.as_ref() {
+                fs::remove_file(journal_path(dir))?;
+            }
+
+            return Err(error);
+        }
+        if let Some(dir) = self.journal_dir.as_ref() {
+            fs::remove_file(journal_path(dir))?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl<L: LabelStore> LabelStore for TransactionalLabelStore<L> {
+    async fn labels(&self) -> io::Result<Vec<Label>> {
+        let _guard = self.lock.read().await;
+        self.inner.labels().await
+    }
+
+    async fn create_label(&self, name: &str) -> io::Result<Label> {
+        let _guard = self.lock.read().await;
+        self.inner.create_label(name).await
+    }
+
+    async fn get_label(&self, name: &str) -> io::Result<Option<Label>> {
+        let _guard = self.lock.read().await;
+        self.inner.get_label(name).await
+    }
+
+    async fn set_label_option(
+        &self,
+        label: &Label,
+        layer: Option<[u32; 5]>,
+    ) -> io::Result<Option<Label>> {
+        let _guard = self.lock.read().await;
+        self.inner.set_label_option(label, layer).await
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<bool> {
+        let _guard = self.lock.read().await;
+        self.inner.delete(name).await
+    }
+}
+
+/// Setting several heads at once, independent of the type of the
+/// underlying label store.
+pub trait HeadTransactions: Send + Sync {
+    fn set_heads(&self, updates: Vec<HeadUpdate>) -> io::Result<bool>;
+}
+
+impl<L: LabelStore + 'static> HeadTransactions for TransactionalLabelStore<L> {
+    fn set_heads(&self, updates: Vec<HeadUpdate>) -> io::Result<bool> {
+        task_sync(self.clone().transact(updates))
+    }
+}
 
 predicates! {
     pub semidet fn create_named_graph(context, store_term, graph_name_term, graph_term) {
@@ -79,6 +499,30 @@ predicates! {
 
         into_prolog_result(result)
     }
+
+    pub semidet fn nb_set_heads(context, store_term, updates_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let transactions = match store.head_transactions() {
+            Some(transactions) => transactions,
+            None => return context.raise_exception(&term!{context: error(head_transactions_not_available, _)}?),
+        };
+
+        let mut updates = Vec::new();
+        for update_term in context.term_list_iter(updates_term) {
+            let [graph_version_term, layer_term] = context.compound_terms(&update_term)?;
+            let [graph_term, version_term] = context.compound_terms(&graph_version_term)?;
+            let graph: WrappedNamedGraph = graph_term.get_ex()?;
+            let version: u64 = version_term.get_ex()?;
+            let layer: WrappedLayer = layer_term.get_ex()?;
+            updates.push(HeadUpdate {
+                name: graph.name().to_string(),
+                version,
+                layer: Some(layer.name()),
+            });
+        }
+
+        into_prolog_result(context.try_or_die(transactions.set_heads(updates))?)
+    }
 }
 
 wrapped_clone_blob!("named_graph", pub WrappedNamedGraph, SyncNamedGraph);
@@ -88,3 +532,207 @@ impl CloneBlobImpl for WrappedNamedGraph {
         write!(stream, "<named_graph {}>", self.name())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use terminus_store::storage::directory::DirectoryLabelStore;
+    use terminus_store::storage::memory::MemoryLabelStore;
+
+    fn id(index: u32) -> LayerId {
+        [index, 0, 0, 0, 0]
+    }
+
+    fn update(name: &str, version: u64, layer: u32) -> HeadUpdate {
+        HeadUpdate {
+            name: name.to_string(),
+            version,
+            layer: Some(id(layer)),
+        }
+    }
+
+    fn set_label<L: LabelStore>(labels: &L, name: &str, layer: u32) {
+        let label = task_sync(labels.get_label(name)).unwrap().unwrap();
+        task_sync(labels.set_label_option(&label, Some(id(layer))))
+            .unwrap()
+            .unwrap();
+    }
+
+    fn head<L: LabelStore>(labels: &L, name: &str) -> (Option<LayerId>, u64) {
+        let label = task_sync(labels.get_label(name)).unwrap().unwrap();
+
+        (label.layer, label.version)
+    }
+
+    #[test]
+    fn journal_entries_round_trip_through_lines() {
+        let entries = [
+            JournalEntry {
+                update: update("a graph", 3, 1),
+                previous: None,
+            },
+            JournalEntry {
+                update: update("other", 0, 2),
+                previous: Some(id(7)),
+            },
+        ];
+        for entry in entries {
+            assert_eq!(
+                Some(entry.clone()),
+                JournalEntry::from_line(entry.to_line().trim_end())
+            );
+        }
+        assert_eq!(None, JournalEntry::from_line("3 - nonsense graph"));
+    }
+
+    #[test]
+    fn transactions_set_all_heads_or_none() {
+        let store = TransactionalLabelStore::memory(MemoryLabelStore::new());
+        task_sync(store.create_label("a")).unwrap();
+        task_sync(store.create_label("b")).unwrap();
+
+        let updates = vec![update("a", 0, 1), update("b", 1, 2)];
+        assert!(!store.set_heads(updates).unwrap());
+        assert_eq!((None, 0), head(&store, "a"));
+        assert_eq!((None, 0), head(&store, "b"));
+
+        let updates = vec![update("a", 0, 1), update("b", 0, 2)];
+        assert!(store.set_heads(updates).unwrap());
+        assert_eq!((Some(id(1)), 1), head(&store, "a"));
+        assert_eq!((Some(id(2)), 1), head(&store, "b"));
+
+        let updates = vec![update("a", 1, 3), update("a", 1, 4)];
+        assert!(store.set_heads(updates).is_err());
+    }
+
+    /// Labels `a` and `b` in a directory, with a journal of a
+    /// transaction that moved `a` from 1 to 2 but was interrupted
+    /// before it moved `b` to 3.
+    fn interrupted_transaction(dir: &Path) -> DirectoryLabelStore {
+        let labels = DirectoryLabelStore::new(dir);
+        task_sync(labels.create_label("a")).unwrap();
+        task_sync(labels.create_label("b")).unwrap();
+        set_label(&labels, "a", 1);
+        write_journal(
+            dir,
+            &[
+                JournalEntry {
+                    update: update("a", 1, 2),
+                    previous: Some(id(1)),
+                },
+                JournalEntry {
+                    update: update("b", 0, 3),
+                    previous: None,
+                },
+            ],
+        )
+        .unwrap();
+        set_label(&labels, "a", 2);
+
+        labels
+    }
+
+    #[test]
+    fn interrupted_transactions_are_rolled_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let labels = interrupted_transaction(dir.path());
+
+        TransactionalLabelStore::directory(DirectoryLabelStore::new(dir.path()), dir.path())
+            .unwrap();
+        assert_eq!((Some(id(2)), 2), head(&labels, "a"));
+        assert_eq!((Some(id(3)), 1), head(&labels, "b"));
+        assert!(!journal_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn interrupted_transactions_with_moved_labels_are_undone() {
+        let dir = tempfile::tempdir().unwrap();
+        let labels = interrupted_transaction(dir.path());
+        set_label(&labels, "b", 4);
+
+        let result =
+            TransactionalLabelStore::directory(DirectoryLabelStore::new(dir.path()), dir.path());
+        let error = result.err().unwrap();
+        assert!(error.to_string().contains("labels b changed"));
+        assert_eq!((Some(id(1)), 3), head(&labels, "a"));
+        assert_eq!((Some(id(4)), 1), head(&labels, "b"));
+        assert!(!journal_path(dir.path()).exists());
+        assert!(dir.path().join(CONFLICTED_HEADS_JOURNAL).exists());
+
+        TransactionalLabelStore::directory(DirectoryLabelStore::new(dir.path()), dir.path())
+            .unwrap();
+        assert_eq!((Some(id(1)), 3), head(&labels, "a"));
+    }
+
+    #[test]
+    fn leftover_journals_are_recovered_before_a_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let store =
+            TransactionalLabelStore::directory(DirectoryLabelStore::new(dir.path()), dir.path())
+                .unwrap();
+        let labels = interrupted_transaction(dir.path());
+
+        assert!(store.set_heads(vec![update("b", 1, 5)]).unwrap());
+        assert_eq!((Some(id(2)), 2), head(&labels, "a"));
+        assert_eq!((Some(id(5)), 2), head(&labels, "b"));
+        assert!(!journal_path(dir.path()).exists());
+    }
+
+    /// A label store in which setting label `a` for the first time
+    /// makes another process move label `b`.
+    struct MovingLabelStore(DirectoryLabelStore, AtomicBool);
+
+    #[async_trait]
+    impl LabelStore for MovingLabelStore {
+        async fn labels(&self) -> io::Result<Vec<Label>> {
+            self.0.labels().await
+        }
+
+        async fn create_label(&self, name: &str) -> io::Result<Label> {
+            self.0.create_label(name).await
+        }
+
+        async fn get_label(&self, name: &str) -> io::Result<Option<Label>> {
+            self.0.get_label(name).await
+        }
+
+        async fn set_label_option(
+            &self,
+            label: &Label,
+            layer: Option<[u32; 5]>,
+        ) -> io::Result<Option<Label>> {
+            if label.name == "a" && !self.1.swap(true, Ordering::SeqCst) {
+                let b = self.0.get_label("b").await?.unwrap();
+                self.0.set_label_option(&b, Some(id(4))).await?;
+            }
+
+            self.0.set_label_option(label, layer).await
+        }
+
+        async fn delete(&self, name: &str) -> io::Result<bool> {
+            self.0.delete(name).await
+        }
+    }
+
+    #[test]
+    fn failed_transactions_are_undone_right_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let labels = DirectoryLabelStore::new(dir.path());
+        task_sync(labels.create_label("a")).unwrap();
+        task_sync(labels.create_label("b")).unwrap();
+        set_label(&labels, "a", 1);
+        let store = TransactionalLabelStore::directory(
+            MovingLabelStore(DirectoryLabelStore::new(dir.path()), AtomicBool::new(false)),
+            dir.path(),
+        )
+        .unwrap();
+
+        let updates = vec![update("a", 1, 2), update("b", 0, 3)];
+        let error = store.set_heads(updates).err().unwrap();
+        assert!(error.to_string().contains("label b changed"));
+        assert_eq!((Some(id(1)), 3), head(&labels, "a"));
+        assert_eq!((Some(id(4)), 1), head(&labels, "b"));
+        assert!(!journal_path(dir.path()).exists());
+    }
+}
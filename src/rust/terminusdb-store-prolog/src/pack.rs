@@ -0,0 +1,738 @@
+//! Streamed packs.
+//!
+//! A regular pack holds all exported layers in a single byte vector,
+//! which for big databases means holding several copies of the whole
+//! database in memory. A streamed pack instead holds one regular pack
+//! per layer, written and read one at a time, so only a single layer
+//! needs to be in memory at once.
+//!
+//! A streamed pack starts with an uncompressed header:
+//!
+//! - the magic bytes `TDBSPACK`
+//! - a version byte
+//! - a flags byte, where bit 0 means the rest is zstd compressed
+//!
+//! It is followed by a frame per layer, parents before children:
+//!
+//! - the byte 1
+//! - the layer id, as 5 big-endian u32s
+//! - the byte 0 if the layer has no parent, or the byte 1 followed by
+//!   the parent id
+//! - the length of the layer pack as a big-endian u64, and the pack
+//!   itself
+//!
+//! The frames are closed by the byte 0, followed by a sha256 trailer
+//! over everything from the first frame up to and including that
+//! closing byte.
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use swipl::prelude::*;
+use terminus_store::storage::{name_to_string, string_to_name};
+use terminus_store::store::sync::*;
+use terminus_store::Layer;
+
+use crate::layer_ids::*;
+use crate::store::WrappedStore;
+
+pub const PACK_STREAM_MAGIC: &[u8; 8] = b"TDBSPACK";
+const PACK_STREAM_VERSION: u8 = 1;
+const FLAG_ZSTD: u8 = 1;
+
+const FRAME_END: u8 = 0;
+const FRAME_LAYER: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn write_layer_id<W: Write>(writer: &mut W, id: LayerId) -> io::Result<()> {
+    for part in id {
+        writer.write_all(&part.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_layer_id<R: Read>(reader: &mut R) -> io::Result<LayerId> {
+    let mut id = [0; 5];
+    for part in id.iter_mut() {
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        *part = u32::from_be_bytes(buf);
+    }
+
+    Ok(id)
+}
+
+/// Order the layers so that parents come before their children, and
+/// look up the parent of each.
+fn parents_first(
+    store: &SyncStore,
+    layer_ids: &[LayerId],
+) -> io::Result<Vec<(LayerId, Option<LayerId>)>> {
+    let mut parents = Vec::with_capacity(layer_ids.len());
+    for id in layer_ids {
+        let layer = get_layer(store, *id)?;
+        parents.push((*id, layer.parent_name()));
+    }
+
+    let requested: HashSet<LayerId> = layer_ids.iter().cloned().collect();
+    let mut emitted = HashSet::with_capacity(parents.len());
+    let mut ordered = Vec::with_capacity(parents.len());
+    for (id, parent) in parents.iter() {
+        // walk up to the first ancestor that is either already
+        // emitted or not part of the export, then emit downwards
+        let mut chain = vec![(*id, *parent)];
+        let mut cur = *parent;
+        while let Some(p) = cur {
+            if !requested.contains(&p) || emitted.contains(&p) {
+                break;
+            }
+            let grandparent = parents
+                .iter()
+                .find(|(id, _)| *id == p)
+                .and_then(|(_, parent)| *parent);
+            chain.push((p, grandparent));
+            cur = grandparent;
+        }
+        for (id, parent) in chain.into_iter().rev() {
+            if emitted.insert(id) {
+                ordered.push((id, parent));
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+fn write_frames<W: Write>(
+    store: &SyncStore,
+    layers: &[(LayerId, Option<LayerId>)],
+    writer: W,
+) -> io::Result<W> {
+    let mut writer = HashingWriter {
+        inner: writer,
+        hasher: Sha256::new(),
+    };
+    for (id, parent) in layers {
+        let pack = store.export_layers(Box::new(std::iter::once(*id)))?;
+        writer.write_all(&[FRAME_LAYER])?;
+        write_layer_id(&mut writer, *id)?;
+        match parent {
+            Some(parent) => {
+                writer.write_all(&[1])?;
+                write_layer_id(&mut writer, *parent)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+        writer.write_all(&(pack.len() as u64).to_be_bytes())?;
+        writer.write_all(&pack)?;
+    }
+    writer.write_all(&[FRAME_END])?;
+
+    let HashingWriter { mut inner, hasher } = writer;
+    inner.write_all(&hasher.finalize())?;
+
+    Ok(inner)
+}
+
+/// Export the given layers as a streamed pack, optionally zstd
+/// compressed.
+pub fn export_pack_stream<W: Write>(
+    store: &SyncStore,
+    layer_ids: &[LayerId],
+    mut writer: W,
+    compress: bool,
+) -> io::Result<()> {
+    let layers = parents_first(store, layer_ids)?;
+
+    writer.write_all(PACK_STREAM_MAGIC)?;
+    let flags = if compress { FLAG_ZSTD } else { 0 };
+    writer.write_all(&[PACK_STREAM_VERSION, flags])?;
+    if compress {
+        let mut encoder = zstd::Encoder::new(writer, ZSTD_LEVEL)?;
+        encoder.include_checksum(true)?;
+        let encoder = write_frames(store, &layers, encoder)?;
+        encoder.finish()?.flush()
+    } else {
+        write_frames(store, &layers, writer)?.flush()
+    }
+}
+
+/// A file in the temporary directory, removed again when dropped.
+struct SpoolFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl SpoolFile {
+    fn create() -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let path = std::env::temp_dir().join(format!(
+            "terminusdb-pack-{}-{nanos}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Self { path, file })
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Read all frames, writing the packs of the layers the store doesn't
+/// have yet to `spool`, and check the trailer. Returns the ids and
+/// pack lengths of the spooled layers, in the order they were written.
+fn spool_frames<R: Read>(
+    store: &SyncStore,
+    reader: R,
+    spool: &mut File,
+) -> io::Result<(Vec<(LayerId, u64)>, R)> {
+    let mut reader = HashingReader {
+        inner: reader,
+        hasher: Sha256::new(),
+    };
+    let mut spool_writer = BufWriter::new(spool);
+    let mut seen = HashSet::new();
+    let mut spooled = Vec::new();
+    loop {
+        match read_u8(&mut reader)? {
+            FRAME_END => break,
+            FRAME_LAYER => {}
+            tag => return Err(invalid_data(format!("unknown frame type {tag}"))),
+        }
+        let id = read_layer_id(&mut reader)?;
+        let parent = match read_u8(&mut reader)? {
+            0 => None,
+            1 => Some(read_layer_id(&mut reader)?),
+            _ => return Err(invalid_data("invalid parent marker")),
+        };
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let len = u64::from_be_bytes(len);
+
+        let known = !seen.insert(id) || store.get_layer_from_id(id)?.is_some();
+        if !known {
+            if let Some(parent) = parent {
+                if !seen.contains(&parent) && store.get_layer_from_id(parent)?.is_none() {
+                    return Err(invalid_data(format!(
+                        "parent {} of layer {} is not known",
+                        name_to_string(parent),
+                        name_to_string(id)
+                    )));
+                }
+            }
+        }
+        let copied = if known {
+            io::copy(&mut (&mut reader).take(len), &mut io::sink())?
+        } else {
+            io::copy(&mut (&mut reader).take(len), &mut spool_writer)?
+        };
+        if copied != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !known {
+            spooled.push((id, len));
+        }
+    }
+    spool_writer.flush()?;
+
+    let HashingReader { mut inner, hasher } = reader;
+    let mut checksum = [0; 32];
+    inner.read_exact(&mut checksum)?;
+    if hasher.finalize().as_slice() != checksum {
+        return Err(invalid_data("pack checksum does not match"));
+    }
+
+    Ok((spooled, inner))
+}
+
+/// Import the layers written to `spool` by [`spool_frames`].
+fn import_spooled(
+    store: &SyncStore,
+    spooled: &[(LayerId, u64)],
+    spool: &mut File,
+) -> io::Result<Vec<LayerId>> {
+    spool.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(spool);
+    let mut imported = Vec::with_capacity(spooled.len());
+    for (id, len) in spooled {
+        let mut pack = Vec::with_capacity(*len as usize);
+        (&mut reader).take(*len).read_to_end(&mut pack)?;
+        store.import_layers(&pack, Box::new(std::iter::once(*id)))?;
+        imported.push(*id);
+    }
+
+    Ok(imported)
+}
+
+/// Import the layers of a streamed pack that the store doesn't have
+/// yet, returning their ids.
+///
+/// The layers are spooled to a temporary file while the stream is
+/// read, and only imported once the whole stream has been read and its
+/// checksum checked out, so a truncated or corrupted stream leaves the
+/// store as it was.
+pub fn import_pack_stream<R: Read>(store: &SyncStore, mut reader: R) -> io::Result<Vec<LayerId>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != PACK_STREAM_MAGIC {
+        return Err(invalid_data("not a streamed pack"));
+    }
+    let mut version_and_flags = [0; 2];
+    reader.read_exact(&mut version_and_flags)?;
+    let [version, flags] = version_and_flags;
+    if version != PACK_STREAM_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported streamed pack version {version}"
+        )));
+    }
+
+    let mut spool = SpoolFile::create()?;
+    let spooled = if flags & FLAG_ZSTD != 0 {
+        let decoder = zstd::Decoder::new(reader)?.single_frame();
+        let (spooled, mut decoder) = spool_frames(store, decoder, &mut spool.file)?;
+        // read up to the end of the zstd frame, which makes the
+        // decoder verify the frame checksum
+        if decoder.read(&mut [0])? != 0 {
+            return Err(invalid_data("unexpected data after pack checksum"));
+        }
+
+        spooled
+    } else {
+        spool_frames(store, reader, &mut spool.file)?.0
+    };
+
+    import_spooled(store, &spooled, &mut spool.file)
+}
+
+/// The layers in the ancestry of `head` that a receiver which has the
+/// `known` layers is missing, parents first.
+///
+/// As a layer can only be present together with all its ancestors, the
+/// walk up from `head` stops at the first known layer.
+pub fn missing_layer_ids(
+    store: &SyncStore,
+    head: LayerId,
+    known: &HashSet<LayerId>,
+) -> io::Result<Vec<LayerId>> {
+    let mut missing = Vec::new();
+    let mut cur = Some(head);
+    while let Some(id) = cur {
+        if known.contains(&id) {
+            break;
+        }
+        missing.push(id);
+        cur = get_layer(store, id)?.parent_name();
+    }
+    missing.reverse();
+
+    Ok(missing)
+}
+
+/// A pack bringing a receiver up to date with a layer.
+pub struct DeltaPack {
+    pub pack: Vec<u8>,
+    /// The layers in the pack, parents first.
+    pub layer_ids: Vec<LayerId>,
+    /// The layer the receiver should use as its new head. This is the
+    /// requested layer, unless the delta was squashed.
+    pub head: LayerId,
+}
+
+/// Export the layers a receiver which has the `known` layers is
+/// missing to be able to load `head`.
+///
+/// With `squash`, all missing layers are squashed into a single layer
+/// on top of the newest known ancestor, or into a base layer if the
+/// receiver has none of the ancestors. That layer has the same content
+/// as `head`, but a different id, and is left unreferenced in the local
+/// store.
+pub fn export_delta_pack(
+    store: &SyncStore,
+    head: LayerId,
+    known: &HashSet<LayerId>,
+    squash: bool,
+) -> io::Result<DeltaPack> {
+    let mut layer_ids = missing_layer_ids(store, head, known)?;
+    let mut head = head;
+    if squash && layer_ids.len() > 1 {
+        let layer = get_layer(store, head)?;
+        let squashed = match get_layer(store, layer_ids[0])?.parent_name() {
+            Some(base) => layer.squash_upto(&get_layer(store, base)?)?,
+            None => layer.squash()?,
+        };
+        head = squashed.name();
+        layer_ids = vec![head];
+    }
+
+    let pack = store.export_layers(Box::new(layer_ids.clone().into_iter()))?;
+
+    Ok(DeltaPack {
+        pack,
+        layer_ids,
+        head,
+    })
+}
+
+fn layer_ids_from_term<C: QueryableContextType>(
+    context: &Context<C>,
+    layer_ids_term: &Term,
+) -> PrologResult<Vec<LayerId>> {
+    let layer_id_strings: Vec<String> = layer_ids_term.get_ex()?;
+    let mut layer_ids = Vec::with_capacity(layer_id_strings.len());
+    for layer_id_string in layer_id_strings {
+        layer_ids.push(context.try_or_die(string_to_name(&layer_id_string))?);
+    }
+
+    Ok(layer_ids)
+}
+
+predicates! {
+    pub semidet fn pack_export_delta(context, store_term, layer_id_term, known_ids_term, squash_term, pack_term, head_id_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let layer_id: String = layer_id_term.get_ex()?;
+        let layer_id = context.try_or_die(string_to_name(&layer_id))?;
+        let known: HashSet<LayerId> = layer_ids_from_term(context, known_ids_term)?.into_iter().collect();
+        let squash: bool = squash_term.get_ex()?;
+
+        let delta = context.try_or_die(export_delta_pack(&store, layer_id, &known, squash))?;
+
+        pack_term.unify(delta.pack.as_slice())?;
+        head_id_term.unify(name_to_string(delta.head))
+    }
+
+    pub semidet fn pack_export_to_stream(context, store_term, layer_ids_term, stream_term, compress_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let layer_ids = layer_ids_from_term(context, layer_ids_term)?;
+        let compress: bool = compress_term.get_ex()?;
+        let stream: WritablePrologStream = stream_term.get_ex()?;
+
+        context.try_or_die(export_pack_stream(&store, &layer_ids, stream, compress))
+    }
+
+    pub semidet fn pack_export_to_file(context, store_term, layer_ids_term, path_term, compress_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let layer_ids = layer_ids_from_term(context, layer_ids_term)?;
+        let compress: bool = compress_term.get_ex()?;
+        let path: PrologText = path_term.get_ex()?;
+        let file = context.try_or_die(File::create(&*path))?;
+
+        context.try_or_die(export_pack_stream(&store, &layer_ids, BufWriter::new(file), compress))
+    }
+
+    pub semidet fn pack_import_from_stream(context, store_term, stream_term, imported_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let stream: ReadablePrologStream = stream_term.get_ex()?;
+        let imported = context.try_or_die(import_pack_stream(&store, stream))?;
+
+        unify_layer_ids(imported_term, imported)
+    }
+
+    pub semidet fn pack_import_from_file(context, store_term, path_term, imported_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let path: PrologText = path_term.get_ex()?;
+        let file = context.try_or_die(File::open(&*path))?;
+        let imported = context.try_or_die(import_pack_stream(&store, BufReader::new(file)))?;
+
+        unify_layer_ids(imported_term, imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+
+    /// A store with a base layer and a child on top of it, returning
+    /// their ids.
+    fn create_layers(store: &SyncStore) -> (LayerId, LayerId) {
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("duck", "says", "quack"))
+            .unwrap();
+        builder
+            .remove_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let child = builder.commit().unwrap();
+
+        (base.name(), child.name())
+    }
+
+    fn export(store: &SyncStore, layer_ids: &[LayerId], compress: bool) -> Vec<u8> {
+        let mut stream = Vec::new();
+        export_pack_stream(store, layer_ids, &mut stream, compress).unwrap();
+
+        stream
+    }
+
+    fn assert_imported(store: &SyncStore, child: LayerId) {
+        let layer = store.get_layer_from_id(child).unwrap().unwrap();
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("duck", "says", "quack")));
+        assert!(!layer.value_triple_exists(&ValueTriple::new_node("cow", "says", "moo")));
+    }
+
+    #[test]
+    fn round_trip_with_and_without_compression() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+
+        for compress in [false, true] {
+            // children before parents, to check the export reorders them
+            let stream = export(&source, &[child, base], compress);
+            assert_eq!(PACK_STREAM_MAGIC, &stream[..8]);
+            assert_eq!(if compress { FLAG_ZSTD } else { 0 }, stream[9]);
+
+            let destination = open_sync_memory_store();
+            let imported = import_pack_stream(&destination, stream.as_slice()).unwrap();
+            assert_eq!(vec![base, child], imported);
+            assert_imported(&destination, child);
+
+            // importing again skips the layers that are already there
+            let imported = import_pack_stream(&destination, stream.as_slice()).unwrap();
+            assert!(imported.is_empty());
+        }
+    }
+
+    #[test]
+    fn frames_are_written_parents_first() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+        let stream = export(&source, &[child, base], false);
+
+        let mut reader = &stream[10..];
+        assert_eq!(FRAME_LAYER, read_u8(&mut reader).unwrap());
+        assert_eq!(base, read_layer_id(&mut reader).unwrap());
+        assert_eq!(0, read_u8(&mut reader).unwrap());
+        let mut len = [0; 8];
+        reader.read_exact(&mut len).unwrap();
+        reader = &reader[u64::from_be_bytes(len) as usize..];
+
+        assert_eq!(FRAME_LAYER, read_u8(&mut reader).unwrap());
+        assert_eq!(child, read_layer_id(&mut reader).unwrap());
+        assert_eq!(1, read_u8(&mut reader).unwrap());
+        assert_eq!(base, read_layer_id(&mut reader).unwrap());
+        reader.read_exact(&mut len).unwrap();
+        reader = &reader[u64::from_be_bytes(len) as usize..];
+
+        assert_eq!(FRAME_END, read_u8(&mut reader).unwrap());
+        assert_eq!(32, reader.len());
+    }
+
+    #[test]
+    fn unknown_frame_types_are_rejected() {
+        let source = open_sync_memory_store();
+        let (base, _) = create_layers(&source);
+        let mut stream = export(&source, &[base], false);
+        stream[10] = 7;
+
+        let destination = open_sync_memory_store();
+        let error = import_pack_stream(&destination, stream.as_slice()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(destination.get_layer_from_id(base).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_parents_are_rejected() {
+        let source = open_sync_memory_store();
+        let (_, child) = create_layers(&source);
+        let stream = export(&source, &[child], false);
+
+        let destination = open_sync_memory_store();
+        let error = import_pack_stream(&destination, stream.as_slice()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(destination.get_layer_from_id(child).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_streams_import_nothing() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+
+        for compress in [false, true] {
+            let stream = export(&source, &[base, child], compress);
+            for cut in [stream.len() - 1, stream.len() - 40, stream.len() / 2, 9] {
+                let destination = open_sync_memory_store();
+                assert!(import_pack_stream(&destination, &stream[..cut]).is_err());
+                assert!(destination.get_layer_from_id(base).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn bad_checksum_trailers_import_nothing() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+        let mut stream = export(&source, &[base, child], false);
+        let last = stream.len() - 1;
+        stream[last] ^= 0xff;
+
+        let destination = open_sync_memory_store();
+        let error = import_pack_stream(&destination, stream.as_slice()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(destination.get_layer_from_id(base).unwrap().is_none());
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        let source = open_sync_memory_store();
+        let (base, _) = create_layers(&source);
+        let mut stream = export(&source, &[base], false);
+        stream[8] = PACK_STREAM_VERSION + 1;
+
+        let destination = open_sync_memory_store();
+        let error = import_pack_stream(&destination, stream.as_slice()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    fn add_layer(store: &SyncStore, parent: LayerId, triple: ValueTriple) -> LayerId {
+        let builder = store
+            .get_layer_from_id(parent)
+            .unwrap()
+            .unwrap()
+            .open_write()
+            .unwrap();
+        builder.add_value_triple(triple).unwrap();
+
+        builder.commit().unwrap().name()
+    }
+
+    #[test]
+    fn missing_layers_stop_at_the_first_known_layer() {
+        let store = open_sync_memory_store();
+        let (base, child) = create_layers(&store);
+
+        let known = HashSet::new();
+        assert_eq!(
+            vec![base, child],
+            missing_layer_ids(&store, child, &known).unwrap()
+        );
+        let known = vec![base].into_iter().collect();
+        assert_eq!(
+            vec![child],
+            missing_layer_ids(&store, child, &known).unwrap()
+        );
+        let known = vec![child].into_iter().collect();
+        assert!(missing_layer_ids(&store, child, &known).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unsquashed_delta_packs_hold_the_missing_layers() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+        let destination = open_sync_memory_store();
+        let base_pack = source
+            .export_layers(Box::new(std::iter::once(base)))
+            .unwrap();
+        destination
+            .import_layers(&base_pack, Box::new(std::iter::once(base)))
+            .unwrap();
+
+        let known = vec![base].into_iter().collect();
+        let delta = export_delta_pack(&source, child, &known, false).unwrap();
+        assert_eq!(child, delta.head);
+        assert_eq!(vec![child], delta.layer_ids);
+
+        destination
+            .import_layers(&delta.pack, Box::new(delta.layer_ids.into_iter()))
+            .unwrap();
+        assert_imported(&destination, child);
+    }
+
+    #[test]
+    fn squashed_delta_packs_hold_a_single_layer() {
+        let source = open_sync_memory_store();
+        let (base, child) = create_layers(&source);
+        let grandchild = add_layer(&source, child, ValueTriple::new_node("pig", "says", "oink"));
+
+        // without known layers, everything is squashed into a base layer
+        let delta = export_delta_pack(&source, grandchild, &HashSet::new(), true).unwrap();
+        assert_ne!(grandchild, delta.head);
+        assert_eq!(vec![delta.head], delta.layer_ids);
+        let destination = open_sync_memory_store();
+        destination
+            .import_layers(&delta.pack, Box::new(delta.layer_ids.into_iter()))
+            .unwrap();
+        let layer = destination.get_layer_from_id(delta.head).unwrap().unwrap();
+        assert_eq!(None, layer.parent_name());
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("pig", "says", "oink")));
+        assert!(layer.value_triple_exists(&ValueTriple::new_node("duck", "says", "quack")));
+        assert!(!layer.value_triple_exists(&ValueTriple::new_node("cow", "says", "moo")));
+
+        // with a known base, the squashed layer sits on top of it
+        let known = vec![base].into_iter().collect();
+        let delta = export_delta_pack(&source, grandchild, &known, true).unwrap();
+        assert_eq!(vec![delta.head], delta.layer_ids);
+        let squashed = source.get_layer_from_id(delta.head).unwrap().unwrap();
+        assert_eq!(Some(base), squashed.parent_name());
+        assert!(squashed.value_triple_exists(&ValueTriple::new_node("pig", "says", "oink")));
+        assert!(!squashed.value_triple_exists(&ValueTriple::new_node("cow", "says", "moo")));
+
+        // a single missing layer is sent as is
+        let known = vec![child].into_iter().collect();
+        let delta = export_delta_pack(&source, grandchild, &known, true).unwrap();
+        assert_eq!(grandchild, delta.head);
+    }
+}
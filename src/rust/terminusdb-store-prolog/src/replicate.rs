@@ -0,0 +1,396 @@
+//! Replication of named graphs from one store to another.
+//!
+//! The layers that the destination is missing are copied one at a
+//! time, so that each layer's parent and the layers it refers to
+//! through `layer:identifier` are copied before it. When all layers
+//! are there, the destination labels are moved to the source heads.
+//! Repeating this keeps the destination up to date as a replica.
+//!
+//! Progress is recorded in a checkpoint file. It first lists the heads
+//! that are being replicated, as
+//!
+//! ```text
+//! head <layer or -> <label name>
+//! ```
+//!
+//! and then each layer once it has been copied, as
+//!
+//! ```text
+//! copied <layer>
+//! ```
+//!
+//! If replication is interrupted, the next run for the same labels
+//! continues with the recorded heads and skips the copied layers. The
+//! checkpoint is removed once the labels have been moved. A label that
+//! has no head in the source has its head cleared in the destination.
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use swipl::prelude::*;
+use terminus_store::storage::{name_to_string, string_to_name};
+use terminus_store::store::sync::*;
+
+use crate::gc::referenced_layers;
+use crate::layer_ids::*;
+use crate::named_graph::HeadUpdate;
+use crate::store::*;
+
+/// The progress of a replication.
+struct Checkpoint {
+    path: PathBuf,
+    heads: Vec<(String, Option<LayerId>)>,
+    copied: HashSet<LayerId>,
+}
+
+impl Checkpoint {
+    fn read(path: &Path) -> io::Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut heads = Vec::new();
+        let mut copied = HashSet::new();
+        // a line that was only partially written is skipped, the layer
+        // it was about is then looked at again
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("head"), Some(layer), Some(name)) => {
+                    if let Some(layer) = field_to_layer(layer) {
+                        heads.push((name.to_string(), layer));
+                    }
+                }
+                (Some("copied"), Some(layer), None) => {
+                    if let Ok(layer) = string_to_name(layer) {
+                        copied.insert(layer);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            heads,
+            copied,
+        }))
+    }
+
+    /// Start a new replication of the given heads. The heads are
+    /// written to a temporary file first, so that a checkpoint always
+    /// holds all of them.
+    fn create(path: &Path, heads: Vec<(String, Option<LayerId>)>) -> io::Result<Self> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = File::create(&temp_path)?;
+        for (name, layer) in heads.iter() {
+            writeln!(file, "head {} {}", layer_to_field(*layer), name)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            heads,
+            copied: HashSet::new(),
+        })
+    }
+
+    fn record_copied(&mut self, layer: LayerId) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(format!("copied {}\n", name_to_string(layer)).as_bytes())?;
+        file.sync_data()?;
+        self.copied.insert(layer);
+
+        Ok(())
+    }
+
+    fn names(&self) -> BTreeSet<&str> {
+        self.heads.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn remove(self) -> io::Result<()> {
+        fs::remove_file(self.path)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplicationReport {
+    /// The layers that were copied, in the order they were copied in.
+    pub copied: Vec<LayerId>,
+    /// Whether an interrupted replication was resumed.
+    pub resumed: bool,
+}
+
+fn source_heads(
+    source: &SyncStore,
+    names: &[String],
+) -> io::Result<Vec<(String, Option<LayerId>)>> {
+    let mut heads = Vec::with_capacity(names.len());
+    for name in names {
+        let graph = source.open(name)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("label {} not found in the source store", name),
+            )
+        })?;
+        heads.push((name.clone(), graph.head()?.map(|layer| layer.name())));
+    }
+
+    Ok(heads)
+}
+
+/// The layers the destination is missing to load the given heads, in
+/// an order where every layer comes after its parent and after the
+/// layers it refers to. A layer that the destination has is taken to
+/// be complete, as replication never copies a layer before the layers
+/// it depends on.
+fn layers_to_copy(
+    source: &SyncStore,
+    destination: &SyncStore,
+    heads: &[(String, Option<LayerId>)],
+    copied: &HashSet<LayerId>,
+) -> io::Result<Vec<LayerId>> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack: Vec<(LayerId, bool)> = heads
+        .iter()
+        .filter_map(|(_, layer)| layer.map(|layer| (layer, false)))
+        .collect();
+    while let Some((id, dependencies_done)) = stack.pop() {
+        if dependencies_done {
+            order.push(id);
+            continue;
+        }
+        if !visited.insert(id)
+            || copied.contains(&id)
+            || destination.get_layer_from_id(id)?.is_some()
+        {
+            continue;
+        }
+        // layers may refer to layers that are no longer around, those
+        // are skipped like garbage collection does
+        let layer = match source.get_layer_from_id(id)? {
+            Some(layer) => layer,
+            None => continue,
+        };
+
+        stack.push((id, true));
+        if let Some(parent) = layer.parent_name() {
+            stack.push((parent, false));
+        }
+        for referenced in referenced_layers(&layer) {
+            stack.push((referenced, false));
+        }
+    }
+
+    Ok(order)
+}
+
+fn copy_layer(source: &SyncStore, destination: &SyncStore, id: LayerId) -> io::Result<()> {
+    // make sure the layer is there, rather than exporting an empty pack
+    get_layer(source, id)?;
+    let pack = source.export_layers(Box::new(iter::once(id)))?;
+
+    destination.import_layers(pack.as_slice(), Box::new(iter::once(id)))
+}
+
+/// Move the destination labels to the replicated heads, all at once if
+/// the destination supports that. Labels without a head in the source
+/// have their head cleared.
+fn move_labels(destination: &LoggedStore, heads: &[(String, Option<LayerId>)]) -> io::Result<()> {
+    let mut moves = Vec::new();
+    for (name, layer) in heads.iter() {
+        let graph = match destination.open(name)? {
+            Some(graph) => graph,
+            None => destination.create(name)?,
+        };
+        let (head, version) = graph.head_version()?;
+        if head.map(|head| head.name()) != *layer {
+            moves.push((graph, version, *layer));
+        }
+    }
+
+    match destination.head_transactions() {
+        Some(transactions) => {
+            let updates = moves
+                .iter()
+                .map(|(graph, version, layer)| HeadUpdate {
+                    name: graph.name().to_string(),
+                    version: *version,
+                    layer: *layer,
+                })
+                .collect();
+            if !transactions.set_heads(updates)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "labels in the destination store changed during replication",
+                ));
+            }
+        }
+        None => {
+            for (graph, _, layer) in moves {
+                match layer {
+                    Some(layer) => graph.force_set_head(&get_layer(destination, layer)?)?,
+                    // named graphs can't clear their head, so the label
+                    // is created again without one
+                    None => {
+                        destination.delete(graph.name())?;
+                        destination.create(graph.name())?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replicate the named graphs from the source to the destination,
+/// keeping track of progress in the given checkpoint file.
+pub fn replicate(
+    source: &SyncStore,
+    destination: &LoggedStore,
+    names: &[String],
+    checkpoint_path: &Path,
+) -> io::Result<ReplicationReport> {
+    let mut report = ReplicationReport::default();
+    let requested: BTreeSet<&str> = names.iter().map(String::as_str).collect();
+    let mut checkpoint = match Checkpoint::read(checkpoint_path)? {
+        Some(checkpoint) if checkpoint.names() == requested => {
+            report.resumed = true;
+            checkpoint
+        }
+        _ => Checkpoint::create(checkpoint_path, source_heads(source, names)?)?,
+    };
+
+    for id in layers_to_copy(source, destination, &checkpoint.heads, &checkpoint.copied)? {
+        copy_layer(source, destination, id)?;
+        checkpoint.record_copied(id)?;
+        report.copied.push(id);
+    }
+
+    move_labels(destination, &checkpoint.heads)?;
+    checkpoint.remove()?;
+
+    Ok(report)
+}
+
+predicates! {
+    pub semidet fn replicate_store(context, source_term, destination_term, graph_names_term, checkpoint_term, copied_term) {
+        let source: WrappedStore = source_term.get_ex()?;
+        let destination: WrappedStore = destination_term.get_ex()?;
+        let graph_names: Vec<String> = graph_names_term.get_ex()?;
+        let checkpoint: PrologText = checkpoint_term.get_ex()?;
+
+        let report = context.try_or_die(replicate(&source, &destination, &graph_names, Path::new(&*checkpoint)))?;
+
+        let copied: Vec<String> = report.copied.into_iter().map(name_to_string).collect();
+        copied_term.unify(copied.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+    use terminus_store::storage::memory::MemoryLayerStore;
+
+    /// A memory store like `open_memory_store` opens.
+    fn memory_store() -> LoggedStore {
+        LoggedStore::open(None, MemoryLayerStore::new()).unwrap()
+    }
+
+    fn add_layer(
+        parent: Option<&SyncStoreLayer>,
+        store: &SyncStore,
+        subject: &str,
+    ) -> SyncStoreLayer {
+        let builder = match parent {
+            Some(parent) => parent.open_write().unwrap(),
+            None => store.create_base_layer().unwrap(),
+        };
+        builder
+            .add_value_triple(ValueTriple::new_node(subject, "says", "hello"))
+            .unwrap();
+
+        builder.commit().unwrap()
+    }
+
+    fn head(store: &SyncStore, name: &str) -> Option<LayerId> {
+        store
+            .open(name)
+            .unwrap()
+            .unwrap()
+            .head()
+            .unwrap()
+            .map(|layer| layer.name())
+    }
+
+    #[test]
+    fn interrupted_replications_resume_from_the_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = dir.path().join("checkpoint");
+        let source = memory_store();
+        let destination = memory_store();
+        let base = add_layer(None, &source, "cow");
+        let child = add_layer(Some(&base), &source, "duck");
+        let graph = source.create("main").unwrap();
+        assert!(graph.set_head(&child).unwrap());
+
+        // a run that got as far as copying the base layer
+        let names = vec!["main".to_string()];
+        let mut checkpoint =
+            Checkpoint::create(&checkpoint_path, source_heads(&source, &names).unwrap()).unwrap();
+        copy_layer(&source, &destination, base.name()).unwrap();
+        checkpoint.record_copied(base.name()).unwrap();
+        // with a partially written line at the end
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&checkpoint_path)
+            .unwrap();
+        file.write_all(b"copied 12").unwrap();
+
+        // the source moved on in the meantime
+        let newer = add_layer(Some(&child), &source, "pig");
+        assert!(graph.set_head(&newer).unwrap());
+
+        let report = replicate(&source, &destination, &names, &checkpoint_path).unwrap();
+        assert!(report.resumed);
+        assert_eq!(vec![child.name()], report.copied);
+        assert_eq!(Some(child.name()), head(&destination, "main"));
+        assert!(!checkpoint_path.exists());
+
+        let report = replicate(&source, &destination, &names, &checkpoint_path).unwrap();
+        assert!(!report.resumed);
+        assert_eq!(vec![newer.name()], report.copied);
+        assert_eq!(Some(newer.name()), head(&destination, "main"));
+    }
+
+    #[test]
+    fn labels_without_a_head_are_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = dir.path().join("checkpoint");
+        let source = memory_store();
+        let destination = memory_store();
+        let layer = add_layer(None, &source, "cow");
+        let graph = source.create("main").unwrap();
+        assert!(graph.set_head(&layer).unwrap());
+
+        let names = vec!["main".to_string()];
+        replicate(&source, &destination, &names, &checkpoint_path).unwrap();
+        assert_eq!(Some(layer.name()), head(&destination, "main"));
+
+        source.delete("main").unwrap();
+        source.create("main").unwrap();
+        let report = replicate(&source, &destination, &names, &checkpoint_path).unwrap();
+        assert!(report.copied.is_empty());
+        assert_eq!(None, head(&destination, "main"));
+    }
+}
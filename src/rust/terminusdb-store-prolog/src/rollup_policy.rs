@@ -0,0 +1,317 @@
+//! Deciding when and how far to roll up a layer stack.
+//!
+//! Queries get slower as layer stacks get deeper, since every layer in
+//! the stack has to be consulted. The stack of a layer that was rolled
+//! up ends at its rollup, so the depth of a stack is the number of
+//! layers that queries actually go through. This is measured on the
+//! layers as the layer store loads them, through their rollups, rather
+//! than by following the parents a layer was originally built on,
+//! which would keep counting layers that a rollup already covers.
+//!
+//! Nothing is done as long as the depth is within `max_depth`. Once it
+//! isn't, the newer layers are rolled up onto an older layer that has
+//! at least `merge_ratio` times as many changes as all layers above it
+//! together. Such a layer is large compared to what would be merged
+//! onto it, and is left alone, as rolling it up again would mostly
+//! repeat work that was done before. The newest such layer is picked
+//! that leaves a stack within `max_depth`. If there is none, the whole
+//! stack is rolled up.
+//!
+//! Checking the depth is cheap, so this can be done after every commit.
+//! Per-layer change counts are only looked at when a rollup is due.
+use std::io;
+
+use swipl::prelude::*;
+use terminus_store::layer::InternalLayer;
+use terminus_store::storage::{name_to_string, LayerStore};
+use terminus_store::store::sync::*;
+use terminus_store::Layer;
+
+use crate::layer::*;
+use crate::layer_ids::*;
+use crate::store::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RollupPolicy {
+    /// The deepest a stack may get before it is rolled up.
+    pub max_depth: usize,
+    /// How many times more changes a layer needs than the layers above
+    /// it to be rolled up onto, rather than into.
+    pub merge_ratio: f64,
+    /// Whether to use the cheaper `imprecise_rollup_upto`.
+    pub imprecise: bool,
+}
+
+impl Default for RollupPolicy {
+    fn default() -> Self {
+        Self {
+            max_depth: 8,
+            merge_ratio: 4.0,
+            imprecise: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupAction {
+    None,
+    Rollup,
+    RollupUpto(LayerId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollupPlan {
+    pub action: RollupAction,
+    pub depth_before: usize,
+    pub depth_after: usize,
+    /// The number of layers merged into the new rollup.
+    pub merged: usize,
+}
+
+impl RollupPlan {
+    fn nothing(depth: usize) -> Self {
+        Self {
+            action: RollupAction::None,
+            depth_before: depth,
+            depth_after: depth,
+            merged: 0,
+        }
+    }
+}
+
+/// Decide how to roll up a stack, given as the layer ids and their
+/// number of additions and removals, oldest first.
+pub fn plan_rollup(stack: &[(LayerId, usize)], policy: &RollupPolicy) -> RollupPlan {
+    let depth = stack.len();
+    if depth <= policy.max_depth {
+        return RollupPlan::nothing(depth);
+    }
+
+    // rolling up onto the layer at index i leaves a stack of i + 2
+    let mut above = stack[depth - 1].1;
+    for i in (0..depth - 1).rev() {
+        let (id, changes) = stack[i];
+        if depth - 1 - i >= 2
+            && i + 2 <= policy.max_depth
+            && changes as f64 >= policy.merge_ratio * above as f64
+        {
+            return RollupPlan {
+                action: RollupAction::RollupUpto(id),
+                depth_before: depth,
+                depth_after: i + 2,
+                merged: depth - 1 - i,
+            };
+        }
+        above += changes;
+    }
+
+    RollupPlan {
+        action: RollupAction::Rollup,
+        depth_before: depth,
+        depth_after: 1,
+        merged: depth,
+    }
+}
+
+/// The ids of the layers that queries on the given layer go through,
+/// oldest first. Below a rolled up layer, these continue with the layer
+/// its rollup was made on, if any.
+fn query_stack(layers: &dyn LayerStore, id: LayerId) -> io::Result<Vec<LayerId>> {
+    let layer = task_sync(layers.get_layer(id))?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("layer {} not found", name_to_string(id)),
+        )
+    })?;
+    let mut names = vec![layer.name()];
+    let mut current: &InternalLayer = &layer;
+    while let Some(parent) = current.immediate_parent() {
+        names.push(parent.name());
+        current = parent;
+    }
+    names.reverse();
+
+    Ok(names)
+}
+
+/// Apply the policy to the stack of the given layer. With `dry_run`,
+/// the plan is only returned.
+pub fn optimize(
+    store: &LoggedStore,
+    layer: &SyncStoreLayer,
+    policy: &RollupPolicy,
+    dry_run: bool,
+) -> io::Result<RollupPlan> {
+    let names = query_stack(store.layer_store(), layer.name())?;
+    if names.len() <= policy.max_depth {
+        return Ok(RollupPlan::nothing(names.len()));
+    }
+
+    let mut stack = Vec::with_capacity(names.len());
+    for id in names {
+        let layer = get_layer(store, id)?;
+        let changes = layer.triple_layer_addition_count()? + layer.triple_layer_removal_count()?;
+        stack.push((id, changes));
+    }
+
+    let plan = plan_rollup(&stack, policy);
+    if !dry_run {
+        match plan.action {
+            RollupAction::None => {}
+            RollupAction::Rollup => layer.rollup()?,
+            RollupAction::RollupUpto(upto) => {
+                let upto = get_layer(store, upto)?;
+                if policy.imprecise {
+                    layer.imprecise_rollup_upto(&upto)?
+                } else {
+                    layer.rollup_upto(&upto)?
+                }
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+predicates! {
+    #[name("optimize_layer")]
+    pub semidet fn optimize_layer7(context, store_term, layer_term, max_depth_term, merge_ratio_term, imprecise_term, dry_run_term, report_term) {
+        let store: WrappedStore = store_term.get_ex()?;
+        let layer: WrappedLayer = layer_term.get_ex()?;
+        let max_depth: u64 = max_depth_term.get_ex()?;
+        if max_depth == 0 {
+            return context.raise_exception(&term!{context: error(domain_error(positive_integer, #max_depth_term), _)}?);
+        }
+        let policy = RollupPolicy {
+            max_depth: max_depth as usize,
+            merge_ratio: merge_ratio_term.get_ex()?,
+            imprecise: imprecise_term.get_ex()?,
+        };
+        let dry_run: bool = dry_run_term.get_ex()?;
+
+        let plan = context.try_or_die(optimize(&store, &layer, &policy, dry_run))?;
+
+        let action = context.new_term_ref();
+        match plan.action {
+            RollupAction::None => action.unify(Atom::new("none"))?,
+            RollupAction::Rollup => action.unify(Atom::new("rollup"))?,
+            RollupAction::RollupUpto(upto) => {
+                let upto = name_to_string(upto);
+                action.unify(term!{context: rollup_upto(#upto)}?)?
+            }
+        }
+        let depth_before = plan.depth_before as u64;
+        let depth_after = plan.depth_after as u64;
+        let merged = plan.merged as u64;
+        report_term.unify(term!{context: rollup_report(#&action, #depth_before, #depth_after, #merged)}?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(index: usize) -> LayerId {
+        [index as u32, 0, 0, 0, 0]
+    }
+
+    fn plan(
+        action: RollupAction,
+        depth_before: usize,
+        depth_after: usize,
+        merged: usize,
+    ) -> RollupPlan {
+        RollupPlan {
+            action,
+            depth_before,
+            depth_after,
+            merged,
+        }
+    }
+
+    #[test]
+    fn plan_rollup_table() {
+        let cases: Vec<(&str, usize, f64, Vec<usize>, RollupPlan)> = vec![
+            ("empty stack", 8, 4.0, vec![], RollupPlan::nothing(0)),
+            ("at max depth", 8, 4.0, vec![1; 8], RollupPlan::nothing(8)),
+            (
+                "one past max depth without a large layer",
+                8,
+                4.0,
+                vec![1; 9],
+                plan(RollupAction::Rollup, 9, 1, 9),
+            ),
+            (
+                "onto a large base",
+                2,
+                2.0,
+                vec![10, 1, 1, 1],
+                plan(RollupAction::RollupUpto(id(0)), 4, 2, 3),
+            ),
+            (
+                "merge ratio met exactly",
+                2,
+                2.0,
+                vec![6, 1, 1, 1],
+                plan(RollupAction::RollupUpto(id(0)), 4, 2, 3),
+            ),
+            (
+                "merge ratio just missed",
+                2,
+                2.0,
+                vec![5, 1, 1, 1],
+                plan(RollupAction::Rollup, 4, 1, 4),
+            ),
+            (
+                "newest large layer within max depth",
+                3,
+                1.0,
+                vec![100, 50, 1, 1, 1],
+                plan(RollupAction::RollupUpto(id(1)), 5, 3, 3),
+            ),
+            (
+                "large layer that would leave too deep a stack",
+                2,
+                1.0,
+                vec![1, 1, 100, 1, 1],
+                plan(RollupAction::Rollup, 5, 1, 5),
+            ),
+            (
+                "never onto the layer right below the top",
+                3,
+                1.0,
+                vec![1, 1, 100, 1],
+                plan(RollupAction::Rollup, 4, 1, 4),
+            ),
+            (
+                "zero merge ratio",
+                2,
+                0.0,
+                vec![1, 1, 1, 1],
+                plan(RollupAction::RollupUpto(id(0)), 4, 2, 3),
+            ),
+            (
+                "max depth of one",
+                1,
+                0.0,
+                vec![100, 1],
+                plan(RollupAction::Rollup, 2, 1, 2),
+            ),
+        ];
+
+        for (name, max_depth, merge_ratio, changes, expected) in cases {
+            let stack: Vec<_> = changes
+                .into_iter()
+                .enumerate()
+                .map(|(index, changes)| (id(index), changes))
+                .collect();
+            let policy = RollupPolicy {
+                max_depth,
+                merge_ratio,
+                imprecise: false,
+            };
+
+            assert_eq!(expected, plan_rollup(&stack, &policy), "{name}");
+        }
+    }
+}
@@ -1,42 +1,53 @@
 use crate::builder::*;
+use crate::label_log::*;
 use crate::layer::*;
 use crate::named_graph::*;
 use std::io::{self, Cursor};
+use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::Arc;
 use swipl::prelude::*;
 use terminus_store::storage::archive::DirectoryArchiveBackend;
 use terminus_store::storage::archive::LruArchiveBackend;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::memory::{MemoryLabelStore, MemoryLayerStore};
 use terminus_store::storage::CachedLayerStore;
 use terminus_store::storage::LockingHashMapLayerCache;
 use terminus_store::storage::{
-    archive::ArchiveLayerStore, name_to_string, pack_layer_parents, string_to_name, PackError,
+    archive::ArchiveLayerStore, name_to_string, pack_layer_parents, string_to_name, LabelStore,
+    LayerStore, PackError,
 };
 use terminus_store::store::{sync::*, Store};
 
 use terminusdb_grpc_labelstore_client::GrpcLabelStore;
 
 predicates! {
-    pub semidet fn open_memory_store(_context, term) {
-        let store = open_sync_memory_store();
+    pub semidet fn open_memory_store(context, term) {
+        let store = context.try_or_die(LoggedStore::open(None, MemoryLayerStore::new()))?;
         term.unify(&WrappedStore(store))
     }
 
-    pub semidet fn open_directory_store(_context, dir_term, out_term) {
+    pub semidet fn open_directory_store(context, dir_term, out_term) {
         let dir: PrologText = dir_term.get_ex()?;
-        let store = open_sync_directory_store(&*dir);
+        let store = context.try_or_die(LoggedStore::open(Some(&*dir), DirectoryLayerStore::new(&*dir)))?;
         out_term.unify(&WrappedStore(store))
     }
 
-    pub semidet fn open_raw_archive_store(_context, dir_term, out_term) {
+    pub semidet fn open_raw_archive_store(context, dir_term, out_term) {
         let dir: PrologText = dir_term.get_ex()?;
-        let store = open_sync_raw_archive_store(&*dir);
+        let layer_backend = DirectoryArchiveBackend::new((&*dir).into());
+        let layer_store = ArchiveLayerStore::new(layer_backend.clone(), layer_backend);
+        let store = context.try_or_die(LoggedStore::open(Some(&*dir), layer_store))?;
         out_term.unify(&WrappedStore(store))
     }
 
-    pub semidet fn open_archive_store(_context, dir_term, cache_size_term, out_term) {
+    pub semidet fn open_archive_store(context, dir_term, cache_size_term, out_term) {
         let dir: PrologText = dir_term.get_ex()?;
         let cache_size: usize = cache_size_term.get_ex::<u64>()? as usize;
-        let store = open_sync_archive_store(&*dir, cache_size);
+        let directory_layer_backend = DirectoryArchiveBackend::new((&*dir).into());
+        let layer_backend = LruArchiveBackend::new(directory_layer_backend.clone(), directory_layer_backend, cache_size);
+        let layer_store = ArchiveLayerStore::new(layer_backend.clone(), layer_backend);
+        let store = context.try_or_die(LoggedStore::open(Some(&*dir), layer_store))?;
         out_term.unify(&WrappedStore(store))
     }
 
@@ -51,9 +62,9 @@ predicates! {
 
         let label_store = context.try_or_die_generic(task_sync(GrpcLabelStore::new(address.to_string(), pool_size as usize)))?;
 
-        let store = SyncStore::wrap(Store::new(label_store, layer_store));
+        let store = SyncStore::wrap(Store::new(label_store, layer_store.clone()));
 
-        out_term.unify(&WrappedStore(store))
+        out_term.unify(&WrappedStore(LoggedStore::new(store, layer_store, None, None)))
     }
 
     pub semidet fn open_write(context, store_or_graph_or_layer_term, builder_term) {
@@ -165,4 +176,91 @@ predicates! {
     }
 }
 
-wrapped_clone_blob!("store", pub WrappedStore, SyncStore, defaults);
+/// A store, together with its layer store, the log of its label
+/// changes if it keeps one, and a way to set several heads at once if
+/// it supports that.
+#[derive(Clone)]
+pub struct LoggedStore {
+    store: SyncStore,
+    layer_store: CachedLayerStore,
+    label_log: Option<LabelLog>,
+    head_transactions: Option<Arc<dyn HeadTransactions>>,
+}
+
+impl LoggedStore {
+    pub fn new(
+        store: SyncStore,
+        layer_store: CachedLayerStore,
+        label_log: Option<LabelLog>,
+        head_transactions: Option<Arc<dyn HeadTransactions>>,
+    ) -> Self {
+        Self {
+            store,
+            layer_store,
+            label_log,
+            head_transactions,
+        }
+    }
+
+    /// A store on the given layer store that logs its label changes
+    /// and supports head transactions. Its labels are kept in `dir`,
+    /// or without one, in memory.
+    pub fn open<L: LayerStore + 'static>(dir: Option<&str>, layer_store: L) -> io::Result<Self> {
+        let layer_store = CachedLayerStore::new(layer_store, LockingHashMapLayerCache::new());
+        match dir {
+            Some(dir) => {
+                let label_log = LabelLog::directory(dir);
+                let label_store = TransactionalLabelStore::directory(
+                    LoggingLabelStore::new(DirectoryLabelStore::new(dir), label_log.clone()),
+                    dir,
+                )?;
+
+                Ok(Self::transactional(label_store, label_log, layer_store))
+            }
+            None => {
+                let label_log = LabelLog::memory();
+                let label_store = TransactionalLabelStore::memory(LoggingLabelStore::new(
+                    MemoryLabelStore::new(),
+                    label_log.clone(),
+                ));
+
+                Ok(Self::transactional(label_store, label_log, layer_store))
+            }
+        }
+    }
+
+    fn transactional<L: LabelStore + 'static>(
+        label_store: TransactionalLabelStore<L>,
+        label_log: LabelLog,
+        layer_store: CachedLayerStore,
+    ) -> Self {
+        let transactions = Arc::new(label_store.clone());
+        let store = SyncStore::wrap(Store::new(label_store, layer_store.clone()));
+
+        Self::new(store, layer_store, Some(label_log), Some(transactions))
+    }
+
+    /// The layer store, which loads layers the way queries see them,
+    /// through their rollups.
+    pub fn layer_store(&self) -> &CachedLayerStore {
+        &self.layer_store
+    }
+
+    pub fn label_log(&self) -> Option<&LabelLog> {
+        self.label_log.as_ref()
+    }
+
+    pub fn head_transactions(&self) -> Option<&dyn HeadTransactions> {
+        self.head_transactions.as_deref()
+    }
+}
+
+impl Deref for LoggedStore {
+    type Target = SyncStore;
+
+    fn deref(&self) -> &SyncStore {
+        &self.store
+    }
+}
+
+wrapped_clone_blob!("store", pub WrappedStore, LoggedStore, defaults);
@@ -0,0 +1,651 @@
+//! Integrity checking for directory and archive stores.
+//!
+//! A commit that gets interrupted, for example because the disk ran
+//! full, can leave layers behind that are incomplete, or labels that
+//! point at layers that never got written. `verify_store_at` walks all
+//! labels and layers of a store and reports what it finds.
+//!
+//! Every layer is fully loaded and the triples it adds and removes are
+//! resolved to strings, so damaged dictionaries and indexes are found
+//! too. Besides that, the additions and removals of every child layer
+//! are checked against its parent as it loads now. A child records its
+//! changes against the content its parent had when the child was
+//! built, so if they don't apply, the parent's rollup has diverged from
+//! the original, or the child itself is damaged.
+//!
+//! In archive stores, every rolled up layer is also loaded without its
+//! rollup, and both are checked to hold the same triples. Directory
+//! stores keep rollups among the files of the layer itself, so there a
+//! rollup is only checked through the children of the rolled up layer.
+//!
+//! The check should be run while no other process is using the store.
+//! Encrypted archive stores can't be read without their keys, and are
+//! refused.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use swipl::prelude::*;
+use terminus_store::layer::InternalLayer;
+use terminus_store::storage::archive::{
+    ArchiveLayerStore, ArchiveMetadataBackend, DirectoryArchiveBackend,
+};
+use terminus_store::storage::consts::LayerFileEnum;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::{
+    name_to_string, CachedLayerStore, LabelStore, LayerStore, LockingHashMapLayerCache,
+};
+use terminus_store::store::{sync::*, Store};
+use terminus_store::Layer;
+use terminusdb_store_convert::encryption::has_encrypted_archives;
+
+use crate::layer_ids::LayerId;
+
+/// Broken layers are moved into this directory inside the store
+/// directory.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// The cache size used for the archive store opened to load layers,
+/// in megabytes.
+pub const VERIFY_CACHE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    Directory,
+    Archive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A label points at a layer that does not exist.
+    DanglingLabel { label: String, layer: LayerId },
+    /// A label points at a layer that can't be loaded.
+    BrokenLabel { label: String, layer: LayerId },
+    /// A layer's parent does not exist.
+    MissingParent { layer: LayerId, parent: LayerId },
+    /// A layer is fine by itself, but one of its ancestors is broken.
+    BrokenAncestor { layer: LayerId, ancestor: LayerId },
+    /// A layer's files are incomplete or don't parse.
+    CorruptLayer { layer: LayerId, message: String },
+    /// A layer's changes don't apply to its parent as it loads now.
+    InconsistentDelta { layer: LayerId, parent: LayerId },
+    /// A rolled up layer's rollup doesn't hold the same triples as the
+    /// layer itself.
+    RollupMismatch { layer: LayerId },
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub problems: Vec<Problem>,
+    /// The layers that were moved into quarantine.
+    pub quarantined: Vec<LayerId>,
+}
+
+/// Parse `directory` or `archive` into a store kind.
+pub fn store_kind_from_term<C: QueryableContextType>(
+    context: &Context<'_, C>,
+    kind_term: &Term,
+) -> PrologResult<StoreKind> {
+    let kind: PrologText = kind_term.get_ex()?;
+    match &*kind {
+        "directory" => Ok(StoreKind::Directory),
+        "archive" => Ok(StoreKind::Archive),
+        _ => context.raise_exception(
+            &term! {context: error(domain_error(oneof([directory, archive]), #kind_term), _)}?,
+        ),
+    }
+}
+
+/// The parts of a store that the check needs. The label and layer
+/// stores give access to the raw metadata, while layers are loaded
+/// through a regular store so that rollups are used like they would
+/// be in normal operation. For archive stores, `originals` loads
+/// layers while ignoring their rollups.
+struct OpenedStore {
+    labels: DirectoryLabelStore,
+    layers: Box<dyn LayerStore>,
+    store: SyncStore,
+    originals: Option<SyncStore>,
+}
+
+/// An archive metadata backend that hides all rollups, so layers are
+/// loaded from their own archives.
+#[derive(Clone)]
+struct WithoutRollups<M>(M);
+
+#[async_trait]
+impl<M: ArchiveMetadataBackend> ArchiveMetadataBackend for WithoutRollups<M> {
+    async fn get_layer_names(&self) -> io::Result<Vec<LayerId>> {
+        self.0.get_layer_names().await
+    }
+
+    async fn layer_exists(&self, id: LayerId) -> io::Result<bool> {
+        self.0.layer_exists(id).await
+    }
+
+    async fn layer_size(&self, id: LayerId) -> io::Result<u64> {
+        self.0.layer_size(id).await
+    }
+
+    async fn layer_file_exists(&self, id: LayerId, file_type: LayerFileEnum) -> io::Result<bool> {
+        self.0.layer_file_exists(id, file_type).await
+    }
+
+    async fn get_layer_structure_size(
+        &self,
+        id: LayerId,
+        file_type: LayerFileEnum,
+    ) -> io::Result<usize> {
+        self.0.get_layer_structure_size(id, file_type).await
+    }
+
+    async fn get_rollup(&self, _id: LayerId) -> io::Result<Option<LayerId>> {
+        Ok(None)
+    }
+
+    async fn set_rollup(&self, _id: LayerId, _rollup: LayerId) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "rollups can't be set while they are hidden",
+        ))
+    }
+
+    async fn get_parent(&self, id: LayerId) -> io::Result<Option<LayerId>> {
+        self.0.get_parent(id).await
+    }
+}
+
+fn open_store(path: &Path, kind: StoreKind) -> OpenedStore {
+    let labels = DirectoryLabelStore::new(path);
+    match kind {
+        StoreKind::Directory => OpenedStore {
+            labels,
+            layers: Box::new(CachedLayerStore::new(
+                DirectoryLayerStore::new(path),
+                LockingHashMapLayerCache::new(),
+            )),
+            store: open_sync_directory_store(path),
+            originals: None,
+        },
+        StoreKind::Archive => {
+            let backend = DirectoryArchiveBackend::new(path.into());
+            OpenedStore {
+                labels,
+                layers: Box::new(CachedLayerStore::new(
+                    ArchiveLayerStore::new(backend.clone(), backend),
+                    LockingHashMapLayerCache::new(),
+                )),
+                store: open_sync_archive_store(path, VERIFY_CACHE_SIZE),
+                originals: Some(SyncStore::wrap(Store::new(
+                    DirectoryLabelStore::new(path),
+                    CachedLayerStore::new(
+                        ArchiveLayerStore::new(WithoutRollups(backend.clone()), backend),
+                        LockingHashMapLayerCache::new(),
+                    ),
+                ))),
+            }
+        }
+    }
+}
+
+/// Fail on encrypted archive stores, whose layers would otherwise all
+/// look corrupt.
+pub(crate) fn reject_encrypted(path: &Path, kind: StoreKind) -> io::Result<()> {
+    if kind == StoreKind::Archive && has_encrypted_archives(path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the store at {} is encrypted", path.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic while loading layer".to_string()
+    }
+}
+
+/// Run a check that may panic on corrupt data, turning the panic into
+/// an error.
+fn guarded<T, F: FnOnce() -> io::Result<T>>(f: F) -> Result<T, String> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(payload) => Err(panic_message(payload)),
+    }
+}
+
+/// Load a layer and resolve every triple it adds or removes, returning
+/// the loaded layer. The triples of its ancestors are resolved when
+/// those are checked themselves.
+fn load_layer(store: &SyncStore, id: LayerId) -> io::Result<SyncStoreLayer> {
+    let layer = store
+        .get_layer_from_id(id)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "layer could not be found"))?;
+    for triple in layer.triple_additions().chain(layer.triple_removals()) {
+        if layer.id_subject(triple.subject).is_none()
+            || layer.id_predicate(triple.predicate).is_none()
+            || layer.id_object(triple.object).is_none()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "triple {} {} {} refers to an unknown id",
+                    triple.subject, triple.predicate, triple.object
+                ),
+            ));
+        }
+    }
+
+    Ok(layer)
+}
+
+fn is_rollup(layers: &dyn LayerStore, id: LayerId) -> io::Result<bool> {
+    Ok(task_sync(layers.get_layer(id))?
+        .map(|layer| matches!(*layer, InternalLayer::Rollup(_)))
+        .unwrap_or(false))
+}
+
+/// Check that the changes of a child apply to its parent: additions
+/// can't already be there, and removals have to be.
+fn delta_applies(layer: &SyncStoreLayer, parent: &SyncStoreLayer) -> bool {
+    layer
+        .triple_additions()
+        .all(|t| !parent.triple_exists(t.subject, t.predicate, t.object))
+        && layer
+            .triple_removals()
+            .all(|t| parent.triple_exists(t.subject, t.predicate, t.object))
+}
+
+/// Check that a rolled up layer holds exactly the triples of its
+/// original.
+fn rollup_matches(rolled_up: &SyncStoreLayer, original: &SyncStoreLayer) -> bool {
+    rolled_up.triples().count() == original.triples().count()
+        && original.triples().all(|t| {
+            original
+                .id_triple_to_string(&t)
+                .map(|triple| rolled_up.value_triple_exists(&triple))
+                .unwrap_or(false)
+        })
+}
+
+/// Order layers so that parents come before their children. Layers
+/// whose parent is not part of `parents` come first.
+fn parents_first(parents: &HashMap<LayerId, Option<LayerId>>) -> Vec<LayerId> {
+    let mut ordered = Vec::with_capacity(parents.len());
+    let mut emitted = HashSet::with_capacity(parents.len());
+    let mut ids: Vec<LayerId> = parents.keys().cloned().collect();
+    ids.sort();
+    for id in ids {
+        let mut chain = Vec::new();
+        let mut cur = Some(id);
+        while let Some(c) = cur {
+            // checking the chain itself stops us on a parent cycle
+            if emitted.contains(&c) || chain.contains(&c) || !parents.contains_key(&c) {
+                break;
+            }
+            chain.push(c);
+            cur = parents[&c];
+        }
+        for c in chain.into_iter().rev() {
+            emitted.insert(c);
+            ordered.push(c);
+        }
+    }
+
+    ordered
+}
+
+/// The files and directories that make up a layer.
+///
+/// Layers are stored under a directory named after the first
+/// characters of their id, as a directory or files named after the id.
+/// Rather than depend on the exact layout of each backend, any entry
+/// in the store directory or one of its prefix directories that
+/// starts with the layer id is included.
+pub fn layer_entries(path: &Path, id: LayerId) -> io::Result<Vec<PathBuf>> {
+    let name = name_to_string(id);
+    let mut result = Vec::new();
+    for dir in [path.to_path_buf(), path.join(&name[..3])] {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(&name) {
+                result.push(entry.path());
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Move everything belonging to a layer into the quarantine directory.
+fn quarantine_layer(path: &Path, id: LayerId) -> io::Result<bool> {
+    let entries = layer_entries(path, id)?;
+    if entries.is_empty() {
+        return Ok(false);
+    }
+
+    let quarantine = path.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine)?;
+    for entry in entries {
+        let target = quarantine.join(entry.file_name().unwrap());
+        fs::rename(entry, target)?;
+    }
+
+    Ok(true)
+}
+
+/// Check the integrity of the store at the given path.
+///
+/// With `repair`, layers that can't be loaded, either because they
+/// are damaged themselves or because an ancestor is, are moved into
+/// the quarantine directory of the store. Labels are never changed, as
+/// pointing them elsewhere would lose history. Those that point at
+/// broken layers are reported so they can be reset by hand.
+pub fn verify_store_at(path: &Path, kind: StoreKind, repair: bool) -> io::Result<VerifyReport> {
+    reject_encrypted(path, kind)?;
+    let opened = open_store(path, kind);
+    let mut report = VerifyReport::default();
+
+    let layer_ids = task_sync(opened.layers.layers())?;
+    let existing: HashSet<LayerId> = layer_ids.iter().cloned().collect();
+
+    // first look at the metadata only, to find out which layers can't
+    // be loaded anyway
+    let mut parents = HashMap::with_capacity(layer_ids.len());
+    let mut broken: HashMap<LayerId, LayerId> = HashMap::new();
+    for id in layer_ids.iter() {
+        match guarded(|| task_sync(opened.layers.get_layer_parent_name(*id))) {
+            Ok(Some(parent)) if !existing.contains(&parent) => {
+                report
+                    .problems
+                    .push(Problem::MissingParent { layer: *id, parent });
+                broken.insert(*id, *id);
+                parents.insert(*id, Some(parent));
+            }
+            Ok(parent) => {
+                parents.insert(*id, parent);
+            }
+            Err(message) => {
+                report.problems.push(Problem::CorruptLayer {
+                    layer: *id,
+                    message,
+                });
+                broken.insert(*id, *id);
+                parents.insert(*id, None);
+            }
+        }
+    }
+
+    // then load the layers, parents first, so that a broken layer is
+    // reported once and its descendants refer to it
+    let mut loaded: HashMap<LayerId, SyncStoreLayer> = HashMap::new();
+    let order = parents_first(&parents);
+    let mut children: HashMap<LayerId, usize> = HashMap::new();
+    for id in order.iter() {
+        if let Some(parent) = parents[id] {
+            *children.entry(parent).or_default() += 1;
+        }
+    }
+    for id in order {
+        let parent = parents[&id];
+        if let Some(ancestor) = parent.and_then(|p| broken.get(&p).cloned()) {
+            if !broken.contains_key(&id) {
+                report.problems.push(Problem::BrokenAncestor {
+                    layer: id,
+                    ancestor,
+                });
+                broken.insert(id, ancestor);
+            }
+        }
+        if broken.contains_key(&id) {
+            continue;
+        }
+
+        let layer = match guarded(|| load_layer(&opened.store, id)) {
+            Ok(layer) => layer,
+            Err(message) => {
+                report
+                    .problems
+                    .push(Problem::CorruptLayer { layer: id, message });
+                broken.insert(id, id);
+                continue;
+            }
+        };
+        let rolled_up = match guarded(|| is_rollup(&*opened.layers, id)) {
+            Ok(rolled_up) => rolled_up,
+            Err(message) => {
+                report
+                    .problems
+                    .push(Problem::CorruptLayer { layer: id, message });
+                broken.insert(id, id);
+                continue;
+            }
+        };
+        if let Some(parent) = parent {
+            let parent_layer = loaded[&parent].clone();
+            // only keep parents around while their children still
+            // need them
+            let remaining = children.get_mut(&parent).unwrap();
+            *remaining -= 1;
+            if *remaining == 0 {
+                loaded.remove(&parent);
+            }
+
+            // a rolled up layer loads with the changes of its rollup
+            // rather than its own, so it is compared with its original
+            // below instead
+            if !rolled_up {
+                match guarded(|| Ok(delta_applies(&layer, &parent_layer))) {
+                    Ok(true) => {}
+                    Ok(false) => report
+                        .problems
+                        .push(Problem::InconsistentDelta { layer: id, parent }),
+                    Err(message) => {
+                        report
+                            .problems
+                            .push(Problem::CorruptLayer { layer: id, message });
+                        broken.insert(id, id);
+                        continue;
+                    }
+                }
+            }
+        }
+        if let (true, Some(originals)) = (rolled_up, &opened.originals) {
+            // the layer loads fine through its rollup, so a damaged
+            // original is reported, but doesn't make the layer broken
+            match guarded(|| Ok(rollup_matches(&layer, &load_layer(originals, id)?))) {
+                Ok(true) => {}
+                Ok(false) => report.problems.push(Problem::RollupMismatch { layer: id }),
+                Err(message) => report
+                    .problems
+                    .push(Problem::CorruptLayer { layer: id, message }),
+            }
+        }
+        if children.contains_key(&id) {
+            loaded.insert(id, layer);
+        }
+    }
+
+    for label in task_sync(opened.labels.labels())? {
+        if let Some(layer) = label.layer {
+            if !existing.contains(&layer) {
+                report.problems.push(Problem::DanglingLabel {
+                    label: label.name,
+                    layer,
+                });
+            } else if broken.contains_key(&layer) {
+                report.problems.push(Problem::BrokenLabel {
+                    label: label.name,
+                    layer,
+                });
+            }
+        }
+    }
+
+    if repair {
+        let mut to_quarantine: Vec<LayerId> = broken.keys().cloned().collect();
+        to_quarantine.sort();
+        for id in to_quarantine {
+            if quarantine_layer(path, id)? {
+                report.quarantined.push(id);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn problem_term<'a, C: QueryableContextType>(
+    context: &'a Context<'_, C>,
+    problem: Problem,
+) -> PrologResult<Term<'a>> {
+    match problem {
+        Problem::DanglingLabel { label, layer } => {
+            let layer = name_to_string(layer);
+            term! {context: dangling_label(#label, #layer)}
+        }
+        Problem::BrokenLabel { label, layer } => {
+            let layer = name_to_string(layer);
+            term! {context: broken_label(#label, #layer)}
+        }
+        Problem::MissingParent { layer, parent } => {
+            let layer = name_to_string(layer);
+            let parent = name_to_string(parent);
+            term! {context: missing_parent(#layer, #parent)}
+        }
+        Problem::BrokenAncestor { layer, ancestor } => {
+            let layer = name_to_string(layer);
+            let ancestor = name_to_string(ancestor);
+            term! {context: broken_ancestor(#layer, #ancestor)}
+        }
+        Problem::CorruptLayer { layer, message } => {
+            let layer = name_to_string(layer);
+            term! {context: corrupt_layer(#layer, #message)}
+        }
+        Problem::InconsistentDelta { layer, parent } => {
+            let layer = name_to_string(layer);
+            let parent = name_to_string(parent);
+            term! {context: inconsistent_delta(#layer, #parent)}
+        }
+        Problem::RollupMismatch { layer } => {
+            let layer = name_to_string(layer);
+            term! {context: rollup_mismatch(#layer)}
+        }
+    }
+}
+
+predicates! {
+    pub semidet fn verify_store(context, path_term, kind_term, repair_term, problems_term, quarantined_term) {
+        let path: PrologText = path_term.get_ex()?;
+        let kind = store_kind_from_term(context, kind_term)?;
+        let repair: bool = repair_term.get_ex()?;
+
+        let report = context.try_or_die(verify_store_at(Path::new(&*path), kind, repair))?;
+
+        let mut problem_terms = Vec::with_capacity(report.problems.len());
+        for problem in report.problems {
+            problem_terms.push(problem_term(context, problem)?);
+        }
+        problems_term.unify(problem_terms.as_slice())?;
+
+        let quarantined: Vec<String> = report.quarantined.into_iter().map(name_to_string).collect();
+        quarantined_term.unify(quarantined.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+
+    /// Create an archive store with a base layer and a child on top of
+    /// it, labelled `animals`, returning the id of the child.
+    fn create_store(path: &Path) -> LayerId {
+        let store = open_sync_archive_store(path, VERIFY_CACHE_SIZE);
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("cow", "says", "moo"))
+            .unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("duck", "says", "quack"))
+            .unwrap();
+        let child = builder.commit().unwrap();
+        let graph = store.create("animals").unwrap();
+        assert!(graph.set_head(&child).unwrap());
+
+        child.name()
+    }
+
+    #[test]
+    fn intact_store_has_no_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let child = create_store(dir.path());
+        let store = open_sync_archive_store(dir.path(), VERIFY_CACHE_SIZE);
+        store
+            .get_layer_from_id(child)
+            .unwrap()
+            .unwrap()
+            .rollup()
+            .unwrap();
+
+        let report = verify_store_at(dir.path(), StoreKind::Archive, false).unwrap();
+        assert_eq!(Vec::<Problem>::new(), report.problems);
+    }
+
+    #[test]
+    fn dangling_labels_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let child = create_store(dir.path());
+        let backend = DirectoryArchiveBackend::new(dir.path().into());
+        for entry in layer_entries(dir.path(), child).unwrap() {
+            fs::remove_file(entry).unwrap();
+        }
+        assert!(!task_sync(backend.layer_exists(child)).unwrap());
+
+        let report = verify_store_at(dir.path(), StoreKind::Archive, false).unwrap();
+        assert_eq!(
+            vec![Problem::DanglingLabel {
+                label: "animals".to_string(),
+                layer: child
+            }],
+            report.problems
+        );
+    }
+
+    #[test]
+    fn diverging_rollups_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let child = create_store(dir.path());
+        let store = open_sync_archive_store(dir.path(), VERIFY_CACHE_SIZE);
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .add_value_triple(ValueTriple::new_node("pig", "says", "oink"))
+            .unwrap();
+        let unrelated = builder.commit().unwrap();
+        let backend = DirectoryArchiveBackend::new(dir.path().into());
+        task_sync(backend.set_rollup(child, unrelated.name())).unwrap();
+
+        let report = verify_store_at(dir.path(), StoreKind::Archive, false).unwrap();
+        assert_eq!(
+            vec![Problem::RollupMismatch { layer: child }],
+            report.problems
+        );
+    }
+}
@@ -479,6 +479,11 @@ document_handler(get, Path, Request, System_DB, Auth) :-
             param_value_search_or_json_optional(Search, JSON, minimized, boolean, true, Minimized),
             param_value_search_or_json_optional(Search, JSON, as_list, boolean, false, As_List),
             param_value_search_or_json_optional(Search, JSON, unfold, boolean, true, Unfold),
+            param_value_search_or_json_optional(Search, JSON, max_depth, nonnegative_integer, unlimited, Max_Depth),
+            param_value_search_or_json_optional(Search, JSON, fields, list, _, Fields),
+            param_value_search_or_json_optional(Search, JSON, exclude, list, _, Exclude),
+            param_value_search_or_json_optional(Search, JSON, canonical, boolean, false, Canonical),
+            document_shape(Fields, Exclude, Max_Depth, Canonical, Shape),
             param_value_search_or_json_optional(Search, JSON, id, non_empty_atom, _, Id),
             param_value_search_or_json_optional(Search, JSON, type, non_empty_atom, _, Type),
 
@@ -505,7 +510,8 @@ document_handler(get, Path, Request, System_DB, Auth) :-
                          as_list: As_List,
                          compress: Compress_Ids,
                          unfold: Unfold,
-                         minimized: Minimized
+                         minimized: Minimized,
+                         shape: Shape
                      },
 
             api_read_document_selector(
@@ -576,6 +582,9 @@ document_handler(delete, Path, Request, System_DB, Auth) :-
             param_value_search_optional(Search, type, non_empty_atom, _, Type),
             param_value_search_optional(Search, require_migration, boolean, false, Require_Migration),
             param_value_search_optional(Search, allow_destructive_migration, boolean, false, Allow_Destructive_Migration),
+            param_value_search_optional(Search, if_match, non_empty_atom, _, If_Match),
+            die_if((nonvar(If_Match), var(Id)),
+                   error(if_match_requires_id, _)),
 
             read_data_version_header(Request, Requested_Data_Version),
             Options = options{
@@ -583,7 +592,8 @@ document_handler(delete, Path, Request, System_DB, Auth) :-
                           message : Message,
                           graph_type : Graph_Type,
                           require_migration: Require_Migration,
-                          allow_destructive_migration: Allow_Destructive_Migration
+                          allow_destructive_migration: Allow_Destructive_Migration,
+                          if_match: If_Match
                       },
 
             (   Nuke = true
@@ -619,6 +629,7 @@ document_handler(put, Path, Request, System_DB, Auth) :-
             param_value_search_optional(Search, raw_json, boolean, false, Raw_JSON),
             param_value_search_optional(Search, require_migration, boolean, false, Require_Migration),
             param_value_search_optional(Search, allow_destructive_migration, boolean, false, Allow_Destructive_Migration),
+            param_value_search_optional(Search, if_match, non_empty_atom, _, If_Match),
 
             read_data_version_header(Request, Requested_Data_Version),
             Options = options{
@@ -628,7 +639,8 @@ document_handler(put, Path, Request, System_DB, Auth) :-
                 create : Create,
                 raw_json : Raw_JSON,
                 require_migration: Require_Migration,
-                allow_destructive_migration: Allow_Destructive_Migration
+                allow_destructive_migration: Allow_Destructive_Migration,
+                if_match : If_Match
             },
             api_replace_documents(System_DB, Auth, Path, Stream, Requested_Data_Version, New_Data_Version, Ids, Options),
 
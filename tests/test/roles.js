@@ -62,4 +62,50 @@ describe('roles', function () {
     expect(resultGet2.status).to.equal(200)
     expect(resultGet2.body.action).to.deep.equal(['meta_read_access'])
   })
+
+  it('passes add and update with access policies', async function () {
+    const agent = new Agent().auth()
+    const roleName = util.randomString()
+    const policy = { class: 'Person', hide: ['salary'], mask: ['email'] }
+    const resultPost = await agent
+      .post('/api/roles')
+      .send({
+        name: roleName,
+        action: ['instance_read_access'],
+        policy: [policy],
+      })
+    expect(resultPost.status).to.equal(200)
+
+    const resultGet1 = await agent
+      .get(`/api/roles/${roleName}`)
+    expect(resultGet1.status).to.equal(200)
+    expect(resultGet1.body.policy).to.have.lengthOf(1)
+    expect(resultGet1.body.policy[0]).to.deep.include({ '@type': 'AccessPolicy', class: 'Person' })
+    expect(resultGet1.body.policy[0].hide).to.deep.equal(['salary'])
+
+    // updating the actions keeps the policies
+    const resultPut1 = await agent
+      .put('/api/roles')
+      .send({
+        name: roleName,
+        action: ['instance_read_access', 'schema_read_access'],
+      })
+    expect(resultPut1.status).to.equal(200)
+    const resultGet2 = await agent
+      .get(`/api/roles/${roleName}`)
+    expect(resultGet2.body.policy).to.have.lengthOf(1)
+
+    const resultPut2 = await agent
+      .put('/api/roles')
+      .send({
+        name: roleName,
+        action: ['instance_read_access'],
+        policy: [{ class: 'Person', restriction: 'Public' }],
+      })
+    expect(resultPut2.status).to.equal(200)
+    const resultGet3 = await agent
+      .get(`/api/roles/${roleName}`)
+    expect(resultGet3.body.policy).to.have.lengthOf(1)
+    expect(resultGet3.body.policy[0]).to.deep.include({ class: 'Person', restriction: 'Public' })
+  })
 })